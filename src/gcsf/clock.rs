@@ -0,0 +1,18 @@
+use std::time::SystemTime;
+
+/// A source of the current time. `FileManager` and `DriveFacade` ask their clock instead of the
+/// system, so that tests can control the passing of time.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
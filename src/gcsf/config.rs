@@ -1,76 +1,555 @@
+use super::file::DEFAULT_DUPLICATE_NAME_TEMPLATE;
+use failure::{err_msg, Error};
+use settings;
+use std::cmp;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+/// The largest write which the fuse crate can receive.
+const MAX_FUSE_WRITE_BYTES: u32 = 16 * 1024 * 1024;
+
+/// How the contents in the disk cache are compressed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheCompression {
+    /// Contents are stored as they are.
+    None,
+    /// Contents are compressed with gzip.
+    Gzip,
+    /// Contents are compressed with Zstandard.
+    Zstd,
+}
+
 /// Provides a few properties of the file system that can be configured. Includes sensible
 /// defaults for the absent values.
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct Config {
     /// Show additional logging info?
     pub debug: Option<bool>,
+    /// The name of the root directory in debug output.
+    pub root_name: Option<String>,
     /// Perform a mount check and fail early if it fails.
     pub mount_check: Option<bool>,
+    /// How many times to retry a failed mount check.
+    pub mount_check_retries: Option<u32>,
+    /// Whether to give up if the mount check keeps failing.
+    pub mount_check_fatal: Option<bool>,
+    /// Only mount "My Drive", without any special directories.
+    pub mirror_mode: Option<bool>,
+    /// Refuse to mount a session whose account is already mounted by another session.
+    pub refuse_duplicate_accounts: Option<bool>,
+    /// Mount the file system again after its FUSE session ends.
+    pub auto_remount: Option<bool>,
     /// How long to cache the contents of a file after it has been accessed.
     pub cache_max_seconds: Option<u64>,
     /// How how many files to cache.
     pub cache_max_items: Option<u64>,
+    /// Files larger than this are streamed instead of cached.
+    pub cache_max_file_bytes: Option<u64>,
+    /// Where to store file contents between runs. The disk cache is disabled if absent.
+    pub cache_dir: Option<PathBuf>,
+    /// How the contents in `cache_dir` are compressed: "none", "gzip" or "zstd".
+    pub cache_compression: Option<String>,
+    /// Serve the last known content of files which can not be read after a failed sync.
+    pub serve_stale_on_sync_error: Option<bool>,
+    /// The Drive id of "My Drive", which is otherwise looked up on startup.
+    pub root_drive_id: Option<String>,
+    /// The Drive id of a Shared Drive which is mounted instead of "My Drive".
+    pub shared_drive_id: Option<String>,
+    /// Keep the content of files being edited in scratch files on disk instead of in memory.
+    pub scratch_on_disk: Option<bool>,
+    /// Expose the Drive labels of files as extended attributes.
+    pub fetch_labels: Option<bool>,
+    /// Expose the Drive descriptions of files as a writable extended attribute.
+    pub expose_descriptions: Option<bool>,
+    /// Expose the Drive parents of files as extended attributes.
+    pub expose_parents: Option<bool>,
+    /// List the `.` and `..` entries in every directory.
+    pub dot_entries: Option<bool>,
+    /// The offset added to the inode numbers of all files.
+    pub inode_base: Option<u64>,
+    /// How many ranges of a single large file to download concurrently.
+    pub download_parallelism: Option<usize>,
+    /// How many bytes streamed reads fetch around the requested offset.
+    pub download_chunk_size: Option<u64>,
     /// How long to cache the size and capacity of the file system.
     pub cache_statfs_seconds: Option<u64>,
+    /// How much free space to report when the real quota is unknown or unlimited.
+    pub reported_free_bytes: Option<u64>,
+    /// How many inodes `statfs` reports in total.
+    pub max_inodes: Option<u64>,
+    /// How much of the Drive quota to keep free by refusing writes.
+    pub reserve_bytes: Option<u64>,
     /// How many seconds to wait before checking for remote changes and updating them locally.
     pub sync_interval: Option<u64>,
+    /// How many seconds a sync may wait for remote changes before it is given up on.
+    pub sync_timeout_seconds: Option<u64>,
+    /// How many seconds to wait between the log lines which confirm that the mount is alive.
+    pub heartbeat_interval_seconds: Option<u64>,
+    /// How many seconds to spend uploading pending writes when the file system is unmounted.
+    pub shutdown_flush_timeout: Option<u64>,
+    /// How long before its expiry the access token should be refreshed.
+    pub token_refresh_margin: Option<u64>,
+    /// How many seconds Drive connections may be idle before TCP keepalive probes are sent.
+    pub tcp_keepalive_seconds: Option<u64>,
+    /// How many requests to Drive may be in flight at the same time, across all sessions.
+    pub global_api_concurrency: Option<usize>,
+    /// How many listings to request from Drive at the same time while populating.
+    pub api_concurrency: Option<usize>,
     /// Mount options.
     pub mount_options: Option<Vec<String>>,
+    /// The largest read request which the kernel may send, in bytes.
+    pub max_read_bytes: Option<u32>,
+    /// The largest write request which the kernel may send, in bytes.
+    pub max_write_bytes: Option<u32>,
+    /// Which of the mutually exclusive `allow_other` and `allow_root` mount options to keep if both
+    /// are present.
+    pub prefer_allow_other: Option<bool>,
     /// Config directory (see XDG_CONFIG_HOME).
     pub config_dir: Option<PathBuf>,
     /// Session name.
     pub session_name: Option<String>,
     /// If true, use InstalledRedirect auth flow instead of InstalledInteractive.
     pub authorize_using_code: Option<bool>,
+    /// How many seconds `gcsf login` waits for the authorization to be completed.
+    pub login_timeout_seconds: Option<u64>,
     /// If set to true, all files with identical name will get an increasing number attached to the suffix.
     pub rename_identical_files: Option<bool>,
+    /// The order in which Drive lists files, e.g. "createdTime".
+    pub list_order: Option<String>,
+    /// How the names of files with identical names are rendered.
+    pub duplicate_name_template: Option<String>,
     /// If set to true, will add an extension to special files (docs, presentations, sheets, drawings, sites), e.g. "\#.ods" for spreadsheets.
     pub add_extensions_to_special_files: Option<bool>,
+    /// What separates the names of special files from their added extensions.
+    pub special_extension_separator: Option<String>,
+    /// Replace control and zero-width characters in the names under which files are shown.
+    pub sanitize_control_chars: Option<bool>,
+    /// The formats in which special files are exported.
+    pub export_formats: Option<ExportFormats>,
+    /// Comma separated lists of formats in which special files are shown as a directory of exports.
+    pub multi_export: Option<ExportFormats>,
     /// If set to true, deleted files and folder will not be moved to Trash Folder, instead they get deleted permanently.
     pub skip_trash: Option<bool>,
+    /// How long deleted files are held locally before they are deleted on Drive.
+    pub delete_grace_seconds: Option<u64>,
+    /// After how many days files in Trash are deleted permanently.
+    pub trash_auto_empty_days: Option<u64>,
+    /// If set to true, shared files which can no longer be read are removed from "Shared with me".
+    pub hide_expired_shares: Option<bool>,
+    /// If set to true, editor temp files are only created on Drive once they are renamed.
+    pub defer_temp_uploads: Option<bool>,
+    /// When buffered writes are uploaded: on every "flush" or on the "release" of the last handle.
+    pub upload_on: Option<String>,
+    /// If set to true, Drive is only accessed with a read-only scope and the file system is read-only.
+    pub read_only: Option<bool>,
+    /// If set to true, files which are not owned by the account are read-only.
+    pub readonly_not_owned: Option<bool>,
     /// The Google OAuth client secret for Google Drive APIs (see https://console.developers.google.com)
     pub client_secret: Option<String>,
+    /// The base URL of the Google APIs, without a trailing slash.
+    pub api_base_url: Option<String>,
+    /// The user agent sent with every Drive API request.
+    pub user_agent: Option<String>,
+    /// Drive folders which should be placed at fixed paths in the file system.
+    pub folder_mappings: Option<Vec<FolderMapping>>,
+    /// Drive ids of files and folders which are left out of the file system.
+    pub exclude_ids: Option<Vec<String>>,
+    /// Glob patterns of files which are left out of the file system.
+    pub exclude_patterns: Option<Vec<String>>,
+    /// How many levels below the root folders are listed while mounting.
+    pub max_tree_depth: Option<usize>,
+}
+
+/// The formats in which special files (docs, sheets, slides, drawings, sites) are exported when
+/// they are read. Each format can be given either as a MIME type or as an extension, e.g. "pdf" or
+/// "svg". Absent values fall back to the defaults.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ExportFormats {
+    /// Export format for documents. Defaults to "odt".
+    pub document: Option<String>,
+    /// Export format for spreadsheets. Defaults to "ods".
+    pub spreadsheet: Option<String>,
+    /// Export format for presentations. Defaults to "odp".
+    pub presentation: Option<String>,
+    /// Export format for drawings. Defaults to "png".
+    pub drawing: Option<String>,
+    /// Export format for sites. Defaults to "txt". Most sites can not be exported, in which case
+    /// a placeholder containing a link to the site is shown instead.
+    pub site: Option<String>,
+}
+
+lazy_static! {
+    static ref DEFAULT_EXPORT_TYPES: HashMap<&'static str, &'static str> = hashmap! {
+        "application/vnd.google-apps.document" => "application/vnd.oasis.opendocument.text",
+        "application/vnd.google-apps.presentation" => "application/vnd.oasis.opendocument.presentation",
+        "application/vnd.google-apps.spreadsheet" => "application/vnd.oasis.opendocument.spreadsheet",
+        "application/vnd.google-apps.drawing" => "image/png",
+        "application/vnd.google-apps.site" => "text/plain",
+    };
+}
+
+lazy_static! {
+    /// Export formats which can be referred to by their extension instead of their MIME type.
+    static ref EXPORT_FORMAT_ALIASES: HashMap<&'static str, &'static str> = hashmap! {
+        "odt" => "application/vnd.oasis.opendocument.text",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "rtf" => "application/rtf",
+        "epub" => "application/epub+zip",
+        "html" => "text/html",
+        "txt" => "text/plain",
+        "pdf" => "application/pdf",
+        "ods" => "application/vnd.oasis.opendocument.spreadsheet",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "csv" => "text/csv",
+        "tsv" => "text/tab-separated-values",
+        "odp" => "application/vnd.oasis.opendocument.presentation",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "png" => "image/png",
+        "jpg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+    };
+}
+
+impl ExportFormats {
+    /// The configured export format for a given Drive MIME type, if any.
+    fn get(&self, mime_type: &str) -> Option<&String> {
+        match mime_type {
+            "application/vnd.google-apps.document" => self.document.as_ref(),
+            "application/vnd.google-apps.spreadsheet" => self.spreadsheet.as_ref(),
+            "application/vnd.google-apps.presentation" => self.presentation.as_ref(),
+            "application/vnd.google-apps.drawing" => self.drawing.as_ref(),
+            "application/vnd.google-apps.site" => self.site.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Places a Drive folder at a fixed path in the file system, regardless of its Drive hierarchy.
+#[derive(Deserialize, Clone, Debug)]
+pub struct FolderMapping {
+    /// The Drive id of the folder, or its path relative to the root of the file system.
+    pub drive_id_or_path: String,
+    /// The path (relative to the root of the file system) where the folder should appear.
+    pub mount_path: String,
 }
 
 impl Config {
+    /// Reads a config from a TOML file. Syntax errors and invalid values are reported along with
+    /// where they occur, e.g. "expected an equals, found a newline at line 3 column 5".
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let invalid = |e: settings::ConfigError| {
+            err_msg(format!("Invalid configuration file {:?}: {}", path, e))
+        };
+
+        let mut settings = settings::Config::default();
+        settings
+            .merge(settings::File::from(path))
+            .map_err(invalid)?;
+        settings.try_into::<Config>().map_err(invalid)
+    }
+
     /// Whether to show additional logging info.
     pub fn debug(&self) -> bool {
         self.debug.unwrap_or(false)
     }
 
+    /// The name of the root directory, as shown in debug output. It is never used as a path
+    /// component. Defaults to "/".
+    pub fn root_name(&self) -> String {
+        self.root_name.clone().unwrap_or_else(|| String::from("/"))
+    }
+
     /// Whether to perform a mount check before creating the file system and fail early if it fails.
     pub fn mount_check(&self) -> bool {
         self.mount_check.unwrap_or(true)
     }
 
+    /// How many times to retry a failed mount check, waiting a second between attempts.
+    pub fn mount_check_retries(&self) -> u32 {
+        self.mount_check_retries.unwrap_or(2)
+    }
+
+    /// Whether to give up if the mount check still fails after all retries. If false, GCSF tries
+    /// to mount the real file system anyway.
+    pub fn mount_check_fatal(&self) -> bool {
+        self.mount_check_fatal.unwrap_or(true)
+    }
+
+    /// Whether to mount an exact mirror of "My Drive": there are no special directories such as
+    /// "Trash", "Shared with me", ".search" or ".gcsf", and files outside of "My Drive" are left out.
+    pub fn mirror_mode(&self) -> bool {
+        self.mirror_mode.unwrap_or(false)
+    }
+
+    /// Whether to refuse mounting a session whose Drive account is already mounted by another
+    /// session. If false, GCSF only warns about it.
+    pub fn refuse_duplicate_accounts(&self) -> bool {
+        self.refuse_duplicate_accounts.unwrap_or(false)
+    }
+
+    /// Whether to mount the file system again after its FUSE session ends (e.g. because it was
+    /// unmounted externally) instead of exiting.
+    pub fn auto_remount(&self) -> bool {
+        self.auto_remount.unwrap_or(false)
+    }
+
     /// How long to cache the contents of a file after it has been accessed.
     pub fn cache_max_seconds(&self) -> Duration {
         Duration::from_secs(self.cache_max_seconds.unwrap_or(10))
     }
 
+    /// Files larger than this many bytes are never downloaded in full. Reads fetch just the
+    /// requested range from Drive instead. Only applies to files which are not exported. All files
+    /// are cached if absent.
+    pub fn cache_max_file_bytes(&self) -> Option<u64> {
+        self.cache_max_file_bytes
+    }
+
     /// How how many files to cache.
     pub fn cache_max_items(&self) -> u64 {
         self.cache_max_items.unwrap_or(10)
     }
 
+    /// How many bytes around the requested offset are fetched by reads from files which are
+    /// streamed because of `cache_max_file_bytes`. The chunk is kept in memory, so that further
+    /// reads within it are served without another request. One chunk is kept per file, for at
+    /// most `cache_max_items` files. Defaults to 8 MiB.
+    pub fn download_chunk_size(&self) -> u64 {
+        cmp::max(self.download_chunk_size.unwrap_or(8 * 1024 * 1024), 1)
+    }
+
+    /// How many ranges of a single large file to download concurrently. Bounded to 8 in order to
+    /// stay clear of Drive's rate limits. Defaults to 1, i.e. sequential downloads.
+    pub fn download_parallelism(&self) -> usize {
+        cmp::min(cmp::max(self.download_parallelism.unwrap_or(1), 1), 8)
+    }
+
+    /// The directory where this session stores file contents between runs, if the disk cache is
+    /// enabled.
+    pub fn cache_dir(&self) -> Option<PathBuf> {
+        self.cache_dir
+            .as_ref()
+            .map(|dir| dir.join(Path::new(self.session_name())))
+    }
+
+    /// How the contents in the disk cache are compressed. Unknown values leave them uncompressed.
+    pub fn cache_compression(&self) -> CacheCompression {
+        let compression = self
+            .cache_compression
+            .as_ref()
+            .map(|c| c.to_lowercase())
+            .unwrap_or_default();
+        match compression.as_str() {
+            "gzip" => CacheCompression::Gzip,
+            "zstd" => CacheCompression::Zstd,
+            _ => CacheCompression::None,
+        }
+    }
+
+    /// Whether the content of files changed by a sync is kept until they are read again. If the
+    /// sync fails and a file can not be read from Drive, reads fall back to its last known content
+    /// instead of failing, and the file is marked with the `user.gcsf.possibly_stale` extended
+    /// attribute.
+    pub fn serve_stale_on_sync_error(&self) -> bool {
+        self.serve_stale_on_sync_error.unwrap_or(false)
+    }
+
+    /// The directory where the content of files being edited is kept until it is flushed, if
+    /// `scratch_on_disk` is enabled. Scratch files live next to the disk cache, so `cache_dir` must
+    /// be set as well.
+    pub fn scratch_dir(&self) -> Option<PathBuf> {
+        if !self.scratch_on_disk.unwrap_or(false) {
+            return None;
+        }
+
+        self.cache_dir
+            .as_ref()
+            .map(|dir| dir.join(format!(".{}.scratch", self.session_name())))
+    }
+
+    /// Whether to expose the Drive labels of files as read-only extended attributes named
+    /// `user.drive.label.<labelId>.<fieldId>`. Each file's labels are fetched on first access.
+    pub fn fetch_labels(&self) -> bool {
+        self.fetch_labels.unwrap_or(false)
+    }
+
+    /// Whether to expose the Drive description of each file as the extended attribute
+    /// `user.drive.description`. Setting the attribute updates the description on Drive.
+    pub fn expose_descriptions(&self) -> bool {
+        self.expose_descriptions.unwrap_or(false)
+    }
+
+    /// Whether to expose the Drive parents of each file as the read-only extended attributes
+    /// `user.drive.parents` (comma-separated Drive ids) and `user.drive.parentCount`, which helps
+    /// finding out why files with several parents appear where they do.
+    pub fn expose_parents(&self) -> bool {
+        self.expose_parents.unwrap_or(false)
+    }
+
+    /// Whether directory listings start with the `.` and `..` entries, which some programs rely
+    /// on. The `..` entry of the root points to the root itself.
+    pub fn dot_entries(&self) -> bool {
+        self.dot_entries.unwrap_or(true)
+    }
+
+    /// The offset added to the inode numbers of all files, including the root and the special
+    /// directories, so that several mounts can use disjoint inode ranges. The kernel still refers
+    /// to the root as 1. Defaults to 0.
+    pub fn inode_base(&self) -> u64 {
+        self.inode_base.unwrap_or(0)
+    }
+
     /// How long to cache the size and capacity of the filesystem. These are the values reported by `df`.
     pub fn cache_statfs_seconds(&self) -> Duration {
         Duration::from_secs(self.cache_statfs_seconds.unwrap_or(100))
     }
 
+    /// How much free space to report when the real quota is unknown or unlimited. Defaults to 1 TiB.
+    pub fn reported_free_bytes(&self) -> u64 {
+        self.reported_free_bytes.unwrap_or(1 << 40)
+    }
+
+    /// How many inodes `statfs` reports in total. If absent, the total is computed from the
+    /// current number of files, since Drive does not limit the number of files.
+    pub fn max_inodes(&self) -> Option<u64> {
+        self.max_inodes.filter(|&max| max > 0)
+    }
+
+    /// How much of the Drive quota to keep free. Writes which would leave less than this are
+    /// refused with `ENOSPC`. Disabled (0) by default.
+    pub fn reserve_bytes(&self) -> u64 {
+        self.reserve_bytes.unwrap_or(0)
+    }
+
     /// How many seconds to wait before checking for remote changes and updating them locally.
     pub fn sync_interval(&self) -> Duration {
         Duration::from_secs(self.sync_interval.unwrap_or(10))
     }
 
-    /// A list of mount options.
+    /// How long a sync may wait for the list of remote changes. Changes are then fetched on a
+    /// separate thread, which is given up on if it takes longer, so that a connection which hangs
+    /// does not freeze the file system. The sync is retried after the next interval. No timeout
+    /// is enforced if absent or 0.
+    pub fn sync_timeout(&self) -> Option<Duration> {
+        self.sync_timeout_seconds
+            .filter(|&seconds| seconds > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// How often a heartbeat line is logged by a background thread, which lets headless setups
+    /// confirm that the mount is alive. No heartbeat is logged if absent or 0.
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat_interval_seconds
+            .filter(|&seconds| seconds > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// How long to spend uploading pending writes when the file system is unmounted. Files which
+    /// could not be uploaded in time are logged.
+    pub fn shutdown_flush_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_flush_timeout.unwrap_or(30))
+    }
+
+    /// How long before its expiry the access token should be refreshed. Checked on every sync.
+    pub fn token_refresh_margin(&self) -> Duration {
+        Duration::from_secs(self.token_refresh_margin.unwrap_or(300))
+    }
+
+    /// How long Drive connections may be idle before TCP keepalive probes are sent, which detects
+    /// connections that were dropped silently. Keepalive is disabled if absent.
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        self.tcp_keepalive_seconds.map(Duration::from_secs)
+    }
+
+    /// How many requests to Drive may be in flight at the same time. The limit is shared by all
+    /// sessions mounted by the process, including syncs and downloads. Unlimited if absent or 0.
+    pub fn global_api_concurrency(&self) -> Option<usize> {
+        self.global_api_concurrency.filter(|&limit| limit > 0)
+    }
+
+    /// How many listings to request from Drive at the same time while populating the file
+    /// system. With more than one, Trash is listed by a separate worker while My Drive is being
+    /// listed. Both are listed page by page, so values above 2 behave like 2. Defaults to 2; 0 is
+    /// treated as 1.
+    pub fn api_concurrency(&self) -> usize {
+        cmp::max(1, self.api_concurrency.unwrap_or(2))
+    }
+
+    /// A list of mount options. If both `allow_other` and `allow_root` are present, one of them is
+    /// dropped according to `prefer_allow_other` (libfuse refuses to mount otherwise).
+    /// `max_read_bytes` and `max_write_bytes` replace the `max_read` and `max_write` options.
     pub fn mount_options(&self) -> Vec<String> {
-        match self.mount_options {
+        let mut options = match self.mount_options {
             Some(ref options) => options.clone(),
             None => Vec::new(),
+        };
+
+        let max_write = self.max_write_bytes.map(|bytes| {
+            if bytes > MAX_FUSE_WRITE_BYTES {
+                warn!(
+                    "max_write_bytes is larger than what FUSE supports. Using {} instead.",
+                    MAX_FUSE_WRITE_BYTES
+                );
+            }
+            cmp::min(bytes, MAX_FUSE_WRITE_BYTES)
+        });
+        for &(name, bytes) in &[("max_read", self.max_read_bytes), ("max_write", max_write)] {
+            if let Some(bytes) = bytes {
+                let prefix = format!("{}=", name);
+                options.retain(|option| !option.trim().starts_with(&prefix));
+                options.push(format!("{}{}", prefix, bytes));
+            }
+        }
+        // Without `big_writes`, the kernel splits writes into pages regardless of `max_write`.
+        if max_write.is_some() && !has_mount_option(&options, "big_writes") {
+            options.push(String::from("big_writes"));
+        }
+
+        if has_mount_option(&options, "allow_other") && has_mount_option(&options, "allow_root") {
+            let dropped = match self.prefer_allow_other {
+                Some(true) => "allow_root",
+                Some(false) => "allow_other",
+                None => {
+                    warn!(
+                        "Mount options `allow_other` and `allow_root` are mutually exclusive. \
+                         Mounting will most likely fail. Set `prefer_allow_other` in the config \
+                         file to choose one of them."
+                    );
+                    return options;
+                }
+            };
+
+            warn!(
+                "Mount options `allow_other` and `allow_root` are mutually exclusive. Dropping `{}`.",
+                dropped
+            );
+            options.retain(|option| option.trim() != dropped);
+        }
+
+        options
+    }
+
+    /// Adds a mount option for this run unless it is already present. An `allow_other` or
+    /// `allow_root` option added this way takes precedence over the other one.
+    pub fn add_mount_option(&mut self, option: &str) {
+        match option {
+            "allow_other" => self.prefer_allow_other = Some(true),
+            "allow_root" => self.prefer_allow_other = Some(false),
+            _ => {}
+        }
+
+        let options = self.mount_options.get_or_insert_with(Vec::new);
+        if !has_mount_option(options, option) {
+            options.push(option.to_string());
+        }
+    }
+
+    /// Overrides `skip_trash` for this run, e.g. from the command line. `None` keeps the
+    /// configured value.
+    pub fn override_skip_trash(&mut self, skip_trash: Option<bool>) {
+        if skip_trash.is_some() {
+            self.skip_trash = skip_trash;
         }
     }
 
@@ -84,6 +563,39 @@ impl Config {
         Path::new(self.config_dir.as_ref().unwrap()).join(Path::new(self.session_name()))
     }
 
+    /// The file which records the account of this session while it is mounted.
+    pub fn account_file(&self) -> PathBuf {
+        self.config_dir()
+            .join(Path::new(&format!(".{}.account", self.session_name())))
+    }
+
+    /// The file which the output of a daemonized mount of this session goes to.
+    pub fn log_file(&self) -> PathBuf {
+        self.config_dir()
+            .join(Path::new(&format!(".{}.log", self.session_name())))
+    }
+
+    /// The file which holds the PID of a daemonized mount of this session if there is no runtime
+    /// dir.
+    pub fn pid_file(&self) -> PathBuf {
+        self.config_dir()
+            .join(Path::new(&format!(".{}.pid", self.session_name())))
+    }
+
+    /// The file whose existence records that sync is paused for this session, so that it stays
+    /// paused across mounts.
+    pub fn sync_paused_file(&self) -> PathBuf {
+        self.config_dir()
+            .join(Path::new(&format!(".{}.sync_paused", self.session_name())))
+    }
+
+    /// The file which records where the disk cache of this session currently lives. Used for
+    /// moving the cache when `cache_dir` changes.
+    pub fn cache_location_file(&self) -> PathBuf {
+        self.config_dir()
+            .join(Path::new(&format!(".{}.cache_dir", self.session_name())))
+    }
+
     /// The path to the config dir.
     pub fn config_dir(&self) -> &PathBuf {
         self.config_dir.as_ref().unwrap()
@@ -99,25 +611,216 @@ impl Config {
         self.authorize_using_code.unwrap_or(true)
     }
 
+    /// How long `gcsf login` waits for the authorization to be completed in the browser, or for
+    /// the code to be entered if `authorize_using_code` is set. Defaults to 5 minutes. Waits
+    /// indefinitely if 0.
+    pub fn login_timeout(&self) -> Option<Duration> {
+        match self.login_timeout_seconds.unwrap_or(300) {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
     /// If set to true, all files with identical name will get an increasing number attached to the suffix.
     /// The mount-time also rises dramatically with higher file counts. Not recommended.
     pub fn rename_identical_files(&self) -> bool {
         self.rename_identical_files.unwrap_or(false)
     }
 
+    /// How the names of files with identical names are rendered when `rename_identical_files` is
+    /// enabled. `{name}` is the full name, `{stem}` and `{ext}` are the name without and with only
+    /// its extension (including the dot) and `{n}` is the number of the duplicate. Defaults to
+    /// "{name}.{n}", e.g. "report.pdf.1".
+    pub fn duplicate_name_template(&self) -> String {
+        self.duplicate_name_template
+            .clone()
+            .unwrap_or_else(|| DEFAULT_DUPLICATE_NAME_TEMPLATE.to_string())
+    }
+
+    /// The `orderBy` parameter used when listing files, e.g. "createdTime" or "name,modifiedTime
+    /// desc". Files with identical names are numbered in this order, so a fixed order keeps their
+    /// numbers stable across mounts. Drive's default order is used if absent.
+    pub fn list_order(&self) -> Option<String> {
+        self.list_order.clone()
+    }
+
+    /// The Drive id of the Shared Drive which is mounted instead of "My Drive", if any. The id of
+    /// a Shared Drive is also the id of its root folder.
+    pub fn shared_drive_id(&self) -> Option<String> {
+        self.shared_drive_id.clone()
+    }
+
     /// If set to true, all files with identical name will get an increasing number attached to the suffix.
     pub fn add_extensions_to_special_files(&self) -> bool {
         self.add_extensions_to_special_files.unwrap_or(false)
     }
 
+    /// What separates the name of a special file from the extension added by
+    /// `add_extensions_to_special_files`. May be empty. Defaults to "#".
+    pub fn special_extension_separator(&self) -> String {
+        self.special_extension_separator
+            .clone()
+            .unwrap_or_else(|| String::from("#"))
+    }
+
+    /// Whether control characters (e.g. newlines) and invisible formatting characters (e.g.
+    /// zero-width spaces) in the names of Drive files are replaced by "_" in the names under which
+    /// the files are shown. Drive keeps the original names.
+    pub fn sanitize_control_chars(&self) -> bool {
+        self.sanitize_control_chars.unwrap_or(false)
+    }
+
+    /// The MIME type in which files of a given Drive MIME type are exported. Returns `None` for
+    /// files which can be downloaded as they are.
+    pub fn export_type(&self, mime_type: &str) -> Option<String> {
+        let configured = self
+            .export_formats
+            .as_ref()
+            .and_then(|formats| formats.get(mime_type))
+            .map(|format| export_format_mime_type(format));
+
+        configured.or_else(|| {
+            DEFAULT_EXPORT_TYPES
+                .get::<str>(mime_type)
+                .map(|mime| mime.to_string())
+        })
+    }
+
+    /// The MIME types in which files of a given Drive MIME type are exported if they are shown as
+    /// a directory with one file per format, e.g. "Report/Report.pdf" and "Report/Report.docx".
+    /// Empty for files which are shown as a single file.
+    pub fn multi_export_types(&self, mime_type: &str) -> Vec<String> {
+        self.multi_export
+            .as_ref()
+            .and_then(|formats| formats.get(mime_type))
+            .map(|formats| {
+                formats
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|format| !format.is_empty())
+                    .map(export_format_mime_type)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// If set to true, deleted files and folder will not be moved to Trash Folder, instead they get deleted permanently.
     pub fn skip_trash(&self) -> bool {
         self.skip_trash.unwrap_or(false)
     }
 
+    /// How long deleted files are held in ".gcsf/pending-deletes" before they are trashed or
+    /// deleted on Drive. Files which are re-created in the meantime are restored instead. Defaults
+    /// to 0, i.e. files are deleted on Drive right away.
+    pub fn delete_grace_period(&self) -> Duration {
+        Duration::from_secs(self.delete_grace_seconds.unwrap_or(0))
+    }
+
+    /// How long files stay in Trash before they are deleted permanently during sync. Their age
+    /// counts from when GCSF saw them being trashed, or from the mount for files which were
+    /// already in Trash. Disabled if absent or 0.
+    pub fn trash_retention(&self) -> Option<Duration> {
+        self.trash_auto_empty_days
+            .filter(|&days| days > 0)
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    /// If set to true, files under "Shared with me" which can not be read because access to them
+    /// was denied (e.g. their sharing permission expired) are removed from the file system on the
+    /// next sync. Otherwise, reading them fails with EACCES.
+    pub fn hide_expired_shares(&self) -> bool {
+        self.hide_expired_shares.unwrap_or(false)
+    }
+
+    /// If set to true, files which look like editor temp files (e.g. "file.txt.swp", "file.txt~"
+    /// or ".#file.txt") only exist locally. They are created on Drive once they are renamed to a
+    /// regular name, and renaming one over an existing file uploads it as the new content of that
+    /// file.
+    pub fn defer_temp_uploads(&self) -> bool {
+        self.defer_temp_uploads.unwrap_or(false)
+    }
+
+    /// Whether buffered writes are only uploaded once the last open handle of a file is released
+    /// (`upload_on = "release"`) instead of whenever a handle is closed (`upload_on = "flush"`, the
+    /// default). This coalesces the writes of programs which flush many times into one upload.
+    pub fn upload_on_release(&self) -> bool {
+        self.upload_on
+            .as_ref()
+            .map_or(false, |when| when == "release")
+    }
+
+    /// Drive folders which should be placed at fixed paths in the file system.
+    pub fn folder_mappings(&self) -> Vec<FolderMapping> {
+        self.folder_mappings.clone().unwrap_or_default()
+    }
+
+    /// Drive ids of files and folders which never enter the file tree. The descendants of excluded
+    /// folders are left out as well.
+    pub fn exclude_ids(&self) -> Vec<String> {
+        self.exclude_ids.clone().unwrap_or_default()
+    }
+
+    /// Glob patterns of files which never enter the file tree, matched against their Drive names.
+    /// Patterns starting with "/" are matched against the path from the root instead. The
+    /// descendants of matching folders are left out as well.
+    pub fn exclude_patterns(&self) -> Vec<String> {
+        self.exclude_patterns.clone().unwrap_or_default()
+    }
+
+    /// How many levels below the root folders are listed while mounting. The children of deeper
+    /// folders are listed once the folder is accessed. At least 1; `None` means unlimited.
+    pub fn max_tree_depth(&self) -> Option<usize> {
+        self.max_tree_depth.map(|depth| cmp::max(depth, 1))
+    }
+
+    /// If set to true, Drive is only accessed with a read-only scope and the file system is
+    /// read-only. Tokens which were only granted read-only access have the same effect.
+    pub fn read_only(&self) -> bool {
+        self.read_only.unwrap_or(false)
+    }
+
+    /// If set to true, files which are owned by someone else are shown without write permissions
+    /// and writes to them are refused, even if the account may edit them. Folders are not affected,
+    /// so files can still be created in shared folders.
+    pub fn readonly_not_owned(&self) -> bool {
+        self.readonly_not_owned.unwrap_or(false)
+    }
+
     /// The Google OAuth client secret for Google Drive APIs. Create your own
     /// credentials at https://console.developers.google.com and paste them here
     pub fn client_secret(&self) -> &String {
         self.client_secret.as_ref().unwrap()
     }
+
+    /// The base URL of the Google APIs, e.g. "https://www.googleapis.com". Can point to a local
+    /// mock server for testing, in which case the `auth_uri` and `token_uri` of `client_secret`
+    /// should point to it as well.
+    pub fn api_base_url(&self) -> &str {
+        self.api_base_url
+            .as_ref()
+            .map_or("https://www.googleapis.com", |url| {
+                url.trim_end_matches('/')
+            })
+    }
+
+    /// The user agent sent with every Drive API request, which identifies GCSF traffic in the
+    /// Cloud Console. Defaults to e.g. "gcsf/0.1.28".
+    pub fn user_agent(&self) -> String {
+        self.user_agent
+            .clone()
+            .unwrap_or_else(|| format!("gcsf/{}", env!("CARGO_PKG_VERSION")))
+    }
+}
+
+/// The MIME type of an export format, which is given either as a MIME type or as an extension.
+fn export_format_mime_type(format: &str) -> String {
+    EXPORT_FORMAT_ALIASES
+        .get::<str>(format)
+        .map(|mime| mime.to_string())
+        .unwrap_or_else(|| format.to_string())
+}
+
+/// Whether a list of mount options contains a given option.
+pub fn has_mount_option(options: &[String], name: &str) -> bool {
+    options.iter().any(|option| option.trim() == name)
 }
@@ -1,19 +1,45 @@
-use super::Config;
+use super::file::exported_document_id;
+use super::{CacheCompression, Clock, Config, SystemClock};
 use drive3;
 use failure::{err_msg, Error};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use hyper;
 use hyper::client::Response;
 use hyper_native_tls::NativeTlsClient;
 use lru_time_cache::LruCache;
+use md5;
 use mime_sniffer::MimeTypeSniffer;
 use oauth2;
 use serde_json;
+use socket2::Socket;
 use std::cmp;
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 const PAGE_SIZE: i32 = 1000;
+/// Files smaller than this are always downloaded in one piece.
+const MIN_PARALLEL_DOWNLOAD_SIZE: u64 = 16 * 1024 * 1024;
+/// Files at least this large are downloaded in chunks which survive restarts, if the disk cache is
+/// enabled.
+const MIN_RESUMABLE_DOWNLOAD_SIZE: u64 = 64 * 1024 * 1024;
+/// How much of a resumable download is lost at most when GCSF is interrupted.
+const RESUMABLE_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+/// How often a rate limited request for a page of changes is retried.
+const RATE_LIMIT_RETRIES: u32 = 5;
+/// How long to wait before retrying a rate limited request for the first time. The wait doubles
+/// with every retry.
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(1);
 type DriveId = String;
 type DriveIdRef<'a> = &'a str;
 
@@ -25,6 +51,59 @@ type GcAuthenticator = oauth2::Authenticator<
 >;
 type GcDrive = drive3::Drive<GcClient, GcAuthenticator>;
 
+/// A range of a streamed file, which serves reads within it without another request.
+struct Chunk {
+    /// The offset of the first byte of the chunk within the file.
+    start: u64,
+    /// Whether the chunk reaches the end of the file.
+    last: bool,
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    /// The `size` bytes at `offset`, if the chunk holds them. Bytes beyond the end of the file are
+    /// left out.
+    fn slice(&self, offset: u64, size: u64) -> Option<&[u8]> {
+        let len = self.data.len() as u64;
+        if offset < self.start || (offset + size > self.start + len && !self.last) {
+            return None;
+        }
+        let from = cmp::min(offset - self.start, len) as usize;
+        let to = cmp::min(offset - self.start + size, len) as usize;
+        Some(&self.data[from..to])
+    }
+}
+
+/// The changes fetched by a `ChangesWorker`, along with the changes token to continue from.
+type ChangesResult = Result<(Vec<drive3::Change>, Option<String>), Error>;
+
+/// A thread with its own `DriveFacade`, which fetches changes starting at the tokens it receives.
+struct ChangesWorker {
+    requests: mpsc::Sender<Option<String>>,
+    results: mpsc::Receiver<ChangesResult>,
+}
+
+impl ChangesWorker {
+    fn spawn(config: Config, rate_limit_backoff: Duration) -> Self {
+        let (requests, received_requests) = mpsc::channel::<Option<String>>();
+        let (sent_results, results) = mpsc::channel();
+        thread::spawn(move || {
+            let mut df = DriveFacade::new(&config);
+            df.rate_limit_backoff = rate_limit_backoff;
+            for token in received_requests {
+                df.changes_token = token;
+                let result = df
+                    .fetch_all_changes()
+                    .map(|changes| (changes, df.changes_token.clone()));
+                if sent_results.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+        ChangesWorker { requests, results }
+    }
+}
+
 /// Provides a simple high-level interface for interacting with the Google Drive API.
 pub struct DriveFacade {
     /// The `drive3::Drive` hub used for interacting with the API.
@@ -36,14 +115,396 @@ pub struct DriveFacade {
     /// Maps Drive IDs to a list of pending write operations that must be applied on them.
     pending_writes: HashMap<DriveId, Vec<PendingWrite>>,
 
+    /// Drive IDs of files whose pending writes could not be uploaded. Their pending writes are kept
+    /// and the upload is retried by `retry_failed_flushes()`.
+    failed_flushes: HashSet<DriveId>,
+
+    /// Drive IDs of files which were truncated to zero bytes. Their pending writes are applied on
+    /// empty content instead of their current content on Drive.
+    truncated: HashSet<DriveId>,
+
+    /// Drive IDs of files which could not be read because access to them was denied, e.g. because
+    /// the permission through which they were shared has expired.
+    denied: HashSet<DriveId>,
+
+    /// Local ids of files which look like editor temp files and are not created on Drive (yet).
+    /// Their content is kept in their pending writes, applied on empty content.
+    deferred: HashSet<DriveId>,
+
     /// The LRU cache used for storing the file contents for any given Drive ID.
     cache: LruCache<DriveId, Vec<u8>>,
 
+    /// The chunk which was last fetched by `read_streaming()` for any given Drive ID.
+    chunks: LruCache<DriveId, Chunk>,
+
+    /// The last known content of files which were invalidated, if
+    /// `Config::serve_stale_on_sync_error` is enabled. The content is kept in memory if it was
+    /// cached there and in a ".stale" file in the disk cache otherwise.
+    stale: HashMap<DriveId, Option<Vec<u8>>>,
+
+    /// Drive IDs of files whose last known content was served instead of their current content.
+    possibly_stale: HashSet<DriveId>,
+
+    /// Whether the last sync failed. Only then is stale content served.
+    sync_failed: bool,
+
+    /// The directory where file contents are stored between runs. The disk cache is disabled if
+    /// this is `None`.
+    cache_dir: Option<PathBuf>,
+
+    /// The directory where the content of files being edited is kept. Edits are kept in memory if
+    /// this is `None`.
+    scratch_dir: Option<PathBuf>,
+
+    /// Drive labels of files, as returned by `labels()`.
+    labels: HashMap<DriveId, Vec<(String, String)>>,
+
     /// Keeps track of the page token used for receiving changes from the `changes.list` API endpoint.
     changes_token: Option<String>,
 
+    /// How long to wait before retrying a rate limited request for the first time.
+    pub(crate) rate_limit_backoff: Duration,
+
+    /// Fetches changes on its own thread if `Config::sync_timeout` is set. Replaced by a new one
+    /// if it does not answer in time.
+    changes_worker: Option<ChangesWorker>,
+
     /// The root id is only stored once, effectively caching the root id.
     root_id: Option<String>,
+
+    /// The id of the Shared Drive which is mounted instead of "My Drive", if any.
+    shared_drive_id: Option<DriveId>,
+
+    /// Whether `root_id` was pinned through `Config::root_drive_id` and has not been checked
+    /// against Drive yet.
+    root_id_unverified: bool,
+
+    /// Whether the file system is read-only, either because it is configured so or because the
+    /// token only grants read access to Drive.
+    read_only: bool,
+
+    /// Whether requests use the read-only scope rather than the full one. Tokens are only found
+    /// under the scope they were issued for, so this follows the stored token.
+    read_only_scope: bool,
+
+    /// The uploads and downloads which are currently in progress.
+    transfers: TransferRegistry,
+
+    /// Tells the time, e.g. in order to decide whether the access token is about to expire.
+    clock: Arc<dyn Clock>,
+
+    /// The config used for creating the hub. Kept around in order to recreate the hub after the
+    /// access token is refreshed.
+    config: Config,
+}
+
+/// The progress of an upload or a download.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transfer {
+    /// Whether the file is being uploaded rather than downloaded.
+    pub upload: bool,
+    /// How many bytes were transferred so far.
+    pub done: u64,
+    /// The size of the file, if known in advance.
+    pub total: Option<u64>,
+}
+
+/// The transfers in progress, by Drive id. Shared with the threads which perform them.
+pub type TransferRegistry = Arc<Mutex<HashMap<DriveId, Transfer>>>;
+
+/// Keeps a transfer listed in a `TransferRegistry` until it is dropped.
+pub struct TransferGuard {
+    id: DriveId,
+    registry: TransferRegistry,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        if let Ok(mut transfers) = self.registry.lock() {
+            transfers.remove(&self.id);
+        }
+    }
+}
+
+/// A reader which records the number of bytes read from it as the progress of a transfer.
+pub struct ProgressReader<R> {
+    inner: R,
+    id: DriveId,
+    registry: TransferRegistry,
+}
+
+impl<R> ProgressReader<R> {
+    /// Wraps `inner`, reporting progress on the transfer of `id` in `registry`.
+    pub fn new(inner: R, id: DriveIdRef, registry: TransferRegistry) -> Self {
+        ProgressReader {
+            inner,
+            id: id.to_string(),
+            registry,
+        }
+    }
+
+    fn update<F: FnOnce(&mut Transfer)>(&self, f: F) {
+        if let Ok(mut transfers) = self.registry.lock() {
+            if let Some(transfer) = transfers.get_mut(&self.id) {
+                f(transfer);
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.update(|transfer| transfer.done += read as u64);
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // Uploads seek back to the first byte which Drive has not received yet.
+        let position = self.inner.seek(pos)?;
+        self.update(|transfer| transfer.done = position);
+        Ok(position)
+    }
+}
+
+/// Opens the TCP connections of the Drive client. If `keepalive` is set, TCP keepalive probes are
+/// sent on idle connections, so that connections which were dropped while a mount was idle are
+/// detected instead of hanging the next request.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeepaliveConnector {
+    keepalive: Option<Duration>,
+}
+
+impl KeepaliveConnector {
+    /// Creates a connector which sends keepalive probes after connections are idle for
+    /// `keepalive`, or never if `None`.
+    pub fn new(keepalive: Option<Duration>) -> Self {
+        KeepaliveConnector { keepalive }
+    }
+}
+
+impl hyper::net::NetworkConnector for KeepaliveConnector {
+    type Stream = hyper::net::HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        let stream = hyper::net::HttpConnector.connect(host, port, scheme)?;
+        if self.keepalive.is_none() {
+            return Ok(stream);
+        }
+
+        let socket = Socket::from(stream.0);
+        socket.set_keepalive(self.keepalive)?;
+        Ok(hyper::net::HttpStream(socket.into_tcp_stream()))
+    }
+}
+
+/// Limits how many requests are in flight at the same time. Requests wait for a permit, which is
+/// held until the connection of the request is closed.
+#[derive(Debug, Default)]
+pub struct RequestLimiter {
+    /// The number of permits in use and the maximum number of permits, if any.
+    permits: Mutex<(usize, Option<usize>)>,
+    released: Condvar,
+}
+
+impl RequestLimiter {
+    /// Creates a limiter which allows at most `limit` requests at the same time, or any number of
+    /// requests if `None`.
+    pub fn new(limit: Option<usize>) -> Self {
+        RequestLimiter {
+            permits: Mutex::new((0, limit)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Changes the maximum number of requests. Requests which are already in flight are not
+    /// affected.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.permits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .1 = limit;
+        self.released.notify_all();
+    }
+
+    /// The number of requests in flight.
+    pub fn in_flight(&self) -> usize {
+        self.permits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+    }
+
+    /// Waits until a request can be made and returns a permit for it. The permit is given back when
+    /// it is dropped.
+    pub fn acquire(limiter: &Arc<RequestLimiter>) -> RequestPermit {
+        let mut permits = limiter
+            .permits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        while permits.1.map_or(false, |limit| permits.0 >= limit) {
+            permits = limiter
+                .released
+                .wait(permits)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        permits.0 += 1;
+        RequestPermit {
+            limiter: Arc::clone(limiter),
+        }
+    }
+}
+
+/// Allows one request to be in flight. See `RequestLimiter`.
+#[derive(Debug)]
+pub struct RequestPermit {
+    limiter: Arc<RequestLimiter>,
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        let mut permits = self
+            .limiter
+            .permits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        permits.0 = permits.0.saturating_sub(1);
+        self.limiter.released.notify_one();
+    }
+}
+
+/// Opens connections through another connector once a `RequestLimiter` permits it. Since every
+/// request opens its own connection, this limits the number of requests in flight.
+#[derive(Debug)]
+pub struct LimitedConnector<C> {
+    inner: C,
+    limiter: Arc<RequestLimiter>,
+}
+
+impl<C> LimitedConnector<C> {
+    /// Creates a connector which waits for a permit of `limiter` before each connection.
+    pub fn new(inner: C, limiter: Arc<RequestLimiter>) -> Self {
+        LimitedConnector { inner, limiter }
+    }
+}
+
+impl<C> hyper::net::NetworkConnector for LimitedConnector<C>
+where
+    C: hyper::net::NetworkConnector,
+    C::Stream: hyper::net::NetworkStream + Send,
+{
+    type Stream = LimitedStream<C::Stream>;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        let permit = RequestLimiter::acquire(&self.limiter);
+        Ok(LimitedStream {
+            stream: self.inner.connect(host, port, scheme)?,
+            _permit: permit,
+        })
+    }
+}
+
+/// A connection opened by a `LimitedConnector`, which holds a permit until it is closed.
+#[derive(Debug)]
+pub struct LimitedStream<S> {
+    /// The underlying connection.
+    pub stream: S,
+    _permit: RequestPermit,
+}
+
+impl<S: Read> Read for LimitedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for LimitedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: hyper::net::NetworkStream> hyper::net::NetworkStream for LimitedStream<S> {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.stream.close(how)
+    }
+}
+
+/// A client for requests which the Drive hub does not support. Its requests count towards the
+/// process-wide limit of requests in flight.
+fn limited_client() -> Result<hyper::Client, Error> {
+    Ok(hyper::Client::with_connector(LimitedConnector::new(
+        hyper::net::HttpsConnector::new(NativeTlsClient::new()?),
+        Arc::clone(&API_LIMITER),
+    )))
+}
+
+/// Lists files through plain requests which carry the access token of the facade it was created
+/// by. Unlike the facade, it can be moved to another thread, e.g. in order to list Trash while the
+/// facade lists My Drive.
+pub struct FileLister {
+    url: String,
+    access_token: String,
+    user_agent: String,
+    params: Vec<(String, String)>,
+}
+
+impl FileLister {
+    /// Lists all matching files, page by page.
+    pub fn list(&self) -> Result<Vec<drive3::File>, Error> {
+        let client = limited_client()?;
+        let mut all_files = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut params = self.params.clone();
+            if let Some(token) = page_token {
+                params.push((String::from("pageToken"), token));
+            }
+            let url = hyper::Url::parse_with_params(&self.url, &params)?;
+            let response = client
+                .get(url)
+                .header(hyper::header::Authorization(hyper::header::Bearer {
+                    token: self.access_token.clone(),
+                }))
+                .header(hyper::header::UserAgent(self.user_agent.clone()))
+                .send()?;
+
+            let status = response.status;
+            let body = read_content(response)?;
+            if !status.is_success() {
+                return Err(err_msg(format!(
+                    "Could not list files: {} {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                )));
+            }
+
+            let filelist: drive3::FileList = serde_json::from_slice(&body)?;
+            all_files.extend(filelist.files.unwrap_or_default());
+            page_token = filelist.next_page_token;
+            if page_token.is_none() {
+                return Ok(all_files);
+            }
+        }
+    }
 }
 
 /// Represents a write operation that has been performed from the user's point of view but has not
@@ -55,21 +516,14 @@ struct PendingWrite {
     data: Vec<u8>,
 }
 
-lazy_static! {
-    static ref MIME_TYPES: HashMap<&'static str, &'static str> = hashmap! {
-        "application/vnd.google-apps.document" => "application/vnd.oasis.opendocument.text",
-        "application/vnd.google-apps.presentation" => "application/vnd.oasis.opendocument.presentation",
-        "application/vnd.google-apps.spreadsheet" => "application/vnd.oasis.opendocument.spreadsheet",
-        "application/vnd.google-apps.drawing" => "image/png",
-        "application/vnd.google-apps.site" => "text/plain",
-    };
-}
-
 lazy_static! {
     static ref UNEXPORTABLE_MIME_TYPES: HashSet<&'static str> = hashset! {
         "application/vnd.google-apps.form",
         "application/vnd.google-apps.map",
     };
+
+    /// Limits the requests to Drive of all sessions in the process.
+    static ref API_LIMITER: Arc<RequestLimiter> = Arc::new(RequestLimiter::new(None));
 }
 
 impl DriveFacade {
@@ -80,23 +534,147 @@ impl DriveFacade {
         let ttl = config.cache_max_seconds();
         let max_count = config.cache_max_items() as usize;
 
+        if let Err(e) = relocate_cache(config) {
+            error!("Could not move the disk cache: {}", e);
+        }
+
+        API_LIMITER.set_limit(config.global_api_concurrency());
+
+        let cache_dir = config.cache_dir();
+        if let Some(ref dir) = cache_dir {
+            if let Err(e) = fs::create_dir_all(dir) {
+                error!("Could not create cache directory {:?}: {}", dir, e);
+            }
+        }
+
+        let scratch_dir = config.scratch_dir();
+        if let Some(ref dir) = scratch_dir {
+            if let Err(e) = fs::create_dir_all(dir) {
+                error!("Could not create scratch directory {:?}: {}", dir, e);
+            }
+        }
+
+        let token = fs::read_to_string(config.token_file()).unwrap_or_default();
+        let read_only = config.read_only() || is_read_only_token(&token);
+        // A full-scope token also grants reads, whereas asking for a read-only token which is not
+        // stored would start an OAuth flow in the middle of the mount.
+        let read_only_scope = read_only && !has_full_scope_token(&token);
+        if read_only {
+            info!("Drive access is read-only. The file system will be mounted as read-only.");
+        }
+
         DriveFacade {
             hub: DriveFacade::create_drive(&config).unwrap(),
             buff: Vec::new(),
             pending_writes: HashMap::new(),
+            failed_flushes: HashSet::new(),
+            truncated: HashSet::new(),
+            denied: HashSet::new(),
+            deferred: HashSet::new(),
             cache: LruCache::<String, Vec<u8>>::with_expiry_duration_and_capacity(ttl, max_count),
-            root_id: None,
+            chunks: LruCache::<String, Chunk>::with_expiry_duration_and_capacity(ttl, max_count),
+            stale: HashMap::new(),
+            possibly_stale: HashSet::new(),
+            sync_failed: false,
+            cache_dir,
+            scratch_dir,
+            labels: HashMap::new(),
+            root_id: config
+                .shared_drive_id()
+                .or_else(|| config.root_drive_id.clone()),
+            shared_drive_id: config.shared_drive_id(),
+            root_id_unverified: config.shared_drive_id.is_none() && config.root_drive_id.is_some(),
             changes_token: None,
+            rate_limit_backoff: RATE_LIMIT_BACKOFF,
+            changes_worker: None,
+            read_only,
+            read_only_scope,
+            transfers: TransferRegistry::default(),
+            clock: Arc::new(SystemClock),
+            config: config.clone(),
         }
     }
 
-    /// Creates a Drive authenticator.
-    fn create_drive_auth(config: &Config) -> Result<GcAuthenticator, Error> {
+    /// Replaces the clock used for telling the time.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// The uploads and downloads which are currently in progress.
+    pub fn transfers(&self) -> &TransferRegistry {
+        &self.transfers
+    }
+
+    /// Lists a transfer in `transfers()` until the returned guard is dropped.
+    pub fn track_transfer(
+        &self,
+        drive_id: DriveIdRef,
+        upload: bool,
+        total: Option<u64>,
+    ) -> TransferGuard {
+        if let Ok(mut transfers) = self.transfers.lock() {
+            transfers.insert(
+                drive_id.to_string(),
+                Transfer {
+                    upload,
+                    done: 0,
+                    total,
+                },
+            );
+        }
+        TransferGuard {
+            id: drive_id.to_string(),
+            registry: Arc::clone(&self.transfers),
+        }
+    }
+
+    /// Whether the token only grants read access to Drive.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The OAuth scope used for all requests.
+    fn scope(&self) -> drive3::Scope {
+        if self.read_only_scope {
+            drive3::Scope::Readonly
+        } else {
+            drive3::Scope::Full
+        }
+    }
+
+    /// Parses the OAuth client secret provided in the config.
+    fn application_secret(config: &Config) -> Result<oauth2::ApplicationSecret, Error> {
         let secret: oauth2::ConsoleApplicationSecret =
             serde_json::from_str(config.client_secret())?;
-        let secret = secret
+        secret
             .installed
-            .ok_or_else(|| err_msg("ConsoleApplicationSecret.installed is None"))?;
+            .ok_or_else(|| err_msg("ConsoleApplicationSecret.installed is None"))
+    }
+
+    /// The OAuth flow used for obtaining tokens.
+    fn flow_type(config: &Config) -> oauth2::FlowType {
+        if config.authorize_using_code() {
+            oauth2::FlowType::InstalledInteractive
+        } else {
+            oauth2::FlowType::InstalledRedirect(8081)
+        }
+    }
+
+    /// Authorizes GCSF to access Drive and stores the token in the token file of the session. Fails
+    /// if the authorization is not completed within `Config::login_timeout`.
+    pub fn login(config: &Config) -> Result<(), Error> {
+        let config = config.clone();
+        with_login_timeout(config.login_timeout(), move || {
+            // Create a DriveFacade which will store the authentication token in the desired file.
+            // And make an arbitrary request in order to trigger the authentication process.
+            let mut df = DriveFacade::new(&config);
+            df.root_id().map(|_| ())
+        })
+    }
+
+    /// Creates a Drive authenticator.
+    fn create_drive_auth(config: &Config) -> Result<GcAuthenticator, Error> {
+        let secret = Self::application_secret(config)?;
 
         let auth = oauth2::Authenticator::new(
             &secret,
@@ -104,33 +682,100 @@ impl DriveFacade {
             hyper::Client::with_connector(hyper::net::HttpsConnector::new(NativeTlsClient::new()?)),
             oauth2::DiskTokenStorage::new(&config.token_file().to_str().unwrap().to_string())
                 .unwrap(),
-            Some(if config.authorize_using_code() {
-                oauth2::FlowType::InstalledInteractive
-            } else {
-                oauth2::FlowType::InstalledRedirect(8081)
-            }),
+            Some(Self::flow_type(config)),
         );
 
         Ok(auth)
     }
 
+    /// Refreshes the access tokens stored in the token file if they expire within
+    /// `token_refresh_margin`, so that requests never have to wait for a refresh (or fail because
+    /// of an expired token). The refreshed tokens are persisted to the token file and the hub is
+    /// recreated in order to use them.
+    pub fn refresh_token_if_needed(&mut self) -> Result<(), Error> {
+        let token_file = self.config.token_file();
+        let content = fs::read_to_string(&token_file)?;
+        let margin = self.config.token_refresh_margin().as_secs() as i64;
+        let now = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        if !token_expires_within(&content, now, margin) {
+            return Ok(());
+        }
+
+        debug!("Access token is about to expire. Refreshing it.");
+        let secret = Self::application_secret(&self.config)?;
+        let mut tokens = stored_tokens(&content);
+
+        for entry in &mut tokens {
+            let refresh_token = match entry
+                .pointer("/token/refresh_token")
+                .and_then(serde_json::Value::as_str)
+            {
+                Some(refresh_token) => refresh_token.to_string(),
+                None => continue,
+            };
+
+            let client = hyper::Client::with_connector(hyper::net::HttpsConnector::new(
+                NativeTlsClient::new()?,
+            ));
+            let mut flow = oauth2::RefreshFlow::new(client);
+            let token =
+                match *flow.refresh_token(Self::flow_type(&self.config), &secret, &refresh_token) {
+                    oauth2::RefreshResult::Success(ref token) => token.clone(),
+                    oauth2::RefreshResult::Error(ref e) => {
+                        return Err(err_msg(format!("Could not refresh access token: {}", e)));
+                    }
+                    oauth2::RefreshResult::RefreshError(ref e, ref description) => {
+                        return Err(err_msg(format!(
+                            "Could not refresh access token: {} {:?}",
+                            e, description
+                        )));
+                    }
+                };
+
+            entry["token"] = serde_json::to_value(&token)?;
+        }
+
+        let mut stored: serde_json::Value = serde_json::from_str(&content)?;
+        stored["tokens"] = serde_json::Value::Array(tokens);
+        fs::write(&token_file, serde_json::to_string(&stored)?)?;
+        self.hub = Self::create_drive(&self.config)?;
+        info!("Refreshed the access token ahead of its expiry.");
+
+        Ok(())
+    }
+
     /// Creates a drive hub.
     fn create_drive(config: &Config) -> Result<GcDrive, Error> {
         let auth = Self::create_drive_auth(config)?;
-        Ok(drive3::Drive::new(
-            hyper::Client::with_connector(hyper::net::HttpsConnector::new(NativeTlsClient::new()?)),
+        let mut hub = drive3::Drive::new(
+            hyper::Client::with_connector(LimitedConnector::new(
+                hyper::net::HttpsConnector::with_connector(
+                    NativeTlsClient::new()?,
+                    KeepaliveConnector::new(config.tcp_keepalive()),
+                ),
+                Arc::clone(&API_LIMITER),
+            )),
             auth,
-        ))
+        );
+        hub.root_url(format!("{}/", config.api_base_url()));
+        hub.base_url(format!("{}/drive/v3/", config.api_base_url()));
+        hub.user_agent(config.user_agent());
+        Ok(hub)
+    }
+
+    /// The URL of a path below the `files` endpoint of the Drive API, e.g. "<id>?alt=media".
+    fn files_url(&self, path: &str) -> String {
+        format!("{}/drive/v3/files/{}", self.config.api_base_url(), path)
     }
 
     /// Will still detect a file even if it is in Trash.
     fn contains(&self, id: DriveIdRef) -> Result<bool, Error> {
-        let response = self
-            .hub
-            .files()
-            .get(&id)
-            .add_scope(drive3::Scope::Full)
-            .doit();
+        let response = self.hub.files().get(&id).add_scope(self.scope()).doit();
 
         match response {
             Ok((_, file)) => Ok(file.id == Some(id.to_string())),
@@ -147,8 +792,12 @@ impl DriveFacade {
         self.hub
             .files()
             .get(id)
-            .param("fields", "id,name,parents,mimeType,webContentLink")
-            .add_scope(drive3::Scope::Full)
+            .supports_team_drives(self.shared_drive_id.is_some())
+            .param(
+                "fields",
+                "id,name,parents,mimeType,size,md5Checksum,webContentLink,webViewLink",
+            )
+            .add_scope(self.scope())
             .doit()
             .map(|(_response, file)| file)
             .map_err(|e| err_msg(format!("{:#?}", e)))
@@ -162,98 +811,258 @@ impl DriveFacade {
         drive_id: &str,
         mime_type: Option<String>,
     ) -> Result<Vec<u8>, Error> {
+        // One of the exports in the directory of a document, in the format of its MIME type.
+        if let Some(document_id) = exported_document_id(drive_id) {
+            let mime =
+                mime_type.ok_or_else(|| err_msg(format!("{} has no export format", drive_id)))?;
+            return self.export(document_id, &mime);
+        }
+
         if let Some(mime) = mime_type.clone() {
             if UNEXPORTABLE_MIME_TYPES.contains::<str>(&mime) {
-                return Ok(format!(
-                    "UNEXPORTABLE_FILE: The MIME type of this \
-                     file is {:?}, which can not be exported from Drive. Web \
-                     content link provided by Drive: {:?}\n",
-                    mime,
-                    self.get_file_metadata(drive_id)
-                        .ok()
-                        .map(|metadata| metadata.web_view_link)
-                        .unwrap_or_default()
-                )
-                .as_bytes()
-                .to_vec());
+                return Ok(self.unexportable_placeholder(drive_id, &mime));
             }
         }
 
-        let export_type: Option<&'static str> = mime_type
-            .and_then(|ref t| MIME_TYPES.get::<str>(&t))
-            .cloned();
+        let export_type = mime_type.as_ref().and_then(|t| self.config.export_type(t));
 
-        let mut response = match export_type {
-            Some(t) => {
-                let response = self
-                    .hub
-                    .files()
-                    .export(drive_id, &t)
-                    .add_scope(drive3::Scope::Full)
-                    .doit()
-                    .map_err(|e| err_msg(format!("{:#?}", e)))?;
-
-                debug!("response: {:?}", &response);
-                response
-            }
-            None => {
-                let (response, _empty_file) = self
-                    .hub
-                    .files()
-                    .get(&drive_id)
-                    .supports_team_drives(false)
-                    .param("alt", "media")
-                    .add_scope(drive3::Scope::Full)
-                    .doit()
-                    .map_err(|e| err_msg(format!("{:#?}", e)))?;
-                response
+        match export_type {
+            Some(t) => match (self.export(drive_id, &t), mime_type.as_ref()) {
+                (Ok(content), _) => Ok(content),
+                // Most sites can not be exported at all, so link to them instead of failing.
+                (Err(e), Some(mime)) if mime == "application/vnd.google-apps.site" => {
+                    warn!("Could not export site {}: {}", drive_id, e);
+                    Ok(self.unexportable_placeholder(drive_id, mime))
+                }
+                (Err(e), _) => Err(e),
+            },
+            None if self.config.download_parallelism() > 1 || self.cache_dir.is_some() => {
+                let size = self
+                    .get_file_metadata(drive_id)?
+                    .size
+                    .and_then(|size| size.parse::<u64>().ok())
+                    .unwrap_or(0);
+
+                if self.cache_dir.is_some() && size >= MIN_RESUMABLE_DOWNLOAD_SIZE {
+                    let _transfer = self.track_transfer(drive_id, false, Some(size));
+                    self.download_resumable(drive_id, size)
+                } else if self.config.download_parallelism() > 1
+                    && size >= MIN_PARALLEL_DOWNLOAD_SIZE
+                {
+                    let _transfer = self.track_transfer(drive_id, false, Some(size));
+                    self.download_in_parallel(drive_id, size)
+                } else {
+                    self.download(drive_id)
+                }
             }
-        };
+            None => self.download(drive_id),
+        }
+    }
 
-        let mut content: Vec<u8> = Vec::new();
-        let _result = response.read_to_end(&mut content);
+    /// Downloads the content of a file in `download_parallelism` concurrent ranges. Uses the
+    /// access token from the token file, which is kept fresh during sync.
+    fn download_in_parallel(&self, drive_id: DriveIdRef, size: u64) -> Result<Vec<u8>, Error> {
+        let access_token = self.access_token()?;
+        let url = self.files_url(&format!("{}?alt=media", drive_id));
+        let ranges = split_ranges(size, self.config.download_parallelism());
+        debug!("Downloading {} in ranges {:?}", drive_id, &ranges);
+        let user_agent = self.config.user_agent();
+        let id = drive_id.to_string();
+        let transfers = Arc::clone(&self.transfers);
+
+        fetch_ranges(ranges, move |start, end| {
+            download_range(&url, &access_token, &user_agent, start, end)
+                .map(|response| ProgressReader::new(response, &id, Arc::clone(&transfers)))
+                .and_then(read_content)
+        })
+    }
 
-        Ok(content)
+    /// Downloads the content of a file into the disk cache, chunk by chunk. If a previous download
+    /// of the file was interrupted, only the chunks which are still missing are downloaded.
+    fn download_resumable(&self, drive_id: DriveIdRef, size: u64) -> Result<Vec<u8>, Error> {
+        let path = self
+            .disk_cache_path(drive_id)
+            .ok_or_else(|| err_msg("The disk cache is disabled"))?;
+        let access_token = self.access_token()?;
+        let url = self.files_url(&format!("{}?alt=media", drive_id));
+        let user_agent = self.config.user_agent();
+
+        resume_download(&path, size, RESUMABLE_CHUNK_SIZE, |start, end| {
+            download_range(&url, &access_token, &user_agent, start, end)
+                .map(|response| {
+                    ProgressReader::new(response, drive_id, Arc::clone(&self.transfers))
+                })
+                .and_then(read_content)
+        })
     }
 
-    /// Applies all pending writes accumulated so far on a data buffer. The pending writes are then
-    /// cleared.
-    fn apply_pending_writes_on_data(&mut self, id: DriveId, data: &mut Vec<u8>) {
-        self.pending_writes
-            .entry(id.clone())
-            .or_insert_with(Vec::new)
+    /// The current access token, as stored in the token file. Used for requests which the Drive
+    /// hub does not support.
+    fn access_token(&self) -> Result<String, Error> {
+        stored_tokens(&fs::read_to_string(self.config.token_file())?)
             .iter()
-            .filter(|write| write.id == id)
-            .for_each(|pending_write| {
-                debug!(
-                    "Applying pending write with offset {} on {}",
-                    &pending_write.offset, &pending_write.id
-                );
-                let required_size = pending_write.offset + pending_write.data.len();
+            .filter_map(|token| token.pointer("/token/access_token"))
+            .filter_map(serde_json::Value::as_str)
+            .next()
+            .map(String::from)
+            .ok_or_else(|| err_msg("The token file does not contain an access token"))
+    }
 
-                data.resize(required_size, 0);
-                data[pending_write.offset..].copy_from_slice(&pending_write.data[..]);
-            });
+    /// Returns the Drive labels of a file as (xattr name, value) pairs, e.g.
+    /// ("user.drive.label.<labelId>.<fieldId>", "Confidential"). Labels are fetched once per file
+    /// and cached until the file changes. Always empty unless `fetch_labels` is enabled.
+    pub fn labels(&mut self, drive_id: DriveIdRef) -> Result<Vec<(String, String)>, Error> {
+        if !self.config.fetch_labels() {
+            return Ok(Vec::new());
+        }
+        if let Some(labels) = self.labels.get(drive_id) {
+            return Ok(labels.clone());
+        }
+
+        let client = limited_client()?;
+        let response = client
+            .get(self.files_url(&format!("{}/listLabels", drive_id)).as_str())
+            .header(hyper::header::Authorization(hyper::header::Bearer {
+                token: self.access_token()?,
+            }))
+            .header(hyper::header::UserAgent(self.config.user_agent()))
+            .send()?;
+
+        let status = response.status;
+        let body = String::from_utf8_lossy(&read_content(response)?).into_owned();
+        if !status.is_success() {
+            return Err(err_msg(format!(
+                "Could not list labels of {}: {} {}",
+                drive_id, status, body
+            )));
+        }
 
-        self.pending_writes.remove(&id);
+        let labels = parse_labels(&body)?;
+        self.labels.insert(drive_id.to_string(), labels.clone());
+        Ok(labels)
     }
 
-    /// Returns the Drive ID of the root "My Drive" directory
-    pub fn root_id(&mut self) -> Result<&String, Error> {
-        if self.root_id.is_some() {
-            return Ok(self.root_id.as_ref().unwrap());
+    /// Writes the content of a file to `out` and returns how many bytes were written. The file is
+    /// exported in `export_mime` if given. Otherwise, Google Docs files are exported in their
+    /// configured format and other files are downloaded as they are.
+    pub fn write_content<W: Write>(
+        &self,
+        drive_id: DriveIdRef,
+        export_mime: Option<&str>,
+        out: &mut W,
+    ) -> Result<u64, Error> {
+        let content = match export_mime {
+            Some(mime) => self.export(drive_id, mime)?,
+            None => {
+                let mime_type = self.get_file_metadata(drive_id)?.mime_type;
+                self.get_file_content(drive_id, mime_type)?
+            }
+        };
+        out.write_all(&content)?;
+        out.flush()?;
+        Ok(content.len() as u64)
+    }
+
+    /// Downloads the content of a file in one piece.
+    fn download(&self, drive_id: DriveIdRef) -> Result<Vec<u8>, Error> {
+        let _transfer = self.track_transfer(drive_id, false, None);
+        let (response, _empty_file) = self
+            .hub
+            .files()
+            .get(&drive_id)
+            .supports_team_drives(self.shared_drive_id.is_some())
+            .param("alt", "media")
+            .add_scope(self.scope())
+            .doit()
+            .map_err(|e| err_msg(format!("{:#?}", e)))?;
+
+        let total = response
+            .headers
+            .get::<hyper::header::ContentLength>()
+            .map(|length| length.0);
+        if let Ok(mut transfers) = self.transfers.lock() {
+            if let Some(transfer) = transfers.get_mut(drive_id) {
+                transfer.total = total;
+            }
         }
+        read_content(ProgressReader::new(
+            response,
+            drive_id,
+            Arc::clone(&self.transfers),
+        ))
+    }
 
-        let parent = self
+    /// Exports a Google Docs file (Docs, Sheets, Slides, Drawings) in a given MIME type, e.g.
+    /// "text/plain" for reading Docs as plain text.
+    pub fn export(&self, drive_id: DriveIdRef, mime_type: &str) -> Result<Vec<u8>, Error> {
+        let response = self
             .hub
             .files()
-            .list()
-            .param("fields", "files(parents)")
-            .spaces("drive")
-            .corpora("user")
-            .page_size(1)
-            .q("'root' in parents")
-            .add_scope(drive3::Scope::Full)
+            .export(drive_id, mime_type)
+            .add_scope(self.scope())
+            .doit()
+            .map_err(|e| err_msg(format!("{:#?}", e)))?;
+
+        debug!("response: {:?}", &response);
+        read_content(response)
+    }
+
+    /// The content shown in place of files which can not be exported from Drive.
+    fn unexportable_placeholder(&self, drive_id: &str, mime: &str) -> Vec<u8> {
+        format!(
+            "UNEXPORTABLE_FILE: The MIME type of this \
+             file is {:?}, which can not be exported from Drive. Web \
+             content link provided by Drive: {:?}\n",
+            mime,
+            self.get_file_metadata(drive_id)
+                .ok()
+                .and_then(|metadata| metadata.web_view_link)
+                .unwrap_or_default()
+        )
+        .as_bytes()
+        .to_vec()
+    }
+
+    /// Checks a root id pinned through `Config::root_drive_id` against Drive. Does nothing if the
+    /// root id was not pinned or has already been checked.
+    pub fn verify_root_id(&mut self) -> Result<(), Error> {
+        if !self.root_id_unverified {
+            return Ok(());
+        }
+
+        let actual = self
+            .get_file_metadata("root")?
+            .id
+            .ok_or_else(|| err_msg("Drive did not return the id of 'My Drive'"))?;
+        self.root_id_unverified = false;
+
+        if self.root_id.as_ref() != Some(&actual) {
+            return Err(err_msg(format!(
+                "The configured root_drive_id {:?} does not match the actual id of 'My Drive' \
+                 ({:?}). Please fix or remove it from the config file.",
+                self.root_id, actual
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the Drive ID of the root "My Drive" directory, or of the Shared Drive which is mounted
+    /// instead
+    pub fn root_id(&mut self) -> Result<&String, Error> {
+        if self.root_id.is_some() {
+            return Ok(self.root_id.as_ref().unwrap());
+        }
+
+        let parent = self
+            .hub
+            .files()
+            .list()
+            .param("fields", "files(parents)")
+            .spaces("drive")
+            .corpora("user")
+            .page_size(1)
+            .q("'root' in parents")
+            .add_scope(self.scope())
             .doit()
             .map_err(|e| err_msg(format!("{:#?}", e)))?
             .1
@@ -278,10 +1087,14 @@ impl DriveFacade {
 
     /// Returns the start page token for the `changes.list` API endpoint.
     fn get_start_page_token(&mut self) -> Result<String, Error> {
-        self.hub
-            .changes()
-            .get_start_page_token()
-            .add_scope(drive3::Scope::Full)
+        let mut request = self.hub.changes().get_start_page_token();
+        if let Some(ref shared_drive_id) = self.shared_drive_id {
+            request = request
+                .team_drive_id(shared_drive_id)
+                .supports_team_drives(true);
+        }
+        request
+            .add_scope(self.scope())
             .doit()
             .map_err(|e| err_msg(format!("{:#?}", e)))
             .map(|result| {
@@ -305,63 +1118,212 @@ impl DriveFacade {
     }
 
     /// Returns a list of all changes reported by Drive which are more recent than the changes
-    /// token indicates.
+    /// token indicates. If `Config::sync_timeout` is set, the changes are fetched by a worker
+    /// thread and the attempt is given up on once the timeout passes. The changes token is left
+    /// as it was in that case, so that the changes are fetched again by the next sync.
     pub fn get_all_changes(&mut self) -> Result<Vec<drive3::Change>, Error> {
+        let timeout = match self.config.sync_timeout() {
+            Some(timeout) => timeout,
+            None => return self.fetch_all_changes(),
+        };
+
+        let worker = match self.changes_worker.take() {
+            Some(worker) => worker,
+            None => ChangesWorker::spawn(self.config.clone(), self.rate_limit_backoff),
+        };
+        worker
+            .requests
+            .send(self.changes_token.clone())
+            .map_err(|_| err_msg("The worker fetching changes has stopped"))?;
+
+        match worker.results.recv_timeout(timeout) {
+            Ok(result) => {
+                self.changes_worker = Some(worker);
+                let (changes, token) = result?;
+                self.changes_token = token;
+                Ok(changes)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // The worker stops once its request returns, since nobody listens anymore.
+                warn!(
+                    "Fetching changes took longer than {:?}. Giving up until the next sync.",
+                    timeout
+                );
+                Err(err_msg("Timed out while fetching changes"))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(err_msg("The worker fetching changes has stopped"))
+            }
+        }
+    }
+
+    /// Fetches all changes since the changes token. Rate limited pages are retried with backoff.
+    /// The changes token only advances once all pages were received, so that no changes are
+    /// skipped if a page fails.
+    fn fetch_all_changes(&mut self) -> Result<Vec<drive3::Change>, Error> {
         let mut all_changes = Vec::new();
+        let mut token = self.changes_token()?.clone();
 
         loop {
-            let token = self.changes_token()?.clone();
-            let (_response, changelist) = self.hub
-                .changes()
-                .list(&token)
-                .param("fields", "kind,newStartPageToken,changes(kind,type,time,removed,fileId,file(name,id,size,mimeType,owners,parents,trashed,modifiedTime,createdTime,viewedByMeTime))")
-                .spaces("drive")
-                .restrict_to_my_drive(true)
-                // Whether to include changes indicating that items have been removed from the list of changes, for example by deletion or loss of access. (Default: true)
-                .include_removed(false) // ^wtf?
-                .supports_team_drives(false)
-                .include_team_drive_items(false)
-                .page_size(PAGE_SIZE)
-                .add_scope(drive3::Scope::Full)
-                .doit()
-                .map_err(|e| err_msg(format!("{:#?}", e)))?;
+            let mut delay = self.rate_limit_backoff;
+            let mut retries = 0;
+            let changelist = loop {
+                match self.get_changes_page(&token) {
+                    Err(ref e) if retries < RATE_LIMIT_RETRIES && is_rate_limit_error(e) => {
+                        retries += 1;
+                        warn!(
+                            "Changes are rate limited. Retrying in {:?} ({}/{}).",
+                            delay, retries, RATE_LIMIT_RETRIES
+                        );
+                        thread::sleep(delay);
+                        delay *= 2;
+                    }
+                    result => break result.map_err(err_msg)?,
+                }
+            };
 
             match changelist.changes {
                 Some(changes) => all_changes.extend(changes),
                 _ => warn!("Changelist does not contain any changes!"),
             };
 
-            self.changes_token = changelist.next_page_token;
-            if self.changes_token.is_none() {
-                self.changes_token = changelist.new_start_page_token;
-                break;
+            match (changelist.next_page_token, changelist.new_start_page_token) {
+                (Some(next_page), _) => token = next_page,
+                // Without a new start page token, the next sync continues after the last page.
+                (None, new_start_page) => {
+                    self.changes_token = Some(new_start_page.unwrap_or(token));
+                    break;
+                }
             }
         }
 
         Ok(all_changes)
     }
 
+    /// The fields of files which are requested when listing files or changes.
+    fn file_fields(&self) -> String {
+        let mut fields = String::from(
+            "name,id,size,md5Checksum,mimeType,owners,parents,trashed,explicitlyTrashed,modifiedTime,createdTime,viewedByMeTime",
+        );
+        if self.config.expose_descriptions() {
+            fields.push_str(",description");
+        }
+        if self.config.readonly_not_owned() {
+            fields.push_str(",ownedByMe");
+        }
+        fields
+    }
+
+    /// Requests a single page of changes from the `changes.list` API endpoint.
+    fn get_changes_page(&self, token: &str) -> Result<drive3::ChangeList, String> {
+        let mut request = self
+            .hub
+            .changes()
+            .list(token)
+            .param(
+                "fields",
+                &format!(
+                    "kind,nextPageToken,newStartPageToken,changes(kind,type,time,removed,fileId,file({}))",
+                    self.file_fields()
+                ),
+            )
+            .spaces("drive")
+            // Whether to include changes indicating that items have been removed from the list of changes, for example by deletion or loss of access. (Default: true)
+            .include_removed(false); // ^wtf?
+        request = match self.shared_drive_id {
+            Some(ref shared_drive_id) => request
+                .team_drive_id(shared_drive_id)
+                .supports_team_drives(true)
+                .include_team_drive_items(true),
+            None => request
+                .restrict_to_my_drive(true)
+                .supports_team_drives(false)
+                .include_team_drive_items(false),
+        };
+        request
+            .page_size(PAGE_SIZE)
+            .add_scope(self.scope())
+            .doit()
+            .map(|(_response, changelist)| changelist)
+            .map_err(|e| format!("{:#?}", e))
+    }
+
     /// Returns a list of all files from Drive. If the `parents` list is provided, only files which are children of any one of the list's elements are returned. If `trashed` is provided, only files which are trashed/not trashed are returned. The two filters can be used together.
     pub fn get_all_files(
         &mut self,
         parents: Option<Vec<DriveId>>,
         trashed: Option<bool>,
     ) -> Result<Vec<drive3::File>, Error> {
+        self.list_files(parents, trashed, None)
+    }
+
+    /// Like `get_all_files`, but also returns the files listed before a page could not be
+    /// retrieved, along with the error.
+    pub fn get_all_files_partially(
+        &mut self,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+    ) -> (Vec<drive3::File>, Result<(), Error>) {
+        self.list_files_partially(parents, trashed, None)
+    }
+
+    /// Returns all non-trashed files which match a Drive search query, e.g.
+    /// `name contains 'budget'`.
+    pub fn search(&mut self, query: &str) -> Result<Vec<drive3::File>, Error> {
+        self.list_files(None, Some(false), Some(query))
+    }
+
+    /// Lists files matching the filters of `get_all_files` and, optionally, an additional search
+    /// query.
+    fn list_files(
+        &mut self,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+        search: Option<&str>,
+    ) -> Result<Vec<drive3::File>, Error> {
+        let (files, result) = self.list_files_partially(parents, trashed, search);
+        result.map(|_| files)
+    }
+
+    /// Lists files like `list_files`. If a page can not be retrieved, the files of the previous
+    /// pages are returned along with the error.
+    fn list_files_partially(
+        &mut self,
+        parents: Option<Vec<DriveId>>,
+        trashed: Option<bool>,
+        search: Option<&str>,
+    ) -> (Vec<drive3::File>, Result<(), Error>) {
         let mut all_files = Vec::new();
         let mut page_token: Option<String> = None;
         let mut current_page = 1;
         loop {
-            let mut request = self.hub.files()
+            let mut request = self
+                .hub
+                .files()
                 .list()
-                .param("fields", "nextPageToken,files(name,id,size,mimeType,owners,parents,trashed,modifiedTime,createdTime,viewedByMeTime)")
+                .param(
+                    "fields",
+                    &format!("nextPageToken,files({})", self.file_fields()),
+                )
                 .spaces("drive") // TODO: maybe add photos as well
-                .corpora("user")
                 .page_size(PAGE_SIZE)
-                .add_scope(drive3::Scope::Full);
+                .add_scope(self.scope());
+            // A Shared Drive is listed on its own, including its Trash.
+            request = match self.shared_drive_id {
+                Some(ref shared_drive_id) => request
+                    .corpora("teamDrive")
+                    .team_drive_id(shared_drive_id)
+                    .supports_team_drives(true)
+                    .include_team_drive_items(true),
+                None => request.corpora("user"),
+            };
 
             if let Some(token) = page_token {
                 request = request.page_token(&token);
             };
+            if let Some(order) = self.config.list_order() {
+                request = request.order_by(&order);
+            }
 
             let mut query_chain: Vec<String> = Vec::new();
             if let Some(ref p) = parents {
@@ -376,32 +1338,66 @@ impl DriveFacade {
             if let Some(trash) = trashed {
                 query_chain.push(format!("trashed = {}", trash));
             }
+            if let Some(q) = search {
+                query_chain.push(format!("({})", q));
+            }
 
             let query = query_chain.join(" and ");
-            let (_, filelist) = request
-                .q(&query)
-                .doit()
-                .map_err(|e| err_msg(format!("{:#?}", e)))?;
-
-            match filelist.files {
-                Some(files) => {
-                    info!(
-                        "Received page {} containing {} files",
-                        current_page,
-                        files.len()
-                    );
-                    all_files.extend(files);
-                }
-                _ => warn!("Filelist does not contain any files!"),
+            let filelist = match request.q(&query).doit() {
+                Ok((_, filelist)) => filelist,
+                Err(e) => return (all_files, Err(err_msg(format!("{:#?}", e)))),
             };
 
+            // Drive may leave out the list of files entirely if there are none, e.g. for a new
+            // account. That is an empty page, not an error.
+            let files = filelist.files.unwrap_or_default();
+            info!(
+                "Received page {} containing {} files",
+                current_page,
+                files.len()
+            );
+            all_files.extend(files);
+
             current_page += 1;
             page_token = filelist.next_page_token;
             if page_token.is_none() {
                 break;
             }
         }
-        Ok(all_files)
+        (all_files, Ok(()))
+    }
+
+    /// A lister for the files in Trash, which lists them like `get_all_files(None, Some(true))`
+    /// but without using this facade.
+    pub fn trash_lister(&self) -> Result<FileLister, Error> {
+        let mut params = vec![
+            (
+                String::from("fields"),
+                format!("nextPageToken,files({})", self.file_fields()),
+            ),
+            (String::from("spaces"), String::from("drive")),
+            (String::from("pageSize"), PAGE_SIZE.to_string()),
+            (String::from("q"), String::from("trashed = true")),
+        ];
+        match self.shared_drive_id {
+            Some(ref shared_drive_id) => params.extend(vec![
+                (String::from("corpora"), String::from("teamDrive")),
+                (String::from("teamDriveId"), shared_drive_id.clone()),
+                (String::from("supportsTeamDrives"), String::from("true")),
+                (String::from("includeTeamDriveItems"), String::from("true")),
+            ]),
+            None => params.push((String::from("corpora"), String::from("user"))),
+        }
+        if let Some(order) = self.config.list_order() {
+            params.push((String::from("orderBy"), order));
+        }
+
+        Ok(FileLister {
+            url: format!("{}/drive/v3/files", self.config.api_base_url()),
+            access_token: self.access_token()?,
+            user_agent: self.config.user_agent(),
+            params,
+        })
     }
 
     /// Reads the contents of a Drive file starting at a certain offset.
@@ -413,27 +1409,370 @@ impl DriveFacade {
         offset: usize,
         size: usize,
     ) -> Option<&[u8]> {
+        // Zero-length reads are sometimes used as probes. They never need the file's content.
+        if size == 0 {
+            return Some(&[][..]);
+        }
+
+        if let Some(path) = self.existing_scratch_path(drive_id) {
+            return match read_range(&path, offset, size) {
+                Ok(data) => {
+                    self.buff = data;
+                    Some(&self.buff)
+                }
+                Err(e) => {
+                    error!("Could not read scratch file {:?}: {}", &path, e);
+                    None
+                }
+            };
+        }
+
+        // Content which is not cached is kept here, so that the cached content never needs to be
+        // copied as a whole.
+        let uncached = if self.load_cached_content(drive_id) {
+            None
+        } else if self.truncated.contains(drive_id) {
+            Some(Vec::new())
+        } else {
+            Some(match self.get_file_content(&drive_id, mime_type) {
+                Ok(data) => {
+                    self.denied.remove(drive_id);
+                    self.store_content(drive_id, &data);
+                    data
+                }
+                Err(e) => match self.stale_content(drive_id) {
+                    Some(data) if self.sync_failed => {
+                        warn!(
+                            "Serving the last known content of {} since the last sync failed: {}",
+                            drive_id, e
+                        );
+                        self.possibly_stale.insert(drive_id.to_string());
+                        data
+                    }
+                    _ => {
+                        self.note_read_error(drive_id, &e);
+                        return None;
+                    }
+                },
+            })
+        };
+        let data: &[u8] = match uncached {
+            Some(ref data) => data,
+            None => self.cache.get(drive_id)?,
+        };
+
+        // Writes which have not been flushed yet must be visible to readers as well. They are
+        // overlaid on a copy of the requested range so that the cache keeps mirroring Drive.
+        self.buff = match self.pending_writes.get(drive_id) {
+            Some(writes) => overlay_window(data, writes, offset, size),
+            None => {
+                data[cmp::min(data.len(), offset)..cmp::min(data.len(), offset + size)].to_vec()
+            }
+        };
+        Some(&self.buff)
+    }
+
+    /// The number of bytes held in memory by the pending writes of a file.
+    #[cfg(test)]
+    pub fn pending_write_bytes(&self, id: DriveIdRef) -> usize {
+        self.pending_writes
+            .get(id)
+            .map(|writes| writes.iter().map(|write| write.data.len()).sum())
+            .unwrap_or(0)
+    }
+
+    /// The number of bytes written to all files which are not flushed to Drive yet.
+    pub fn unflushed_bytes(&self) -> u64 {
+        self.pending_writes
+            .values()
+            .flat_map(|writes| writes.iter())
+            .map(|write| write.data.len() as u64)
+            .sum()
+    }
+
+    /// Stores the content of a Drive file in the cache, as if it had just been downloaded.
+    #[cfg(test)]
+    pub fn cache_content(&mut self, drive_id: DriveIdRef, data: &[u8]) {
+        self.store_content(drive_id, data);
+    }
+
+    /// Returns the cached content of a Drive file, looking in memory first and on disk second.
+    pub fn cached_content(&mut self, drive_id: DriveIdRef) -> Option<Vec<u8>> {
+        if !self.load_cached_content(drive_id) {
+            return None;
+        }
+        self.cache.get(drive_id).cloned()
+    }
+
+    /// Makes sure that the cached content of a Drive file is held in memory, loading it from the
+    /// disk cache if needed. Returns whether the content is cached.
+    fn load_cached_content(&mut self, drive_id: DriveIdRef) -> bool {
         if self.cache.contains_key(drive_id) {
-            let data = self.cache.get(drive_id).unwrap();
-            self.buff =
-                data[cmp::min(data.len(), offset)..cmp::min(data.len(), offset + size)].to_vec();
+            return true;
+        }
+
+        let data = match self.disk_cache_path(drive_id) {
+            Some(path) => match read_cache_entry(&path) {
+                Ok(data) => data,
+                Err(_) => return false,
+            },
+            None => return false,
+        };
+        self.cache.insert(drive_id.to_string(), data);
+        true
+    }
+
+    /// The size of the content of a Drive file, if it is cached in memory.
+    pub fn cached_size(&mut self, drive_id: DriveIdRef) -> Option<u64> {
+        self.cache.get(drive_id).map(|data| data.len() as u64)
+    }
+
+    /// Stores the content of a Drive file in the memory cache and, if enabled, on disk.
+    fn store_content(&mut self, drive_id: DriveIdRef, data: &[u8]) {
+        self.drop_stale(drive_id);
+        self.cache.insert(drive_id.to_string(), data.to_vec());
+
+        if let Some(path) = self.disk_cache_path(drive_id) {
+            if let Err(e) = write_cache_entry(&path, data, self.config.cache_compression()) {
+                error!("Could not write {:?} to the disk cache: {}", &path, e);
+            }
+        }
+    }
+
+    /// Downloads the content of a file into the disk cache, unless it is cached already. The content
+    /// is not kept in memory. Returns whether the file was downloaded.
+    pub fn prefetch(
+        &mut self,
+        drive_id: DriveIdRef,
+        mime_type: Option<String>,
+    ) -> Result<bool, Error> {
+        let path = self
+            .disk_cache_path(drive_id)
+            .ok_or_else(|| err_msg("The disk cache is disabled"))?;
+        if path.exists() {
+            return Ok(false);
+        }
+
+        let data = self.get_file_content(drive_id, mime_type)?;
+        write_cache_entry(&path, &data, self.config.cache_compression())?;
+        Ok(true)
+    }
+
+    /// Drops the cached content of a Drive file from memory. The disk cache is left untouched.
+    pub fn evict(&mut self, drive_id: DriveIdRef) {
+        self.cache.remove(drive_id);
+    }
+
+    /// Drops the cached content of a Drive file, both from memory and from disk. If
+    /// `Config::serve_stale_on_sync_error` is enabled, the content is kept as its last known
+    /// content instead.
+    pub fn invalidate(&mut self, drive_id: DriveIdRef) {
+        let cached = self.cache.remove(drive_id);
+        self.chunks.remove(drive_id);
+        self.denied.remove(drive_id);
+        self.labels.remove(drive_id);
+
+        let keep_stale = self.config.serve_stale_on_sync_error();
+        let kept_in_memory = keep_stale && cached.is_some();
+        if kept_in_memory {
+            self.stale.insert(drive_id.to_string(), cached);
+        }
+
+        if let Some(path) = self.disk_cache_path(drive_id) {
+            if keep_stale && !kept_in_memory && path.exists() {
+                match fs::rename(&path, path.with_extension("stale")) {
+                    Ok(()) => {
+                        self.stale.insert(drive_id.to_string(), None);
+                    }
+                    Err(e) => error!(
+                        "Could not keep the last known content of {:?}: {}",
+                        &path, e
+                    ),
+                }
+            }
+
+            let (partial, extents) = partial_download_paths(&path);
+            for path in &[path, partial, extents] {
+                if path.exists() {
+                    if let Err(e) = fs::remove_file(path) {
+                        error!("Could not remove {:?} from the disk cache: {}", &path, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The last known content of a Drive file, if it was kept when the file was invalidated.
+    fn stale_content(&self, drive_id: DriveIdRef) -> Option<Vec<u8>> {
+        match self.stale.get(drive_id)? {
+            Some(data) => Some(data.clone()),
+            None => read_cache_entry(&self.disk_cache_path(drive_id)?.with_extension("stale")).ok(),
+        }
+    }
+
+    /// Forgets the last known content of a Drive file, e.g. because its current content was
+    /// fetched.
+    fn drop_stale(&mut self, drive_id: DriveIdRef) {
+        self.possibly_stale.remove(drive_id);
+        if let Some(None) = self.stale.remove(drive_id) {
+            if let Some(path) = self.disk_cache_path(drive_id) {
+                let _ = fs::remove_file(path.with_extension("stale"));
+            }
+        }
+    }
+
+    /// Records whether the last sync failed. Last known content is only served after a failed
+    /// sync and it is dropped after a successful one.
+    pub fn set_sync_failed(&mut self, failed: bool) {
+        self.sync_failed = failed;
+        if !failed {
+            let ids: Vec<DriveId> = self.stale.keys().cloned().collect();
+            for id in ids {
+                self.drop_stale(&id);
+            }
+        }
+    }
+
+    /// Whether the last known content of a file was served because its current content could not
+    /// be fetched after a failed sync.
+    pub fn is_possibly_stale(&self, drive_id: DriveIdRef) -> bool {
+        self.possibly_stale.contains(drive_id)
+    }
+
+    /// The path where the content of a Drive file is stored in the disk cache.
+    fn disk_cache_path(&self, drive_id: DriveIdRef) -> Option<PathBuf> {
+        self.cache_dir.as_ref().map(|dir| dir.join(drive_id))
+    }
+
+    /// Whether reads from a file of `file_size` bytes should be streamed with `read_streaming()`
+    /// rather than served from its full content, because it is larger than
+    /// `cache_max_file_bytes`. Exported files and files with unsaved changes are never streamed.
+    pub fn streams(&self, drive_id: DriveIdRef, mime_type: Option<&str>, file_size: u64) -> bool {
+        let max = match self.config.cache_max_file_bytes() {
+            Some(max) => max,
+            None => return false,
+        };
+        let exported = mime_type.map_or(false, |mime| {
+            self.config.export_type(mime).is_some() || UNEXPORTABLE_MIME_TYPES.contains(mime)
+        });
+
+        file_size > max
+            && !exported
+            && exported_document_id(drive_id).is_none()
+            && !self.pending_writes.contains_key(drive_id)
+            && self.existing_scratch_path(drive_id).is_none()
+    }
+
+    /// Reads `size` bytes of a file starting at `offset` from Drive. The whole chunk of
+    /// `Config::download_chunk_size` bytes around them is fetched and kept in memory, so that
+    /// further reads within it do not need another request.
+    pub fn read_streaming(
+        &mut self,
+        drive_id: DriveIdRef,
+        offset: usize,
+        size: usize,
+    ) -> Option<&[u8]> {
+        if size == 0 {
+            return Some(&[][..]);
+        }
+        let (offset, size) = (offset as u64, size as u64);
+
+        if let Some(data) = self
+            .chunks
+            .get(drive_id)
+            .and_then(|chunk| chunk.slice(offset, size))
+        {
+            self.buff = data.to_vec();
             return Some(&self.buff);
         }
 
-        match self.get_file_content(&drive_id, mime_type) {
+        let chunk_size = self.config.download_chunk_size();
+        let start = offset - offset % chunk_size;
+        let end = cmp::max(start + chunk_size, offset + size);
+        let url = self.files_url(&format!("{}?alt=media", drive_id));
+        let data = self.access_token().and_then(|access_token| {
+            download_range(
+                &url,
+                &access_token,
+                &self.config.user_agent(),
+                start,
+                end - 1,
+            )
+            .and_then(read_content)
+        });
+
+        match data {
             Ok(data) => {
-                self.buff = data[cmp::min(data.len(), offset)..cmp::min(data.len(), offset + size)]
-                    .to_vec();
-                self.cache.insert(drive_id.to_string(), data.to_vec());
+                let chunk = Chunk {
+                    start,
+                    last: (data.len() as u64) < end - start,
+                    data,
+                };
+                self.buff = chunk.slice(offset, size).unwrap_or_default().to_vec();
+                self.chunks.insert(drive_id.to_string(), chunk);
                 Some(&self.buff)
             }
             Err(e) => {
-                error!("Got error: {:?}", e);
+                self.note_read_error(drive_id, &e);
                 None
             }
         }
     }
 
+    /// Logs an error which occurred while reading the content of a file. Files which could not be
+    /// read because access to them was denied are remembered until they are read successfully.
+    pub(crate) fn note_read_error(&mut self, drive_id: DriveIdRef, error: &Error) {
+        error!("Could not read {}: {}", drive_id, error);
+        if is_permission_error(&error.to_string()) {
+            self.denied.insert(drive_id.to_string());
+        }
+    }
+
+    /// Whether the last attempt to read a file failed because access to it was denied.
+    pub fn is_denied(&self, drive_id: DriveIdRef) -> bool {
+        self.denied.contains(drive_id)
+    }
+
+    /// Returns and forgets the Drive IDs of all files which could not be read because access to
+    /// them was denied.
+    pub fn take_denied(&mut self) -> Vec<DriveId> {
+        self.denied.drain().collect()
+    }
+
+    /// Returns the Drive IDs of all files stored in the disk cache.
+    pub fn disk_cached_ids(&self) -> Vec<DriveId> {
+        let dir = match self.cache_dir {
+            Some(ref dir) => dir,
+            None => return Vec::new(),
+        };
+
+        fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    // Partial downloads have an extension. Drive ids never contain dots.
+                    .filter(|name| !name.contains('.'))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Compares the cached content of a Drive file with the MD5 checksum reported by Drive.
+    /// Returns `None` if the file is not cached or if Drive does not provide a checksum for it
+    /// (e.g. Docs and Sheets).
+    pub fn verify_cached_content(&mut self, drive_id: DriveIdRef) -> Result<Option<bool>, Error> {
+        let data = match self.cached_content(drive_id) {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .get_file_metadata(drive_id)?
+            .md5_checksum
+            .map(|expected| md5_checksum(&data) == expected))
+    }
+
     /// Creates a new file on Drive. If successful, returns the file id.
     pub fn create(&mut self, drive_file: &drive3::File) -> Result<DriveId, Error> {
         let dummy_file = DummyFile::new(&[]);
@@ -441,7 +1780,7 @@ impl DriveFacade {
             .files()
             .create(drive_file.clone())
             .use_content_as_indexable_text(true)
-            .supports_team_drives(false)
+            .supports_team_drives(self.shared_drive_id.is_some())
             .ignore_default_visibility(true)
             .upload(dummy_file, "application/octet-stream".parse().unwrap())
             .map_err(|e| err_msg(format!("{:#?}", e)))
@@ -452,10 +1791,43 @@ impl DriveFacade {
             })
     }
 
+    /// Checks whether files can be created on Drive by creating an empty folder under `parent` and
+    /// deleting it permanently right after.
+    pub fn probe_write(&mut self, parent: DriveIdRef) -> Result<(), Error> {
+        let probe = drive3::File {
+            name: Some(String::from(".gcsf-write-probe")),
+            mime_type: Some(String::from("application/vnd.google-apps.folder")),
+            parents: Some(vec![parent.to_string()]),
+            ..Default::default()
+        };
+
+        let id = self.create(&probe)?;
+        self.delete_permanently(&id)?;
+        Ok(())
+    }
+
     /// Writes some data to a Drive file starting at a certain offset.
     /// This is a lazy operation. It creates a pending write which only gets executed when flus()
     /// is called.
     pub fn write(&mut self, id: DriveId, offset: usize, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        if self.scratch_dir.is_some() {
+            match self.write_to_scratch(&id, offset, data) {
+                Ok(()) => {
+                    // An empty list of pending writes marks the file as dirty.
+                    self.pending_writes.entry(id).or_insert_with(Vec::new);
+                    return;
+                }
+                Err(e) => warn!(
+                    "Could not write to the scratch file of {}, keeping the write in memory: {}",
+                    &id, e
+                ),
+            }
+        }
+
         let pending_write = PendingWrite {
             id: id.clone(),
             offset,
@@ -468,13 +1840,95 @@ impl DriveFacade {
             .push(pending_write);
     }
 
+    /// Uploads the pending writes of all files, giving up on the remaining ones once `timeout` has
+    /// passed. Returns the ids of the files which could not be saved.
+    pub fn flush_all(&mut self, timeout: Duration) -> Vec<DriveId> {
+        let ids: Vec<DriveId> = self.pending_writes.keys().cloned().collect();
+        flush_until(ids, timeout, |id| self.flush(id))
+    }
+
+    /// Whether a file has writes which were not uploaded to Drive yet.
+    pub fn has_pending_writes(&self, id: DriveIdRef) -> bool {
+        self.pending_writes.contains_key(id)
+    }
+
+    /// How many files have writes which were not uploaded to Drive yet.
+    pub fn pending_write_count(&self) -> usize {
+        self.pending_writes.len()
+    }
+
+    /// How many files have their content cached in memory.
+    pub fn cached_count(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Discards the content of a file. The file is uploaded as empty (plus any later writes) on
+    /// the next flush.
+    pub fn truncate(&mut self, id: DriveIdRef) {
+        if let Some(path) = self.existing_scratch_path(id) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Could not remove scratch file {:?}: {}", &path, e);
+            }
+        }
+        self.invalidate(id);
+        self.store_content(id, &[]);
+        self.pending_writes.insert(id.to_string(), Vec::new());
+        self.truncated.insert(id.to_string());
+    }
+
+    /// Starts tracking a file which only exists locally under `local_id`. Its writes are kept until
+    /// its content is moved to a Drive file with `move_content()`, and flushing it does nothing.
+    pub fn defer_creation(&mut self, local_id: DriveIdRef) {
+        self.truncate(local_id);
+        self.deferred.insert(local_id.to_string());
+    }
+
+    /// Whether a file only exists locally, see `defer_creation()`.
+    pub fn is_deferred(&self, id: DriveIdRef) -> bool {
+        self.deferred.contains(id)
+    }
+
+    /// Replaces the content of the file `to` with the content of the file `from`, including writes
+    /// which have not been uploaded yet. `from` is forgotten. The new content of `to` is uploaded
+    /// on its next flush.
+    pub fn move_content(&mut self, from: DriveIdRef, to: DriveIdRef) {
+        let scratch = self.existing_scratch_path(from);
+        self.truncate(to);
+        if let (Some(from_path), Some(dir)) = (scratch, self.scratch_dir.as_ref()) {
+            if let Err(e) = fs::rename(&from_path, dir.join(to)) {
+                warn!("Could not move scratch file {:?}: {}", &from_path, e);
+            }
+        }
+
+        let mut writes = self.pending_writes.remove(from).unwrap_or_default();
+        for write in &mut writes {
+            write.id = to.to_string();
+        }
+        self.pending_writes.insert(to.to_string(), writes);
+        self.discard(from);
+    }
+
+    /// Forgets a file and any writes which were not uploaded yet, without touching Drive.
+    pub fn discard(&mut self, id: DriveIdRef) {
+        if let Some(path) = self.existing_scratch_path(id) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Could not remove scratch file {:?}: {}", &path, e);
+            }
+        }
+        self.invalidate(id);
+        self.pending_writes.remove(id);
+        self.failed_flushes.remove(id);
+        self.truncated.remove(id);
+        self.deferred.remove(id);
+    }
+
     /// Deletes a file permanently from Drive.
     pub fn delete_permanently(&mut self, id: DriveIdRef) -> Result<bool, Error> {
         self.hub
             .files()
             .delete(&id)
-            .supports_team_drives(false)
-            .add_scope(drive3::Scope::Full)
+            .supports_team_drives(self.shared_drive_id.is_some())
+            .add_scope(self.scope())
             .doit()
             .map(|response| response.status.is_success())
             .map_err(|e| err_msg(format!("{:#?}", e)))
@@ -484,29 +1938,103 @@ impl DriveFacade {
     pub fn move_to(
         &mut self,
         id: DriveIdRef,
+        source_parent: Option<DriveIdRef>,
         parent: DriveIdRef,
         new_name: &str,
+    ) -> Result<(Response, drive3::File), Error> {
+        self.update_location(id, source_parent, parent, new_name, None)
+    }
+
+    /// Moves a trashed file out of Trash. A file which was trashed explicitly is restored, while a
+    /// file which is only trashed because one of its ancestors is leaves that ancestor, which stays
+    /// in Trash together with its other children.
+    pub fn restore_to(
+        &mut self,
+        id: DriveIdRef,
+        parent: DriveIdRef,
+        new_name: &str,
+        explicitly_trashed: bool,
+    ) -> Result<(Response, drive3::File), Error> {
+        let trashed = if explicitly_trashed {
+            Some(false)
+        } else {
+            None
+        };
+        self.update_location(id, None, parent, new_name, trashed)
+    }
+
+    /// Renames a file, moves it from `source_parent` (or from all of its parents) to `parent` and
+    /// optionally changes whether it is trashed, in a single update.
+    fn update_location(
+        &mut self,
+        id: DriveIdRef,
+        source_parent: Option<DriveIdRef>,
+        parent: DriveIdRef,
+        new_name: &str,
+        trashed: Option<bool>,
     ) -> Result<(Response, drive3::File), Error> {
         let current_parents = self
             .get_file_metadata(id)?
             .parents
-            .unwrap_or_else(|| vec![String::from("root")])
-            .join(",");
+            .unwrap_or_else(|| vec![String::from("root")]);
+        let removed_parents = parents_to_remove(&current_parents, source_parent).join(",");
 
         let f = drive3::File {
             name: Some(new_name.to_string()),
+            trashed,
             ..Default::default()
         };
         self.hub
             .files()
             .update(f, id)
-            .remove_parents(&current_parents)
+            .supports_team_drives(self.shared_drive_id.is_some())
+            .remove_parents(&removed_parents)
             .add_parents(parent)
-            .add_scope(drive3::Scope::Full)
+            .add_scope(self.scope())
             .doit_without_upload()
             .map_err(|e| err_msg(format!("DriveFacade::move_to() {}", e)))
     }
 
+    /// Adds a parent to a Drive file, which then appears in one more folder.
+    pub fn add_parent(&mut self, id: DriveIdRef, parent: DriveIdRef) -> Result<(), Error> {
+        self.hub
+            .files()
+            .update(drive3::File::default(), id)
+            .add_parents(parent)
+            .add_scope(self.scope())
+            .doit_without_upload()
+            .map(|_| ())
+            .map_err(|e| err_msg(format!("DriveFacade::add_parent() {}", e)))
+    }
+
+    /// Removes one parent from a Drive file without touching its other parents.
+    pub fn remove_parent(&mut self, id: DriveIdRef, parent: DriveIdRef) -> Result<(), Error> {
+        self.hub
+            .files()
+            .update(drive3::File::default(), id)
+            .remove_parents(parent)
+            .add_scope(self.scope())
+            .doit_without_upload()
+            .map(|_| ())
+            .map_err(|e| err_msg(format!("DriveFacade::remove_parent() {}", e)))
+    }
+
+    /// Sets the description of a Drive file. An empty description removes it.
+    pub fn set_description(&mut self, id: DriveIdRef, description: &str) -> Result<(), Error> {
+        let f = drive3::File {
+            description: Some(description.to_string()),
+            ..Default::default()
+        };
+
+        self.hub
+            .files()
+            .update(f, id)
+            .add_scope(self.scope())
+            .doit_without_upload()
+            .map(|_| ())
+            .map_err(|e| err_msg(format!("DriveFacade::set_description() {}", e)))
+    }
+
     /// Marks a Google Drive file as trashed.
     pub fn move_to_trash(&mut self, id: DriveId) -> Result<(), Error> {
         let f = drive3::File {
@@ -517,7 +2045,8 @@ impl DriveFacade {
         self.hub
             .files()
             .update(f, &id)
-            .add_scope(drive3::Scope::Full)
+            .supports_team_drives(self.shared_drive_id.is_some())
+            .add_scope(self.scope())
             .doit_without_upload()
             .map(|_| ())
             .map_err(|e| err_msg(format!("DriveFacade::move_to_trash() {}", e)))
@@ -529,7 +2058,18 @@ impl DriveFacade {
             debug!("flush({}): no pending writes", id);
             return Ok(());
         }
-        self.cache.remove(id);
+        if self.deferred.contains(id) {
+            debug!("flush({}): not created on Drive until it is renamed", id);
+            return Ok(());
+        }
+        if self.writes_are_redundant(id) {
+            // Some programs flush on every close, often without having changed anything.
+            debug!("flush({}): content is unchanged", id);
+            self.pending_writes.remove(id);
+            self.failed_flushes.remove(id);
+            return Ok(());
+        }
+        self.invalidate(id);
 
         if let Ok(false) = self.contains(id) {
             return Err(err_msg(format!(
@@ -538,13 +2078,125 @@ impl DriveFacade {
             )));
         }
 
-        let mut file_data = self.get_file_content(&id, None).unwrap_or_default();
-        self.apply_pending_writes_on_data(DriveId::from(id), &mut file_data);
+        match self.upload_pending_writes(id) {
+            Ok(()) => {
+                self.failed_flushes.remove(id);
+                Ok(())
+            }
+            Err(e) => {
+                // The pending writes are kept so that no data is lost. The upload will be retried.
+                self.failed_flushes.insert(id.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether the pending writes of a file leave its cached content unchanged, e.g. because the
+    /// same data was written again. Files whose content is not cached are assumed to change.
+    fn writes_are_redundant(&mut self, id: DriveIdRef) -> bool {
+        if self.truncated.contains(id) || self.existing_scratch_path(id).is_some() {
+            return false;
+        }
+        if !self.load_cached_content(id) {
+            return false;
+        }
+        let content = match self.cache.get(id) {
+            Some(content) => content,
+            None => return false,
+        };
+        let writes = match self.pending_writes.get(id) {
+            Some(writes) => writes,
+            None => return true,
+        };
+
+        // Only the ranges covered by the writes can differ from the cached content.
+        writes.iter().all(|write| {
+            let end = write.offset + write.data.len();
+            end <= content.len()
+                && overlay_window(content, writes, write.offset, write.data.len())[..]
+                    == content[write.offset..end]
+        })
+    }
+
+    /// Downloads the current content of a file, applies its pending writes and uploads the result.
+    /// The pending writes are only dropped if the upload succeeds.
+    fn upload_pending_writes(&mut self, id: DriveIdRef) -> Result<(), Error> {
+        let scratch = self.existing_scratch_path(id);
+        let mut file_data = match scratch {
+            Some(ref path) => fs::read(path)?,
+            None if self.truncated.contains(id) => Vec::new(),
+            None => self.get_file_content(&id, None)?,
+        };
+        if let Some(writes) = self.pending_writes.get(id) {
+            overlay_pending_writes(writes, &mut file_data);
+        }
         self.update_file_content(DriveId::from(id), &file_data)?;
+        self.pending_writes.remove(id);
+        self.truncated.remove(id);
+
+        if let Some(path) = scratch {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Could not remove scratch file {:?}: {}", &path, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The path of the scratch file of a Drive file, if it exists.
+    fn existing_scratch_path(&self, drive_id: DriveIdRef) -> Option<PathBuf> {
+        self.scratch_dir
+            .as_ref()
+            .map(|dir| dir.join(drive_id))
+            .filter(|path| path.exists())
+    }
+
+    /// Applies a write to the scratch file of a Drive file. The scratch file is created from the
+    /// current content of the file (including writes still held in memory) on the first write.
+    fn write_to_scratch(
+        &mut self,
+        id: DriveIdRef,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let path = match self.existing_scratch_path(id) {
+            Some(path) => path,
+            None => {
+                let mut content = match self.cached_content(id) {
+                    Some(content) => content,
+                    None if self.truncated.contains(id) => Vec::new(),
+                    None => self.get_file_content(id, None)?,
+                };
+                if let Some(writes) = self.pending_writes.remove(id) {
+                    overlay_pending_writes(&writes, &mut content);
+                }
+
+                let path = self
+                    .scratch_dir
+                    .as_ref()
+                    .ok_or_else(|| err_msg("Scratch files are disabled"))?
+                    .join(id);
+                fs::write(&path, &content)?;
+                path
+            }
+        };
 
+        let mut file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.write_all(data)?;
         Ok(())
     }
 
+    /// Retries uploading the pending writes of all files whose previous flush failed.
+    pub fn retry_failed_flushes(&mut self) {
+        let ids: Vec<DriveId> = self.failed_flushes.iter().cloned().collect();
+        for id in ids {
+            info!("Retrying to flush {}", &id);
+            if let Err(e) = self.flush(&id) {
+                error!("Could not flush {}: {}", &id, e);
+            }
+        }
+    }
+
     /// Updates the content of a file on Drive. The MIME type is guessed appropriately based on the
     /// content.
     fn update_file_content(
@@ -563,14 +2215,105 @@ impl DriveFacade {
             ..Default::default()
         };
 
+        let _transfer = self.track_transfer(&id, true, Some(data.len() as u64));
+        let content = ProgressReader::new(DummyFile::new(data), &id, Arc::clone(&self.transfers));
+
         self.hub
             .files()
             .update(file, &id)
-            .add_scope(drive3::Scope::Full)
-            .upload_resumable(DummyFile::new(data), mime_guess.parse().unwrap())
+            .supports_team_drives(self.shared_drive_id.is_some())
+            .add_scope(self.scope())
+            .upload_resumable(content, mime_guess.parse().unwrap())
             .map_err(|e| err_msg(format!("{:#?}", e)))
     }
 
+    /// Shares a file with the given role ("reader" or "writer") and returns its `webViewLink`. If
+    /// `anyone` is set, anyone with the link gets access. Otherwise, the file is shared with the
+    /// domain of the account, which only works for Google Workspace accounts.
+    pub fn create_permission(
+        &mut self,
+        id: DriveIdRef,
+        role: &str,
+        anyone: bool,
+    ) -> Result<String, Error> {
+        let domain = if anyone {
+            None
+        } else {
+            Some(self.account_domain()?)
+        };
+        let permission = sharing_permission(role, domain.as_ref().map(String::as_str))?;
+
+        self.hub
+            .permissions()
+            .create(permission, id)
+            .add_scope(self.scope())
+            .doit()
+            .map_err(|e| err_msg(format!("{:#?}", e)))?;
+
+        self.get_file_metadata(id)?
+            .web_view_link
+            .ok_or_else(|| err_msg(format!("Drive did not report a link for {}", id)))
+    }
+
+    /// Removes the permissions which share a file with anyone who has its link. Returns how many
+    /// permissions were removed.
+    pub fn revoke_anyone_permissions(&mut self, id: DriveIdRef) -> Result<usize, Error> {
+        let (_response, permission_list) = self
+            .hub
+            .permissions()
+            .list(id)
+            .add_scope(self.scope())
+            .doit()
+            .map_err(|e| err_msg(format!("{:#?}", e)))?;
+
+        let permission_ids =
+            anyone_permission_ids(&permission_list.permissions.unwrap_or_default());
+        for permission_id in &permission_ids {
+            self.hub
+                .permissions()
+                .delete(id, permission_id)
+                .add_scope(self.scope())
+                .doit()
+                .map_err(|e| err_msg(format!("{:#?}", e)))?;
+        }
+        Ok(permission_ids.len())
+    }
+
+    /// The domain of the account's email address, e.g. "example.com".
+    fn account_domain(&mut self) -> Result<String, Error> {
+        let (_response, about) = self
+            .hub
+            .about()
+            .get()
+            .param("fields", "user(emailAddress)")
+            .add_scope(self.scope())
+            .doit()
+            .map_err(|e| err_msg(format!("{:#?}", e)))?;
+
+        about
+            .user
+            .and_then(|user| user.email_address)
+            .and_then(|email| email.rsplit('@').next().map(String::from))
+            .ok_or_else(|| err_msg("Drive did not report the email address of the account"))
+    }
+
+    /// An id which identifies the Drive account. Sessions logged into the same account share it.
+    pub fn account_id(&mut self) -> Result<String, Error> {
+        let (_response, about) = self
+            .hub
+            .about()
+            .get()
+            .param("fields", "user(permissionId)")
+            .add_scope(self.scope())
+            .doit()
+            .map_err(|e| err_msg(format!("{:#?}", e)))?;
+
+        about
+            .user
+            .and_then(|user| user.permission_id)
+            .ok_or_else(|| err_msg("Drive did not report the id of the account"))
+    }
+
     /// Returns the size and capacity of the Drive account. In some cases, the limit can be absent.
     pub fn size_and_capacity(&mut self) -> Result<(u64, Option<u64>), Error> {
         let (_response, about) = self
@@ -578,7 +2321,7 @@ impl DriveFacade {
             .about()
             .get()
             .param("fields", "storageQuota")
-            .add_scope(drive3::Scope::Full)
+            .add_scope(self.scope())
             .doit()
             .map_err(|e| err_msg(format!("{:#?}", e)))?;
 
@@ -593,6 +2336,715 @@ impl DriveFacade {
     }
 }
 
+/// Builds a permission which shares a file with `role` ("reader" or "writer"), either with everyone
+/// in `domain` or, if there is no domain, with anyone who has the link. Link-shared files can not
+/// be found through search.
+pub fn sharing_permission(role: &str, domain: Option<&str>) -> Result<drive3::Permission, Error> {
+    if role != "reader" && role != "writer" {
+        return Err(err_msg(format!(
+            "Invalid role {:?}. Expected \"reader\" or \"writer\"",
+            role
+        )));
+    }
+
+    Ok(drive3::Permission {
+        role: Some(role.to_string()),
+        type_: Some(String::from(if domain.is_some() {
+            "domain"
+        } else {
+            "anyone"
+        })),
+        domain: domain.map(String::from),
+        allow_file_discovery: Some(false),
+        ..Default::default()
+    })
+}
+
+/// The ids of the permissions which share a file with anyone who has its link.
+pub fn anyone_permission_ids(permissions: &[drive3::Permission]) -> Vec<String> {
+    permissions
+        .iter()
+        .filter(|permission| permission.type_.as_ref().map(String::as_str) == Some("anyone"))
+        .filter_map(|permission| permission.id.clone())
+        .collect()
+}
+
+/// The entries of a token file, which `DiskTokenStorage` stores as `{"tokens": [...]}`. Files
+/// which can not be parsed contain no entries.
+fn stored_tokens(token_json: &str) -> Vec<serde_json::Value> {
+    serde_json::from_str::<serde_json::Value>(token_json)
+        .ok()
+        .and_then(|file| {
+            file.get("tokens")
+                .and_then(|tokens| tokens.as_array())
+                .cloned()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether the content of a token file only grants read-only access to Drive, i.e. none of the
+/// stored tokens was issued for the full Drive scope.
+pub fn is_read_only_token(token_json: &str) -> bool {
+    !stored_scopes(token_json).is_empty() && !has_full_scope_token(token_json)
+}
+
+/// Whether any of the tokens stored in a token file was issued for the full Drive scope.
+fn has_full_scope_token(token_json: &str) -> bool {
+    stored_scopes(token_json)
+        .iter()
+        .any(|scope| scope == drive3::Scope::Full.as_ref())
+}
+
+/// The scopes of all tokens stored in a token file.
+fn stored_scopes(token_json: &str) -> Vec<String> {
+    stored_tokens(token_json)
+        .iter()
+        .filter_map(|token| token.get("scopes").and_then(|scopes| scopes.as_array()))
+        .flat_map(|scopes| scopes.iter().filter_map(|scope| scope.as_str()))
+        .map(String::from)
+        .collect()
+}
+
+/// Whether any of the tokens stored in a token file expires before `now + margin` (both expressed
+/// as UNIX timestamps).
+pub fn token_expires_within(token_json: &str, now: i64, margin: i64) -> bool {
+    stored_tokens(token_json)
+        .iter()
+        .filter_map(|token| {
+            token
+                .pointer("/token/expires_in_timestamp")
+                .and_then(serde_json::Value::as_i64)
+        })
+        .any(|expiry| expiry <= now + margin)
+}
+
+/// The parents which should be removed from a file when it is moved away from `source_parent`.
+/// Files with multiple parents only lose the parent they were moved from. If the source parent is
+/// unknown or is not one of the file's parents, all parents are removed.
+pub fn parents_to_remove(
+    current_parents: &[String],
+    source_parent: Option<DriveIdRef>,
+) -> Vec<String> {
+    match source_parent {
+        Some(source) if current_parents.iter().any(|p| p == source) => vec![source.to_string()],
+        _ => current_parents.to_vec(),
+    }
+}
+
+/// Moves the disk cache of a session to the configured `cache_dir` if it currently lives somewhere
+/// else, so that its content does not have to be downloaded again. Files which can not be moved
+/// are left in place and moving them is retried on the next start.
+pub fn relocate_cache(config: &Config) -> Result<(), Error> {
+    let new_dir = match config.cache_dir() {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    let marker = config.cache_location_file();
+
+    if let Ok(old_dir) = fs::read_to_string(&marker).map(PathBuf::from) {
+        if old_dir != new_dir && old_dir.is_dir() {
+            info!(
+                "Moving the disk cache from {:?} to {:?}",
+                &old_dir, &new_dir
+            );
+            fs::create_dir_all(&new_dir)?;
+
+            let mut left_behind = 0;
+            for entry in fs::read_dir(&old_dir)? {
+                let from = entry?.path();
+                let to = new_dir.join(from.file_name().unwrap_or_default());
+                // Renaming fails across file systems, in which case the file is copied instead.
+                let moved = fs::rename(&from, &to)
+                    .or_else(|_| fs::copy(&from, &to).and_then(|_| fs::remove_file(&from)));
+                if let Err(e) = moved {
+                    warn!("Could not move {:?} to {:?}: {}", &from, &to, e);
+                    left_behind += 1;
+                }
+            }
+
+            if left_behind > 0 {
+                return Err(err_msg(format!(
+                    "{} cached files were left in {:?}",
+                    left_behind, &old_dir
+                )));
+            }
+            let _ = fs::remove_dir(&old_dir);
+        }
+    }
+
+    fs::write(&marker, new_dir.to_string_lossy().as_bytes())?;
+    Ok(())
+}
+
+/// Whether two ids returned by `DriveFacade::account_id` belong to the same account.
+pub fn same_account(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim(), b.trim());
+    !a.is_empty() && a == b
+}
+
+/// Records that this process mounts a session of the given account, so that sessions mounted later
+/// can tell whether they share it.
+pub fn record_account(config: &Config, account_id: &str) -> Result<(), Error> {
+    fs::write(
+        config.account_file(),
+        format!("{}\n{}\n", process::id(), account_id),
+    )?;
+    Ok(())
+}
+
+/// The names of the other mounted sessions which belong to the given account. Records left behind
+/// by processes which are no longer running are ignored.
+pub fn sessions_sharing_account(config: &Config, account_id: &str) -> Vec<String> {
+    account_records(config)
+        .into_iter()
+        .filter(|(session, _)| session != config.session_name())
+        .filter_map(|(session, path)| {
+            let record = fs::read_to_string(path).ok()?;
+            let mut lines = record.lines();
+            let pid = lines.next()?;
+            let other_account = lines.next()?;
+            if process_is_running(pid) && same_account(other_account, account_id) {
+                Some(session)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Removes the account records left behind by processes which are no longer running, e.g. because
+/// they were killed before they could unmount. Returns the names of their sessions.
+pub fn remove_stale_account_records(config: &Config) -> Vec<String> {
+    account_records(config)
+        .into_iter()
+        .filter(|(_, path)| {
+            let running = fs::read_to_string(path)
+                .ok()
+                .and_then(|record| record.lines().next().map(process_is_running))
+                .unwrap_or(false);
+            !running && fs::remove_file(path).is_ok()
+        })
+        .map(|(session, _)| session)
+        .collect()
+}
+
+/// The session names and paths of all account records in the config dir, sorted by session name.
+fn account_records(config: &Config) -> Vec<(String, PathBuf)> {
+    const SUFFIX: &str = ".account";
+
+    let entries = match fs::read_dir(config.config_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut records: Vec<(String, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with('.') || !name.ends_with(SUFFIX) || name.len() <= SUFFIX.len() + 1 {
+                return None;
+            }
+            let session = name[1..name.len() - SUFFIX.len()].to_string();
+            Some((session, entry.path()))
+        })
+        .collect();
+    records.sort();
+    records
+}
+
+/// Whether a process with the given id is running. Assumed to be true where /proc is unavailable.
+fn process_is_running(pid: &str) -> bool {
+    let pid = pid.trim();
+    if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let proc_dir = Path::new("/proc");
+    !proc_dir.join("self").exists() || proc_dir.join(pid).exists()
+}
+
+/// Flushes files one by one until all of them are flushed or `timeout` has passed. Returns the ids
+/// of the files which failed or were not attempted in time.
+pub fn flush_until<F>(ids: Vec<DriveId>, timeout: Duration, mut flush: F) -> Vec<DriveId>
+where
+    F: FnMut(DriveIdRef) -> Result<(), Error>,
+{
+    let start = Instant::now();
+    let total = ids.len();
+    let mut unsaved = Vec::new();
+
+    for (i, id) in ids.into_iter().enumerate() {
+        if start.elapsed() >= timeout {
+            unsaved.push(id);
+            continue;
+        }
+
+        info!("Flushing {} ({}/{})", &id, i + 1, total);
+        if let Err(e) = flush(&id) {
+            error!("Could not flush {}: {}", &id, e);
+            unsaved.push(id);
+        }
+    }
+    unsaved
+}
+
+/// Whether an error message describes a request which was rejected because the user lacks access
+/// to the file, e.g. because the permission through which it was shared has expired. Rate limits
+/// are reported with the same status code but are not permission errors.
+pub fn is_permission_error(message: &str) -> bool {
+    let forbidden = message.contains("403 Forbidden")
+        || message.contains("code: 403")
+        || message.contains("insufficientFilePermissions");
+    forbidden && !message.contains("ateLimitExceeded")
+}
+
+/// Whether an error message describes a request which was rejected because of a rate limit. Such
+/// requests succeed when they are retried later.
+pub fn is_rate_limit_error(message: &str) -> bool {
+    message.contains("429 Too Many Requests")
+        || message.contains("code: 429")
+        || message.contains("ateLimitExceeded")
+}
+
+/// Requests the bytes `start..=end` of a file from Drive.
+fn download_range(
+    url: &str,
+    access_token: &str,
+    user_agent: &str,
+    start: u64,
+    end: u64,
+) -> Result<Response, Error> {
+    let client = limited_client()?;
+    let response = client
+        .get(url)
+        .header(hyper::header::Authorization(hyper::header::Bearer {
+            token: access_token.to_string(),
+        }))
+        .header(hyper::header::UserAgent(user_agent.to_string()))
+        .header(hyper::header::Range::Bytes(vec![
+            hyper::header::ByteRangeSpec::FromTo(start, end),
+        ]))
+        .send()?;
+
+    if !response.status.is_success() {
+        return Err(err_msg(format!(
+            "Downloading bytes {}-{} failed: {}",
+            start, end, response.status
+        )));
+    }
+    Ok(response)
+}
+
+/// Where the partial content and the downloaded extents of a file are kept while it is being
+/// downloaded into the disk cache at `path`.
+fn partial_download_paths(path: &Path) -> (PathBuf, PathBuf) {
+    (
+        path.with_extension("partial"),
+        path.with_extension("extents"),
+    )
+}
+
+/// The inclusive byte ranges of a `size` byte file which are not covered by `extents`, split into
+/// chunks of at most `chunk_size` bytes.
+pub fn missing_ranges(extents: &[(u64, u64)], size: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+    let mut extents = extents.to_vec();
+    extents.sort();
+
+    let mut gaps = Vec::new();
+    let mut next = 0;
+    for &(start, end) in &extents {
+        if start > next {
+            gaps.push((next, cmp::min(start, size)));
+        }
+        next = cmp::max(next, end + 1);
+    }
+    if next < size {
+        gaps.push((next, size));
+    }
+
+    let mut ranges = Vec::new();
+    for (start, end) in gaps {
+        let mut chunk_start = start;
+        while chunk_start < end {
+            let chunk_end = cmp::min(chunk_start + chunk_size, end);
+            ranges.push((chunk_start, chunk_end - 1));
+            chunk_start = chunk_end;
+        }
+    }
+    ranges
+}
+
+/// The headers of disk cache entries. Entries without one of them are stored as they are, e.g. the
+/// ones written before compression was enabled. Uncompressed entries only get a header if
+/// compression is enabled or their content starts like a header, which would be ambiguous.
+const NONE_CACHE_HEADER: &[u8] = b"GCSF-NONE\n";
+const GZIP_CACHE_HEADER: &[u8] = b"GCSF-GZIP\n";
+const ZSTD_CACHE_HEADER: &[u8] = b"GCSF-ZSTD\n";
+
+/// Whether content is in a format which is compressed already, such as images, videos and
+/// archives. Compressing it again would only cost time.
+fn is_compressed_already(data: &[u8]) -> bool {
+    let mime = match data.sniff_mime_type() {
+        Some(mime) => mime,
+        None => return false,
+    };
+    mime.starts_with("image/")
+        || mime.starts_with("video/")
+        || mime.starts_with("audio/")
+        || [
+            "application/zip",
+            "application/x-gzip",
+            "application/gzip",
+            "application/x-rar-compressed",
+        ]
+        .contains(&mime)
+}
+
+/// Encodes content for the disk cache. Content which is compressed already is stored as it is.
+fn encode_cache_entry(data: &[u8], compression: CacheCompression) -> io::Result<Vec<u8>> {
+    let compressed = if is_compressed_already(data) {
+        CacheCompression::None
+    } else {
+        compression
+    };
+    match compressed {
+        CacheCompression::None => {
+            let ambiguous = [NONE_CACHE_HEADER, GZIP_CACHE_HEADER, ZSTD_CACHE_HEADER]
+                .iter()
+                .any(|header| data.starts_with(header));
+            if compression == CacheCompression::None && !ambiguous {
+                Ok(data.to_vec())
+            } else {
+                Ok([NONE_CACHE_HEADER, data].concat())
+            }
+        }
+        CacheCompression::Gzip => {
+            let mut encoder = GzEncoder::new(GZIP_CACHE_HEADER.to_vec(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        CacheCompression::Zstd => {
+            let mut entry = ZSTD_CACHE_HEADER.to_vec();
+            entry.extend(zstd::encode_all(data, 0)?);
+            Ok(entry)
+        }
+    }
+}
+
+/// Decodes a disk cache entry written by `encode_cache_entry`, whichever compression it was
+/// written with.
+fn decode_cache_entry(mut entry: Vec<u8>) -> io::Result<Vec<u8>> {
+    if entry.starts_with(NONE_CACHE_HEADER) {
+        Ok(entry.split_off(NONE_CACHE_HEADER.len()))
+    } else if entry.starts_with(GZIP_CACHE_HEADER) {
+        let mut data = Vec::new();
+        GzDecoder::new(&entry[GZIP_CACHE_HEADER.len()..]).read_to_end(&mut data)?;
+        Ok(data)
+    } else if entry.starts_with(ZSTD_CACHE_HEADER) {
+        zstd::decode_all(&entry[ZSTD_CACHE_HEADER.len()..])
+    } else {
+        Ok(entry)
+    }
+}
+
+/// Reads and decodes the disk cache entry at `path`.
+fn read_cache_entry(path: &Path) -> io::Result<Vec<u8>> {
+    decode_cache_entry(fs::read(path)?)
+}
+
+/// Encodes content with `compression` and writes it to the disk cache entry at `path`.
+fn write_cache_entry(path: &Path, data: &[u8], compression: CacheCompression) -> io::Result<()> {
+    fs::write(path, encode_cache_entry(data, compression)?)
+}
+
+/// Downloads a `size` byte file to `path` using `fetch`, which returns the bytes of an inclusive
+/// range. The content is collected in a partial file next to `path` and the downloaded extents are
+/// recorded after every chunk, so a download which was interrupted continues where it stopped.
+/// The file is moved to `path` once it is complete.
+pub fn resume_download<F>(
+    path: &Path,
+    size: u64,
+    chunk_size: u64,
+    mut fetch: F,
+) -> Result<Vec<u8>, Error>
+where
+    F: FnMut(u64, u64) -> Result<Vec<u8>, Error>,
+{
+    let (partial_path, extents_path) = partial_download_paths(path);
+    let mut extents: Vec<(u64, u64)> = fs::read_to_string(&extents_path)
+        .ok()
+        .and_then(|extents| serde_json::from_str(&extents).ok())
+        .unwrap_or_default();
+    if !partial_path.exists() {
+        extents.clear();
+    }
+
+    let mut partial = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&partial_path)?;
+
+    for (start, end) in missing_ranges(&extents, size, chunk_size) {
+        let data = fetch(start, end)?;
+        if data.len() as u64 != end - start + 1 {
+            return Err(err_msg(format!(
+                "Expected {} bytes for range {}-{}, got {}",
+                end - start + 1,
+                start,
+                end,
+                data.len()
+            )));
+        }
+
+        partial.seek(SeekFrom::Start(start))?;
+        partial.write_all(&data)?;
+        partial.sync_data()?;
+        extents.push((start, end));
+        fs::write(&extents_path, serde_json::to_string(&extents)?)?;
+    }
+
+    partial.set_len(size)?;
+    drop(partial);
+    fs::rename(&partial_path, path)?;
+    fs::remove_file(&extents_path).ok();
+    Ok(fs::read(path)?)
+}
+
+/// Splits `size` bytes into at most `parts` contiguous, inclusive byte ranges of similar length.
+pub fn split_ranges(size: u64, parts: usize) -> Vec<(u64, u64)> {
+    let parts = cmp::max(parts as u64, 1);
+    let chunk = cmp::max((size + parts - 1) / parts, 1);
+
+    (0..parts)
+        .map(|i| (i * chunk, cmp::min((i + 1) * chunk, size)))
+        .filter(|&(start, end)| start < end)
+        .map(|(start, end)| (start, end - 1))
+        .collect()
+}
+
+/// Runs `authenticate` on its own thread and returns its result, or fails with "authentication
+/// timed out" if it does not finish within `timeout`. The thread is left behind in that case, since
+/// the OAuth flow can not be interrupted. Waits indefinitely if `timeout` is `None`.
+pub fn with_login_timeout<F>(timeout: Option<Duration>, authenticate: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<(), Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(authenticate());
+    });
+
+    match timeout {
+        Some(timeout) => receiver.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => err_msg(format!(
+                "Authentication timed out after {} seconds",
+                timeout.as_secs()
+            )),
+            mpsc::RecvTimeoutError::Disconnected => err_msg("Authentication failed"),
+        })?,
+        None => receiver
+            .recv()
+            .map_err(|_| err_msg("Authentication failed"))?,
+    }
+}
+
+/// Prefetches files with `concurrency` threads. Each thread creates its own worker with
+/// `new_worker` (e.g. a `DriveFacade`) and passes it to `fetch` along with the Drive id and MIME
+/// type of each file it picks up. Returns the files which could not be fetched.
+pub fn prefetch_concurrently<W, N, F>(
+    files: Vec<(DriveId, Option<String>)>,
+    concurrency: usize,
+    new_worker: N,
+    fetch: F,
+) -> Vec<(DriveId, Error)>
+where
+    N: Fn() -> W + Send + Sync + 'static,
+    F: Fn(&mut W, DriveIdRef, Option<String>) -> Result<(), Error> + Send + Sync + 'static,
+{
+    let threads = cmp::max(1, cmp::min(concurrency, files.len()));
+    let queue = Arc::new(Mutex::new(files.into_iter().rev().collect::<Vec<_>>()));
+    let new_worker = Arc::new(new_worker);
+    let fetch = Arc::new(fetch);
+
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let new_worker = Arc::clone(&new_worker);
+            let fetch = Arc::clone(&fetch);
+            thread::spawn(move || {
+                let mut worker = new_worker();
+                let mut failures = Vec::new();
+                loop {
+                    let next = queue.lock().unwrap_or_else(PoisonError::into_inner).pop();
+                    let (id, mime) = match next {
+                        Some(file) => file,
+                        None => return failures,
+                    };
+                    if let Err(e) = fetch(&mut worker, &id, mime) {
+                        failures.push((id, e));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut failures = Vec::new();
+    for handle in handles {
+        match handle.join() {
+            Ok(thread_failures) => failures.extend(thread_failures),
+            Err(_) => error!("A prefetch thread panicked"),
+        }
+    }
+    failures
+}
+
+/// Fetches byte ranges concurrently, one thread per range, and concatenates them in order.
+pub fn fetch_ranges<F>(ranges: Vec<(u64, u64)>, fetch: F) -> Result<Vec<u8>, Error>
+where
+    F: Fn(u64, u64) -> Result<Vec<u8>, Error> + Send + Sync + 'static,
+{
+    let fetch = Arc::new(fetch);
+    let handles: Vec<_> = ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let fetch = fetch.clone();
+            thread::spawn(move || fetch(start, end).map(|data| (end - start + 1, data)))
+        })
+        .collect();
+
+    let mut content = Vec::new();
+    for handle in handles {
+        let (expected, data) = handle
+            .join()
+            .map_err(|_| err_msg("A download thread panicked"))??;
+        if data.len() as u64 != expected {
+            return Err(err_msg(format!(
+                "Expected {} bytes but received {}",
+                expected,
+                data.len()
+            )));
+        }
+        content.extend(data);
+    }
+    Ok(content)
+}
+
+/// Turns a `files.listLabels` response into (xattr name, value) pairs. Every label is listed as
+/// `user.drive.label.<labelId>` and each of its fields as `user.drive.label.<labelId>.<fieldId>`.
+/// Fields with multiple values have them joined by commas.
+pub fn parse_labels(response: &str) -> Result<Vec<(String, String)>, Error> {
+    let response: serde_json::Value = serde_json::from_str(response)?;
+    let empty = Vec::new();
+    let labels = response
+        .get("labels")
+        .and_then(serde_json::Value::as_array)
+        .unwrap_or(&empty);
+
+    let mut attrs = Vec::new();
+    for label in labels {
+        let id = match label.get("id").and_then(serde_json::Value::as_str) {
+            Some(id) => id,
+            None => continue,
+        };
+        attrs.push((format!("user.drive.label.{}", id), String::new()));
+
+        let fields = match label.get("fields").and_then(serde_json::Value::as_object) {
+            Some(fields) => fields,
+            None => continue,
+        };
+        for (field_id, field) in fields {
+            let values: Vec<String> = ["text", "integer", "dateString", "selection", "user"]
+                .iter()
+                .filter_map(|kind| field.get(*kind).and_then(serde_json::Value::as_array))
+                .flat_map(|values| values.iter())
+                .map(|value| match *value {
+                    serde_json::Value::String(ref s) => s.clone(),
+                    ref other => other
+                        .get("emailAddress")
+                        .and_then(serde_json::Value::as_str)
+                        .map(String::from)
+                        .unwrap_or_else(|| other.to_string()),
+                })
+                .collect();
+            attrs.push((
+                format!("user.drive.label.{}.{}", id, field_id),
+                values.join(","),
+            ));
+        }
+    }
+    Ok(attrs)
+}
+
+/// Reads at most `size` bytes of a local file, starting at `offset`.
+fn read_range(path: &Path, offset: usize, size: usize) -> Result<Vec<u8>, Error> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset as u64))?;
+
+    let mut data = Vec::with_capacity(size);
+    file.take(size as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Reads the whole body of a download or export response.
+pub fn read_content<R: Read>(mut response: R) -> Result<Vec<u8>, Error> {
+    let mut content: Vec<u8> = Vec::new();
+    response
+        .read_to_end(&mut content)
+        .map_err(|e| err_msg(format!("Could not read response body: {}", e)))?;
+    Ok(content)
+}
+
+/// Returns the MD5 checksum of some data as a lowercase hex string, which is how Drive reports
+/// checksums.
+pub fn md5_checksum(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+/// Applies a list of pending writes on a data buffer, in the order in which they were performed.
+/// The buffer is extended (and padded with zeros) if a write ends past its current length.
+fn overlay_pending_writes(writes: &[PendingWrite], data: &mut Vec<u8>) {
+    for pending_write in writes {
+        debug!(
+            "Applying pending write with offset {} on {}",
+            &pending_write.offset, &pending_write.id
+        );
+        let end = pending_write.offset + pending_write.data.len();
+
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[pending_write.offset..end].copy_from_slice(&pending_write.data[..]);
+    }
+}
+
+/// Returns the `[offset, offset + size)` range of a data buffer as it would look after applying a
+/// list of pending writes with `overlay_pending_writes`. Only the writes which intersect the range
+/// are applied, and only the range itself is copied.
+fn overlay_window(data: &[u8], writes: &[PendingWrite], offset: usize, size: usize) -> Vec<u8> {
+    let len = writes
+        .iter()
+        .map(|write| write.offset + write.data.len())
+        .fold(data.len(), cmp::max);
+    let start = cmp::min(len, offset);
+    let end = cmp::min(len, offset + size);
+
+    let mut window = vec![0; end - start];
+    if start < data.len() {
+        let copied = cmp::min(end, data.len());
+        window[..copied - start].copy_from_slice(&data[start..copied]);
+    }
+    for write in writes {
+        let write_start = cmp::max(start, write.offset);
+        let write_end = cmp::min(end, write.offset + write.data.len());
+        if write_start < write_end {
+            window[write_start - start..write_end - start].copy_from_slice(
+                &write.data[write_start - write.offset..write_end - write.offset],
+            );
+        }
+    }
+    window
+}
+
 /// A virtual (in-memory) file which implements the Read + Seek traits. Can be constructed from a
 /// slice of bytes. Useful for uploading some file content to Drive without actually storing the
 /// file locally on disk.
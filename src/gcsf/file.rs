@@ -1,9 +1,10 @@
+use super::Config;
 use chrono::DateTime;
 use drive3;
 use failure::{err_msg, Error};
 use fuse::{FileAttr, FileType};
 use id_tree::NodeId;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use time::Timespec;
 
 type Inode = u64;
@@ -35,38 +36,139 @@ pub struct File {
 /// These types are somewhat equivalent and can be converted into one another.
 #[derive(Debug, Clone)]
 pub enum FileId {
+    /// The inode of the file.
     Inode(Inode),
+    /// The id of the file on Drive.
     DriveId(String),
+    /// The id of the file's node in the file tree.
     NodeId(NodeId),
-    ParentAndName { parent: Inode, name: String },
+    /// The inode of a parent directory and the name of the file in it.
+    ParentAndName {
+        /// The inode of the parent directory.
+        parent: Inode,
+        /// The name of the file.
+        name: String,
+    },
+}
+
+lazy_static! {
+    static ref DRIVE_DOCUMENTS: HashSet<&'static str> = hashset! {
+            "application/vnd.google-apps.document",
+            "application/vnd.google-apps.presentation",
+            "application/vnd.google-apps.spreadsheet",
+            "application/vnd.google-apps.drawing",
+            "application/vnd.google-apps.site",
+    };
 }
 
 lazy_static! {
     static ref EXTENSIONS: HashMap<&'static str, &'static str> = hashmap! {
-            "application/vnd.google-apps.document" => "#.odt",
-            "application/vnd.google-apps.presentation" => "#.odp",
-            "application/vnd.google-apps.spreadsheet" => "#.ods",
-            "application/vnd.google-apps.drawing" => "#.png",
-            "application/vnd.google-apps.site" => "#.txt",
+            "application/vnd.oasis.opendocument.text" => "odt",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => "docx",
+            "application/rtf" => "rtf",
+            "application/epub+zip" => "epub",
+            "text/html" => "html",
+            "text/plain" => "txt",
+            "application/pdf" => "pdf",
+            "application/vnd.oasis.opendocument.spreadsheet" => "ods",
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => "xlsx",
+            "text/csv" => "csv",
+            "text/tab-separated-values" => "tsv",
+            "application/vnd.oasis.opendocument.presentation" => "odp",
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation" => "pptx",
+            "image/png" => "png",
+            "image/jpeg" => "jpg",
+            "image/svg+xml" => "svg",
     };
 }
 
+/// Renders duplicate names as e.g. "report.pdf.1".
+pub const DEFAULT_DUPLICATE_NAME_TEMPLATE: &str = "{name}.{n}";
+
+/// Separates the Drive id of a document from the extension in the ids of its exports. Drive ids
+/// never contain dots.
+const EXPORT_ID_INFIX: &str = ".export.";
+
+/// The id of the file which holds the export of a Drive document in a given format when the
+/// document is shown as a directory of exports, e.g. "<id>.export.pdf".
+pub fn export_id(document_id: &str, extension: &str) -> String {
+    format!("{}{}{}", document_id, EXPORT_ID_INFIX, extension)
+}
+
+/// The Drive id of the document which a file is an export of, if the file is one of the exports
+/// in the directory of a document.
+pub fn exported_document_id(id: &str) -> Option<&str> {
+    id.find(EXPORT_ID_INFIX).map(|end| &id[..end])
+}
+
+/// The extension of files of a given MIME type, e.g. "pdf" for "application/pdf".
+pub fn extension(mime_type: &str) -> String {
+    EXTENSIONS
+        .get::<str>(mime_type)
+        .map(|ext| ext.to_string())
+        .unwrap_or_else(|| {
+            mime_type
+                .rsplit('/')
+                .next()
+                .unwrap_or(mime_type)
+                .to_string()
+        })
+}
+
+/// Whether a character in a file name may confuse terminals or tools: control characters and
+/// invisible formatting characters, such as zero-width spaces and bidirectional overrides.
+fn is_problematic_char(c: char) -> bool {
+    c.is_control()
+        || match c {
+            '\u{200B}'..='\u{200F}'
+            | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{FEFF}' => true,
+            _ => false,
+        }
+}
+
+/// The suffix added to the name of a special file if `add_extensions_to_special_files` is enabled,
+/// e.g. "#.ods" for spreadsheets. `None` for other files and for documents which are shown as a
+/// directory of exports.
+fn special_suffix(drive_file: &drive3::File, config: &Config) -> Option<String> {
+    if !config.add_extensions_to_special_files() {
+        return None;
+    }
+    let mime = drive_file.mime_type.as_ref()?;
+    if !config.multi_export_types(mime).is_empty() {
+        return None;
+    }
+    let ext = config
+        .export_type(mime)
+        .and_then(|t| EXTENSIONS.get::<str>(&t).cloned())?;
+    Some(format!("{}.{}", config.special_extension_separator(), ext))
+}
+
 impl File {
     /// Creates a new file using a Drive file as a template.
-    pub fn from_drive_file(inode: Inode, drive_file: drive3::File, add_extension: bool) -> Self {
+    pub fn from_drive_file(inode: Inode, drive_file: drive3::File, config: &Config) -> Self {
         let mut size = drive_file
             .size
             .clone()
             .map(|size| size.parse::<u64>().unwrap_or_default())
             .unwrap_or(10 * 1024 * 1024);
 
-        let kind =
-            if drive_file.mime_type == Some(String::from("application/vnd.google-apps.folder")) {
-                size = 512;
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            };
+        // Documents exported in several formats are shown as a directory of exports.
+        let multi_export = drive_file
+            .mime_type
+            .as_ref()
+            .map_or(false, |t| !config.multi_export_types(t).is_empty());
+        let kind = if drive_file.mime_type
+            == Some(String::from("application/vnd.google-apps.folder"))
+            || multi_export
+        {
+            size = 512;
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        };
 
         let times: Vec<_> = vec![
             &drive_file.created_time,
@@ -107,6 +209,8 @@ impl File {
 
         if attr.kind == FileType::Directory {
             attr.size = 512;
+        } else if config.readonly_not_owned() && drive_file.owned_by_me == Some(false) {
+            attr.perm &= !0o222;
         }
 
         let mut filename = drive_file.name.clone().unwrap();
@@ -118,14 +222,8 @@ impl File {
         //     .map(|owner| owner.email_address.unwrap())
         //     .collect();
 
-        if add_extension {
-            let ext = drive_file
-                .mime_type
-                .clone()
-                .and_then(|t| EXTENSIONS.get::<str>(&t));
-            if ext.is_some() {
-                filename = format!("{}{}", filename, ext.unwrap());
-            }
+        if let Some(suffix) = special_suffix(&drive_file, config) {
+            filename.push_str(&suffix);
         }
 
         File {
@@ -133,6 +231,13 @@ impl File {
             name: filename
                 .chars()
                 .filter(|c| File::is_posix(c))
+                .map(|c| {
+                    if config.sanitize_control_chars() && is_problematic_char(c) {
+                        '_'
+                    } else {
+                        c
+                    }
+                })
                 .collect::<String>(),
             attr,
             identical_name_id: None,
@@ -140,6 +245,22 @@ impl File {
         }
     }
 
+    /// The name under which a file which is shown as `name` is stored on Drive. The suffix added by
+    /// `add_extensions_to_special_files` is stripped, so that renaming "notes#.odt" to
+    /// "report#.odt" names the document "report".
+    pub fn drive_name(&self, name: &str, config: &Config) -> String {
+        let suffix = self
+            .drive_file
+            .as_ref()
+            .and_then(|drive_file| special_suffix(drive_file, config));
+        match suffix {
+            Some(ref suffix) if name.len() > suffix.len() && name.ends_with(suffix.as_str()) => {
+                name[..name.len() - suffix.len()].to_string()
+            }
+            _ => name.to_string(),
+        }
+    }
+
     /// Whether a character can be used in a valid POSIX file name.
     /// Read the [Wikipedia article](https://en.wikipedia.org/wiki/Filename)
     fn is_posix(c: &char) -> bool {
@@ -156,6 +277,17 @@ impl File {
             .unwrap_or(false)
     }
 
+    /// Whether this file was trashed on its own rather than because one of its ancestors was.
+    /// Files whose trash state is unknown count as explicitly trashed.
+    pub fn is_explicitly_trashed(&self) -> bool {
+        self.is_trashed()
+            && self
+                .drive_file
+                .as_ref()
+                .and_then(|f| f.explicitly_trashed)
+                .unwrap_or(true)
+    }
+
     // Trashing a file does not trigger a file update from Drive. Therefore this field must be
     // set manually so that GCSF knows that this particular file is trashed and should be deleted
     // permanently the next time unlink() is called.
@@ -163,6 +295,7 @@ impl File {
         let ino = self.inode();
         if let Some(ref mut drive_file) = self.drive_file.as_mut() {
             drive_file.trashed = Some(trashed);
+            drive_file.explicitly_trashed = Some(trashed);
             Ok(())
         } else {
             Err(err_msg(format!(
@@ -178,15 +311,33 @@ impl File {
         self.drive_file
             .as_ref()
             .and_then(|f| f.mime_type.clone())
-            .map(|t| EXTENSIONS.contains_key::<str>(&t))
+            .map(|t| DRIVE_DOCUMENTS.contains::<str>(&t))
             == Some(true)
     }
 
     pub fn name(&self) -> String {
-        match self.identical_name_id {
-            Some(id) => format!("{}.{}", self.name, id),
-            None => self.name.clone(),
-        }
+        self.name_with_template(DEFAULT_DUPLICATE_NAME_TEMPLATE)
+    }
+
+    /// The file name, rendered according to `template` if there are multiple files with the
+    /// same name. See `Config::duplicate_name_template` for the supported placeholders.
+    pub fn name_with_template(&self, template: &str) -> String {
+        let n = match self.identical_name_id {
+            Some(n) => n,
+            None => return self.name.clone(),
+        };
+
+        // Hidden files without any other dot (e.g. ".bashrc") have no extension.
+        let (stem, ext) = match self.name.rfind('.') {
+            Some(dot) if dot > 0 => self.name.split_at(dot),
+            _ => (self.name.as_str(), ""),
+        };
+
+        template
+            .replace("{name}", &self.name)
+            .replace("{stem}", stem)
+            .replace("{ext}", ext)
+            .replace("{n}", &n.to_string())
     }
 
     pub fn inode(&self) -> Inode {
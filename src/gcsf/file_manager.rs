@@ -1,15 +1,24 @@
-use super::{File, FileId};
+use super::file::{export_id, exported_document_id, extension};
+use super::{Clock, Config, File, FileId, FolderMapping, SystemClock};
 use drive3;
 use failure::{err_msg, Error};
 use fuse::{FileAttr, FileType};
+use glob::{MatchOptions, Pattern};
 use id_tree::InsertBehavior::*;
 use id_tree::MoveBehavior::*;
 use id_tree::RemoveBehavior::*;
 use id_tree::{Node, NodeId, Tree, TreeBuilder};
-use std::collections::HashMap;
+use libc::O_APPEND;
+use serde_json;
+use std::cmp;
 use std::collections::LinkedList;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::time::{Duration, SystemTime};
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use time::Timespec;
 use DriveFacade;
 
@@ -19,17 +28,72 @@ pub type DriveId = String;
 const ROOT_INODE: Inode = 1;
 const TRASH_INODE: Inode = 2;
 const SHARED_INODE: Inode = 3;
+/// The prefix of the ids given to files which are not created on Drive yet.
+const LOCAL_ID_PREFIX: &str = "local-";
 
-macro_rules! unwrap_or_continue {
-    ($res:expr) => {
-        match $res {
-            Some(val) => val,
-            None => {
-                warn!("unwrap_or_continue!(): skipped.");
-                continue;
-            }
-        }
-    };
+/// What the heartbeat log line reports about the mount.
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatStats {
+    /// When remote changes were last checked for.
+    pub last_sync: SystemTime,
+    /// How many files have their content cached in memory.
+    pub cached_items: usize,
+    /// How many files have writes which were not uploaded yet.
+    pub pending_writes: usize,
+}
+
+/// The heartbeat line which is logged at `now`, e.g. "alive: last sync 12s ago, 40 cached items,
+/// 2 pending writes".
+pub fn heartbeat_summary(stats: &HeartbeatStats, now: SystemTime) -> String {
+    format!(
+        "alive: last sync {}s ago, {} cached items, {} pending writes",
+        now.duration_since(stats.last_sync)
+            .unwrap_or_default()
+            .as_secs(),
+        stats.cached_items,
+        stats.pending_writes
+    )
+}
+
+/// The state of an open file handle.
+#[derive(Clone, Debug)]
+pub struct OpenFile {
+    /// The inode of the file which was opened.
+    pub inode: Inode,
+    /// Whether the file was opened with `O_APPEND`, i.e. all writes go to the end of the file.
+    pub append: bool,
+}
+
+/// The output format of `FileManager::write_manifest`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ManifestFormat {
+    /// A JSON array with one object per file.
+    Json,
+    /// A header row followed by one row per file.
+    Csv,
+}
+
+/// A single file in a manifest.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEntry<'a> {
+    path: &'a str,
+    id: Option<&'a str>,
+    size: u64,
+    mime_type: Option<&'a str>,
+    md5_checksum: Option<&'a str>,
+}
+
+/// A file which was deleted locally but not yet on Drive.
+#[derive(Clone, Debug)]
+struct PendingDelete {
+    /// Where the file was before it was deleted.
+    parent: Inode,
+    name: String,
+    /// When the file should be deleted on Drive.
+    deadline: SystemTime,
+    /// Whether the file should be deleted permanently rather than moved to Trash.
+    permanently: bool,
 }
 
 /// Manages files locally and uses a DriveFacade in order to communicate with Google Drive and to ensure consistency between the local and remote state.
@@ -37,30 +101,106 @@ pub struct FileManager {
     /// A representation of the file tree. Each tree node stores the inode of the corresponding file.
     tree: Tree<Inode>,
 
+    /// The Drive ids which are kept out of the tree: the ones in `Config::exclude_ids` and the
+    /// descendants of excluded folders which were seen so far.
+    excluded: HashSet<DriveId>,
+
+    /// The compiled `Config::exclude_patterns`.
+    exclude_patterns: Vec<Pattern>,
+
+    /// Folders at `Config::max_tree_depth` whose children are listed once the folder is accessed.
+    unloaded: HashSet<Inode>,
+
+    /// The Drive ids of the files which were left out below `unloaded` folders. Changes to them
+    /// are ignored, since they are listed again when their folder is loaded.
+    beyond_depth: HashSet<DriveId>,
+
     /// Maps inodes to the corresponding files.
     pub files: HashMap<Inode, File>,
 
     /// Maps inodes to corresponding node ids that `tree` uses.
     pub node_ids: HashMap<Inode, NodeId>,
 
-    /// Maps Google Drive ids (i.e strings) to corresponding inodes.
+    /// Maps Google Drive ids (i.e strings) to corresponding inodes. If a Drive file is added more
+    /// than once, the first inode keeps the mapping; see `duplicate_drive_ids`.
     pub drive_ids: HashMap<DriveId, Inode>,
 
+    /// Further inodes of Drive files which were added more than once, e.g. while a change
+    /// reconstructs a file. One of them takes over the mapping in `drive_ids` once the mapped
+    /// inode is deleted.
+    duplicate_drive_ids: HashMap<DriveId, Vec<Inode>>,
+
+    /// Maps the Drive ids of folders placed according to `Config::folder_mappings` to the inode of
+    /// their local parent and their local name. These folders are not moved back under their Drive
+    /// parent during sync.
+    grafts: HashMap<DriveId, (Inode, String)>,
+
+    /// The inode of the ".search" directory, whose subdirectories are Drive search queries.
+    search_dir: Option<Inode>,
+
+    /// Maps the inodes of search directories to their query and the time it was last run.
+    searches: HashMap<Inode, (String, Option<SystemTime>)>,
+
+    /// The inode of ".gcsf/transfers", which lists the uploads and downloads in progress.
+    transfers_file: Option<Inode>,
+
+    /// The inode of ".gcsf/sync_enabled", which pauses and resumes sync when written to.
+    sync_enabled_file: Option<Inode>,
+
+    /// Whether sync is paused. Persisted in `Config::sync_paused_file()`.
+    sync_paused: bool,
+
+    /// Whether `populate` failed partway, so that some files of "My Drive" are still missing.
+    /// They are listed again during the next sync.
+    populate_incomplete: bool,
+
+    /// The inode of ".gcsf/pending-deletes", which holds deleted files during their grace period.
+    pending_deletes_dir: Option<Inode>,
+
+    /// Files held in ".gcsf/pending-deletes", by inode.
+    pending_deletes: HashMap<Inode, PendingDelete>,
+
+    /// When files were moved to "Trash", by inode. Used for emptying the Trash after
+    /// `Config::trash_retention`.
+    trashed_at: HashMap<Inode, SystemTime>,
+
+    /// Additional (parent, name) paths of files which were hard linked. The tree only holds the
+    /// original path of each file.
+    links: HashMap<Inode, Vec<(Inode, String)>>,
+
+    /// Counts how many times each inode was handed to the kernel. Once the kernel forgets an
+    /// inode entirely, the memory used by its cached content is released.
+    lookups: HashMap<Inode, u64>,
+
+    /// The open file handles, by the handle number given to the kernel.
+    open_files: HashMap<u64, OpenFile>,
+    /// How many handles of each file are open.
+    open_handles: HashMap<Inode, usize>,
+
+    /// The last handle number given to the kernel.
+    last_fh: u64,
+
     /// A `DriveFacade` is used in order to communicate with the Google Drive API.
     pub df: DriveFacade,
 
+    /// Tells the time, e.g. in order to decide whether a sync is due.
+    clock: Arc<dyn Clock>,
+
     /// The last timestamp when the file manager asked Google Drive for remote changes.
     pub last_sync: SystemTime,
 
+    /// The numbers reported by the heartbeat thread, which are refreshed by the operations that
+    /// change them.
+    heartbeat: Arc<Mutex<HeartbeatStats>>,
+
     /// Specifies how much time is needed to pass since `last_sync` for a new sync to be performed.
     pub sync_interval: Duration,
 
     /// Rename duplicate files if enabled.
     pub rename_identical_files: bool,
 
-    /// Add an extension to special files (docs, presentations, sheets, drawings, sites).
-    /// e.g. "#.ods" for spreadsheets.
-    pub add_extensions_to_special_files: bool,
+    /// Decides how special files (docs, presentations, sheets, drawings, sites) are named.
+    config: Config,
 
     /// If enabled, deleting files will remove them permanently instead of moving them to Trash.
     /// Deleting trashed files always removes them permanently.
@@ -70,135 +210,778 @@ pub struct FileManager {
 }
 
 impl FileManager {
-    /// Creates a new FileManager with a specific `sync_interval` and an injected `DriveFacade`.
+    /// Creates a new FileManager configured by `config` and an injected `DriveFacade`.
     /// Also populates the manager's file tree with files contained in "My Drive" and "Trash".
-    pub fn with_drive_facade(
-        rename_identical_files: bool,
-        add_extensions_to_special_files: bool,
-        skip_trash: bool,
-        sync_interval: Duration,
-        df: DriveFacade,
-    ) -> Result<Self, Error> {
-        let mut manager = FileManager {
+    /// If only some of the files could be listed, the manager holds those and lists the rest
+    /// during the next sync.
+    pub fn with_drive_facade(config: &Config, df: DriveFacade) -> Result<Self, Error> {
+        let mut manager = FileManager::new(config, df);
+        manager.set_exclude_patterns(&config.exclude_patterns())?;
+
+        // Trash does not depend on the rest of the tree, so a worker can list it meanwhile.
+        let trash_worker = if !config.mirror_mode() && config.api_concurrency() > 1 {
+            match manager.df.trash_lister() {
+                Ok(lister) => Some(thread::spawn(move || lister.list())),
+                Err(e) => {
+                    warn!("Could not list Trash alongside My Drive: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if let Err(e) = manager.populate() {
+            if !manager.contains(&FileId::Inode(ROOT_INODE)) {
+                return Err(err_msg(format!("Could not populate file system:\n{}", e)));
+            }
+            error!(
+                "Could not list all files. Mounting the ones listed so far and retrying during \
+                 the next sync:\n{}",
+                e
+            );
+            manager.populate_incomplete = true;
+        }
+        if !config.mirror_mode() {
+            let trashed = match trash_worker {
+                Some(worker) => worker
+                    .join()
+                    .map_err(|_| err_msg("The worker listing Trash panicked"))
+                    .and_then(|files| files),
+                None => manager.df.get_all_files(None, Some(true)),
+            };
+            trashed
+                .and_then(|files| manager.populate_trash(files))
+                .map_err(|e| err_msg(format!("Could not populate trash dir:\n{}", e)))?;
+        }
+        manager
+            .apply_folder_mappings(&config.folder_mappings())
+            .map_err(|e| err_msg(format!("Could not apply folder mappings:\n{}", e)))?;
+        if let Some(interval) = config.heartbeat_interval() {
+            manager.spawn_heartbeat(interval);
+        }
+        Ok(manager)
+    }
+
+    /// Starts a thread which logs a heartbeat line every `interval` until the manager is dropped.
+    fn spawn_heartbeat(&mut self, interval: Duration) {
+        self.update_heartbeat();
+        let stats = Arc::downgrade(&self.heartbeat);
+        let clock = Arc::clone(&self.clock);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let stats = match stats.upgrade() {
+                Some(stats) => stats,
+                None => break,
+            };
+            let stats = *stats.lock().unwrap();
+            info!("{}", heartbeat_summary(&stats, clock.now()));
+        });
+    }
+
+    /// Refreshes the numbers reported by the heartbeat thread.
+    fn update_heartbeat(&mut self) {
+        let stats = HeartbeatStats {
+            last_sync: self.last_sync,
+            cached_items: self.df.cached_count(),
+            pending_writes: self.df.pending_write_count(),
+        };
+        if let Ok(mut heartbeat) = self.heartbeat.lock() {
+            *heartbeat = stats;
+        }
+    }
+
+    /// The heartbeat line which describes the current state of the mount.
+    pub fn heartbeat(&mut self) -> String {
+        self.update_heartbeat();
+        let stats = *self.heartbeat.lock().unwrap();
+        heartbeat_summary(&stats, self.clock.now())
+    }
+
+    /// Compiles the glob patterns of the files which are kept out of the tree. Fails if any of them
+    /// is invalid.
+    pub(crate) fn set_exclude_patterns(&mut self, patterns: &[String]) -> Result<(), Error> {
+        self.exclude_patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Pattern::new(pattern)
+                    .map_err(|e| err_msg(format!("Invalid exclude pattern {:?}: {}", pattern, e)))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Creates a new FileManager with an empty file tree. Does not communicate with Drive.
+    pub(crate) fn new(config: &Config, df: DriveFacade) -> Self {
+        FileManager {
             tree: TreeBuilder::new().with_node_capacity(500).build(),
+            excluded: config.exclude_ids().into_iter().collect(),
+            exclude_patterns: Vec::new(),
+            unloaded: HashSet::new(),
+            beyond_depth: HashSet::new(),
             files: HashMap::new(),
             node_ids: HashMap::new(),
             drive_ids: HashMap::new(),
+            duplicate_drive_ids: HashMap::new(),
+            grafts: HashMap::new(),
+            search_dir: None,
+            searches: HashMap::new(),
+            transfers_file: None,
+            sync_enabled_file: None,
+            sync_paused: config.sync_paused_file().exists(),
+            populate_incomplete: false,
+            pending_deletes_dir: None,
+            pending_deletes: HashMap::new(),
+            trashed_at: HashMap::new(),
+            open_files: HashMap::new(),
+            open_handles: HashMap::new(),
+            last_fh: 0,
+            links: HashMap::new(),
+            lookups: HashMap::new(),
+            clock: Arc::new(SystemClock),
             last_sync: SystemTime::now(),
-            rename_identical_files,
-            add_extensions_to_special_files,
-            skip_trash,
-            sync_interval,
+            heartbeat: Arc::new(Mutex::new(HeartbeatStats {
+                last_sync: SystemTime::now(),
+                cached_items: 0,
+                pending_writes: 0,
+            })),
+            rename_identical_files: config.rename_identical_files(),
+            config: config.clone(),
+            skip_trash: config.skip_trash(),
+            sync_interval: config.sync_interval(),
             df,
-            last_inode: 2,
-        };
+            // Inodes up to SHARED_INODE are reserved for the special directories.
+            last_inode: SHARED_INODE,
+        }
+    }
 
-        manager
-            .populate()
-            .map_err(|e| err_msg(format!("Could not populate file system:\n{}", e)))?;
-        manager
-            .populate_trash()
-            .map_err(|e| err_msg(format!("Could not populate trash dir:\n{}", e)))?;
-        Ok(manager)
+    /// Replaces the clock used for telling the time, also in the `DriveFacade`. The time of the
+    /// last sync is reset to the current time of the new clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.last_sync = clock.now();
+        self.df.set_clock(Arc::clone(&clock));
+        self.clock = clock;
+    }
+
+    /// Whether `sync_interval` has passed since the last sync.
+    pub fn sync_due(&self) -> bool {
+        self.clock
+            .now()
+            .duration_since(self.last_sync)
+            .unwrap_or_default()
+            >= self.sync_interval
     }
 
     /// Tries to retrieve recent changes from the `DriveFacade` and apply them locally in order to
-    /// maintain data consistency. Fails early if not enough time has passed since the last sync.
+    /// maintain data consistency. Fails early if sync is paused or if not enough time has passed
+    /// since the last sync.
     pub fn sync(&mut self) -> Result<(), Error> {
-        if SystemTime::now().duration_since(self.last_sync).unwrap() < self.sync_interval {
+        if self.sync_paused {
+            return Err(err_msg("Sync is paused. Will do nothing."));
+        }
+        if !self.sync_due() {
             return Err(err_msg(
                 "Not enough time has passed since last sync. Will do nothing.",
             ));
         }
 
         info!("Checking for changes and possibly applying them.");
-        self.last_sync = SystemTime::now();
+        self.last_sync = self.clock.now();
+        if self.populate_incomplete {
+            if let Err(e) = self.complete_populate() {
+                warn!("Could not list the missing files: {}", e);
+            }
+        }
+        if let Err(e) = self.df.refresh_token_if_needed() {
+            warn!("{}", e);
+        }
+        self.df.retry_failed_flushes();
+        self.apply_pending_deletes(false);
+        self.empty_old_trash();
+        self.prune_expired_shares();
+        if let Err(e) = self.df.verify_root_id() {
+            error!("{}", e);
+        }
 
+        let result = self.apply_all_changes();
+        self.df.set_sync_failed(result.is_err());
+        self.update_heartbeat();
+        result
+    }
+
+    /// Retrieves all changes since the last sync from Drive and applies them locally.
+    fn apply_all_changes(&mut self) -> Result<(), Error> {
         for change in self
             .df
             .get_all_changes()?
             .into_iter()
             .filter(|change| change.file.is_some())
         {
-            debug!("Processing a change from {:?}", &change.time);
-            let id = FileId::DriveId(change.file_id.unwrap());
-            let drive_f = change.file.unwrap();
-
-            // New file. Create it locally
-            if !self.contains(&id) {
-                debug!("New file. Create it locally");
-                let f = File::from_drive_file(
-                    self.next_available_inode(),
-                    drive_f.clone(),
-                    self.add_extensions_to_special_files,
-                );
-                debug!("newly created file: {:#?}", &f);
+            self.apply_change(change)?;
+        }
 
-                let parent = f.drive_parent().unwrap();
-                debug!("drive parent: {:#?}", &parent);
-                self.add_file_locally(f, Some(FileId::DriveId(parent)))?;
-                debug!("self.add_file_locally() finished");
+        Ok(())
+    }
+
+    /// Applies a single change retrieved from Drive to the local file tree.
+    pub(crate) fn apply_change(&mut self, change: drive3::Change) -> Result<(), Error> {
+        debug!("Processing a change from {:?}", &change.time);
+        let drive_id = change.file_id.unwrap();
+        self.df.invalidate(&drive_id);
+        self.invalidate_exports(&drive_id);
+        let id = FileId::DriveId(drive_id.clone());
+        let drive_f = change.file.unwrap();
+
+        if !self.exclude_patterns.is_empty() {
+            self.drop_pattern_excluded(vec![drive_f.clone()]);
+        }
+        if self.is_excluded(&drive_id, &drive_f) {
+            debug!("{:?} is excluded. Keeping it out of the tree.", &id);
+            self.excluded.insert(drive_id);
+            if self.contains(&id) {
+                self.delete_children_locally(&id)?;
+                self.delete_locally(&id)?;
             }
+            return Ok(());
+        }
 
-            // Trashed file. Move it to trash locally
-            if Some(true) == drive_f.trashed {
-                debug!("Trashed file. Move it to trash locally");
-                let result = self.move_file_to_trash(&id, false);
-                if result.is_err() {
-                    error!("Could not move to trash: {:?}", result)
-                }
-                continue;
+        if self.is_beyond_depth(&drive_id, &drive_f) {
+            debug!(
+                "{:?} is below a folder which is not loaded yet. Ignoring it.",
+                &id
+            );
+            self.beyond_depth.insert(drive_id);
+            if self.contains(&id) {
+                self.delete_children_locally(&id)?;
+                self.delete_locally(&id)?;
             }
+            return Ok(());
+        }
 
-            // Removed file. Remove it locally.
-            if let Some(true) = change.removed {
-                debug!("Removed file. Remove it locally.");
-                let result = self.delete_locally(&id);
-                if result.is_err() {
-                    error!("Could not delete locally: {:?}", result)
-                }
-                continue;
+        if let Some(inode) = self.get_inode(&id) {
+            if self.pending_deletes.contains_key(&inode) {
+                debug!("{:?} is about to be deleted. Ignoring its changes.", &id);
+                return Ok(());
             }
+        }
 
-            // Anything else: reconstruct the file locally and move it under its parent.
-            debug!("Anything else: reconstruct the file locally and move it under its parent.");
-            let new_parent = {
-                let add_extension = self.add_extensions_to_special_files;
-                let f = unwrap_or_continue!(self.get_mut_file(&id));
-                *f = File::from_drive_file(f.inode(), drive_f.clone(), add_extension);
-                FileId::DriveId(f.drive_parent().unwrap())
+        // New file. Create it locally
+        let mut orphaned = false;
+        if !self.contains(&id) {
+            debug!("New file. Create it locally");
+            let f =
+                File::from_drive_file(self.next_available_inode(), drive_f.clone(), &self.config);
+            debug!("newly created file: {:#?}", &f);
+
+            let parent = f.drive_parent();
+            debug!("drive parent: {:#?}", &parent);
+            let parent = match parent.map(FileId::DriveId) {
+                Some(ref parent) if self.contains(parent) => parent.clone(),
+                _ if self.config.mirror_mode() => {
+                    debug!("{:?} is not in My Drive. Ignoring it in mirror mode.", &id);
+                    return Ok(());
+                }
+                // Shared files often come without a parent, or with one which is not visible.
+                // They wait in "Shared with me" until a later change places them elsewhere.
+                _ => {
+                    warn!(
+                        "The parent of {:?} is unknown. Placing it in \"Shared with me\".",
+                        &id
+                    );
+                    orphaned = true;
+                    FileId::Inode(SHARED_INODE)
+                }
             };
-            let result = self.move_locally(&id, &new_parent);
+            self.add_file_locally(f, Some(parent))?;
+            debug!("self.add_file_locally() finished");
+        }
+
+        // Trashed file. Move it to trash locally
+        if Some(true) == drive_f.trashed {
+            debug!("Trashed file. Move it to trash locally");
+            let result = self.move_file_to_trash(&id, false);
+            if result.is_err() {
+                error!("Could not move to trash: {:?}", result)
+            }
+            return Ok(());
+        }
+
+        // Removed file. Remove it locally.
+        if let Some(true) = change.removed {
+            debug!("Removed file. Remove it locally.");
+            let result = self.delete_locally(&id);
+            if result.is_err() {
+                error!("Could not delete locally: {:?}", result)
+            }
+            return Ok(());
+        }
+
+        if orphaned {
+            return Ok(());
+        }
+
+        // Restored file. Move it out of trash locally
+        if Some(false) == drive_f.trashed && self.is_in_trash(&id) {
+            debug!("Restored file. Move it out of trash locally");
+            let result = self.restore_from_trash(&id, drive_f);
             if result.is_err() {
-                error!("Could not move locally: {:?}", result)
+                error!("Could not restore from trash: {:?}", result)
             }
+            return Ok(());
         }
 
+        // Anything else: reconstruct the file locally and move it under its parent.
+        debug!("Anything else: reconstruct the file locally and move it under its parent.");
+        let result = self.update_locally(&id, drive_f);
+        if result.is_err() {
+            error!("Could not update locally: {:?}", result)
+        }
         Ok(())
     }
 
+    /// Moves a file which was restored on Drive out of the local Trash and under its Drive parent.
+    /// If the parent is not known locally, the file ends up in "Shared with me", like it would when
+    /// populating the file tree.
+    fn restore_from_trash(&mut self, id: &FileId, drive_file: drive3::File) -> Result<(), Error> {
+        let parent_is_known = drive_file
+            .parents
+            .as_ref()
+            .and_then(|parents| parents.first())
+            .map_or(false, |parent| {
+                let parent = FileId::DriveId(parent.clone());
+                self.contains(&parent) && !self.is_in_trash(&parent)
+            });
+        if parent_is_known {
+            return self.update_locally(id, drive_file);
+        }
+
+        let config = self.config.clone();
+        {
+            let f = self
+                .get_mut_file(id)
+                .ok_or_else(|| err_msg(format!("Cannot find {:?}", id)))?;
+            *f = File::from_drive_file(f.inode(), drive_file, &config);
+        }
+        self.move_locally(id, &FileId::Inode(SHARED_INODE))
+    }
+
+    /// Reconstructs a local file from its updated Drive counterpart and moves it under its parent.
+    /// The file type may have changed in the meantime: if a folder turned into a regular file, its
+    /// local children are dropped since they can no longer be reached.
+    pub(crate) fn update_locally(
+        &mut self,
+        id: &FileId,
+        drive_file: drive3::File,
+    ) -> Result<(), Error> {
+        let graft = match id {
+            FileId::DriveId(ref drive_id) => self.grafts.get(drive_id).cloned(),
+            _ => None,
+        };
+        let config = self.config.clone();
+
+        let (no_longer_dir, new_parent) = {
+            let f = self
+                .get_mut_file(id)
+                .ok_or_else(|| err_msg(format!("Cannot find {:?}", id)))?;
+            let was_dir = f.kind() == FileType::Directory;
+            *f = File::from_drive_file(f.inode(), drive_file, &config);
+
+            if f.inode() == ROOT_INODE {
+                // "My Drive" keeps the configured root name and stays where it is.
+                f.name = config.root_name();
+                return Ok(());
+            }
+
+            let new_parent = match graft {
+                Some((parent, name)) => {
+                    f.name = name;
+                    FileId::Inode(parent)
+                }
+                None => FileId::DriveId(
+                    f.drive_parent()
+                        .ok_or_else(|| err_msg(format!("{:?} has no parent", id)))?,
+                ),
+            };
+            (was_dir && f.kind() != FileType::Directory, new_parent)
+        };
+
+        if no_longer_dir {
+            debug!("{:?} is no longer a folder. Dropping its children.", id);
+            self.delete_children_locally(id)?;
+        }
+
+        self.move_locally(id, &new_parent)
+    }
+
     /// Retrieves all files and directories shown in "My Drive" and "Shared with me" and adds them locally.
+    /// If listing fails partway, the files listed so far are still added before the error is
+    /// returned.
     fn populate(&mut self) -> Result<(), Error> {
+        let (files, result) = self.df.get_all_files_partially(None, Some(false));
+        self.populate_with(files)?;
+        result
+    }
+
+    /// Lists the files of "My Drive" again after `populate` failed partway and adds the ones
+    /// which are still missing. Files waiting in "Shared with me" are moved under their parents
+    /// once those are known.
+    pub(crate) fn complete_populate(&mut self) -> Result<(), Error> {
+        let files = self.df.get_all_files(None, Some(false))?;
+        let missing: Vec<drive3::File> = files
+            .into_iter()
+            .filter(|file| match file.id {
+                Some(ref id) => {
+                    !self.contains(&FileId::DriveId(id.clone())) && !self.beyond_depth.contains(id)
+                }
+                None => false,
+            })
+            .collect();
+        let missing = self.drop_pattern_excluded(missing);
+        info!("Adding {} files which were missing.", missing.len());
+
+        // "Shared with me" holds the files until their parents are found, even in mirror mode.
+        let mirror_mode = self.config.mirror_mode();
+        if mirror_mode {
+            self.add_shared_dir()?;
+        }
+        for drive_file in missing {
+            let file = File::from_drive_file(self.next_available_inode(), drive_file, &self.config);
+            self.add_file_locally(file, Some(FileId::Inode(SHARED_INODE)))?;
+        }
+        let mut waiting: Vec<Inode> = self
+            .get_children(&FileId::Inode(SHARED_INODE))
+            .unwrap_or_default()
+            .into_iter()
+            .map(File::inode)
+            .collect();
+        waiting.sort();
+        self.move_under_parents(waiting);
+        self.drop_excluded()?;
+        self.trim_to_depth()?;
+        if mirror_mode {
+            self.drop_shared_dir()?;
+        }
+
+        self.populate_incomplete = false;
+        Ok(())
+    }
+
+    /// Builds the file tree from the files listed on Drive. In `mirror_mode`, there are no special
+    /// directories and only the files in "My Drive" are kept.
+    pub(crate) fn populate_with(&mut self, files: Vec<drive3::File>) -> Result<(), Error> {
         let root = self.new_root_file();
         self.add_file_locally(root, None)?;
 
-        let shared = self.new_special_dir("Shared with me", Some(SHARED_INODE));
-        self.add_file_locally(shared, Some(FileId::Inode(ROOT_INODE)))?;
+        // Files are placed in "Shared with me" until their parent is found, so it is needed even
+        // in mirror mode.
+        self.add_shared_dir()?;
+        if !self.config.mirror_mode() {
+            self.add_search_dir()?;
+            self.add_status_dir()?;
+        }
+
+        self.add_listed_files(files)?;
+        self.trim_to_depth()?;
+        if self.config.mirror_mode() {
+            self.drop_shared_dir()?;
+        }
+        Ok(())
+    }
+
+    /// Leaves out the children of the Drive folders which are `Config::max_tree_depth` levels
+    /// below the root. They are listed by `load_children` once their folder is accessed. Trash is
+    /// not affected.
+    fn trim_to_depth(&mut self) -> Result<(), Error> {
+        let max_depth = match self.config.max_tree_depth() {
+            Some(max_depth) => max_depth,
+            None => return Ok(()),
+        };
+        let root = match self.tree.root_node_id() {
+            Some(root) => root.clone(),
+            None => return Ok(()),
+        };
+
+        let mut trimmed = Vec::new();
+        let mut stack = vec![(0, root)];
+        while let Some((depth, node_id)) = stack.pop() {
+            let file = self.get_file(&FileId::NodeId(node_id.clone())).unwrap();
+            if file.inode() == TRASH_INODE {
+                continue;
+            }
+            if depth < max_depth {
+                for child in self.tree.children_ids(&node_id)? {
+                    stack.push((depth + 1, child.clone()));
+                }
+            } else if file.kind() == FileType::Directory && file.drive_id().is_some() {
+                trimmed.push(file.inode());
+            }
+        }
+
+        for inode in trimmed {
+            let mut stack = vec![inode];
+            while let Some(inode) = stack.pop() {
+                let children: Vec<(Inode, Option<DriveId>)> = self
+                    .get_children(&FileId::Inode(inode))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|child| (child.inode(), child.drive_id()))
+                    .collect();
+                for (child, child_id) in children {
+                    stack.push(child);
+                    self.beyond_depth.extend(child_id);
+                }
+            }
+            self.delete_children_locally(&FileId::Inode(inode))?;
+            self.unloaded.insert(inode);
+        }
+        Ok(())
+    }
+
+    /// Whether a Drive file belongs below a folder whose children are not loaded yet.
+    fn is_beyond_depth(&self, drive_id: &str, drive_file: &drive3::File) -> bool {
+        self.beyond_depth.contains(drive_id)
+            || drive_file.parents.as_ref().map_or(false, |parents| {
+                parents.iter().any(|p| {
+                    self.beyond_depth.contains(p)
+                        || self
+                            .get_inode(&FileId::DriveId(p.clone()))
+                            .map_or(false, |parent| self.unloaded.contains(&parent))
+                })
+            })
+    }
+
+    /// Lists the children of a folder which were left out because of `Config::max_tree_depth`.
+    /// Its child folders are loaded the same way once they are accessed. Does nothing for files
+    /// which are loaded already.
+    pub fn load_children(&mut self, id: &FileId) -> Result<(), Error> {
+        let inode = match self.get_inode(id) {
+            Some(inode) if self.unloaded.contains(&inode) => inode,
+            _ => return Ok(()),
+        };
+        let drive_id = self
+            .get_drive_id(id)
+            .ok_or_else(|| err_msg(format!("{:?} has no Drive id", id)))?;
+
+        debug!("Loading the children of {:?}", id);
+        let files = self.df.get_all_files(Some(vec![drive_id]), Some(false))?;
+        self.unloaded.remove(&inode);
+        for drive_file in self.drop_pattern_excluded(files) {
+            let child_id = match drive_file.id {
+                Some(ref child_id) => child_id.clone(),
+                None => continue,
+            };
+            self.beyond_depth.remove(&child_id);
+            if self.contains(&FileId::DriveId(child_id.clone()))
+                || self.is_excluded(&child_id, &drive_file)
+            {
+                continue;
+            }
+
+            let file = File::from_drive_file(self.next_available_inode(), drive_file, &self.config);
+            let child = file.inode();
+            let is_dir = file.kind() == FileType::Directory;
+            self.add_file_locally(file, Some(FileId::Inode(inode)))?;
+            if is_dir {
+                self.unloaded.insert(child);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds files in the order in which Drive listed them and moves them under their parents.
+    /// Files with identical names are numbered in this order.
+    pub(crate) fn add_listed_files(&mut self, files: Vec<drive3::File>) -> Result<(), Error> {
+        for drive_file in self.drop_pattern_excluded(files) {
+            let file = File::from_drive_file(self.next_available_inode(), drive_file, &self.config);
+            self.add_file_locally(file, Some(FileId::Inode(SHARED_INODE)))?;
+        }
 
-        for drive_file in self.df.get_all_files(None, Some(false))? {
-            let file = File::from_drive_file(
-                self.next_available_inode(),
-                drive_file,
-                self.add_extensions_to_special_files,
+        // Files are moved in inode order, so that the tree does not depend on the order of the
+        // HashMap.
+        let mut inodes: Vec<Inode> = self.files.keys().cloned().collect();
+        inodes.sort();
+        self.move_under_parents(inodes);
+        self.drop_excluded()
+    }
+
+    /// Whether a Drive file is kept out of the tree, either because it is excluded itself or
+    /// because one of its parents is.
+    fn is_excluded(&self, drive_id: &str, drive_file: &drive3::File) -> bool {
+        self.excluded.contains(drive_id)
+            || drive_file.parents.as_ref().map_or(false, |parents| {
+                parents.iter().any(|p| self.excluded.contains(p))
+            })
+    }
+
+    /// Leaves out the listed files which match `Config::exclude_patterns`, along with the files
+    /// inside matching folders, before they are assigned an inode. Their ids are added to
+    /// `excluded`, so that files which later appear inside them are left out as well.
+    fn drop_pattern_excluded(&mut self, files: Vec<drive3::File>) -> Vec<drive3::File> {
+        if self.exclude_patterns.is_empty() {
+            return files;
+        }
+
+        let listed: HashMap<DriveId, (String, Option<DriveId>)> = files
+            .iter()
+            .filter_map(|file| {
+                let name = file.name.clone().unwrap_or_default();
+                let parent = file.parents.as_ref().and_then(|p| p.first().cloned());
+                Some((file.id.clone()?, (name, parent)))
+            })
+            .collect();
+        let (kept, dropped): (Vec<drive3::File>, Vec<drive3::File>) =
+            files.into_iter().partition(|file| match file.id {
+                Some(ref id) => !self.matches_exclude_patterns(id, &listed),
+                None => true,
+            });
+
+        for file in dropped {
+            debug!(
+                "{:?} matches an exclude pattern. Leaving it out.",
+                &file.name
             );
-            self.add_file_locally(file, Some(FileId::Inode(3)))?;
+            self.excluded.extend(file.id);
+        }
+        kept
+    }
+
+    /// Whether a file or one of its ancestors matches `Config::exclude_patterns`. Ancestors are
+    /// looked up in `listed` first and in the tree second. Patterns starting with "/" only match
+    /// files whose ancestors are known up to the root.
+    fn matches_exclude_patterns(
+        &self,
+        drive_id: &str,
+        listed: &HashMap<DriveId, (String, Option<DriveId>)>,
+    ) -> bool {
+        let root_id = self.get_drive_id(&FileId::Inode(ROOT_INODE));
+
+        // The Drive names from the file up to the root.
+        let mut names = Vec::new();
+        let mut rooted = false;
+        let mut current = Some(drive_id.to_string());
+        while let Some(id) = current.take() {
+            if self.excluded.contains(&id) {
+                return true;
+            }
+            if root_id.as_ref() == Some(&id) {
+                rooted = true;
+                break;
+            }
+            match listed.get(&id) {
+                // Folders can not contain themselves, but a broken listing should not hang.
+                Some(&(ref name, ref parent)) if names.len() <= listed.len() => {
+                    names.push(name.clone());
+                    current = parent.clone();
+                }
+                Some(_) => break,
+                None => {
+                    if let Some(above) = self.drive_path_components(&id) {
+                        names.extend(above.into_iter().rev());
+                        rooted = true;
+                    }
+                }
+            }
         }
+        names.reverse();
+
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        (0..names.len()).any(|i| {
+            let path = format!("/{}", names[..=i].join("/"));
+            self.exclude_patterns.iter().any(|pattern| {
+                if pattern.as_str().starts_with('/') {
+                    rooted && pattern.matches_with(&path, options)
+                } else {
+                    pattern.matches(&names[i])
+                }
+            })
+        })
+    }
 
+    /// The Drive names of a file in the tree and of its ancestors, starting below the root. `None`
+    /// if the file is not in the tree or not below the root, e.g. in "Shared with me".
+    fn drive_path_components(&self, drive_id: &str) -> Option<Vec<String>> {
+        let drive_name = |file: &File| {
+            file.drive_file
+                .as_ref()
+                .and_then(|drive_file| drive_file.name.clone())
+                .unwrap_or_else(|| file.name.clone())
+        };
+
+        let node_id = self.get_node_id(&FileId::DriveId(drive_id.to_string()))?;
+        let mut names = vec![drive_name(self.get_file(&FileId::NodeId(node_id.clone()))?)];
+        for ancestor in self.tree.ancestor_ids(&node_id).ok()? {
+            let file = self.get_file(&FileId::NodeId(ancestor.clone()))?;
+            if file.inode() == ROOT_INODE {
+                names.reverse();
+                return Some(names);
+            }
+            names.push(drive_name(file));
+        }
+        None
+    }
+
+    /// Removes the excluded files from the tree, together with their descendants. Descendants
+    /// which were not placed under an excluded folder, e.g. because they wait in "Shared with me",
+    /// are found by their Drive parents. Does not communicate with Drive.
+    fn drop_excluded(&mut self) -> Result<(), Error> {
+        if self.excluded.is_empty() {
+            return Ok(());
+        }
+        loop {
+            let excluded: Vec<(Inode, DriveId)> = self
+                .files
+                .values()
+                .filter_map(|file| {
+                    let drive_id = file.drive_id()?;
+                    let drive_file = file.drive_file.as_ref()?;
+                    if self.is_excluded(&drive_id, drive_file) {
+                        Some((file.inode(), drive_id))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            if excluded.is_empty() {
+                return Ok(());
+            }
+
+            for (inode, drive_id) in excluded {
+                let id = FileId::Inode(inode);
+                if !self.contains(&id) {
+                    // Already removed along with an excluded ancestor.
+                    continue;
+                }
+                self.excluded.insert(drive_id);
+                let mut stack = vec![inode];
+                while let Some(inode) = stack.pop() {
+                    let children: Vec<(Inode, Option<DriveId>)> = self
+                        .get_children(&FileId::Inode(inode))
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|child| (child.inode(), child.drive_id()))
+                        .collect();
+                    for (child, child_id) in children {
+                        stack.push(child);
+                        self.excluded.extend(child_id);
+                    }
+                }
+                self.delete_children_locally(&id)?;
+                self.delete_locally(&id)?;
+            }
+        }
+    }
+
+    /// Moves files under their Drive parents, in the given order. Files whose parent is not known
+    /// stay where they are.
+    fn move_under_parents(&mut self, inodes: Vec<Inode>) {
         let mut moves: LinkedList<(FileId, FileId)> = LinkedList::new();
-        for (inode, file) in &self.files {
-            if let Some(parent) = file.drive_parent() {
+        for inode in inodes {
+            if let Some(parent) = self.files[&inode].drive_parent() {
                 if self.contains(&FileId::DriveId(parent.clone())) {
-                    moves.push_back((FileId::Inode(*inode), FileId::DriveId(parent)));
+                    moves.push_back((FileId::Inode(inode), FileId::DriveId(parent)));
                 }
             }
         }
@@ -208,23 +991,279 @@ impl FileManager {
                 error!("{}", e);
             }
         }
+    }
 
-        Ok(())
+    /// Adds the `trashed` files and directories locally in a special directory.
+    fn populate_trash(&mut self, trashed: Vec<drive3::File>) -> Result<(), Error> {
+        self.add_trash_dir()?;
+
+        let now = self.clock.now();
+        for drive_file in self.drop_pattern_excluded(trashed) {
+            let file = File::from_drive_file(self.next_available_inode(), drive_file, &self.config);
+            self.trashed_at.insert(file.inode(), now);
+            self.add_file_locally(file, Some(FileId::Inode(TRASH_INODE)))?;
+        }
+
+        self.drop_excluded()
     }
 
-    /// Retrieves all trashed files and directories and adds them locally in a special directory.
-    fn populate_trash(&mut self) -> Result<(), Error> {
-        let root_id = self.df.root_id()?.to_string();
-        let trash = self.new_special_dir("Trash", Some(TRASH_INODE));
-        self.add_file_locally(trash.clone(), Some(FileId::DriveId(root_id)))?;
+    /// Places Drive folders at fixed local paths, regardless of their Drive hierarchy. Each
+    /// mapping identifies a folder either by its Drive id or by its local path and moves it
+    /// (along with its subtree) to `mount_path`. Fails if two mappings conflict with each other
+    /// or with an existing file.
+    pub(crate) fn apply_folder_mappings(
+        &mut self,
+        mappings: &[FolderMapping],
+    ) -> Result<(), Error> {
+        for mapping in mappings {
+            let source = self
+                .drive_ids
+                .get(&mapping.drive_id_or_path)
+                .cloned()
+                .or_else(|| self.resolve_path(&mapping.drive_id_or_path))
+                .ok_or_else(|| {
+                    err_msg(format!(
+                        "Cannot find {:?} by Drive id or by path",
+                        &mapping.drive_id_or_path
+                    ))
+                })?;
+            let drive_id = self.get_drive_id(&FileId::Inode(source)).ok_or_else(|| {
+                err_msg(format!(
+                    "{:?} is not a Drive folder",
+                    &mapping.drive_id_or_path
+                ))
+            })?;
+            if self.grafts.contains_key(&drive_id) {
+                return Err(err_msg(format!(
+                    "{:?} is mapped more than once",
+                    &mapping.drive_id_or_path
+                )));
+            }
+
+            let mount_path = mapping.mount_path.trim_end_matches('/');
+            let (parent_path, name) = match mount_path.rfind('/') {
+                Some(pos) => (&mount_path[..pos], &mount_path[pos + 1..]),
+                None => ("", mount_path),
+            };
+            if name.is_empty() {
+                return Err(err_msg(format!(
+                    "Invalid mount path {:?}",
+                    &mapping.mount_path
+                )));
+            }
 
-        for drive_file in self.df.get_all_files(None, Some(true))? {
-            let file = File::from_drive_file(
-                self.next_available_inode(),
-                drive_file,
-                self.add_extensions_to_special_files,
+            let parent = self.resolve_path(parent_path).ok_or_else(|| {
+                err_msg(format!(
+                    "Parent of mount path {:?} does not exist",
+                    &mapping.mount_path
+                ))
+            })?;
+            if self.contains(&FileId::ParentAndName {
+                parent,
+                name: name.to_string(),
+            }) {
+                return Err(err_msg(format!(
+                    "Mount path {:?} is already taken",
+                    &mapping.mount_path
+                )));
+            }
+
+            let source_node = self
+                .get_node_id(&FileId::Inode(source))
+                .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", source)))?;
+            let parent_node = self
+                .get_node_id(&FileId::Inode(parent))
+                .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", parent)))?;
+            if parent_node == source_node
+                || self
+                    .tree
+                    .ancestor_ids(&parent_node)?
+                    .any(|id| *id == source_node)
+            {
+                return Err(err_msg(format!(
+                    "Mount path {:?} is inside the mapped folder {:?}",
+                    &mapping.mount_path, &mapping.drive_id_or_path
+                )));
+            }
+
+            self.tree.move_node(&source_node, ToParent(&parent_node))?;
+            let file = self
+                .get_mut_file(&FileId::Inode(source))
+                .ok_or_else(|| err_msg("File doesn't exist"))?;
+            file.name = name.to_string();
+            file.identical_name_id = None;
+
+            info!(
+                "Mapped {:?} to {:?}",
+                &mapping.drive_id_or_path, &mapping.mount_path
             );
-            self.add_file_locally(file, Some(FileId::Inode(trash.inode())))?;
+            self.grafts.insert(drive_id, (parent, name.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the inode of the file found at a given path, relative to the root directory.
+    pub fn resolve_path(&self, path: &str) -> Option<Inode> {
+        path.split('/')
+            .filter(|component| !component.is_empty())
+            .fold(Some(ROOT_INODE), |parent, name| {
+                self.get_inode(&FileId::ParentAndName {
+                    parent: parent?,
+                    name: name.to_string(),
+                })
+            })
+    }
+
+    /// The Drive ids and MIME types of the files which are worth prefetching at or under `ino`,
+    /// descending into subdirectories if `recursive` is set. Files which are streamed are left out,
+    /// since reads never use their cached content.
+    pub fn prefetch_targets(&self, ino: Inode, recursive: bool) -> Vec<(DriveId, Option<String>)> {
+        let mut targets = Vec::new();
+        let mut stack = vec![(ino, true)];
+
+        while let Some((ino, descend)) = stack.pop() {
+            let file = match self.get_file(&FileId::Inode(ino)) {
+                Some(file) => file,
+                None => continue,
+            };
+
+            if file.kind() == FileType::Directory {
+                if descend {
+                    for child in self.get_children(&FileId::Inode(ino)).unwrap_or_default() {
+                        stack.push((child.inode(), recursive));
+                    }
+                }
+                continue;
+            }
+
+            let mime = file
+                .drive_file
+                .as_ref()
+                .and_then(|drive_file| drive_file.mime_type.clone());
+            if let Some(drive_id) = file.drive_id() {
+                if !self
+                    .df
+                    .streams(&drive_id, mime.as_ref().map(String::as_str), file.attr.size)
+                {
+                    targets.push((drive_id, mime));
+                }
+            }
+        }
+
+        targets
+    }
+
+    /// Writes the path, Drive id, size, MIME type and MD5 checksum of every file (but not directory)
+    /// in the tree. Entries are written as the tree is walked, so the manifest is never held in
+    /// memory as a whole.
+    pub fn write_manifest<W: io::Write>(
+        &self,
+        w: &mut W,
+        format: ManifestFormat,
+    ) -> io::Result<()> {
+        let root = match self.tree.root_node_id() {
+            Some(root) => root.clone(),
+            None => return Ok(()),
+        };
+
+        match format {
+            ManifestFormat::Json => write!(w, "[")?,
+            ManifestFormat::Csv => writeln!(w, "path,id,size,mimeType,md5Checksum")?,
+        }
+
+        let mut first = true;
+        let mut stack = vec![(root, String::new())];
+        while let Some((node_id, path)) = stack.pop() {
+            let file = match self.get_file(&FileId::NodeId(node_id.clone())) {
+                Some(file) => file,
+                None => continue,
+            };
+
+            if file.kind() == FileType::Directory {
+                let children = self
+                    .tree
+                    .children_ids(&node_id)
+                    .unwrap()
+                    .collect::<Vec<_>>();
+                // Pushed in reverse so that children are written in the order of the tree.
+                for child_id in children.into_iter().rev() {
+                    if let Some(child) = self.get_file(&FileId::NodeId(child_id.clone())) {
+                        let child_path = format!("{}/{}", path, self.display_name(child));
+                        stack.push((child_id.clone(), child_path));
+                    }
+                }
+                continue;
+            }
+
+            let drive_file = file.drive_file.as_ref();
+            let entry = ManifestEntry {
+                path: &path,
+                id: drive_file.and_then(|f| f.id.as_ref()).map(String::as_str),
+                size: file.attr.size,
+                mime_type: drive_file
+                    .and_then(|f| f.mime_type.as_ref())
+                    .map(String::as_str),
+                md5_checksum: drive_file
+                    .and_then(|f| f.md5_checksum.as_ref())
+                    .map(String::as_str),
+            };
+
+            match format {
+                ManifestFormat::Json => {
+                    write!(w, "{}\n  ", if first { "" } else { "," })?;
+                    serde_json::to_writer(&mut *w, &entry)?;
+                }
+                ManifestFormat::Csv => writeln!(
+                    w,
+                    "{},{},{},{},{}",
+                    csv_field(entry.path),
+                    csv_field(entry.id.unwrap_or_default()),
+                    entry.size,
+                    csv_field(entry.mime_type.unwrap_or_default()),
+                    csv_field(entry.md5_checksum.unwrap_or_default()),
+                )?,
+            }
+            first = false;
+        }
+
+        if format == ManifestFormat::Json {
+            writeln!(w, "{}]", if first { "" } else { "\n" })?;
+        }
+        Ok(())
+    }
+
+    /// Renders the file tree, one file per line and indented by its depth. Files which are more
+    /// than `max_depth` levels below the root are left out.
+    pub fn tree(&self, max_depth: Option<usize>) -> String {
+        let mut tree = String::new();
+        // Writing to a String never fails.
+        self.write_tree(&mut tree, max_depth).unwrap();
+        tree
+    }
+
+    fn write_tree<W: fmt::Write>(&self, w: &mut W, max_depth: Option<usize>) -> fmt::Result {
+        if self.tree.root_node_id().is_none() {
+            return Ok(());
+        }
+
+        let mut stack: Vec<(usize, &NodeId)> = vec![(0, self.tree.root_node_id().unwrap())];
+
+        while !stack.is_empty() {
+            let (level, node_id) = stack.pop().unwrap();
+
+            for _ in 0..level {
+                write!(w, "\t")?;
+            }
+
+            let file = self.get_file(&FileId::NodeId(node_id.clone())).unwrap();
+            writeln!(w, "{:3} => {}", file.inode(), file.name)?;
+
+            if max_depth.map_or(true, |depth| level < depth) {
+                self.tree.children_ids(node_id).unwrap().for_each(|id| {
+                    stack.push((level + 1, id));
+                });
+            }
         }
 
         Ok(())
@@ -232,7 +1271,7 @@ impl FileManager {
 
     /// Creates a new File struct which represents the root directory. If possible, it fills in the exact DriveId. If not, it
     /// keeps using "root" as a placeholder id.
-    fn new_root_file(&mut self) -> File {
+    pub(crate) fn new_root_file(&mut self) -> File {
         let mut drive_file = drive3::File::default();
 
         let fallback_id = String::from("root");
@@ -240,7 +1279,7 @@ impl FileManager {
         drive_file.id = Some(root_id.to_string());
 
         File {
-            name: String::from("."),
+            name: self.config.root_name(),
             attr: FileAttr {
                 ino: ROOT_INODE,
                 size: 512,
@@ -262,6 +1301,294 @@ impl FileManager {
         }
     }
 
+    /// Adds the ".search" directory under the root. Looking up any name inside it creates a search
+    /// directory for the query with that name.
+    pub(crate) fn add_search_dir(&mut self) -> Result<(), Error> {
+        let dir = self.new_special_dir(".search", None);
+        self.search_dir = Some(dir.inode());
+        self.add_file_locally(dir, Some(FileId::Inode(ROOT_INODE)))
+    }
+
+    /// Adds the "Shared with me" directory, which also holds files whose parent is unknown.
+    pub(crate) fn add_shared_dir(&mut self) -> Result<(), Error> {
+        let shared = self.new_special_dir("Shared with me", Some(SHARED_INODE));
+        self.add_file_locally(shared, Some(FileId::Inode(ROOT_INODE)))
+    }
+
+    /// Adds the "Trash" directory, which holds the files trashed on Drive.
+    pub(crate) fn add_trash_dir(&mut self) -> Result<(), Error> {
+        let trash = self.new_special_dir("Trash", Some(TRASH_INODE));
+        self.add_file_locally(trash, Some(FileId::Inode(ROOT_INODE)))
+    }
+
+    /// Whether a file is somewhere under "Trash".
+    fn is_in_trash(&self, id: &FileId) -> bool {
+        let trash = match self.get_node_id(&FileId::Inode(TRASH_INODE)) {
+            Some(node) => node,
+            None => return false,
+        };
+        self.get_node_id(id)
+            .and_then(|node| self.tree.ancestor_ids(&node).ok())
+            .map_or(false, |mut ancestors| ancestors.any(|id| *id == trash))
+    }
+
+    /// Removes "Shared with me" together with the files which are still in it, i.e. the files which
+    /// are not in "My Drive". Does not communicate with Drive.
+    fn drop_shared_dir(&mut self) -> Result<(), Error> {
+        let shared = FileId::Inode(SHARED_INODE);
+        self.delete_children_locally(&shared)?;
+        if let Some(node_id) = self.get_node_id(&shared) {
+            self.tree.remove_node(node_id, DropChildren)?;
+        }
+        self.files.remove(&SHARED_INODE);
+        self.node_ids.remove(&SHARED_INODE);
+        Ok(())
+    }
+
+    /// Whether a file is somewhere under "Shared with me".
+    fn is_shared(&self, id: &FileId) -> bool {
+        let shared = match self.get_node_id(&FileId::Inode(SHARED_INODE)) {
+            Some(node) => node,
+            None => return false,
+        };
+        self.get_node_id(id)
+            .and_then(|node| self.tree.ancestor_ids(&node).ok())
+            .map_or(false, |mut ancestors| ancestors.any(|id| *id == shared))
+    }
+
+    /// Removes the shared files which could not be read because access to them was denied, if
+    /// `hide_expired_shares` is enabled. Does not communicate with Drive.
+    pub(crate) fn prune_expired_shares(&mut self) {
+        if !self.config.hide_expired_shares() {
+            return;
+        }
+
+        for drive_id in self.df.take_denied() {
+            // Files may have been removed since their read was denied.
+            let id = FileId::DriveId(drive_id);
+            if !self.contains(&id) || !self.is_shared(&id) {
+                continue;
+            }
+
+            info!(
+                "Access to {:?} was revoked or has expired. Removing it.",
+                self.get_file(&id).map(|file| file.name.clone())
+            );
+            if let Err(e) = self.delete_locally(&id) {
+                error!("Could not delete locally: {:?}", e);
+            }
+        }
+    }
+
+    /// Whether an inode is a directory which only exists locally, such as "Shared with me",
+    /// ".search" or ".gcsf". Files can not be moved into them since there is no Drive folder to
+    /// move them to. Trash is not one of them: moving a file there trashes it.
+    pub fn is_pseudo_dir(&self, ino: Inode) -> bool {
+        ino != TRASH_INODE
+            && self.get_file(&FileId::Inode(ino)).map_or(false, |file| {
+                file.kind() == FileType::Directory && file.drive_file.is_none()
+            })
+    }
+
+    /// Adds the ".gcsf" directory, whose files describe the state of GCSF. It also holds deleted
+    /// files during their grace period.
+    pub(crate) fn add_status_dir(&mut self) -> Result<(), Error> {
+        let dir = self.new_special_dir(".gcsf", None);
+        let dir_inode = dir.inode();
+        self.add_file_locally(dir, Some(FileId::Inode(ROOT_INODE)))?;
+
+        let mut transfers = self.new_special_dir("transfers", None);
+        transfers.attr.kind = FileType::RegularFile;
+        transfers.attr.perm = 0o444;
+        transfers.attr.nlink = 1;
+        transfers.attr.size = 0;
+        self.transfers_file = Some(transfers.inode());
+        self.add_file_locally(transfers, Some(FileId::Inode(dir_inode)))?;
+
+        let mut sync_enabled = self.new_special_dir("sync_enabled", None);
+        sync_enabled.attr.kind = FileType::RegularFile;
+        sync_enabled.attr.perm = 0o644;
+        sync_enabled.attr.nlink = 1;
+        sync_enabled.attr.size = 0;
+        self.sync_enabled_file = Some(sync_enabled.inode());
+        self.add_file_locally(sync_enabled, Some(FileId::Inode(dir_inode)))?;
+
+        let pending_deletes = self.new_special_dir("pending-deletes", None);
+        self.pending_deletes_dir = Some(pending_deletes.inode());
+        self.add_file_locally(pending_deletes, Some(FileId::Inode(dir_inode)))
+    }
+
+    /// Whether an inode belongs to ".gcsf/transfers".
+    pub fn is_transfers_file(&self, ino: Inode) -> bool {
+        self.transfers_file == Some(ino)
+    }
+
+    /// Whether an inode belongs to ".gcsf/sync_enabled".
+    pub fn is_sync_enabled_file(&self, ino: Inode) -> bool {
+        self.sync_enabled_file == Some(ino)
+    }
+
+    /// The content of ".gcsf/sync_enabled": "1" while sync is enabled, "0" while it is paused.
+    pub fn sync_enabled_status(&self) -> String {
+        if self.sync_paused {
+            String::from("0\n")
+        } else {
+            String::from("1\n")
+        }
+    }
+
+    /// Whether sync is paused.
+    pub fn sync_paused(&self) -> bool {
+        self.sync_paused
+    }
+
+    /// Pauses or resumes sync. The state is persisted, so it also applies to the next mount of
+    /// this session.
+    pub fn set_sync_paused(&mut self, paused: bool) -> Result<(), Error> {
+        let path = self.config.sync_paused_file();
+        if paused {
+            fs::write(&path, b"")?;
+        } else if path.exists() {
+            fs::remove_file(&path)?;
+        }
+
+        if paused != self.sync_paused {
+            info!("Sync {}.", if paused { "paused" } else { "resumed" });
+        }
+        self.sync_paused = paused;
+        Ok(())
+    }
+
+    /// The content of ".gcsf/transfers", generated from the transfers in progress. Each line
+    /// describes one transfer, e.g. "upload video.mp4 1048576/4194304 bytes (25%)".
+    pub fn transfer_status(&self) -> String {
+        let transfers = match self.df.transfers().lock() {
+            Ok(transfers) => transfers.clone(),
+            Err(_) => return String::new(),
+        };
+
+        let mut lines: Vec<String> = transfers
+            .iter()
+            .map(|(id, transfer)| {
+                let name = self
+                    .get_file(&FileId::DriveId(id.clone()))
+                    .map(|file| file.name.clone())
+                    .unwrap_or_else(|| id.clone());
+                let direction = if transfer.upload {
+                    "upload"
+                } else {
+                    "download"
+                };
+
+                match transfer.total {
+                    Some(total) if total > 0 => format!(
+                        "{} {} {}/{} bytes ({}%)\n",
+                        direction,
+                        name,
+                        transfer.done,
+                        total,
+                        transfer.done * 100 / total
+                    ),
+                    _ => format!("{} {} {} bytes\n", direction, name, transfer.done),
+                }
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+
+    /// Returns the inode of the search directory for the query `name`, creating it if `parent` is
+    /// the ".search" directory. Since names can not contain slashes, "%2F" stands for "/".
+    pub fn lookup_search(&mut self, parent: Inode, name: &str) -> Option<Inode> {
+        if self.search_dir != Some(parent) {
+            return None;
+        }
+
+        let id = FileId::ParentAndName {
+            parent,
+            name: name.to_string(),
+        };
+        if let Some(inode) = self.get_inode(&id) {
+            return Some(inode);
+        }
+
+        let dir = self.new_special_dir(name, None);
+        let inode = dir.inode();
+        self.add_file_locally(dir, Some(FileId::Inode(parent)))
+            .ok()?;
+        self.searches
+            .insert(inode, (name.replace("%2F", "/"), None));
+        Some(inode)
+    }
+
+    /// Whether an inode belongs to a search directory.
+    pub fn is_search(&self, ino: Inode) -> bool {
+        self.searches.contains_key(&ino)
+    }
+
+    /// Runs the query of a search directory unless it ran recently. Invalid queries result in an
+    /// empty directory.
+    pub fn refresh_search(&mut self, ino: Inode) -> Result<(), Error> {
+        let query = match self.searches.get(&ino) {
+            Some(&(_, Some(last_run)))
+                if self
+                    .clock
+                    .now()
+                    .duration_since(last_run)
+                    .unwrap_or_default()
+                    < self.sync_interval =>
+            {
+                return Ok(());
+            }
+            Some(&(ref query, _)) => query.clone(),
+            None => return Err(err_msg(format!("{:?} is not a search directory", ino))),
+        };
+
+        let results = self.df.search(&query).unwrap_or_else(|e| {
+            warn!("Search {:?} failed: {}", &query, e);
+            Vec::new()
+        });
+        self.set_search_results(ino, results)
+    }
+
+    /// Replaces the content of a search directory with the given Drive files. The results are
+    /// copies of the files shown elsewhere in the tree and are not indexed by their Drive id.
+    pub(crate) fn set_search_results(
+        &mut self,
+        ino: Inode,
+        results: Vec<drive3::File>,
+    ) -> Result<(), Error> {
+        let node_id = self
+            .get_node_id(&FileId::Inode(ino))
+            .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", ino)))?;
+
+        let old_results: Vec<Inode> = self
+            .get_children(&FileId::Inode(ino))
+            .map(|children| children.iter().map(|child| child.inode()).collect())
+            .unwrap_or_default();
+        for inode in old_results {
+            if let Some(old_node) = self.node_ids.remove(&inode) {
+                self.tree.remove_node(old_node, DropChildren)?;
+            }
+            self.files.remove(&inode);
+        }
+
+        for drive_file in results {
+            let file = File::from_drive_file(self.next_available_inode(), drive_file, &self.config);
+            let result_node = self
+                .tree
+                .insert(Node::new(file.inode()), UnderNode(&node_id))?;
+            self.node_ids.insert(file.inode(), result_node);
+            self.files.insert(file.inode(), file);
+        }
+
+        let now = self.clock.now();
+        if let Some(search) = self.searches.get_mut(&ino) {
+            search.1 = Some(now);
+        }
+        Ok(())
+    }
+
     /// Creates a new File struct which represents a directory that does not necessarily exist on
     /// Drive.
     fn new_special_dir(&mut self, name: &str, preferred_inode: Option<Inode>) -> File {
@@ -310,8 +1637,7 @@ impl FileManager {
         match file_id {
             FileId::Inode(inode) => self.node_ids.get(&inode).cloned(),
             FileId::DriveId(drive_id) => self.get_node_id(&FileId::Inode(
-                self.get_inode(&FileId::DriveId(drive_id.to_string()))
-                    .unwrap(),
+                self.get_inode(&FileId::DriveId(drive_id.to_string()))?,
             )),
             FileId::NodeId(node_id) => Some(node_id.clone()),
             ref pn @ FileId::ParentAndName { .. } => {
@@ -321,12 +1647,113 @@ impl FileManager {
         }
     }
 
+    /// Returns the NodeId of a file, making sure that it still points to the file. A stale NodeId,
+    /// e.g. one left behind by an inconsistent sync, is replaced by the one found in the tree.
+    fn valid_node_id(&mut self, id: &FileId) -> Result<NodeId, Error> {
+        let inode = self
+            .get_inode(id)
+            .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", &id)))?;
+        if let Some(node_id) = self.node_ids.get(&inode) {
+            if self
+                .tree
+                .get(node_id)
+                .map_or(false, |node| *node.data() == inode)
+            {
+                return Ok(node_id.clone());
+            }
+        }
+
+        let node_id = self
+            .find_node_id(inode)
+            .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", &id)))?;
+        warn!(
+            "The node_id of inode {} was stale. Using the one in the tree.",
+            inode
+        );
+        self.node_ids.insert(inode, node_id.clone());
+        Ok(node_id)
+    }
+
+    /// Searches the tree for the node which holds an inode.
+    fn find_node_id(&self, inode: Inode) -> Option<NodeId> {
+        let mut stack = vec![self.tree.root_node_id()?.clone()];
+        while let Some(node_id) = stack.pop() {
+            if *self.tree.get(&node_id).ok()?.data() == inode {
+                return Some(node_id);
+            }
+            stack.extend(self.tree.children_ids(&node_id).ok()?.cloned());
+        }
+        None
+    }
+
+    /// Moves a file under a new parent in the tree. Fails without changing anything if either file
+    /// is missing or if a folder would be moved into itself or into one of its subfolders. Returns
+    /// the NodeIds of the old parent (if any) and of the new parent.
+    fn move_node(
+        &mut self,
+        id: &FileId,
+        new_parent: &FileId,
+    ) -> Result<(Option<NodeId>, NodeId), Error> {
+        let node_id = self.valid_node_id(id)?;
+        let target_id = self
+            .valid_node_id(new_parent)
+            .map_err(|_| err_msg("Target node doesn't exist"))?;
+
+        let into_itself = node_id == target_id
+            || self
+                .tree
+                .ancestor_ids(&target_id)?
+                .any(|ancestor| *ancestor == node_id);
+        if into_itself {
+            return Err(err_msg(format!(
+                "Cannot move {:?} into itself or one of its subfolders",
+                &id
+            )));
+        }
+
+        let source_id = self.tree.get(&node_id)?.parent().cloned();
+        self.tree
+            .move_node(&node_id, ToParent(&target_id))
+            .map_err(|e| {
+                err_msg(format!(
+                    "Cannot move {:?} to {:?}: {:?}",
+                    &id, &new_parent, e
+                ))
+            })?;
+        Ok((source_id, target_id))
+    }
+
     /// Returns the DriveId of a file identified by a given id.
     /// The DriveId points to a Google Drive file.
     pub fn get_drive_id(&self, id: &FileId) -> Option<DriveId> {
         self.get_file(id)?.drive_id()
     }
 
+    /// Returns the Drive id of the folder under which a file currently appears locally. For files
+    /// with multiple parents on Drive, this is the parent the file is shown under.
+    pub fn local_parent_drive_id(&self, id: &FileId) -> Option<DriveId> {
+        let node_id = self.get_node_id(id)?;
+        let parent = self.tree.get(&node_id).ok()?.parent()?.clone();
+        self.get_drive_id(&FileId::NodeId(parent))
+    }
+
+    /// Returns the inode of the directory under which a file appears locally, i.e. the inode of its
+    /// `..` entry. The root is its own parent.
+    pub fn parent_inode(&self, ino: Inode) -> Option<Inode> {
+        if ino == ROOT_INODE {
+            return Some(ROOT_INODE);
+        }
+        let node_id = self.get_node_id(&FileId::Inode(ino))?;
+        let parent = self.tree.get(&node_id).ok()?.parent()?.clone();
+        self.get_inode(&FileId::NodeId(parent))
+    }
+
+    /// The name under which a file is shown, which depends on `Config::duplicate_name_template`
+    /// for files with identical names.
+    pub fn display_name(&self, file: &File) -> String {
+        file.name_with_template(&self.config.duplicate_name_template())
+    }
+
     /// Returns the inode of a file identified by a given id.
     pub fn get_inode(&self, id: &FileId) -> Option<Inode> {
         match id {
@@ -344,57 +1771,232 @@ impl FileManager {
             } => self
                 .get_children(&FileId::Inode(*parent))?
                 .into_iter()
-                .find(|child| child.name() == *name)
-                .map(|child| child.inode()),
+                .find(|child| self.display_name(child) == *name)
+                .map(|child| child.inode())
+                .or_else(|| self.find_link(*parent, name)),
         }
     }
 
-    /// Returns the children of a directory identified by a given id.
-    pub fn get_children(&self, id: &FileId) -> Option<Vec<&File>> {
-        let node_id = self.get_node_id(&id)?;
-        let children: Vec<&File> = self
-            .tree
-            .children(&node_id)
-            .unwrap()
-            .map(|child| self.get_file(&FileId::Inode(*child.data())))
-            .filter(Option::is_some)
-            .map(Option::unwrap)
+    /// Returns the inode of the file hard linked as `name` under `parent`, if any.
+    fn find_link(&self, parent: Inode, name: &str) -> Option<Inode> {
+        self.links
+            .iter()
+            .find(|&(_, paths)| paths.iter().any(|&(p, ref n)| p == parent && n == name))
+            .map(|(inode, _)| *inode)
+    }
+
+    /// Returns the hard links (inode, name) which appear under a given directory.
+    pub fn links_under(&self, parent: Inode) -> Vec<(Inode, String)> {
+        let mut links: Vec<(Inode, String)> = self
+            .links
+            .iter()
+            .flat_map(|(inode, paths)| {
+                paths
+                    .iter()
+                    .filter(move |&&(p, _)| p == parent)
+                    .map(move |&(_, ref name)| (*inode, name.clone()))
+            })
             .collect();
+        links.sort();
+        links
+    }
 
-        Some(children)
+    /// Returns the number of children of a file in the file tree, not counting hard links.
+    pub fn child_count(&self, id: &FileId) -> Option<usize> {
+        let node_id = self.get_node_id(&id)?;
+        Some(self.tree.get(&node_id).ok()?.children().len())
+    }
+
+    /// Returns the children of a directory identified by a given id. Returns `None` if the file
+    /// does not exist or is not a directory.
+    pub fn get_children(&self, id: &FileId) -> Option<Vec<&File>> {
+        self.get_children_from(id, 0).map(Iterator::collect)
+    }
+
+    /// Returns the children of a directory identified by a given id, skipping the first `offset`
+    /// of them. The children are produced lazily, so paging through a huge directory does not
+    /// require collecting all of its entries on every call. Returns `None` if the file does not
+    /// exist or is not a directory.
+    pub fn get_children_from<'a>(
+        &'a self,
+        id: &FileId,
+        offset: usize,
+    ) -> Option<impl Iterator<Item = &'a File> + 'a> {
+        if self.get_file(id)?.kind() != FileType::Directory {
+            return None;
+        }
+        let node_id = self.get_node_id(&id)?;
+        let child_ids = self.tree.get(&node_id).ok()?.children();
+        let start = cmp::min(offset, child_ids.len());
+
+        Some(
+            child_ids[start..]
+                .iter()
+                .filter_map(move |child_id| self.get_file(&FileId::NodeId(child_id.clone()))),
+        )
     }
 
     /// Returns a const reference to a file identified by a given id.
     pub fn get_file(&self, id: &FileId) -> Option<&File> {
         let inode = self.get_inode(id)?;
+        if !self.is_reachable(inode) {
+            return None;
+        }
         self.files.get(&inode)
     }
 
     /// Returns a mutable reference to a file identified by a given id.
     pub fn get_mut_file(&mut self, id: &FileId) -> Option<&mut File> {
         let inode = self.get_inode(&id)?;
+        if !self.is_reachable(inode) {
+            return None;
+        }
         self.files.get_mut(&inode)
     }
 
-    /// Creates a file on Drive and adds it to the local file tree.
+    /// Whether the file of an inode can be accessed. Files which were removed from the tree while
+    /// the kernel referred to them are only accessible through the handles which are still open.
+    fn is_reachable(&self, inode: Inode) -> bool {
+        self.node_ids.contains_key(&inode) || self.open_handles.contains_key(&inode)
+    }
+
+    /// Records that the kernel received a reference to a given inode (e.g. through `lookup`).
+    pub fn remember(&mut self, ino: Inode) {
+        *self.lookups.entry(ino).or_insert(0) += 1;
+    }
+
+    /// Releases `nlookup` kernel references to a given inode. When no references remain, the
+    /// cached content of the file is dropped from memory. Files which are still in the tree are
+    /// kept because they are needed for resolving paths. Files which were removed from the tree
+    /// while the kernel referred to them are dropped.
+    pub fn forget(&mut self, ino: Inode, nlookup: u64) {
+        let remaining = match self.lookups.get_mut(&ino) {
+            Some(count) => {
+                *count = count.saturating_sub(nlookup);
+                *count
+            }
+            None => return,
+        };
+
+        if remaining == 0 {
+            self.lookups.remove(&ino);
+            if let Some(drive_id) = self.files.get(&ino).and_then(File::drive_id) {
+                self.df.evict(&drive_id);
+            }
+            if !self.node_ids.contains_key(&ino) {
+                self.files.remove(&ino);
+            }
+        }
+    }
+
+    /// The number of kernel references to a given inode.
+    pub fn lookup_count(&self, ino: Inode) -> u64 {
+        self.lookups.get(&ino).cloned().unwrap_or(0)
+    }
+
+    /// Creates a file on Drive and adds it to the local file tree. Editor temp files are only
+    /// created locally if `defer_temp_uploads` is enabled.
     pub fn create_file(&mut self, mut file: File, parent: Option<FileId>) -> Result<(), Error> {
-        let drive_id = self.df.create(file.drive_file.as_ref().unwrap())?;
+        // The file is created on Drive under the folder it is shown in locally, regardless of the
+        // parents it was built with. Otherwise Drive would put it in "My Drive".
+        if let Some(ref parent) = parent {
+            let parent_id = self.get_drive_id(parent).ok_or_else(|| {
+                err_msg(format!(
+                    "Cannot create files in {:?} because it is not a Drive folder",
+                    parent
+                ))
+            })?;
+            if let Some(drive_file) = file.drive_file.as_mut() {
+                drive_file.parents = Some(vec![parent_id]);
+            }
+        }
+
+        let drive_id = if self.config.defer_temp_uploads() && is_editor_temp_name(&file.name) {
+            let local_id = format!("{}{}", LOCAL_ID_PREFIX, file.inode());
+            debug!("Deferring the creation of {:?} on Drive", &file.name);
+            self.df.defer_creation(&local_id);
+            local_id
+        } else {
+            self.df.create(file.drive_file.as_ref().unwrap())?
+        };
         file.set_drive_id(drive_id);
         self.add_file_locally(file, parent)?;
 
         Ok(())
     }
 
-    /// Passes along the FLUSH system call to the `DriveFacade`.
+    /// Creates a directory called `name` in `parent`, both on Drive and locally, and returns its
+    /// attributes. Its times are the current time until Drive reports them during the next sync.
+    pub fn create_directory(&mut self, name: &str, parent: Inode) -> Result<FileAttr, Error> {
+        let now = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let now = Timespec::new(now.as_secs() as i64, now.subsec_nanos() as i32);
+
+        let dir = File {
+            name: name.to_string(),
+            attr: FileAttr {
+                ino: self.next_available_inode(),
+                size: 512,
+                blocks: 1,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::Directory,
+                perm: 0o755,
+                nlink: 2,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+                flags: 0,
+            },
+            identical_name_id: None,
+            drive_file: Some(drive3::File {
+                name: Some(name.to_string()),
+                mime_type: Some(String::from("application/vnd.google-apps.folder")),
+                // Set by `create_file`.
+                parents: None,
+                ..Default::default()
+            }),
+        };
+        let attr = dir.attr;
+        self.create_file(dir, Some(FileId::Inode(parent)))?;
+        Ok(attr)
+    }
+
+    /// Uploads all pending writes, e.g. before unmounting. Returns the Drive ids of the files which
+    /// could not be saved within `timeout`.
+    pub fn flush_all(&mut self, timeout: Duration) -> Vec<DriveId> {
+        self.df.flush_all(timeout)
+    }
+
+    /// Passes along the FLUSH system call to the `DriveFacade`. Files which only exist locally are
+    /// created on Drive first, unless they look like editor temp files.
     pub fn flush(&mut self, id: &FileId) -> Result<(), Error> {
+        if self.is_deferred(id) {
+            let ino = self
+                .get_inode(id)
+                .ok_or_else(|| err_msg(format!("Cannot find inode of {:?}", &id)))?;
+            return self.save_deferred(ino);
+        }
+
         let file = self
             .get_drive_id(&id)
             .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", &id)))?;
-        self.df.flush(&file)
+        let result = self.df.flush(&file);
+        self.update_heartbeat();
+        result
     }
 
     /// Adds a file to the local file tree. Does not communicate with Drive.
-    fn add_file_locally(&mut self, mut file: File, parent: Option<FileId>) -> Result<(), Error> {
+    pub(crate) fn add_file_locally(
+        &mut self,
+        mut file: File,
+        parent: Option<FileId>,
+    ) -> Result<(), Error> {
         let node_id = match parent {
             Some(id) => {
                 let parent_id = self.get_node_id(&id).ok_or_else(|| {
@@ -422,29 +2024,177 @@ impl FileManager {
             None => self.tree.insert(Node::new(file.inode()), AsRoot)?,
         };
 
+        let parent_node = self
+            .tree
+            .get(&node_id)
+            .ok()
+            .and_then(|node| node.parent().cloned());
+        let name = file.name.clone();
+        let inode = file.inode();
+
         self.node_ids.insert(file.inode(), node_id);
-        file.drive_id()
-            .and_then(|drive_id| self.drive_ids.insert(drive_id, file.inode()));
+        if let Some(drive_id) = file.drive_id() {
+            self.map_drive_id(drive_id, inode);
+        }
         self.files.insert(file.inode(), file);
 
+        if let Some(parent_node) = parent_node {
+            self.number_files_shadowed_by_folders(&parent_node, &name);
+        }
+        self.add_exports(inode)
+    }
+
+    /// Adds one read-only file per format to a Drive document which is shown as a directory
+    /// because of `multi_export`, e.g. "Report/Report.pdf". Reading such a file exports the
+    /// document in its format. Does nothing for other files.
+    fn add_exports(&mut self, ino: Inode) -> Result<(), Error> {
+        let (document_id, name, document) = match self.get_file(&FileId::Inode(ino)) {
+            Some(file) if file.kind() == FileType::Directory => {
+                match (file.drive_id(), file.drive_file.as_ref()) {
+                    (Some(id), Some(document)) => (id, file.name.clone(), document.clone()),
+                    _ => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        };
+        let export_types = document
+            .mime_type
+            .as_ref()
+            .map(|mime| self.config.multi_export_types(mime))
+            .unwrap_or_default();
+
+        for export_type in export_types {
+            let extension = extension(&export_type);
+            let export = drive3::File {
+                id: Some(export_id(&document_id, &extension)),
+                name: Some(format!("{}.{}", name, extension)),
+                mime_type: Some(export_type),
+                parents: Some(vec![document_id.clone()]),
+                created_time: document.created_time.clone(),
+                modified_time: document.modified_time.clone(),
+                viewed_by_me_time: document.viewed_by_me_time.clone(),
+                ..Default::default()
+            };
+            let mut file = File::from_drive_file(self.next_available_inode(), export, &self.config);
+            file.attr.perm = 0o444;
+            self.add_file_locally(file, Some(FileId::Inode(ino)))?;
+        }
         Ok(())
     }
 
+    /// Drops the cached content of the exports of a Drive document which is shown as a directory
+    /// of exports, so that they are exported again when they are read.
+    fn invalidate_exports(&mut self, document_id: &str) {
+        let exports: Vec<DriveId> = self
+            .get_children(&FileId::DriveId(document_id.to_string()))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(File::drive_id)
+            .filter(|id| exported_document_id(id) == Some(document_id))
+            .collect();
+        for id in exports {
+            self.df.invalidate(&id);
+        }
+    }
+
+    /// Drive allows a folder and a file with the same name under one parent, but only one of them
+    /// could be reached by that name. Unless `rename_identical_files` numbers all duplicates anyway,
+    /// the files named `name` under `parent` are numbered (e.g. "report.1" next to the folder
+    /// "report") for as long as a folder of the same name is next to them.
+    fn number_files_shadowed_by_folders(&mut self, parent: &NodeId, name: &str) {
+        if self.rename_identical_files {
+            return;
+        }
+
+        let same_name: Vec<(Inode, FileType)> = match self.tree.children(parent) {
+            Ok(children) => children
+                .filter_map(|node| self.files.get(node.data()))
+                .filter(|file| file.name == name)
+                .map(|file| (file.inode(), file.kind()))
+                .collect(),
+            Err(_) => return,
+        };
+        let shadowed = same_name
+            .iter()
+            .any(|&(_, kind)| kind == FileType::Directory);
+
+        for (inode, kind) in same_name {
+            if kind == FileType::Directory {
+                continue;
+            }
+            if let Some(file) = self.files.get_mut(&inode) {
+                file.identical_name_id = if shadowed { Some(1) } else { None };
+            }
+        }
+    }
+
     /// Moves a file somewhere else in the local file tree. Does not communicate with Drive.
     fn move_locally(&mut self, id: &FileId, new_parent: &FileId) -> Result<(), Error> {
-        let current_node = self
-            .get_node_id(&id)
-            .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", &id)))?;
-        let target_node = self
-            .get_node_id(&new_parent)
-            .ok_or_else(|| err_msg("Target node doesn't exist"))?;
+        let name = self
+            .get_file(id)
+            .map(|file| file.name.clone())
+            .unwrap_or_default();
+
+        let (source_node, target_node) = self.move_node(id, new_parent)?;
 
-        self.tree.move_node(&current_node, ToParent(&target_node))?;
+        if let Some(source_node) = source_node {
+            self.number_files_shadowed_by_folders(&source_node, &name);
+        }
+        self.number_files_shadowed_by_folders(&target_node, &name);
         Ok(())
     }
 
+    /// Maps `drive_id` to `ino`, unless it is already mapped to another file which still exists.
+    /// In that case the first inode is kept, so that lookups by Drive id stay stable, and `ino` is
+    /// remembered as a duplicate.
+    fn map_drive_id(&mut self, drive_id: DriveId, ino: Inode) {
+        match self.drive_ids.get(&drive_id).cloned() {
+            Some(existing) if existing != ino && self.files.contains_key(&existing) => {
+                warn!(
+                    "{} is already shown as inode {}, keeping it as the inode for lookups \
+                     instead of {}",
+                    drive_id, existing, ino
+                );
+                let duplicates = self.duplicate_drive_ids.entry(drive_id).or_default();
+                if !duplicates.contains(&ino) {
+                    duplicates.push(ino);
+                }
+            }
+            _ => {
+                self.drive_ids.insert(drive_id, ino);
+            }
+        }
+    }
+
+    /// Removes the mapping of `drive_id` to `ino`. If `ino` was the mapped inode and the Drive
+    /// file was added more than once, one of its remaining inodes takes over.
+    fn unmap_drive_id(&mut self, drive_id: &str, ino: Inode) {
+        if self.drive_ids.get(drive_id) == Some(&ino) {
+            self.drive_ids.remove(drive_id);
+            while let Some(duplicate) = self
+                .duplicate_drive_ids
+                .get_mut(drive_id)
+                .and_then(|duplicates| duplicates.pop())
+            {
+                if self.files.contains_key(&duplicate) {
+                    self.drive_ids.insert(drive_id.to_string(), duplicate);
+                    break;
+                }
+            }
+        } else if let Some(duplicates) = self.duplicate_drive_ids.get_mut(drive_id) {
+            duplicates.retain(|&duplicate| duplicate != ino);
+        }
+        if self
+            .duplicate_drive_ids
+            .get(drive_id)
+            .map_or(false, |duplicates| duplicates.is_empty())
+        {
+            self.duplicate_drive_ids.remove(drive_id);
+        }
+    }
+
     /// Deletes a file and its children from the local file tree. Does not communicate with Drive.
-    fn delete_locally(&mut self, id: &FileId) -> Result<(), Error> {
+    pub(crate) fn delete_locally(&mut self, id: &FileId) -> Result<(), Error> {
         let node_id = self
             .get_node_id(id)
             .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", &id)))?;
@@ -456,43 +2206,341 @@ impl FileManager {
             .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", &id)))?;
 
         self.tree.remove_node(node_id, DropChildren)?;
-        self.files.remove(&inode);
         self.node_ids.remove(&inode);
-        self.drive_ids.remove(&drive_id);
+        self.unmap_drive_id(&drive_id, inode);
+        self.links.remove(&inode);
+        self.drop_if_unreferenced(inode);
+
+        Ok(())
+    }
+
+    /// Drops the `File` of an inode which was removed from the tree, unless the kernel still
+    /// refers to it. In that case `forget` drops it once the kernel releases the inode.
+    fn drop_if_unreferenced(&mut self, inode: Inode) {
+        if self.lookup_count(inode) == 0 {
+            self.files.remove(&inode);
+            self.lookups.remove(&inode);
+        }
+    }
+
+    /// The inodes of the nodes below a file in the tree. Unlike `get_children`, this also works
+    /// for files which stopped being directories but still have children in the tree.
+    fn child_inodes(&self, id: &FileId) -> Vec<Inode> {
+        self.get_node_id(id)
+            .and_then(|node_id| self.tree.get(&node_id).ok())
+            .map(|node| {
+                node.children()
+                    .iter()
+                    .filter_map(|child| self.get_inode(&FileId::NodeId(child.clone())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes all local descendants of a file. Does not communicate with Drive.
+    fn delete_children_locally(&mut self, id: &FileId) -> Result<(), Error> {
+        let children = self.child_inodes(id);
+
+        let mut stack = children.clone();
+        let mut descendants = Vec::new();
+        while let Some(inode) = stack.pop() {
+            stack.extend(self.child_inodes(&FileId::Inode(inode)));
+            descendants.push(inode);
+        }
+
+        for inode in children {
+            if let Some(node_id) = self.get_node_id(&FileId::Inode(inode)) {
+                self.tree.remove_node(node_id, DropChildren)?;
+            }
+        }
+
+        for inode in descendants {
+            if let Some(drive_id) = self.files.get(&inode).and_then(File::drive_id) {
+                self.unmap_drive_id(&drive_id, inode);
+            }
+            self.node_ids.remove(&inode);
+            self.drop_if_unreferenced(inode);
+        }
+
+        Ok(())
+    }
 
+    /// Creates a hard link to a file as `name` under `new_parent`. On Drive, the file gains
+    /// `new_parent` as an additional parent.
+    pub fn link(&mut self, ino: Inode, new_parent: Inode, name: String) -> Result<(), Error> {
+        if !self.is_valid_link_name(ino, &name) {
+            return Err(err_msg(format!(
+                "Hard links must have the name of the file, not {:?}",
+                name
+            )));
+        }
+        let drive_id = self
+            .get_drive_id(&FileId::Inode(ino))
+            .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", ino)))?;
+        let parent_id = self
+            .get_drive_id(&FileId::Inode(new_parent))
+            .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", new_parent)))?;
+
+        self.df.add_parent(&drive_id, &parent_id)?;
+        self.link_locally(ino, new_parent, name)
+    }
+
+    /// Whether a hard link to a file may be called `name`. Drive keeps a single name per file, so
+    /// a link under another name would show up under the name of the file after a remount. The
+    /// name may be the one shown for the file, i.e. with its duplicate-name suffix.
+    pub(crate) fn is_valid_link_name(&self, ino: Inode, name: &str) -> bool {
+        self.get_file(&FileId::Inode(ino)).map_or(false, |file| {
+            file.name == name || self.display_name(file) == name
+        })
+    }
+
+    /// Adds a hard link to the local file tree. Does not communicate with Drive.
+    pub(crate) fn link_locally(
+        &mut self,
+        ino: Inode,
+        new_parent: Inode,
+        name: String,
+    ) -> Result<(), Error> {
+        if self.contains(&FileId::ParentAndName {
+            parent: new_parent,
+            name: name.clone(),
+        }) {
+            return Err(err_msg(format!("{:?} already exists", name)));
+        }
+
+        self.get_mut_file(&FileId::Inode(ino))
+            .ok_or_else(|| err_msg(format!("Cannot find {:?}", ino)))?
+            .attr
+            .nlink += 1;
+        self.links
+            .entry(ino)
+            .or_insert_with(Vec::new)
+            .push((new_parent, name));
         Ok(())
     }
 
+    /// Removes one of several hard links of a file, which only detaches the corresponding parent
+    /// on Drive. Returns `false` without doing anything if the file has a single link left, in
+    /// which case it should be deleted or trashed as usual.
+    pub fn unlink_link(&mut self, parent: Inode, name: &str) -> Result<bool, Error> {
+        let id = FileId::ParentAndName {
+            parent,
+            name: name.to_string(),
+        };
+        let drive_id = match self.get_inode(&id) {
+            Some(ino) if self.links.contains_key(&ino) => self
+                .get_drive_id(&FileId::Inode(ino))
+                .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", ino)))?,
+            _ => return Ok(false),
+        };
+        let parent_id = self
+            .get_drive_id(&FileId::Inode(parent))
+            .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", parent)))?;
+
+        self.df.remove_parent(&drive_id, &parent_id)?;
+        self.unlink_locally(parent, name)
+    }
+
+    /// Removes a hard link from the local file tree. If the original path of the file is removed,
+    /// one of its remaining links takes its place. Does not communicate with Drive.
+    pub(crate) fn unlink_locally(&mut self, parent: Inode, name: &str) -> Result<bool, Error> {
+        let id = FileId::ParentAndName {
+            parent,
+            name: name.to_string(),
+        };
+        let ino = match self.get_inode(&id) {
+            Some(ino) if self.links.contains_key(&ino) => ino,
+            _ => return Ok(false),
+        };
+
+        let mut paths = self.links.remove(&ino).unwrap_or_default();
+        match paths
+            .iter()
+            .position(|&(p, ref n)| p == parent && n == name)
+        {
+            Some(index) => {
+                paths.remove(index);
+            }
+            None => {
+                // The original path is removed, so the file moves to one of its other links.
+                let (new_parent, new_name) = paths.remove(0);
+                self.move_locally(&FileId::Inode(ino), &FileId::Inode(new_parent))?;
+                if let Some(file) = self.get_mut_file(&FileId::Inode(ino)) {
+                    file.name = new_name;
+                    file.identical_name_id = None;
+                }
+            }
+        }
+
+        if let Some(file) = self.get_mut_file(&FileId::Inode(ino)) {
+            file.attr.nlink = file.attr.nlink.saturating_sub(1);
+        }
+        if !paths.is_empty() {
+            self.links.insert(ino, paths);
+        }
+        Ok(true)
+    }
+
     /// Deletes a file locally *and* on Drive.
     pub fn delete(&mut self, id: &FileId) -> Result<(), Error> {
         let drive_id = self
             .get_drive_id(id)
             .ok_or_else(|| err_msg("No such file"))?;
 
-        self.delete_locally(id)?;
+        // The file is only removed locally once Drive has deleted it, so that a failed request
+        // does not make it disappear from the file system.
         match self.df.delete_permanently(&drive_id) {
-            Ok(response) => {
-                debug!("{:?}", response);
-                Ok(())
+            Ok(response) => debug!("{:?}", response),
+            Err(e) => return Err(err_msg(format!("{}", e))),
+        }
+        self.delete_locally(&FileId::DriveId(drive_id))
+    }
+
+    /// Deletes a file permanently or moves it to Trash, locally and on Drive.
+    pub fn remove(&mut self, id: &FileId, permanently: bool) -> Result<(), Error> {
+        if permanently {
+            self.delete(id)
+        } else {
+            self.move_file_to_trash(id, true)
+        }
+    }
+
+    /// Whether deleted files are held locally for a while before they are deleted on Drive. Never
+    /// the case in mirror mode, which has no ".gcsf/pending-deletes" to hold them in.
+    pub fn has_delete_grace_period(&self) -> bool {
+        self.pending_deletes_dir.is_some()
+            && self.config.delete_grace_period() > Duration::from_secs(0)
+    }
+
+    /// Moves a file to ".gcsf/pending-deletes". It is removed on Drive by `apply_pending_deletes()`
+    /// once its grace period ends, unless it is restored before.
+    pub fn delete_later(&mut self, id: &FileId, permanently: bool) -> Result<(), Error> {
+        let holding_dir = self
+            .pending_deletes_dir
+            .ok_or_else(|| err_msg("There is no place to hold pending deletes"))?;
+        let (inode, name) = self
+            .get_file(id)
+            .map(|file| (file.inode(), file.name.clone()))
+            .ok_or_else(|| err_msg(format!("Cannot find {:?}", id)))?;
+        let parent = self
+            .get_node_id(id)
+            .and_then(|node_id| self.tree.get(&node_id).ok()?.parent().cloned())
+            .and_then(|parent_id| self.get_inode(&FileId::NodeId(parent_id)))
+            .ok_or_else(|| err_msg(format!("Cannot find the parent of {:?}", id)))?;
+
+        self.move_locally(&FileId::Inode(inode), &FileId::Inode(holding_dir))?;
+        self.pending_deletes.insert(
+            inode,
+            PendingDelete {
+                parent,
+                name,
+                deadline: self.clock.now() + self.config.delete_grace_period(),
+                permanently,
+            },
+        );
+        Ok(())
+    }
+
+    /// Moves a file which is waiting to be deleted back to where it was, if it was deleted from
+    /// `parent` under `name`. Returns its inode.
+    pub fn restore_pending_delete(&mut self, parent: Inode, name: &str) -> Option<Inode> {
+        let inode = self
+            .pending_deletes
+            .iter()
+            .find(|(_, pending)| pending.parent == parent && pending.name == name)
+            .map(|(inode, _)| *inode)?;
+
+        self.move_locally(&FileId::Inode(inode), &FileId::Inode(parent))
+            .ok()?;
+        self.pending_deletes.remove(&inode);
+        Some(inode)
+    }
+
+    /// Removes the files whose grace period has ended on Drive, or all of them if `all` is set.
+    /// Files which could not be removed are retried on the next call.
+    pub fn apply_pending_deletes(&mut self, all: bool) {
+        let now = self.clock.now();
+        let due: Vec<(Inode, bool)> = self
+            .pending_deletes
+            .iter()
+            .filter(|(_, pending)| all || pending.deadline <= now)
+            .map(|(inode, pending)| (*inode, pending.permanently))
+            .collect();
+
+        for (inode, permanently) in due {
+            match self.remove(&FileId::Inode(inode), permanently) {
+                Ok(()) => {
+                    self.pending_deletes.remove(&inode);
+                }
+                Err(e) => error!("Could not delete inode {}: {}", inode, e),
+            }
+        }
+    }
+
+    /// Permanently deletes the files which have been in Trash for longer than
+    /// `Config::trash_retention`. Files which left the Trash in the meantime are kept.
+    pub fn empty_old_trash(&mut self) {
+        let retention = match self.config.trash_retention() {
+            Some(retention) => retention,
+            None => return,
+        };
+        let now = self.clock.now();
+        let due: Vec<Inode> = self
+            .trashed_at
+            .iter()
+            .filter(|&(_, trashed_at)| {
+                now.duration_since(*trashed_at).unwrap_or_default() >= retention
+            })
+            .map(|(inode, _)| *inode)
+            .collect();
+
+        for inode in due {
+            let id = FileId::Inode(inode);
+            if !self.contains(&id) || !self.is_in_trash(&id) {
+                self.trashed_at.remove(&inode);
+                continue;
+            }
+
+            info!(
+                "Emptying {:?} from Trash.",
+                self.get_file(&id).map(|file| file.name.clone())
+            );
+            match self.delete(&id) {
+                Ok(()) => {
+                    self.trashed_at.remove(&inode);
+                }
+                Err(e) => error!("Could not empty inode {} from Trash: {}", inode, e),
             }
-            Err(e) => Err(err_msg(format!("{}", e))),
         }
     }
 
+    /// Truncates a file to zero bytes.
+    pub fn truncate(&mut self, id: &FileId) -> Result<(), Error> {
+        let drive_id = self
+            .get_drive_id(id)
+            .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", id)))?;
+        self.df.truncate(&drive_id);
+        self.get_mut_file(id)
+            .ok_or_else(|| err_msg(format!("Cannot find {:?}", id)))?
+            .attr
+            .size = 0;
+        Ok(())
+    }
+
     /// Moves a file to the Trash directory locally *and* on Drive.
     pub fn move_file_to_trash(&mut self, id: &FileId, also_on_drive: bool) -> Result<(), Error> {
         debug!("Moving {:?} to trash.", &id);
-        let node_id = self
-            .get_node_id(id)
-            .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", &id)))?;
         let drive_id = self
             .get_drive_id(id)
             .ok_or_else(|| err_msg(format!("Cannot find drive_id of {:?}", &id)))?;
-        let trash_id = self
-            .get_node_id(&FileId::Inode(TRASH_INODE))
-            .ok_or_else(|| err_msg("Cannot find node_id of Trash dir"))?;
-
-        self.tree.move_node(&node_id, ToParent(&trash_id))?;
+        // There is no Trash dir in mirror mode, so trashed files are only removed locally.
+        let trash_id = self.get_node_id(&FileId::Inode(TRASH_INODE));
+        if trash_id.is_some() {
+            self.move_node(id, &FileId::Inode(TRASH_INODE))?;
+            if let Some(inode) = self.get_inode(&FileId::DriveId(drive_id.clone())) {
+                self.trashed_at.insert(inode, self.clock.now());
+            }
+        }
 
         // File cannot be identified by FileId::ParentAndName now because the parent has changed.
         // Using DriveId instead.
@@ -500,9 +2548,12 @@ impl FileManager {
             self.get_mut_file(&FileId::DriveId(drive_id.clone()))
                 .ok_or_else(|| err_msg(format!("Cannot find {:?}", &drive_id)))?
                 .set_trashed(true)?;
-            self.df.move_to_trash(drive_id)?;
+            self.df.move_to_trash(drive_id.clone())?;
         }
 
+        if trash_id.is_none() {
+            self.delete_locally(&FileId::DriveId(drive_id))?;
+        }
         Ok(())
     }
 
@@ -515,6 +2566,176 @@ impl FileManager {
         Ok(file.is_trashed())
     }
 
+    /// The Drive description of a file, if `expose_descriptions` is enabled and the file has a
+    /// non-empty description.
+    pub fn description(&self, id: &FileId) -> Option<String> {
+        if !self.config.expose_descriptions() {
+            return None;
+        }
+        self.get_file(id)?
+            .drive_file
+            .as_ref()?
+            .description
+            .clone()
+            .filter(|description| !description.is_empty())
+    }
+
+    /// Whether a file is read-only because it is owned by someone else and `readonly_not_owned` is
+    /// enabled.
+    pub fn is_read_only_not_owned(&self, ino: Inode) -> bool {
+        self.config.readonly_not_owned()
+            && self
+                .get_file(&FileId::Inode(ino))
+                .filter(|file| file.attr.kind != FileType::Directory)
+                .and_then(|file| file.drive_file.as_ref())
+                .and_then(|drive_file| drive_file.owned_by_me)
+                == Some(false)
+    }
+
+    /// Whether a trashed file was trashed explicitly, as opposed to being trashed because one of its
+    /// ancestors was. `None` for files which are not trashed.
+    pub fn explicitly_trashed(&self, id: &FileId) -> Option<bool> {
+        self.get_file(id)
+            .filter(|file| file.is_trashed())
+            .map(File::is_explicitly_trashed)
+    }
+
+    /// The Drive ids of the parents of a file, if `expose_parents` is enabled and the file is a
+    /// Drive file.
+    pub fn drive_parents(&self, id: &FileId) -> Option<Vec<DriveId>> {
+        if !self.config.expose_parents() {
+            return None;
+        }
+        Some(
+            self.get_file(id)?
+                .drive_file
+                .as_ref()?
+                .parents
+                .clone()
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Sets the Drive description of a file locally *and* on Drive. An empty description removes
+    /// it.
+    pub fn set_description(&mut self, id: &FileId, description: &str) -> Result<(), Error> {
+        let drive_id = self
+            .get_drive_id(id)
+            .ok_or_else(|| err_msg(format!("Cannot find drive_id of {:?}", &id)))?;
+        self.df.set_description(&drive_id, description)?;
+
+        if let Some(drive_file) = self
+            .get_mut_file(id)
+            .and_then(|file| file.drive_file.as_mut())
+        {
+            drive_file.description = Some(description.to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether a file only exists locally because it looks like an editor temp file.
+    pub fn is_deferred(&self, id: &FileId) -> bool {
+        self.get_drive_id(id)
+            .map_or(false, |drive_id| self.df.is_deferred(&drive_id))
+    }
+
+    /// Removes a file which only exists locally, together with its unsaved content.
+    pub fn discard_deferred(&mut self, id: &FileId) -> Result<(), Error> {
+        let drive_id = self
+            .get_drive_id(id)
+            .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", &id)))?;
+        self.delete_locally(id)?;
+        self.df.discard(&drive_id);
+        Ok(())
+    }
+
+    /// Renames a file which only exists locally. Renaming it over an existing Drive file makes its
+    /// content the new content of that file, which keeps the Drive file (and its history). Nothing
+    /// is uploaded until `save_deferred()` is called.
+    fn rename_deferred(
+        &mut self,
+        inode: Inode,
+        local_id: &str,
+        new_parent: Inode,
+        new_name: String,
+    ) -> Result<(), Error> {
+        let target = self
+            .get_inode(&FileId::ParentAndName {
+                parent: new_parent,
+                name: new_name.clone(),
+            })
+            .filter(|&target| target != inode);
+
+        if let Some(target) = target {
+            let target_id = FileId::Inode(target);
+            if self.is_deferred(&target_id) {
+                self.discard_deferred(&target_id)?;
+            } else {
+                let target_drive_id = self
+                    .get_file(&target_id)
+                    .filter(|file| file.kind() == FileType::RegularFile)
+                    .and_then(|file| file.drive_id())
+                    .ok_or_else(|| {
+                        err_msg(format!("Cannot replace {:?} with a file", &new_name))
+                    })?;
+                let size = self
+                    .get_file(&FileId::Inode(inode))
+                    .map(|file| file.attr.size);
+                self.df.move_content(local_id, &target_drive_id);
+                self.delete_locally(&FileId::Inode(inode))?;
+                if let (Some(size), Some(file)) = (size, self.get_mut_file(&target_id)) {
+                    file.attr.size = size;
+                }
+                return Ok(());
+            }
+        }
+
+        self.move_locally(&FileId::Inode(inode), &FileId::Inode(new_parent))?;
+        let file = self
+            .get_mut_file(&FileId::Inode(inode))
+            .ok_or_else(|| err_msg("File doesn't exist"))?;
+        file.name = new_name;
+        if let Some(drive_file) = file.drive_file.as_mut() {
+            drive_file.name = Some(file.name.clone());
+        }
+        Ok(())
+    }
+
+    /// Uploads a file which was renamed while it only existed locally. If it was renamed over an
+    /// existing file, that file is flushed. Otherwise, it is created on Drive unless it still looks
+    /// like an editor temp file.
+    pub fn save_deferred(&mut self, ino: Inode) -> Result<(), Error> {
+        let id = FileId::Inode(ino);
+        let (name, local_id) = self
+            .get_file(&id)
+            .and_then(|file| Some((file.name.clone(), file.drive_id()?)))
+            .ok_or_else(|| err_msg(format!("Cannot find drive id of {:?}", &id)))?;
+
+        if !self.df.is_deferred(&local_id) {
+            return self.flush(&id);
+        }
+        if is_editor_temp_name(&name) {
+            return Ok(());
+        }
+
+        let parent = self
+            .local_parent_drive_id(&id)
+            .ok_or_else(|| err_msg(format!("Cannot find the parent of {:?}", &id)))?;
+        let drive_id = self.df.create(&drive3::File {
+            name: Some(name),
+            parents: Some(vec![parent]),
+            ..Default::default()
+        })?;
+
+        self.df.move_content(&local_id, &drive_id);
+        self.unmap_drive_id(&local_id, ino);
+        self.map_drive_id(drive_id.clone(), ino);
+        if let Some(file) = self.get_mut_file(&id) {
+            file.set_drive_id(drive_id.clone());
+        }
+        self.df.flush(&drive_id)
+    }
+
     /// Moves/renames a file locally *and* on Drive.
     pub fn rename(
         &mut self,
@@ -524,19 +2745,34 @@ impl FileManager {
     ) -> Result<(), Error> {
         // Identify the file by its inode instead of (parent, name) because both the parent and
         // name will probably change in this method.
-        let id = FileId::Inode(
-            self.get_inode(id)
-                .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", &id)))?,
-        );
-
-        let current_node = self
-            .get_node_id(&id)
+        let inode = self
+            .get_inode(id)
             .ok_or_else(|| err_msg(format!("Cannot find node_id of {:?}", &id)))?;
-        let target_node = self
-            .get_node_id(&FileId::Inode(new_parent))
-            .ok_or_else(|| err_msg("Target node doesn't exist"))?;
+        let id = FileId::Inode(inode);
+        if self.is_pseudo_dir(new_parent) {
+            return Err(err_msg(format!(
+                "Cannot move {:?} into inode {}, which is not a Drive folder",
+                &id, new_parent
+            )));
+        }
+        // Moving a file out of ".gcsf/pending-deletes" saves it.
+        self.pending_deletes.remove(&inode);
+        if let Some(local_id) = self.get_drive_id(&id).filter(|id| self.df.is_deferred(id)) {
+            return self.rename_deferred(inode, &local_id, new_parent, new_name);
+        }
+
+        let source_parent = self.local_parent_drive_id(&id);
+        // Moving a file out of "Trash" restores it. Whether it was trashed explicitly decides what
+        // is restored on Drive: the file itself, or only its place outside a trashed ancestor.
+        let into_trash = new_parent == TRASH_INODE || self.is_in_trash(&FileId::Inode(new_parent));
+        let restored = if self.is_in_trash(&id) && !into_trash {
+            self.get_file(&id).map(File::is_explicitly_trashed)
+        } else {
+            None
+        };
 
-        self.tree.move_node(&current_node, ToParent(&target_node))?;
+        // Drive is only changed once the file was moved locally.
+        self.move_node(&id, &FileId::Inode(new_parent))?;
 
         {
             if self.rename_identical_files {
@@ -572,44 +2808,201 @@ impl FileManager {
                 ))
             })?;
 
+        let drive_name = self.get_file(&id).map_or_else(
+            || new_name.clone(),
+            |f| f.drive_name(&new_name, &self.config),
+        );
+
         debug!("parent_id: {}", &parent_id);
-        self.df.move_to(&drive_id, &parent_id, &new_name)?;
+        if let Some(explicitly_trashed) = restored {
+            self.df
+                .restore_to(&drive_id, &parent_id, &drive_name, explicitly_trashed)?;
+            self.trashed_at.remove(&inode);
+            if let Some(file) = self.get_mut_file(&id) {
+                file.set_trashed(false)?;
+            }
+            return Ok(());
+        }
+        self.df.move_to(
+            &drive_id,
+            source_parent.as_ref().map(|p| p.as_str()),
+            &parent_id,
+            &drive_name,
+        )?;
         Ok(())
     }
 
     /// Writes to a file locally *and* on Drive. Note: the pending write is not necessarily applied
-    /// instantly by the `DriveFacade`.
-    pub fn write(&mut self, id: FileId, offset: usize, data: &[u8]) {
-        let drive_id = self.get_drive_id(&id).unwrap();
+    /// instantly by the `DriveFacade`. Writes to a Drive file which has no Drive id yet are kept
+    /// under a local id until the file is created on Drive by `flush`. Fails for files which are not
+    /// Drive files, e.g. the special directories.
+    pub fn write(&mut self, id: FileId, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let drive_id = match self.get_drive_id(&id) {
+            Some(drive_id) => drive_id,
+            None => self.assign_local_id(&id)?,
+        };
         self.df.write(drive_id, offset, data);
+        self.update_heartbeat();
+        Ok(())
     }
-}
 
-impl fmt::Debug for FileManager {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "FileManager(")?;
+    /// Gives a Drive file which was not created on Drive yet a local id, under which its content is
+    /// kept until it is created.
+    fn assign_local_id(&mut self, id: &FileId) -> Result<DriveId, Error> {
+        let ino = match self.get_file(id) {
+            Some(file) if file.drive_file.is_some() => file.inode(),
+            Some(_) => return Err(err_msg(format!("{:?} is not a Drive file", id))),
+            None => return Err(err_msg(format!("Cannot find {:?}", id))),
+        };
 
-        if self.tree.root_node_id().is_none() {
-            return writeln!(f, ")");
+        let local_id = format!("{}{}", LOCAL_ID_PREFIX, ino);
+        debug!(
+            "{:?} has no Drive id yet. Keeping its writes under {}",
+            id, &local_id
+        );
+        self.df.defer_creation(&local_id);
+        self.map_drive_id(local_id.clone(), ino);
+        if let Some(file) = self.get_mut_file(id) {
+            file.set_drive_id(local_id.clone());
         }
+        Ok(local_id)
+    }
 
-        let mut stack: Vec<(u32, &NodeId)> = vec![(0, self.tree.root_node_id().unwrap())];
+    /// Opens a new handle of a file with the given `open` flags and returns its number.
+    pub fn open(&mut self, ino: Inode, flags: u32) -> u64 {
+        self.last_fh += 1;
+        *self.open_handles.entry(ino).or_insert(0) += 1;
+        self.open_files.insert(
+            self.last_fh,
+            OpenFile {
+                inode: ino,
+                append: flags & O_APPEND as u32 != 0,
+            },
+        );
+        self.last_fh
+    }
 
-        while !stack.is_empty() {
-            let (level, node_id) = stack.pop().unwrap();
+    /// Whether `fh` is an open handle of the file `ino`.
+    pub fn is_open(&self, fh: u64, ino: Inode) -> bool {
+        self.open_files
+            .get(&fh)
+            .map_or(false, |open_file| open_file.inode == ino)
+    }
 
-            for _ in 0..level {
-                write!(f, "\t")?;
+    /// Closes a file handle. If `upload_on` is "release", the pending writes of a file are uploaded
+    /// once its last handle is closed.
+    pub fn release(&mut self, fh: u64) -> Result<(), Error> {
+        let ino = match self.open_files.remove(&fh) {
+            Some(open_file) => open_file.inode,
+            None => return Ok(()),
+        };
+        let last = match self.open_handles.get_mut(&ino) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
             }
+            _ => true,
+        };
+        if !last {
+            return Ok(());
+        }
+        self.open_handles.remove(&ino);
+        if self.config.upload_on_release() && self.contains(&FileId::Inode(ino)) {
+            return self.flush(&FileId::Inode(ino));
+        }
+        Ok(())
+    }
 
-            let file = self.get_file(&FileId::NodeId(node_id.clone())).unwrap();
-            writeln!(f, "{:3} => {}", file.inode(), file.name)?;
+    /// Passes along the FLUSH system call, which is made whenever a file handle is closed. Does
+    /// nothing if `upload_on` is "release", since the writes are uploaded once the last handle is
+    /// released instead.
+    pub fn flush_on_close(&mut self, id: &FileId) -> Result<(), Error> {
+        if self.config.upload_on_release() {
+            return Ok(());
+        }
+        self.flush(id)
+    }
 
-            self.tree.children_ids(node_id).unwrap().for_each(|id| {
-                stack.push((level + 1, id));
-            });
+    /// Writes data through the handle `fh` and updates the size of the file. Handles opened with
+    /// `O_APPEND` always write at the current end of the file, since the offset chosen by the
+    /// kernel is stale once another handle extended the file. Returns the offset written at.
+    pub fn write_through_handle(
+        &mut self,
+        fh: u64,
+        ino: Inode,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let id = FileId::Inode(ino);
+        let size = self
+            .get_file(&id)
+            .ok_or_else(|| err_msg(format!("Cannot find {:?}", id)))?
+            .attr
+            .size;
+
+        let offset = match self.open_files.get(&fh) {
+            Some(open_file) if open_file.append => size as usize,
+            _ => offset,
+        };
+        self.write(id.clone(), offset, data)?;
+
+        if let Some(file) = self.get_mut_file(&id) {
+            file.attr.size = cmp::max(size, (offset + data.len()) as u64);
+        }
+        Ok(offset)
+    }
+
+    /// Sets the size of a file to the size of its cached content, i.e. of what Drive returned on the
+    /// last download. This corrects sizes which became stale after a remote edit before the next
+    /// sync, as well as the placeholder size of exported files (docs, sheets, ...), which Drive does
+    /// not know. Files with unsaved changes keep their size.
+    pub fn correct_size(&mut self, ino: Inode) {
+        let id = FileId::Inode(ino);
+        let drive_id = match self.get_file(&id).and_then(File::drive_id) {
+            Some(drive_id) => drive_id,
+            None => return,
+        };
+        if self.df.has_pending_writes(&drive_id) {
+            return;
+        }
+
+        if let Some(size) = self.df.cached_size(&drive_id) {
+            if let Some(file) = self.get_mut_file(&id) {
+                if file.attr.size != size {
+                    debug!(
+                        "Correcting the size of {:?} from {} to {}",
+                        &file.name, file.attr.size, size
+                    );
+                    file.attr.size = size;
+                }
+            }
         }
+    }
+}
 
+/// Whether a file name looks like one of the temp files which editors write before renaming them
+/// over the file being saved, e.g. "file.txt.swp" (vim), "file.txt~" or ".#file.txt" (emacs).
+pub fn is_editor_temp_name(name: &str) -> bool {
+    name.ends_with(".swp")
+        || name.ends_with(".swx")
+        || name.ends_with('~')
+        || name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+}
+
+impl fmt::Debug for FileManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "FileManager(")?;
+        self.write_tree(f, None)?;
         writeln!(f, ")")
     }
 }
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -1,21 +1,30 @@
 use super::{Config, File, FileId, FileManager};
 use drive3;
-use failure::Error;
+use failure::{err_msg, Error};
+use fuse::consts::FOPEN_DIRECT_IO;
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
-    ReplyEntry, ReplyStatfs, ReplyWrite, Request,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request,
+};
+use libc::{
+    EACCES, EBADF, EINVAL, EIO, ENODATA, ENOENT, ENOSPC, ENOTDIR, ENOTRECOVERABLE, ENOTSUP, EPERM,
+    ERANGE, EREMOTE, EROFS, O_ACCMODE, O_RDONLY,
 };
-use libc::{ENOENT, ENOTDIR, ENOTRECOVERABLE, EREMOTE};
 use lru_time_cache::LruCache;
 use std;
 use std::clone::Clone;
 use std::cmp;
 use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use time::Timespec;
 use DriveFacade;
 
 pub type Inode = u64;
 
+const ROOT_INODE: Inode = 1;
 const TRASH_INODE: Inode = 2;
 
 macro_rules! log_result {
@@ -47,61 +56,537 @@ macro_rules! log_result_and_fill_reply {
     };
 }
 
+macro_rules! reject_if_read_only {
+    ($fs:ident, $reply:ident) => {
+        if $fs.manager.df.is_read_only() {
+            $reply.error(EROFS);
+            return;
+        }
+    };
+}
+
+macro_rules! reject_if_not_owned {
+    ($fs:ident, $ino:ident, $reply:ident) => {
+        if $fs.manager.is_read_only_not_owned($ino) {
+            $reply.error(EACCES);
+            return;
+        }
+    };
+}
+
 /// An empty FUSE file system. It can be used in a mounting test aimed to determine whether or
 /// not the real file system can be mounted as well. If the test fails, the application can fail
 /// early instead of wasting time constructing the real file system.
 pub struct NullFs;
 impl Filesystem for NullFs {}
 
+/// The capacity reported by `statfs`. Drive reports no limit for unlimited accounts and the quota
+/// is unknown if it could not be retrieved. In both cases `reported_free_bytes` are added to the
+/// used space so that tools such as `cp` do not refuse to write.
+pub fn reported_capacity(size: u64, capacity: Option<u64>, reported_free_bytes: u64) -> u64 {
+    capacity.unwrap_or_else(|| size.saturating_add(reported_free_bytes))
+}
+
+/// How many free inodes `statfs` reports on top of the used ones if `Config::max_inodes` is absent.
+const DEFAULT_FREE_INODES: u64 = 1 << 20;
+
+/// The total and free inode counts reported by `statfs` for `used` files. Tools which check for
+/// free inodes refuse to create files if none are reported, so there is always some headroom
+/// unless a configured `max_inodes` is used up.
+pub fn inode_counts(used: u64, max_inodes: Option<u64>) -> (u64, u64) {
+    let total = max_inodes
+        .unwrap_or_else(|| used.saturating_add(DEFAULT_FREE_INODES))
+        .max(used);
+    (total, total - used)
+}
+
+/// Whether the used space plus `pending` bytes would leave less than `reserve` bytes of a limited
+/// quota free. Accounts without a known limit never breach the reserve.
+pub fn breaches_reserve(used: u64, limit: Option<u64>, pending: u64, reserve: u64) -> bool {
+    limit.map_or(false, |limit| {
+        used.saturating_add(pending).saturating_add(reserve) > limit
+    })
+}
+
+/// Runs `probe` until it succeeds, at most `retries + 1` times, sleeping for `delay` between
+/// attempts. Returns the last error if all attempts fail.
+pub fn retry<T, F>(retries: u32, delay: Duration, mut probe: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut attempt = 0;
+    loop {
+        match probe() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                if attempt >= retries {
+                    return Err(e);
+                }
+                attempt += 1;
+                warn!("{}. Retrying ({}/{}).", e, attempt, retries);
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+/// Runs `mount` until the file system is unmounted for good. `mount` is expected to block for as
+/// long as its FUSE session is alive. If `auto_remount` is set, the file system is mounted again
+/// every time its session ends, until mounting fails.
+pub fn mount_until_unmounted<F>(auto_remount: bool, mut mount: F) -> Result<(), Error>
+where
+    F: FnMut() -> Result<(), Error>,
+{
+    loop {
+        mount()?;
+
+        if !auto_remount {
+            return Ok(());
+        }
+        info!("The FUSE session has ended. Remounting.");
+    }
+}
+
+/// What a daemonized mount reports to the process which started it, once mounting either
+/// succeeded or failed.
+const MOUNTED: u8 = 0;
+const MOUNT_FAILED: u8 = 1;
+
+/// Tells the process which started a daemonized mount whether the file system was mounted.
+pub fn report_mount_status<W: Write>(mut status: W, mounted: bool) -> io::Result<()> {
+    status.write_all(&[if mounted { MOUNTED } else { MOUNT_FAILED }])
+}
+
+/// Waits for a daemonized mount to report its status and returns the exit code of the process
+/// which started it: 0 if the file system was mounted, 1 if mounting failed or the mount exited
+/// without reporting anything.
+pub fn mount_exit_code<R: Read>(mut status: R) -> i32 {
+    let mut byte = [MOUNT_FAILED];
+    match status.read_exact(&mut byte) {
+        Ok(()) if byte[0] == MOUNTED => 0,
+        _ => 1,
+    }
+}
+
+/// Whether `mountpoint` is listed as a GCSF mount in `mounts`, which is either the content of
+/// /proc/mounts or the output of `mount` on platforms without it.
+pub fn is_gcsf_mount(mounts: &str, mountpoint: &Path) -> bool {
+    mounts.lines().any(|line| {
+        let (fsname, path) = match line.find(" on ") {
+            // `mount` output: "GCSF on /path type fuse (...)" or "GCSF on /path (osxfuse, ...)".
+            Some(i) => {
+                let rest = &line[i + 4..];
+                let end = [" type ", " ("]
+                    .iter()
+                    .filter_map(|sep| rest.find(sep))
+                    .min()
+                    .unwrap_or(rest.len());
+                (line[..i].to_string(), rest[..end].to_string())
+            }
+            // /proc/mounts: "GCSF /path fuse rw,... 0 0", with spaces in paths escaped as \040.
+            None => {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some(fsname), Some(path)) => {
+                        (unescape_mount_field(fsname), unescape_mount_field(path))
+                    }
+                    _ => return false,
+                }
+            }
+        };
+        fsname == "GCSF" && Path::new(&path) == mountpoint
+    })
+}
+
+/// The absolute form of `mountpoint`, relative to `cwd` unless it is absolute already, with "."
+/// and ".." resolved lexically. Unlike `Path::canonicalize`, this never touches the mountpoint
+/// itself, which fails with ENOTCONN once the process serving the mount is gone.
+pub fn absolute_mountpoint(cwd: &Path, mountpoint: &Path) -> PathBuf {
+    let mut path = PathBuf::new();
+    for component in cwd.join(mountpoint).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                path.pop();
+            }
+            other => path.push(other.as_os_str()),
+        }
+    }
+    path
+}
+
+/// Replaces the octal escapes which /proc/mounts uses for whitespace and backslashes.
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4).and_then(|digits| {
+            if bytes[i] != b'\\' || !digits.iter().all(|d| b'0' <= *d && *d <= b'7') {
+                return None;
+            }
+            u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok()
+        });
+        match escape {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// The inode number under which the kernel sees a file, i.e. its inode offset by
+/// `Config::inode_base`.
+pub fn kernel_inode(base: u64, ino: Inode) -> u64 {
+    ino.saturating_add(base)
+}
+
+/// The inode of a file which the kernel refers to by `ino`. The kernel always refers to the root
+/// as 1, whatever its offset inode number is. Numbers which are not offset refer to no file.
+pub fn internal_inode(base: u64, ino: u64) -> Inode {
+    if ino == ROOT_INODE {
+        ROOT_INODE
+    } else if ino > base {
+        ino - base
+    } else {
+        0
+    }
+}
+
+/// The attributes of a file as seen by the kernel, i.e. with its inode offset by
+/// `Config::inode_base`.
+fn kernel_attr(base: u64, attr: &FileAttr) -> FileAttr {
+    FileAttr {
+        ino: kernel_inode(base, attr.ino),
+        ..*attr
+    }
+}
+
+/// The extended attributes which describe the Drive parents of a file: their comma-separated
+/// Drive ids and their count.
+pub fn parent_xattrs(parents: &[String]) -> Vec<(String, String)> {
+    vec![
+        (String::from(PARENTS_XATTR), parents.join(",")),
+        (String::from(PARENT_COUNT_XATTR), parents.len().to_string()),
+    ]
+}
+
+/// Replies to an xattr request with either the size of `data` (if `size` is 0, which is how callers
+/// ask for it) or `data` itself.
+fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
 /// A FUSE file system which is linked to a Google Drive account.
 pub struct Gcsf {
-    manager: FileManager,
-    statfs_cache: LruCache<String, u64>,
+    pub(crate) manager: FileManager,
+    statfs_cache: LruCache<String, (u64, Option<u64>)>,
+    reported_free_bytes: u64,
+    reserve_bytes: u64,
+    max_inodes: Option<u64>,
+    shutdown_flush_timeout: Duration,
+    expose_descriptions: bool,
+    dot_entries: bool,
+    inode_base: u64,
 }
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
 
+/// The extended attribute which holds the Drive description of a file.
+const DESCRIPTION_XATTR: &str = "user.drive.description";
+
+/// The extended attributes which hold the Drive ids of the parents of a file and their count.
+const PARENTS_XATTR: &str = "user.drive.parents";
+const PARENT_COUNT_XATTR: &str = "user.drive.parentCount";
+
+/// The extended attribute which tells whether a trashed file was trashed explicitly or because one
+/// of its ancestors was.
+const EXPLICITLY_TRASHED_XATTR: &str = "user.drive.explicitlyTrashed";
+
+/// The extended attribute which marks files whose last known content was served after a failed
+/// sync, because their current content could not be fetched.
+const POSSIBLY_STALE_XATTR: &str = "user.gcsf.possibly_stale";
+
 impl Gcsf {
+    /// The extended attributes of a file, i.e. its Drive labels, its description, its parents,
+    /// how it was trashed and whether its content is possibly stale. Pseudo-directories have none of them.
+    fn xattrs(&mut self, ino: Inode) -> Result<Vec<(String, String)>, Error> {
+        let mut attrs = match self.manager.get_drive_id(&FileId::Inode(ino)) {
+            Some(drive_id) => {
+                let mut attrs = Vec::new();
+                if self.manager.df.is_possibly_stale(&drive_id) {
+                    attrs.push((String::from(POSSIBLY_STALE_XATTR), String::from("1")));
+                }
+                attrs.extend(self.manager.df.labels(&drive_id)?);
+                attrs
+            }
+            None => Vec::new(),
+        };
+        if let Some(description) = self.manager.description(&FileId::Inode(ino)) {
+            attrs.push((String::from(DESCRIPTION_XATTR), description));
+        }
+        if let Some(parents) = self.manager.drive_parents(&FileId::Inode(ino)) {
+            attrs.extend(parent_xattrs(&parents));
+        }
+        if let Some(explicitly) = self.manager.explicitly_trashed(&FileId::Inode(ino)) {
+            attrs.push((
+                String::from(EXPLICITLY_TRASHED_XATTR),
+                explicitly.to_string(),
+            ));
+        }
+        Ok(attrs)
+    }
+
+    /// Sets the description of a file through the `user.drive.description` extended attribute.
+    /// Other extended attributes can not be changed.
+    fn set_description_xattr(&mut self, ino: Inode, name: &OsStr, value: &[u8]) -> Result<(), i32> {
+        if name != DESCRIPTION_XATTR || !self.expose_descriptions {
+            return Err(ENOTSUP);
+        }
+        let description = std::str::from_utf8(value).map_err(|_| EINVAL)?;
+        self.manager
+            .set_description(&FileId::Inode(ino), description)
+            .map_err(|e| {
+                error!("Could not set the description of inode {}: {}", ino, e);
+                EREMOTE
+            })
+    }
+
+    /// The used space and the limit of the Drive account. Cached for `cache_statfs_seconds`.
+    fn quota(&mut self) -> (u64, Option<u64>) {
+        if let Some(quota) = self.statfs_cache.get("quota") {
+            return *quota;
+        }
+        let quota = self.manager.df.size_and_capacity().unwrap_or((0, None));
+        self.statfs_cache.insert("quota".to_string(), quota);
+        quota
+    }
+
+    /// Whether writing `bytes` more, on top of the writes which are not flushed yet, would leave
+    /// less than `reserve_bytes` free on Drive.
+    fn breaches_reserve(&mut self, bytes: u64) -> bool {
+        if self.reserve_bytes == 0 {
+            return false;
+        }
+        let (used, limit) = self.quota();
+        let pending = self.manager.df.unflushed_bytes().saturating_add(bytes);
+        breaches_reserve(used, limit, pending, self.reserve_bytes)
+    }
+
+    /// Writes `data` at `offset` of a file through the handle `fh`. Returns the number of bytes
+    /// written or the error to reply with.
+    pub(crate) fn write_data(
+        &mut self,
+        ino: Inode,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+    ) -> Result<u32, i32> {
+        // Only handles registered by `open` or `create` can be written through.
+        if !self.manager.is_open(fh, ino) {
+            error!("write: {} is not an open handle of {}", fh, ino);
+            return Err(EBADF);
+        }
+        // Pausing sync only changes local state, so it is also possible on read-only mounts.
+        if self.manager.is_sync_enabled_file(ino) {
+            let paused = match String::from_utf8_lossy(data).trim() {
+                "1" => false,
+                "0" => true,
+                value => {
+                    error!("write: {:?} is neither 1 nor 0", value);
+                    return Err(EINVAL);
+                }
+            };
+            return match self.manager.set_sync_paused(paused) {
+                Ok(()) => Ok(data.len() as u32),
+                Err(e) => {
+                    error!("write: could not persist the sync state: {}", e);
+                    Err(EIO)
+                }
+            };
+        }
+
+        if self.manager.df.is_read_only() {
+            return Err(EROFS);
+        }
+        if self.manager.is_read_only_not_owned(ino) {
+            return Err(EACCES);
+        }
+        if self.manager.is_transfers_file(ino) {
+            return Err(EROFS);
+        }
+
+        if data.is_empty() {
+            return if self.manager.contains(&FileId::Inode(ino)) {
+                Ok(0)
+            } else {
+                Err(ENOENT)
+            };
+        }
+
+        if self.breaches_reserve(data.len() as u64) {
+            error!("write: refusing to write into the reserved free space");
+            return Err(ENOSPC);
+        }
+
+        let offset: usize = cmp::max(offset, 0) as usize;
+        match self.manager.write_through_handle(fh, ino, offset, data) {
+            Ok(_) => Ok(data.len() as u32),
+            Err(e) => {
+                error!("write: {}", e);
+                if self.manager.contains(&FileId::Inode(ino)) {
+                    Err(EIO)
+                } else {
+                    Err(ENOENT)
+                }
+            }
+        }
+    }
+
+    /// Removes the file or directory `name` under `parent`, which is an internal inode. Used by both
+    /// `unlink` and `rmdir`. Returns the error to reply with on failure.
+    pub(crate) fn remove_entry(&mut self, parent: Inode, name: &str) -> Result<(), i32> {
+        if self.manager.df.is_read_only() {
+            return Err(EROFS);
+        }
+
+        let id = FileId::ParentAndName {
+            parent,
+            name: name.to_string(),
+        };
+
+        if !self.manager.contains(&id) {
+            return Err(ENOENT);
+        }
+
+        // Editor temp files which were never created on Drive are only removed locally.
+        if self.manager.is_deferred(&id) {
+            return self.manager.discard_deferred(&id).map_err(|e| {
+                error!("{:?}", e);
+                ENOTRECOVERABLE
+            });
+        }
+
+        // Files with several hard links only lose the unlinked parent.
+        match self.manager.unlink_link(parent, name) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => {
+                error!("unlink: {}", e);
+                return Err(EREMOTE);
+            }
+        }
+
+        let trashed = self.manager.file_is_trashed(&id).map_err(|e| {
+            error!("{:?}", e);
+            EREMOTE
+        })?;
+        let permanently = if trashed {
+            debug!("{:?} is already trashed. Deleting permanently.", id);
+            true
+        } else if self.manager.skip_trash {
+            debug!(
+                "{:?} was not trashed. Deleting it permanently instead of moving to Trash \
+                because skip_trash is enabled in the configuration.",
+                id
+            );
+            true
+        } else {
+            debug!(
+                "{:?} was not trashed. Moving it to Trash instead of deleting permanently.",
+                id
+            );
+            false
+        };
+
+        let res = if self.manager.has_delete_grace_period() {
+            debug!("{:?} is removed on Drive once its grace period ends.", id);
+            self.manager.delete_later(&id, permanently)
+        } else {
+            self.manager.remove(&id, permanently)
+        };
+        res.map_err(|e| {
+            error!("{:?}", e);
+            ENOTRECOVERABLE
+        })
+    }
+
     /// Constructs a Gcsf instance using a given Config.
     pub fn with_config(config: Config) -> Result<Self, Error> {
         Ok(Gcsf {
-            manager: FileManager::with_drive_facade(
-                config.rename_identical_files(),
-                config.add_extensions_to_special_files(),
-                config.skip_trash(),
-                config.sync_interval(),
-                DriveFacade::new(&config),
-            )?,
-            statfs_cache: LruCache::<String, u64>::with_expiry_duration_and_capacity(
+            manager: FileManager::with_drive_facade(&config, DriveFacade::new(&config))?,
+            statfs_cache: LruCache::<String, (u64, Option<u64>)>::with_expiry_duration_and_capacity(
                 config.cache_statfs_seconds(),
-                2,
+                1,
             ),
+            reported_free_bytes: config.reported_free_bytes(),
+            reserve_bytes: config.reserve_bytes(),
+            max_inodes: config.max_inodes(),
+            shutdown_flush_timeout: config.shutdown_flush_timeout(),
+            expose_descriptions: config.expose_descriptions(),
+            dot_entries: config.dot_entries(),
+            inode_base: config.inode_base(),
         })
     }
 }
 
 impl Filesystem for Gcsf {
     fn lookup(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEntry) {
+        let parent = internal_inode(self.inode_base, parent);
         // self.manager.sync();
 
+        if let Err(e) = self.manager.load_children(&FileId::Inode(parent)) {
+            error!("Could not load the children of {}: {}", parent, e);
+            reply.error(EREMOTE);
+            return;
+        }
+
         let name = name.to_str().unwrap().to_string();
+        if let Some(search) = self.manager.lookup_search(parent, &name) {
+            debug!("lookup: search directory {} for {:?}", search, &name);
+        }
         let id = FileId::ParentAndName { parent, name };
 
-        match self.manager.get_file(&id) {
-            Some(ref file) => {
-                reply.entry(&TTL, &file.attr, 0);
-            }
+        let attr = match self.manager.get_file(&id) {
+            Some(file) => file.attr,
             None => {
                 reply.error(ENOENT);
+                return;
             }
         };
+
+        self.manager.remember(attr.ino);
+        reply.entry(&TTL, &kernel_attr(self.inode_base, &attr), 0);
     }
 
     fn getattr(&mut self, _req: &Request, ino: Inode, reply: ReplyAttr) {
+        let ino = internal_inode(self.inode_base, ino);
         // self.manager.sync();
         match self.manager.get_file(&FileId::Inode(ino)) {
+            Some(file) if self.manager.is_transfers_file(ino) => {
+                let mut attr = file.attr;
+                attr.size = self.manager.transfer_status().len() as u64;
+                reply.attr(&TTL, &kernel_attr(self.inode_base, &attr));
+            }
+            Some(file) if self.manager.is_sync_enabled_file(ino) => {
+                let mut attr = file.attr;
+                attr.size = self.manager.sync_enabled_status().len() as u64;
+                reply.attr(&TTL, &kernel_attr(self.inode_base, &attr));
+            }
             Some(file) => {
-                reply.attr(&TTL, &file.attr);
+                reply.attr(&TTL, &kernel_attr(self.inode_base, &file.attr));
             }
             None => {
                 reply.error(ENOENT);
@@ -109,6 +594,44 @@ impl Filesystem for Gcsf {
         };
     }
 
+    fn open(&mut self, _req: &Request, ino: Inode, flags: u32, reply: ReplyOpen) {
+        let ino = internal_inode(self.inode_base, ino);
+        // Removed files can only be used through the handles which were open already.
+        if !self.manager.contains(&FileId::Inode(ino)) {
+            reply.error(ENOENT);
+            return;
+        }
+        if flags & O_ACCMODE as u32 != O_RDONLY as u32 {
+            reject_if_not_owned!(self, ino, reply);
+        }
+        let fh = self.manager.open(ino, flags);
+        if self.manager.is_transfers_file(ino) || self.manager.is_sync_enabled_file(ino) {
+            // Its size changes all the time, so the kernel must read it until the end.
+            reply.opened(fh, FOPEN_DIRECT_IO);
+        } else {
+            reply.opened(fh, 0);
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: Inode,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.manager.release(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("{:?}", e);
+                reply.error(EREMOTE);
+            }
+        }
+    }
+
     fn read(
         &mut self,
         _req: &Request,
@@ -118,56 +641,94 @@ impl Filesystem for Gcsf {
         size: u32,
         reply: ReplyData,
     ) {
-        if !self.manager.contains(&FileId::Inode(ino)) {
+        let ino = internal_inode(self.inode_base, ino);
+        // Removed files can still be read through the handles which are open.
+        if self.manager.get_file(&FileId::Inode(ino)).is_none() {
             reply.error(ENOENT);
             return;
         }
 
-        let (mime, id) = self
-            .manager
-            .get_file(&FileId::Inode(ino))
-            .map(|f| {
-                let mime = f
-                    .drive_file
-                    .as_ref()
-                    .and_then(|f| f.mime_type.as_ref())
-                    .cloned();
-                let id = f.drive_id().unwrap();
-
-                (mime, id)
-            })
-            .unwrap();
+        if self.manager.is_transfers_file(ino) || self.manager.is_sync_enabled_file(ino) {
+            let status = if self.manager.is_transfers_file(ino) {
+                self.manager.transfer_status().into_bytes()
+            } else {
+                self.manager.sync_enabled_status().into_bytes()
+            };
+            let start = cmp::min(cmp::max(offset, 0) as usize, status.len());
+            let end = cmp::min(start + size as usize, status.len());
+            reply.data(&status[start..end]);
+            return;
+        }
+
+        if size == 0 {
+            reply.data(&[]);
+            return;
+        }
 
-        reply.data(
+        let file = self.manager.get_file(&FileId::Inode(ino)).map(|f| {
+            let mime = f
+                .drive_file
+                .as_ref()
+                .and_then(|f| f.mime_type.as_ref())
+                .cloned();
+
+            (mime, f.drive_id(), f.attr.size)
+        });
+        let (mime, id, file_size) = match file {
+            Some((mime, Some(id), file_size)) => (mime, id, file_size),
+            Some((_, None, _)) => {
+                error!("read: {} has no Drive id", ino);
+                reply.error(EIO);
+                return;
+            }
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let data = if self
+            .manager
+            .df
+            .streams(&id, mime.as_ref().map(String::as_str), file_size)
+        {
+            self.manager
+                .df
+                .read_streaming(&id, offset as usize, size as usize)
+        } else {
             self.manager
                 .df
                 .read(&id, mime, offset as usize, size as usize)
-                .unwrap_or(&[]),
-        );
+        };
+
+        match data {
+            Some(data) => reply.data(data),
+            None => {
+                if self.manager.df.is_denied(&id) {
+                    reply.error(EACCES);
+                } else {
+                    reply.data(&[]);
+                }
+            }
+        }
+        self.manager.correct_size(ino);
     }
 
     fn write(
         &mut self,
         _req: &Request,
         ino: Inode,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _flags: u32,
         reply: ReplyWrite,
     ) {
-        let offset: usize = cmp::max(offset, 0) as usize;
-        self.manager.write(FileId::Inode(ino), offset, data);
-
-        match self.manager.get_mut_file(&FileId::Inode(ino)) {
-            Some(ref mut file) => {
-                file.attr.size = offset as u64 + data.len() as u64;
-                reply.written(data.len() as u32);
-            }
-            None => {
-                reply.error(ENOENT);
-            }
-        };
+        let ino = internal_inode(self.inode_base, ino);
+        match self.write_data(ino, fh, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     fn readdir(
@@ -178,21 +739,94 @@ impl Filesystem for Gcsf {
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
+        let ino = internal_inode(self.inode_base, ino);
         if let Err(e) = self.manager.sync() {
             debug!("Could not perform sync: {}", e);
         }
         // println!("current state: {:#?}", self.manager);
 
+        if offset == 0 && self.manager.is_search(ino) {
+            if let Err(e) = self.manager.refresh_search(ino) {
+                warn!("Could not refresh search: {}", e);
+            }
+        }
+
+        if let Err(e) = self.manager.load_children(&FileId::Inode(ino)) {
+            error!("Could not load the children of {}: {}", ino, e);
+            reply.error(EREMOTE);
+            return;
+        }
+
+        match self.manager.get_file(&FileId::Inode(ino)).map(File::kind) {
+            Some(FileType::Directory) => {}
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            }
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        }
+
+        // The offset of an entry is its position in the listing plus one.
         let mut curr_offs = offset + 1;
-        match self.manager.get_children(&FileId::Inode(ino)) {
+        let mut offset = offset as usize;
+        if self.dot_entries {
+            let parent = self.manager.parent_inode(ino).unwrap_or(ino);
+            for &(inode, name) in [(ino, "."), (parent, "..")].iter().skip(offset) {
+                if reply.add(
+                    kernel_inode(self.inode_base, inode),
+                    curr_offs,
+                    FileType::Directory,
+                    name,
+                ) {
+                    reply.ok();
+                    return;
+                }
+                curr_offs += 1;
+            }
+            offset = offset.saturating_sub(2);
+        }
+
+        match self.manager.get_children_from(&FileId::Inode(ino), offset) {
             Some(children) => {
-                for child in children.iter().skip(offset as usize) {
-                    if reply.add(child.inode(), curr_offs, child.kind(), &child.name()) {
+                let mut full = false;
+                for child in children {
+                    let name = self.manager.display_name(child);
+                    if reply.add(
+                        kernel_inode(self.inode_base, child.inode()),
+                        curr_offs,
+                        child.kind(),
+                        &name,
+                    ) {
+                        full = true;
                         break;
                     } else {
                         curr_offs += 1;
                     }
                 }
+
+                // Hard links are listed after the children in the tree.
+                let child_count = self.manager.child_count(&FileId::Inode(ino)).unwrap_or(0);
+                let skipped_links = offset.saturating_sub(child_count);
+                if !full {
+                    for (link, name) in self
+                        .manager
+                        .links_under(ino)
+                        .into_iter()
+                        .skip(skipped_links)
+                    {
+                        let kind = match self.manager.get_file(&FileId::Inode(link)) {
+                            Some(file) => file.kind(),
+                            None => continue,
+                        };
+                        if reply.add(kernel_inode(self.inode_base, link), curr_offs, kind, &name) {
+                            break;
+                        }
+                        curr_offs += 1;
+                    }
+                }
                 reply.ok();
             }
             None => {
@@ -210,6 +844,10 @@ impl Filesystem for Gcsf {
         new_name: &OsStr,
         reply: ReplyEmpty,
     ) {
+        let parent = internal_inode(self.inode_base, parent);
+        let new_parent = internal_inode(self.inode_base, new_parent);
+        reject_if_read_only!(self, reply);
+
         let name = name.to_str().unwrap().to_string();
         let new_name = new_name.to_str().unwrap().to_string();
 
@@ -219,6 +857,12 @@ impl Filesystem for Gcsf {
                 .unwrap_or(0),
         );
 
+        if self.manager.is_pseudo_dir(new_parent) {
+            debug!("rename: inode {} is not a Drive folder", new_parent);
+            reply.error(EPERM);
+            return;
+        }
+
         if new_parent == TRASH_INODE {
             let rename_res = self.manager.rename(&id, parent, new_name);
             log_result!(&rename_res);
@@ -231,6 +875,23 @@ impl Filesystem for Gcsf {
             } else {
                 reply.error(EREMOTE);
             }
+        } else if self.manager.is_deferred(&id) {
+            // Editor temp files are uploaded once they are renamed to their final name.
+            let target = FileId::ParentAndName {
+                parent: new_parent,
+                name: new_name.clone(),
+            };
+            let res = self
+                .manager
+                .rename(&id, new_parent, new_name)
+                .and_then(|()| {
+                    let ino = self
+                        .manager
+                        .get_inode(&target)
+                        .ok_or_else(|| err_msg(format!("Cannot find {:?}", &target)))?;
+                    self.manager.save_deferred(ino)
+                });
+            log_result_and_fill_reply!(res, reply);
         } else {
             log_result_and_fill_reply!(self.manager.rename(&id, new_parent, new_name), reply);
         }
@@ -253,6 +914,19 @@ impl Filesystem for Gcsf {
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
+        let ino = internal_inode(self.inode_base, ino);
+        // Truncating ".gcsf/sync_enabled" before writing to it changes nothing.
+        if self.manager.is_sync_enabled_file(ino) {
+            match self.manager.get_file(&FileId::Inode(ino)) {
+                Some(file) => reply.attr(&TTL, &kernel_attr(self.inode_base, &file.attr)),
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
+
+        reject_if_read_only!(self, reply);
+        reject_if_not_owned!(self, ino, reply);
+
         if !self.manager.contains(&FileId::Inode(ino)) {
             error!("setattr: could not find inode={} in the file tree", ino);
             reply.error(ENOENT);
@@ -279,7 +953,7 @@ impl Filesystem for Gcsf {
         };
 
         file.attr = new_attr;
-        reply.attr(&TTL, &file.attr);
+        reply.attr(&TTL, &kernel_attr(self.inode_base, &file.attr));
     }
 
     fn create(
@@ -288,11 +962,39 @@ impl Filesystem for Gcsf {
         parent: Inode,
         name: &OsStr,
         _mode: u32,
-        _flags: u32,
+        flags: u32,
         reply: ReplyCreate,
     ) {
+        let parent = internal_inode(self.inode_base, parent);
+        reject_if_read_only!(self, reply);
+
+        if self.breaches_reserve(0) {
+            error!("create: refusing to create files in the reserved free space");
+            reply.error(ENOSPC);
+            return;
+        }
+
         let filename = name.to_str().unwrap().to_string();
 
+        // Programs which save files by deleting and re-creating them keep the original Drive file
+        // if it is still within its grace period.
+        if let Some(ino) = self.manager.restore_pending_delete(parent, &filename) {
+            let id = FileId::Inode(ino);
+            match self.manager.truncate(&id) {
+                Ok(()) => {
+                    let attr = self.manager.get_file(&id).unwrap().attr;
+                    self.manager.remember(ino);
+                    let fh = self.manager.open(ino, flags);
+                    reply.created(&TTL, &kernel_attr(self.inode_base, &attr), 0, fh, 0);
+                }
+                Err(e) => {
+                    error!("create: {}", e);
+                    reply.error(EREMOTE);
+                }
+            }
+            return;
+        }
+
         // TODO: these two checks might not be necessary
         if !self.manager.contains(&FileId::Inode(parent)) {
             error!(
@@ -336,10 +1038,8 @@ impl Filesystem for Gcsf {
             drive_file: Some(drive3::File {
                 name: Some(filename),
                 mime_type: None,
-                parents: Some(vec![self
-                    .manager
-                    .get_drive_id(&FileId::Inode(parent))
-                    .unwrap()]),
+                // Set by `FileManager::create_file`.
+                parents: None,
                 ..Default::default()
             }),
         };
@@ -347,7 +1047,9 @@ impl Filesystem for Gcsf {
         let attr = file.attr;
         match self.manager.create_file(file, Some(FileId::Inode(parent))) {
             Ok(()) => {
-                reply.created(&TTL, &attr, 0, 0, 0);
+                self.manager.remember(attr.ino);
+                let fh = self.manager.open(attr.ino, flags);
+                reply.created(&TTL, &kernel_attr(self.inode_base, &attr), 0, fh, 0);
             }
             Err(e) => {
                 error!("create: {}", e);
@@ -357,46 +1059,127 @@ impl Filesystem for Gcsf {
     }
 
     fn unlink(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
-        let id = FileId::ParentAndName {
-            parent,
-            name: name.to_str().unwrap().to_string(),
-        };
+        let parent = internal_inode(self.inode_base, parent);
+        match self.remove_entry(parent, name.to_str().unwrap()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
 
-        if !self.manager.contains(&id) {
-            reply.error(ENOENT);
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: Inode,
+        newparent: Inode,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let ino = internal_inode(self.inode_base, ino);
+        let newparent = internal_inode(self.inode_base, newparent);
+        reject_if_read_only!(self, reply);
+
+        let name = newname.to_str().unwrap().to_string();
+        if let Err(e) = self.manager.link(ino, newparent, name) {
+            error!("link: {}", e);
+            reply.error(EREMOTE);
             return;
         }
 
-        match self.manager.file_is_trashed(&id) {
-            Ok(trashed) => {
-                let res = if trashed {
-                    debug!("{:?} is already trashed. Deleting permanently.", id);
-                    self.manager.delete(&id)
-                } else if self.manager.skip_trash {
-                    debug!(
-                        "{:?} was not trashed. Deleting it permanently instead of moving to Trash \
-                    because skip_trash is enabled in the configuration.",
-                        id
-                    );
-                    self.manager.delete(&id)
-                } else {
-                    debug!(
-                        "{:?} was not trashed. Moving it to Trash instead of deleting permanently.",
-                        id
-                    );
-                    self.manager.move_file_to_trash(&id, true)
-                };
+        match self.manager.get_file(&FileId::Inode(ino)) {
+            Some(file) => {
+                let attr = file.attr;
+                self.manager.remember(attr.ino);
+                reply.entry(&TTL, &kernel_attr(self.inode_base, &attr), 0);
+            }
+            None => reply.error(ENOENT),
+        }
+    }
 
-                log_result_and_fill_reply!(res, reply);
+    fn destroy(&mut self, _req: &Request) {
+        info!("Unmounting. Removing deleted files and uploading pending writes...");
+        self.manager.apply_pending_deletes(true);
+        let unsaved = self.manager.flush_all(self.shutdown_flush_timeout);
+        if unsaved.is_empty() {
+            info!("All files are saved.");
+        } else {
+            error!("Could not save the following files: {:?}", unsaved);
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: Inode, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let ino = internal_inode(self.inode_base, ino);
+        let attrs = match self.xattrs(ino) {
+            Ok(attrs) => attrs,
+            Err(e) => {
+                error!("getxattr: {}", e);
+                reply.error(EREMOTE);
+                return;
             }
+        };
+
+        let name = name.to_string_lossy();
+        match attrs.into_iter().find(|&(ref attr, _)| *attr == name) {
+            Some((_, value)) => reply_xattr(reply, value.as_bytes(), size),
+            None => reply.error(ENODATA),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: Inode, size: u32, reply: ReplyXattr) {
+        let ino = internal_inode(self.inode_base, ino);
+        let attrs = match self.xattrs(ino) {
+            Ok(attrs) => attrs,
             Err(e) => {
-                error!("{:?}", e);
+                error!("listxattr: {}", e);
                 reply.error(EREMOTE);
+                return;
             }
+        };
+
+        let mut names = Vec::new();
+        for (name, _) in attrs {
+            names.extend(name.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(reply, &names, size);
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: Inode,
+        name: &OsStr,
+        value: &[u8],
+        _flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let ino = internal_inode(self.inode_base, ino);
+        reject_if_read_only!(self, reply);
+
+        match self.set_description_xattr(ino, name, value) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
+    fn removexattr(&mut self, _req: &Request, ino: Inode, name: &OsStr, reply: ReplyEmpty) {
+        let ino = internal_inode(self.inode_base, ino);
+        reject_if_read_only!(self, reply);
+
+        if name == DESCRIPTION_XATTR && self.manager.description(&FileId::Inode(ino)).is_none() {
+            reply.error(ENODATA);
+            return;
+        }
+        match self.set_description_xattr(ino, name, b"") {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        let ino = internal_inode(self.inode_base, ino);
+        self.manager.forget(ino, nlookup);
+    }
 
     fn mkdir(
         &mut self,
@@ -406,6 +1189,9 @@ impl Filesystem for Gcsf {
         _mode: u32,
         reply: ReplyEntry,
     ) {
+        let parent = internal_inode(self.inode_base, parent);
+        reject_if_read_only!(self, reply);
+
         let dirname = name.to_str().unwrap().to_string();
 
         // TODO: these two checks might not be necessary
@@ -429,40 +1215,10 @@ impl Filesystem for Gcsf {
             return;
         }
 
-        let dir = File {
-            name: dirname.clone(),
-            attr: FileAttr {
-                ino: self.manager.next_available_inode(),
-                kind: FileType::Directory,
-                size: 512,
-                blocks: 1,
-                atime: Timespec::new(1, 0),
-                mtime: Timespec::new(1, 0),
-                ctime: Timespec::new(1, 0),
-                crtime: Timespec::new(1, 0),
-                perm: 0o644,
-                nlink: 0,
-                uid: 0,
-                gid: 0,
-                rdev: 0,
-                flags: 0,
-            },
-            identical_name_id: None,
-            drive_file: Some(drive3::File {
-                name: Some(dirname),
-                mime_type: Some("application/vnd.google-apps.folder".to_string()),
-                parents: Some(vec![self
-                    .manager
-                    .get_drive_id(&FileId::Inode(parent))
-                    .unwrap()]),
-                ..Default::default()
-            }),
-        };
-
-        let attr = dir.attr;
-        match self.manager.create_file(dir, Some(FileId::Inode(parent))) {
-            Ok(()) => {
-                reply.entry(&TTL, &attr, 0);
+        match self.manager.create_directory(&dirname, parent) {
+            Ok(attr) => {
+                self.manager.remember(attr.ino);
+                reply.entry(&TTL, &kernel_attr(self.inode_base, &attr), 0);
             }
             Err(e) => {
                 error!("mkdir: {}", e);
@@ -472,10 +1228,26 @@ impl Filesystem for Gcsf {
     }
 
     fn rmdir(&mut self, _req: &Request, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
-        self.unlink(_req, parent, name, reply);
+        let parent = internal_inode(self.inode_base, parent);
+        match self.remove_entry(parent, name.to_str().unwrap()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn flush(&mut self, _req: &Request, ino: Inode, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let ino = internal_inode(self.inode_base, ino);
+        match self.manager.flush_on_close(&FileId::Inode(ino)) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("{:?}", e);
+                reply.error(EREMOTE);
+            }
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: Inode, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let ino = internal_inode(self.inode_base, ino);
         match self.manager.flush(&FileId::Inode(ino)) {
             Ok(()) => reply.ok(),
             Err(e) => {
@@ -486,33 +1258,20 @@ impl Filesystem for Gcsf {
     }
 
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        let (size, capacity) = if !self.statfs_cache.contains_key("size")
-            || !self.statfs_cache.contains_key("capacity")
-        {
-            let (size, capacity) = self.manager.df.size_and_capacity().unwrap_or((0, Some(0)));
-            let capacity = capacity.unwrap_or(std::i64::MAX as u64);
-            self.statfs_cache.insert("size".to_string(), size);
-            self.statfs_cache.insert("capacity".to_string(), capacity);
-
-            (size, capacity)
-        } else {
-            // unwrap_or(&0) because the values might have been dropped from the cache since
-            // checking for their existence.
-            let size = self.statfs_cache.get("size").unwrap_or(&0).to_owned();
-            let capacity = self.statfs_cache.get("capacity").unwrap_or(&0).to_owned();
-            (size, capacity)
-        };
+        let (size, capacity) = self.quota();
+        let capacity = reported_capacity(size, capacity, self.reported_free_bytes);
 
         let bsize = 512;
         let blocks: u64 = capacity / bsize + if capacity % bsize > 0 { 1 } else { 0 };
-        let bfree: u64 = (capacity - size) / bsize;
+        let bfree: u64 = capacity.saturating_sub(size) / bsize;
+        let (files, ffree) = inode_counts(self.manager.files.len() as u64, self.max_inodes);
 
         reply.statfs(
             /* blocks:*/ blocks,
             /* bfree: */ bfree,
             /* bavail: */ bfree,
-            /* files: */ std::u64::MAX,
-            /* ffree: */ std::u64::MAX - self.manager.files.len() as u64,
+            /* files: */ files,
+            /* ffree: */ ffree,
             /* bsize: */ bsize as u32,
             /* namelen: */ 1024,
             /* frsize: */ bsize as u32,
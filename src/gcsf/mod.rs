@@ -1,8 +1,20 @@
-pub use self::config::Config;
-pub use self::drive_facade::DriveFacade;
+pub use self::clock::{Clock, SystemClock};
+pub use self::config::{has_mount_option, CacheCompression, Config, ExportFormats, FolderMapping};
+pub use self::drive_facade::{
+    anyone_permission_ids, fetch_ranges, flush_until, is_permission_error, is_rate_limit_error,
+    is_read_only_token, md5_checksum, missing_ranges, parents_to_remove, parse_labels,
+    prefetch_concurrently, read_content, record_account, relocate_cache,
+    remove_stale_account_records, resume_download, same_account, sessions_sharing_account,
+    sharing_permission, split_ranges, token_expires_within, with_login_timeout, DriveFacade,
+    FileLister, KeepaliveConnector, LimitedConnector, LimitedStream, ProgressReader, RequestLimiter,
+    RequestPermit, Transfer, TransferGuard, TransferRegistry,
+};
 pub use self::file::{File, FileId};
-pub use self::file_manager::FileManager;
+pub use self::file_manager::{
+    heartbeat_summary, is_editor_temp_name, FileManager, HeartbeatStats, ManifestFormat,
+};
 
+mod clock;
 mod config;
 mod drive_facade;
 mod file;
@@ -9,8 +9,11 @@
     unused_qualifications
 )]
 extern crate chrono;
+extern crate config as settings;
 extern crate failure;
+extern crate flate2;
 extern crate fuse;
+extern crate glob;
 extern crate google_drive3_fork as drive3;
 extern crate hyper;
 extern crate hyper_native_tls;
@@ -22,21 +25,31 @@ extern crate log;
 #[macro_use]
 extern crate maplit;
 extern crate lru_time_cache;
+extern crate md5;
 extern crate pretty_env_logger;
 extern crate rand;
 extern crate serde;
 extern crate serde_json;
+extern crate socket2;
 #[macro_use]
 extern crate serde_derive;
 extern crate time;
 extern crate yup_oauth2 as oauth2;
+extern crate zstd;
 #[macro_use]
 extern crate lazy_static;
 
 mod gcsf;
 
-pub use gcsf::filesystem::{Gcsf, NullFs};
-pub use gcsf::{Config, DriveFacade, FileManager};
+pub use gcsf::filesystem::{
+    absolute_mountpoint, is_gcsf_mount, mount_exit_code, mount_until_unmounted,
+    report_mount_status, retry, Gcsf, NullFs,
+};
+pub use gcsf::{
+    has_mount_option, prefetch_concurrently, record_account, remove_stale_account_records,
+    sessions_sharing_account, Clock, Config, DriveFacade, ExportFormats, FileId, FileManager,
+    FolderMapping, ManifestFormat,
+};
 
 #[cfg(test)]
 mod tests;
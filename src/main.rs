@@ -1,12 +1,12 @@
 #[macro_use]
 extern crate clap;
-extern crate config;
 extern crate failure;
 extern crate fuse;
 extern crate gcsf;
 #[macro_use]
 extern crate log;
 extern crate itertools;
+extern crate libc;
 extern crate pretty_env_logger;
 extern crate serde;
 extern crate serde_json;
@@ -15,19 +15,32 @@ extern crate xdg;
 use clap::App;
 use failure::{err_msg, Error};
 use itertools::Itertools;
+use std::env;
 use std::ffi::OsStr;
 use std::fs;
+use std::io;
 use std::io::prelude::*;
 use std::iter;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::process::Command;
+use std::time::Duration;
 
-use gcsf::{Config, DriveFacade, Gcsf, NullFs};
+use gcsf::{
+    absolute_mountpoint, has_mount_option, is_gcsf_mount, mount_exit_code, mount_until_unmounted,
+    prefetch_concurrently, record_account, remove_stale_account_records, report_mount_status,
+    retry, sessions_sharing_account, Config, DriveFacade, FileId, FileManager, Gcsf,
+    ManifestFormat, NullFs,
+};
 
 const DEBUG_LOG: &str = "hyper::client=error,hyper::http=error,hyper::net=error,debug";
 
 const INFO_LOG: &str =
     "hyper::client=error,hyper::http=error,hyper::net=error,fuse::session=error,info";
 
-const DEFAULT_CONFIG: &str = r#"
+const DEFAULT_CONFIG: &str = r##"
 ### This is the configuration file that GCSF uses.
 ### It should be placed in $XDG_CONFIG_HOME/gcsf/gcsf.toml, which is usually
 ### defined as $HOME/.config/gcsf/gcsf.toml
@@ -35,6 +48,10 @@ const DEFAULT_CONFIG: &str = r#"
 # Show additional logging info?
 debug = false
 
+# The name of the root directory in debug output, e.g. the file tree logged
+# after each sync. Defaults to "/".
+# root_name = "My Drive"
+
 # Perform a mount check and fail early if it fails. Disable this if you
 # encounter this error:
 #
@@ -42,20 +59,157 @@ debug = false
 #     Could not mount to [...]: Undefined error: 0 (os error 0)
 mount_check = true
 
+# How many times to retry a failed mount check, one second apart. If the check
+# still fails afterwards, GCSF exits unless `mount_check_fatal` is false.
+mount_check_retries = 2
+mount_check_fatal = true
+
+# Two sessions of the same Google account compete for its token and see each
+# other's changes twice. If set to true, GCSF refuses to mount a session whose
+# account is already mounted by another session, unless
+# `--allow-duplicate-account` is passed. Otherwise it only warns.
+# refuse_duplicate_accounts = false
+
+# If set to true, GCSF mounts the file system again whenever its FUSE session
+# ends, e.g. after an external `fusermount -u`. By default, GCSF exits instead.
+auto_remount = false
+
 # How long to cache the contents of a file after it has been accessed.
 cache_max_seconds = 300
 
 # How how many files to cache.
 cache_max_items = 10
 
+# Files larger than this many bytes are never downloaded in full. Reads fetch
+# only the requested range from Drive, so huge media files can be played
+# without filling up the cache. Does not apply to exported files (docs,
+# spreadsheets, ...). Files of any size are cached if absent.
+# cache_max_file_bytes = 1073741824
+
+# Reads from files larger than `cache_max_file_bytes` fetch this many bytes
+# around the requested offset and keep them in memory, so that further reads
+# within them, including backward seeks, need no new request. One chunk is
+# kept per file, for at most `cache_max_items` files, which may take up to
+# `cache_max_items` times this many bytes of memory. Defaults to 8 MiB.
+# download_chunk_size = 8388608
+
+# Where to store the contents of downloaded files between runs. Each session
+# uses a subdirectory named after it. The disk cache is disabled if absent.
+# cache_dir = "/home/user/.cache/gcsf"
+
+# How the contents in `cache_dir` are compressed: "none", "gzip" or "zstd".
+# Compression saves disk space at the cost of CPU time. Content which is
+# compressed already, such as images, videos and zip archives, is stored as it
+# is. Defaults to "none".
+# cache_compression = "zstd"
+
+# If set to true, reads of files which can not be fetched from Drive after a
+# sync failed, e.g. during flaky connectivity, return the last known content of
+# the file instead of failing. Such files have the extended attribute
+# `user.gcsf.possibly_stale` until they are read from Drive again.
+# serve_stale_on_sync_error = true
+
+# How many ranges of a single large file (16 MiB or more) to download
+# concurrently. At most 8. Defaults to 1, i.e. sequential downloads.
+# download_parallelism = 4
+
+# The Drive id of "My Drive". If set, GCSF does not need to look it up when
+# mounting, which makes startup a bit faster. The id is checked during the first
+# sync. It can be found with `gcsf check`.
+# root_drive_id = "0AB1cD2eF3gH4Uk9PVA"
+
+# The Drive id of a Shared Drive to mount instead of "My Drive". The file tree
+# and Trash then come from that Shared Drive, which has a Trash of its own.
+# shared_drive_id = "0AFx1yZ2aB3cDUk9PVA"
+
+# If set to true, the content of files being edited is kept in scratch files
+# next to the disk cache instead of in memory until it is uploaded. Useful for
+# editing large files. Requires `cache_dir`.
+# scratch_on_disk = true
+
+# If set to true, the Drive labels of each file are exposed as read-only
+# extended attributes named `user.drive.label.<labelId>.<fieldId>`, e.g. for
+# `getfattr -d`. Labels are only available for Google Workspace accounts.
+# fetch_labels = true
+
+# If set to true, the Drive description of each file is exposed as the
+# extended attribute `user.drive.description`. Setting it, e.g. with
+# `setfattr -n user.drive.description -v "Q3 drafts" Reports`, updates the
+# description on Drive.
+# expose_descriptions = true
+
+# If set to true, the Drive parents of each file are exposed as the read-only
+# extended attributes `user.drive.parents` (comma-separated Drive ids) and
+# `user.drive.parentCount`. Useful for finding out why a file with several
+# parents appears where it does.
+# expose_parents = true
+
+# If set to false, directory listings leave out the `.` and `..` entries.
+dot_entries = true
+
+# An offset added to the inode numbers of all files, including the root and
+# special directories like Trash. Setting a different base for each mount keeps
+# their inode numbers disjoint, e.g. when mounts are nested. Defaults to 0.
+# inode_base = 1000000
+
+# How many seconds to spend uploading pending writes when the file system is
+# unmounted. Files which could not be saved in time are logged.
+shutdown_flush_timeout = 30
+
 # How long to cache the size and capacity of the file system. These are the
 # values reported by `df`.
 cache_statfs_seconds = 60
 
+# How much free space (in bytes) to report when the real quota is unknown or
+# unlimited. Defaults to 1 TiB.
+# reported_free_bytes = 1099511627776
+
+# How many inodes `df -i` reports in total. The number of free inodes is this
+# minus the number of files. By default, about a million free inodes are
+# reported on top of the current number of files, since Drive has no limit.
+# max_inodes = 10000000
+
+# How much of the Drive quota (in bytes) to keep free. Writes which would leave
+# less than this are refused as if the disk was full. A full Drive quota also
+# stops Gmail and Google Photos from working. Disabled if absent.
+# reserve_bytes = 1073741824
+
 # How many seconds to wait before checking for remote changes and updating them
 # locally.
 sync_interval = 10
 
+# How many seconds a sync may wait for the list of remote changes. If Drive
+# takes longer, e.g. because a connection hangs, the sync is given up on instead
+# of freezing the file system, and retried after the next sync_interval. No
+# timeout is enforced if absent.
+# sync_timeout_seconds = 60
+
+# If set, a line confirming that the mount is alive is logged at this interval
+# (in seconds), with the time since the last sync, the number of cached files
+# and the number of files with pending writes. Disabled if absent or 0.
+# heartbeat_interval_seconds = 300
+
+# How many seconds before its expiry the access token should be refreshed.
+# Tokens are checked on every sync, so this should be larger than
+# sync_interval.
+token_refresh_margin = 300
+
+# How many seconds a connection to Drive may be idle before TCP keepalive
+# probes are sent. This detects connections which were dropped while the mount
+# was idle, which otherwise make the next operation hang. Disabled if absent.
+# tcp_keepalive_seconds = 60
+
+# How many requests to Drive may be in flight at the same time, across all
+# sessions mounted by this process. Requests beyond the limit wait for others
+# to finish, which avoids hitting Drive's rate limits. Unlimited if absent.
+# global_api_concurrency = 8
+
+# How many listings to request from Drive at the same time while mounting. With
+# more than one, Trash is listed alongside My Drive instead of after it, which
+# shortens mounting when Trash holds many files. 1 lists them one after another.
+# Each listing is requested page by page, so values above 2 behave like 2.
+# api_concurrency = 2
+
 # Mount options
 mount_options = [
     "fsname=GCSF",
@@ -66,6 +220,17 @@ mount_options = [
     "max_write=131072"
 ]
 
+# The `allow_other` and `allow_root` mount options are mutually exclusive. If
+# both are present, keep `allow_other` (true) or `allow_root` (false).
+# prefer_allow_other = false
+
+# The largest read and write requests which the kernel may send, in bytes.
+# Larger requests mean fewer round trips for sequential access. These replace
+# the `max_read` and `max_write` mount options; `max_write_bytes` also enables
+# `big_writes`. The kernel may still use smaller requests.
+# max_read_bytes = 131072
+# max_write_bytes = 131072
+
 # If set to true, Google Drive will provide a code after logging in and
 # authorizing GCSF. This code must be copied and pasted into GCSF in order to
 # complete the process. Useful for running GCSF on a remote server.
@@ -74,23 +239,262 @@ mount_options = [
 # This is usually faster and more convenient.
 authorize_using_code = false
 
+# How many seconds `gcsf login` waits for the authorization to be completed in
+# the browser (or for the code to be entered). Login fails with "authentication
+# timed out" afterwards. 0 waits indefinitely.
+login_timeout_seconds = 300
+
 # If set to true, all files with identical name will get an increasing number attached to the suffix.
 rename_identical_files = false
 
+# How duplicate names are rendered when `rename_identical_files` is enabled.
+# {name} is the full name, {stem} the name without its extension, {ext} the
+# extension (including the dot) and {n} the number of the duplicate. The
+# default, "{name}.{n}", renders "report.pdf.1".
+# duplicate_name_template = "{stem} ({n}){ext}"
+
+# The order in which Drive lists files when mounting, e.g. "createdTime" or
+# "name,modifiedTime desc". Files with identical names are numbered in this
+# order, so setting it keeps their numbers stable across mounts.
+# list_order = "createdTime"
+
 # If set to true, will add an extension to special files (docs, presentations, sheets, drawings, sites), e.g. "\#.ods" for spreadsheets.
 add_extensions_to_special_files = false
 
+# What separates the name of a special file from the extension added by
+# `add_extensions_to_special_files`, e.g. "" for "report.ods" instead of
+# "report#.ods". Defaults to "#".
+# special_extension_separator = ""
+
+# If set to true, control characters (e.g. newlines) and invisible formatting
+# characters (e.g. zero-width spaces) in the names of Drive files are shown as
+# "_", so that names of untrusted shared files can not confuse terminals and
+# scripts. The files keep their original names on Drive.
+# sanitize_control_chars = true
+
+# The formats in which special files are exported, given either as an extension or as a MIME
+# type. The defaults are "odt", "ods", "odp", "png" and "txt" respectively. Sites which can not be
+# exported show a link to the site instead. Spreadsheets exported as "ods" or
+# "xlsx" keep all of their sheets, while "csv" only contains the first one.
+# export_formats = { document = "docx", spreadsheet = "xlsx", presentation = "pptx", drawing = "svg", site = "txt" }
+
+# Special files can also be shown as a directory which holds one read-only
+# export per format, e.g. "Report/Report.pdf" and "Report/Report.docx". Each
+# value is a comma separated list of formats, given the same way as in
+# `export_formats`. File types which are not listed are shown as a single file.
+# multi_export = { document = "pdf,docx", spreadsheet = "xlsx,csv" }
+
 # If set to true, deleted files will remove them permanently instead of moving them to Trash.
 # Deleting trashed files always removes them permanently.
 skip_trash = false
 
+# How many seconds deleted files and folders are held in `.gcsf/pending-deletes`
+# before they are moved to Trash (or deleted) on Drive. Files which are created
+# again in the meantime keep their Drive file, and moving a file out of
+# `.gcsf/pending-deletes` restores it. Defaults to 0, i.e. no grace period.
+# delete_grace_seconds = 30
+
+# If set, files which have been in Trash for this many days are deleted
+# permanently. Their age counts from when GCSF saw them being trashed, or from
+# the mount for files which were already in Trash. Disabled by default.
+# trash_auto_empty_days = 30
+
+# Files can be shared with permissions which expire. Reading such a file after
+# its permission expired fails with "Permission denied". If set to true, files
+# under "Shared with me" which can no longer be read are removed on the next
+# sync instead.
+# hide_expired_shares = true
+
+# If set to true, the mount is an exact mirror of "My Drive". There are no
+# special directories ("Trash", "Shared with me", ".search" and ".gcsf"), and
+# trashed files or files outside of "My Drive" are left out. Deleted files are
+# still moved to Trash on Drive unless `skip_trash` is set.
+# mirror_mode = false
+
+# Editors often save files by writing a temp file (e.g. "file.txt.swp",
+# "file.txt~" or ".#file.txt") and renaming it over the original. If set to
+# true, such temp files are not created on Drive. Only the renamed result is
+# uploaded. Temp files which are never renamed are lost when unmounting.
+# defer_temp_uploads = true
+
+# When buffered writes are uploaded: "flush" uploads them every time a file
+# handle is closed, "release" only once the last open handle of the file is
+# released. Editors which open and close a file many times while saving it make
+# fewer uploads with "release". `fsync` always uploads.
+upload_on = "flush"
+
+# If set to true, GCSF will only request read-only access to Drive and the
+# file system will be read-only. Sessions which were logged in with this option
+# are always mounted as read-only.
+read_only = false
+
+# If set to true, files which are owned by someone else are read-only, even if
+# the account is allowed to edit them. This prevents accidental edits to files
+# of collaborators. Folders are not affected.
+# readonly_not_owned = true
+
+# Drive folders which should appear at fixed paths in the file system,
+# regardless of where they are on Drive. Folders can be identified either by
+# their Drive id or by their path.
+# folder_mappings = [
+#     { drive_id_or_path = "Projects/Foo", mount_path = "/work" },
+# ]
+
+# Drive ids of files or folders which are kept out of the file system, together
+# with everything inside the excluded folders. Useful for leaving out a known
+# huge or sensitive folder.
+# exclude_ids = ["1a2B3c4D5e6F7g8H9i0J"]
+
+# Glob patterns of files which are kept out of the file system, together with
+# everything inside matching folders. Patterns are matched against the Drive
+# names of files. Patterns starting with "/" are matched against the path from
+# the root instead, e.g. "/Archive/*.zip".
+# exclude_patterns = ["*.tmp", "~$*", "/Colab Notebooks"]
+
+# How many levels below the root folders are listed while mounting. The
+# children of deeper folders are listed when the folder is first accessed,
+# which keeps pathologically deep hierarchies cheap. At least 1. Unlimited if
+# absent.
+# max_tree_depth = 8
+
+# The base URL of the Google APIs. Only useful for testing against a local mock
+# server, together with a client secret whose `auth_uri` and `token_uri` point
+# to that server.
+# api_base_url = "https://www.googleapis.com"
+
+# The user agent sent with every Drive API request. It identifies GCSF traffic
+# in the Cloud Console. Defaults to "gcsf/<version>".
+# user_agent = "gcsf"
+
 # The Google OAuth client secret for Google Drive APIs. Create your own
 # credentials at https://console.developers.google.com and paste them here
 client_secret = """{"installed":{"client_id":"726003905312-e2mq9mesjc5llclmvc04ef1k7qopv9tu.apps.googleusercontent.com","project_id":"weighty-triode-199418","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://accounts.google.com/o/oauth2/token","auth_provider_x509_cert_url":"https://www.googleapis.com/oauth2/v1/certs","client_secret":"hp83n1Rzz8UpxgCnqvX15qC2","redirect_uris":["urn:ietf:wg:oauth:2.0:oob","http://localhost"]}}"""
-"#;
+"##;
+
+/// Whether `user_allow_other` is set in /etc/fuse.conf. Non-root users need it in order to mount
+/// with `allow_other` or `allow_root`.
+fn fuse_conf_allows_other() -> bool {
+    fs::read_to_string("/etc/fuse.conf")
+        .map(|conf| conf.lines().any(|line| line.trim() == "user_allow_other"))
+        .unwrap_or(false)
+}
+
+/// Warns if another mounted session uses the same account as this one. Returns the account id,
+/// if it could be determined, or an error if mounting should be refused because of it.
+fn check_duplicate_account(
+    config: &Config,
+    allow_duplicate_account: bool,
+) -> Result<Option<String>, Error> {
+    let account_id = match DriveFacade::new(config).account_id() {
+        Ok(account_id) => account_id,
+        Err(e) => {
+            warn!(
+                "Could not determine the Drive account of this session: {}",
+                e
+            );
+            return Ok(None);
+        }
+    };
+
+    let others = sessions_sharing_account(config, &account_id);
+    if !others.is_empty() {
+        warn!(
+            "Session(s) {} already mount the same Drive account. Their tokens and changes may \
+             interfere with this session.",
+            others.join(", ")
+        );
+        if config.refuse_duplicate_accounts() && !allow_duplicate_account {
+            return Err(err_msg(
+                "Refusing to mount. Pass --allow-duplicate-account to mount anyway.",
+            ));
+        }
+    }
+    Ok(Some(account_id))
+}
+
+/// Where the PID of a daemonized mount is written: `$XDG_RUNTIME_DIR/gcsf/<session>.pid`, or the
+/// config dir if there is no runtime dir.
+fn pid_file(config: &Config) -> PathBuf {
+    xdg::BaseDirectories::with_prefix("gcsf")
+        .ok()
+        .and_then(|dirs| {
+            dirs.place_runtime_file(format!("{}.pid", config.session_name()))
+                .ok()
+        })
+        .unwrap_or_else(|| config.pid_file())
+}
+
+/// Forks into the background, so that `gcsf mount` returns to the shell. The parent writes the
+/// PID of the child to `pid_file`, waits for the child to report whether it mounted the file system
+/// and exits accordingly. The child continues in a new session, detached from the terminal, with
+/// its output and logs going to `log_file`. It receives the stream to report its status through.
+fn daemonize(pid_file: &Path, log_file: &Path) -> Result<UnixStream, Error> {
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .map_err(|e| err_msg(format!("Could not open {:?}: {}", log_file, e)))?;
+    let null = fs::File::open("/dev/null")?;
+    let (status_reader, status_writer) = UnixStream::pair()?;
+
+    match unsafe { libc::fork() } {
+        -1 => {
+            return Err(err_msg(format!(
+                "Could not fork: {}",
+                io::Error::last_os_error()
+            )))
+        }
+        0 => {}
+        child => {
+            if let Err(e) = fs::write(pid_file, format!("{}\n", child)) {
+                warn!("Could not write {:?}: {}", pid_file, e);
+            }
+            // Only the child may hold the writing end, so that reading stops if it exits.
+            drop(status_writer);
+            let code = mount_exit_code(status_reader);
+            if code == 0 {
+                println!(
+                    "GCSF is running in the background as process {}. Logs go to {:?}.",
+                    child, log_file
+                );
+            } else {
+                eprintln!("GCSF could not be mounted. See {:?} for details.", log_file);
+            }
+            process::exit(code);
+        }
+    }
+    drop(status_reader);
+
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(err_msg(format!(
+                "Could not detach from the terminal: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    Ok(status_writer)
+}
+
+fn mount_gcsf(config: Config, mountpoint: &str, allow_duplicate_account: bool, daemon: bool) {
+    let account_id = match check_duplicate_account(&config, allow_duplicate_account) {
+        Ok(account_id) => account_id,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
-fn mount_gcsf(config: Config, mountpoint: &str) {
     let vals = config.mount_options();
+    if (has_mount_option(&vals, "allow_other") || has_mount_option(&vals, "allow_root"))
+        && !fuse_conf_allows_other()
+    {
+        warn!("`allow_other` and `allow_root` require `user_allow_other` to be set in /etc/fuse.conf unless GCSF runs as root.");
+    }
+
     let mut options = iter::repeat("-o")
         .interleave_shortest(vals.iter().map(String::as_ref))
         .map(OsStr::new)
@@ -98,37 +502,125 @@ fn mount_gcsf(config: Config, mountpoint: &str) {
     options.pop();
 
     if config.mount_check() {
-        unsafe {
-            match fuse::spawn_mount(NullFs {}, &mountpoint, &options) {
-                Ok(session) => {
-                    debug!("Test mount of NullFs successful. Will mount GCSF next.");
-                    drop(session);
-                }
-                Err(e) => {
-                    error!("Could not mount to {}: {}", &mountpoint, e);
+        let check = retry(
+            config.mount_check_retries(),
+            Duration::from_secs(1),
+            || unsafe {
+                fuse::spawn_mount(NullFs {}, &mountpoint, &options)
+                    .map(drop)
+                    .map_err(|e| err_msg(format!("Could not mount to {}: {}", &mountpoint, e)))
+            },
+        );
+
+        match check {
+            Ok(()) => debug!("Test mount of NullFs successful. Will mount GCSF next."),
+            Err(e) => {
+                error!("{}", e);
+                if config.mount_check_fatal() {
                     return;
                 }
-            };
+                warn!("Mounting anyway because mount_check_fatal is disabled.");
+            }
         }
     }
 
-    info!("Creating and populating file system...");
-    let fs: Gcsf = match Gcsf::with_config(config) {
-        Ok(fs) => fs,
-        Err(e) => {
-            error!("{}", e);
-            return;
+    // Forking only after the checks lets them report failures to the shell.
+    let (pid_file, mut mount_status) = if daemon {
+        let pid_file = pid_file(&config);
+        match daemonize(&pid_file, &config.log_file()) {
+            Ok(status) => (Some(pid_file), Some(status)),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
         }
+    } else {
+        (None, None)
     };
-    info!("File system created.");
 
-    info!("Mounting to {}", &mountpoint);
-    match fuse::mount(fs, &mountpoint, &options) {
-        Ok(()) => {
-            info!("Mounted to {}", &mountpoint);
+    // Recorded after forking, so that the record holds the PID of the process which mounts.
+    if let Some(account_id) = account_id {
+        if let Err(e) = record_account(&config, &account_id) {
+            warn!("Could not record the account of this session: {}", e);
+        }
+    }
+
+    let result = mount_until_unmounted(config.auto_remount(), || {
+        info!("Creating and populating file system...");
+        let fs: Gcsf = Gcsf::with_config(config.clone())?;
+        info!("File system created.");
+
+        info!("Mounting to {}", &mountpoint);
+        let mut session = fuse::Session::new(fs, Path::new(mountpoint), &options)
+            .map_err(|e| err_msg(format!("Could not mount to {}: {}", &mountpoint, e)))?;
+        // The shell which started a daemonized mount only returns once it is mounted.
+        if let Some(status) = mount_status.take() {
+            if let Err(e) = report_mount_status(status, true) {
+                warn!("Could not report the mount status: {}", e);
+            }
+        }
+        session
+            .run()
+            .map_err(|e| err_msg(format!("Could not mount to {}: {}", &mountpoint, e)))?;
+        info!("Unmounted from {}", &mountpoint);
+        Ok(())
+    });
+
+    if let Err(e) = result {
+        error!("{}", e);
+    }
+    if let Some(status) = mount_status.take() {
+        let _ = report_mount_status(status, false);
+    }
+    let _ = fs::remove_file(config.account_file());
+    if let Some(pid_file) = pid_file {
+        let _ = fs::remove_file(pid_file);
+    }
+}
+
+/// Unmounts the GCSF mount at `mountpoint` and removes the account records left behind by mounts
+/// whose process is gone.
+fn unmount(config: &Config, mountpoint: &str) -> Result<(), Error> {
+    // The mountpoint of a mount whose process died can not be accessed anymore.
+    let cwd = env::current_dir()
+        .map_err(|e| err_msg(format!("Could not resolve {}: {}", mountpoint, e)))?;
+    let path = absolute_mountpoint(&cwd, Path::new(mountpoint));
+
+    let mounts = match fs::read_to_string("/proc/mounts") {
+        Ok(mounts) => mounts,
+        Err(_) => {
+            let output = Command::new("mount").output()?;
+            String::from_utf8_lossy(&output.stdout).into_owned()
         }
-        Err(e) => error!("Could not mount to {}: {}", &mountpoint, e),
     };
+    if !is_gcsf_mount(&mounts, &path) {
+        return Err(err_msg(format!("{} is not a GCSF mount", path.display())));
+    }
+
+    let (program, args): (&str, &[&str]) = if cfg!(target_os = "linux") {
+        ("fusermount", &["-u"])
+    } else {
+        ("umount", &[])
+    };
+    let status = Command::new(program)
+        .args(args)
+        .arg(&path)
+        .status()
+        .map_err(|e| err_msg(format!("Could not run {}: {}", program, e)))?;
+    if !status.success() {
+        return Err(err_msg(format!(
+            "Could not unmount {}: {} exited with {}",
+            path.display(),
+            program,
+            status
+        )));
+    }
+    info!("Unmounted {}", path.display());
+
+    for session in remove_stale_account_records(config) {
+        debug!("Removed the stale account record of session {}", session);
+    }
+    Ok(())
 }
 
 fn login(config: &mut Config) -> Result<(), Error> {
@@ -141,11 +633,217 @@ fn login(config: &mut Config) -> Result<(), Error> {
         )));
     }
 
-    // Create a DriveFacade which will store the authentication token in the desired file.
-    // And make an arbitrary request in order to trigger the authentication process.
-    let mut df = DriveFacade::new(&config);
-    let _result = df.root_id();
+    DriveFacade::login(config)
+}
+
+/// Prints the outcome of a single `check` step. Returns true if the step passed.
+fn report_check(name: &str, result: Result<String, Error>) -> bool {
+    match result {
+        Ok(details) => {
+            println!("PASS  {}: {}", name, details);
+            true
+        }
+        Err(e) => {
+            println!("FAIL  {}: {}", name, e);
+            false
+        }
+    }
+}
+
+/// Runs a sequence of diagnostics for a session: token presence, root id resolution, listing
+/// files, creating files and fetching the storage quota. Returns false if any critical check
+/// fails.
+fn check(config: &Config) -> bool {
+    let token_file = config.token_file();
+    let token_result = if token_file.exists() {
+        Ok(format!("{:?}", &token_file))
+    } else {
+        Err(err_msg(format!("{:?} does not exist", &token_file)))
+    };
+    if !report_check("token", token_result) {
+        return false;
+    }
+
+    let secret_result = config
+        .client_secret
+        .as_ref()
+        .map(|_| String::from("present"))
+        .ok_or_else(|| err_msg("no client_secret in the config file"));
+    if !report_check("client secret", secret_result) {
+        return false;
+    }
+
+    let mut df = DriveFacade::new(config);
+    let mut passed = true;
+
+    match df.root_id().map(|id| id.clone()) {
+        Ok(root_id) => {
+            report_check("root id", Ok(root_id.clone()));
+
+            let list_result = df
+                .get_all_files(Some(vec![root_id.clone()]), Some(false))
+                .map(|files| format!("{} files directly under My Drive", files.len()));
+            passed &= report_check("list files", list_result);
+
+            let write_result = df
+                .probe_write(&root_id)
+                .map(|_| String::from("created and deleted a probe folder"));
+            passed &= report_check("write access", write_result);
+        }
+        Err(e) => {
+            passed &= report_check("root id", Err(e));
+        }
+    }
+
+    // The quota is only informative, so a failure here is not critical.
+    let quota_result = df.size_and_capacity().map(|(usage, limit)| match limit {
+        Some(limit) => format!("{} of {} bytes used", usage, limit),
+        None => format!("{} bytes used, no limit", usage),
+    });
+    report_check("quota", quota_result);
+
+    passed
+}
+
+/// Compares the content of every file in the disk cache with the MD5 checksum reported by Drive.
+/// Files which are not cached are not downloaded. Returns false if any mismatch was found.
+fn verify(config: &Config) -> bool {
+    if config.cache_dir().is_none() {
+        println!("The disk cache is disabled. Set `cache_dir` in the config file to enable it.");
+        return true;
+    }
+
+    let mut df = DriveFacade::new(config);
+    let (mut checked, mut ok, mut mismatches, mut skipped) = (0, 0, 0, 0);
+
+    for id in df.disk_cached_ids() {
+        match df.verify_cached_content(&id) {
+            Ok(Some(true)) => {
+                checked += 1;
+                ok += 1;
+            }
+            Ok(Some(false)) => {
+                checked += 1;
+                mismatches += 1;
+                println!("MISMATCH  {}", &id);
+            }
+            Ok(None) => {
+                skipped += 1;
+            }
+            Err(e) => {
+                skipped += 1;
+                println!("ERROR     {}: {}", &id, e);
+            }
+        }
+    }
+
+    println!(
+        "Checked: {}, OK: {}, mismatches: {}, skipped: {}",
+        checked, ok, mismatches, skipped
+    );
+    mismatches == 0
+}
+
+/// Shares the file at `path` (relative to My Drive) and prints its link, or stops sharing it with
+/// anyone who has the link if `revoke` is set.
+fn share(config: &Config, path: &str, role: &str, anyone: bool, revoke: bool) -> Result<(), Error> {
+    let mut manager = FileManager::with_drive_facade(config, DriveFacade::new(config))?;
+    let drive_id = manager
+        .resolve_path(path)
+        .and_then(|inode| manager.get_drive_id(&FileId::Inode(inode)))
+        .ok_or_else(|| err_msg(format!("Could not find {:?} on Drive", path)))?;
+
+    if revoke {
+        let revoked = manager.df.revoke_anyone_permissions(&drive_id)?;
+        println!(
+            "Removed {} link sharing permission(s) from {}",
+            revoked, path
+        );
+    } else {
+        println!("{}", manager.df.create_permission(&drive_id, role, anyone)?);
+    }
+    Ok(())
+}
+
+/// Downloads the files at or under `path` into the disk cache so that they can be read offline.
+fn prefetch(config: &Config, path: &str, recursive: bool, concurrency: &str) -> Result<(), Error> {
+    if config.cache_dir().is_none() {
+        return Err(err_msg("Prefetching requires `cache_dir` to be set"));
+    }
+    let concurrency = concurrency
+        .parse::<usize>()
+        .map_err(|_| err_msg(format!("Invalid concurrency: {:?}", concurrency)))?;
+
+    let manager = FileManager::with_drive_facade(config, DriveFacade::new(config))?;
+    let inode = manager
+        .resolve_path(path)
+        .ok_or_else(|| err_msg(format!("Could not find {:?}", path)))?;
+    let files = manager.prefetch_targets(inode, recursive);
+    let total = files.len();
+    info!("Prefetching {} files", total);
+
+    let worker_config = config.clone();
+    let failures = prefetch_concurrently(
+        files,
+        concurrency,
+        move || DriveFacade::new(&worker_config),
+        |df, id, mime| {
+            if df.prefetch(id, mime)? {
+                info!("Downloaded {}", id);
+            }
+            Ok(())
+        },
+    );
+
+    for (id, e) in &failures {
+        error!("Could not download {}: {}", id, e);
+    }
+    println!("Cached {} of {} files", total - failures.len(), total);
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(err_msg(format!(
+            "{} files could not be downloaded",
+            failures.len()
+        )))
+    }
+}
+
+/// Writes the content of the file with `drive_id` to stdout, without populating the file tree.
+fn cat_id(config: &Config, drive_id: &str, export_mime: Option<&str>) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    DriveFacade::new(config).write_content(drive_id, export_mime, &mut out)?;
+    Ok(())
+}
+
+/// Prints the file tree of a session, at most `depth` levels deep.
+fn tree(config: &Config, depth: Option<&str>) -> Result<(), Error> {
+    let depth = match depth {
+        Some(depth) => Some(
+            depth
+                .parse::<usize>()
+                .map_err(|_| err_msg(format!("Invalid depth: {:?}", depth)))?,
+        ),
+        None => None,
+    };
+    let manager = FileManager::with_drive_facade(config, DriveFacade::new(config))?;
+    print!("{}", manager.tree(depth));
+    Ok(())
+}
 
+/// Prints a manifest of every file in a session, as JSON or CSV.
+fn manifest(config: &Config, format: &str) -> Result<(), Error> {
+    let format = match format {
+        "json" => ManifestFormat::Json,
+        "csv" => ManifestFormat::Csv,
+        _ => return Err(err_msg(format!("Invalid format: {:?}", format))),
+    };
+    let manager = FileManager::with_drive_facade(config, DriveFacade::new(config))?;
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    manager.write_manifest(&mut out, format)?;
+    out.flush()?;
     Ok(())
 }
 
@@ -163,19 +861,21 @@ fn load_conf() -> Result<Config, Error> {
         config_file.write_all(DEFAULT_CONFIG.as_bytes())?;
     }
 
-    let mut settings = config::Config::default();
-    settings
-        .merge(config::File::with_name(config_file.to_str().unwrap()))
-        .expect("Invalid configuration file");
-
-    let mut config = settings.try_into::<Config>()?;
+    let mut config = Config::from_file(&config_file)?;
     config.config_dir = Some(xdg_dirs.get_config_home());
 
     Ok(config)
 }
 
 fn main() {
-    let mut config = load_conf().expect("Could not load configuration file.");
+    let mut config = match load_conf() {
+        Ok(config) => config,
+        Err(e) => {
+            // The logger is configured by the config, so it is not available yet.
+            eprintln!("Could not load configuration file: {}", e);
+            process::exit(1);
+        }
+    };
 
     pretty_env_logger::formatted_builder()
         .parse_filters(if config.debug() { DEBUG_LOG } else { INFO_LOG })
@@ -184,6 +884,13 @@ fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("unmount") {
+        if let Err(e) = unmount(&config, matches.value_of("mountpoint").unwrap()) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
     if let Some(matches) = matches.subcommand_matches("login") {
         config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
 
@@ -219,7 +926,7 @@ fn main() {
             .unwrap()
             .map(Result::unwrap)
             .map(|f| f.file_name().to_str().unwrap().to_string())
-            .filter(|name| name != &exception)
+            .filter(|name| name != &exception && !name.starts_with('.'))
             .collect();
         sessions.sort();
 
@@ -233,10 +940,97 @@ fn main() {
         }
     }
 
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
+
+        if !verify(&config) {
+            process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("check") {
+        config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
+
+        if !check(&config) {
+            process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("share") {
+        config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
+
+        if let Err(e) = share(
+            &config,
+            matches.value_of("path").unwrap(),
+            matches.value_of("role").unwrap(),
+            matches.is_present("anyone"),
+            matches.is_present("revoke"),
+        ) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("cat-id") {
+        config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
+
+        if let Err(e) = cat_id(
+            &config,
+            matches.value_of("drive_id").unwrap(),
+            matches.value_of("export_mime"),
+        ) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("prefetch") {
+        config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
+
+        if let Err(e) = prefetch(
+            &config,
+            matches.value_of("path").unwrap(),
+            matches.is_present("recursive"),
+            matches.value_of("concurrency").unwrap(),
+        ) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("tree") {
+        config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
+
+        if let Err(e) = tree(&config, matches.value_of("depth")) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("manifest") {
+        config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
+
+        if let Err(e) = manifest(&config, matches.value_of("format").unwrap()) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+
     if let Some(matches) = matches.subcommand_matches("mount") {
         let mountpoint = matches.value_of("mountpoint").unwrap();
         config.session_name = Some(matches.value_of("session_name").unwrap().to_string());
 
+        for option in &["allow_other", "allow_root"] {
+            if matches.is_present(option) {
+                config.add_mount_option(option);
+            }
+        }
+        if matches.is_present("skip_trash") {
+            config.override_skip_trash(Some(true));
+        } else if matches.is_present("no_skip_trash") {
+            config.override_skip_trash(Some(false));
+        }
+
         if !config.token_file().exists() {
             error!("Token file {:?} does not exist.", config.token_file());
             error!("Try logging in first using `gcsf login`.");
@@ -250,6 +1044,11 @@ fn main() {
             return;
         }
 
-        mount_gcsf(config, mountpoint);
+        mount_gcsf(
+            config,
+            mountpoint,
+            matches.is_present("allow_duplicate_account"),
+            matches.is_present("daemon"),
+        );
     }
 }
@@ -1,4 +1,4101 @@
+use drive3;
+use failure::err_msg;
+use fuse::FileType;
+use gcsf::filesystem::{
+    absolute_mountpoint, breaches_reserve, inode_counts, internal_inode, is_gcsf_mount,
+    kernel_inode, mount_exit_code, mount_until_unmounted, parent_xattrs, report_mount_status,
+    reported_capacity, retry,
+};
+use gcsf::{
+    anyone_permission_ids, fetch_ranges, flush_until, heartbeat_summary, is_editor_temp_name,
+    is_permission_error, is_rate_limit_error, is_read_only_token, md5_checksum, missing_ranges,
+    parents_to_remove, parse_labels, prefetch_concurrently, read_content, record_account,
+    relocate_cache, remove_stale_account_records, resume_download, same_account,
+    sessions_sharing_account, sharing_permission, split_ranges, token_expires_within,
+    with_login_timeout, CacheCompression, File, FileId, HeartbeatStats, KeepaliveConnector,
+    LimitedConnector, ProgressReader, RequestLimiter,
+};
+use hyper::net::NetworkConnector;
+use libc::{EROFS, O_APPEND, O_WRONLY};
+use serde_json;
+use socket2::Socket;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use {
+    has_mount_option, Clock, Config, DriveFacade, ExportFormats, FileManager, FolderMapping, Gcsf,
+    ManifestFormat,
+};
+
+const CLIENT_SECRET: &str = r#"{"installed":{"client_id":"test","project_id":"test","auth_uri":"http://localhost/auth","token_uri":"http://localhost/token","auth_provider_x509_cert_url":"http://localhost/certs","client_secret":"test","redirect_uris":["urn:ietf:wg:oauth:2.0:oob","http://localhost"]}}"#;
+
+/// A config which does not point to any real session. Constructing a `DriveFacade` from it does
+/// not perform any network requests.
+fn test_config() -> Config {
+    Config {
+        config_dir: Some(env::temp_dir().join(PathBuf::from("gcsf-tests"))),
+        session_name: Some(String::from("nonexistent_session")),
+        client_secret: Some(String::from(CLIENT_SECRET)),
+        ..Default::default()
+    }
+}
+
+/// A FileManager which only contains a root directory. Files can be added to it locally without
+/// communicating with Drive.
+fn test_manager() -> FileManager {
+    let config = test_config();
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &Config::default());
+    manager.add_file_locally(root, None).unwrap();
+    manager
+}
+
+/// Creates a Drive file with a given id, name and (optional) parent.
+fn drive_file(id: &str, name: &str, parent: Option<&str>) -> drive3::File {
+    drive3::File {
+        id: Some(id.to_string()),
+        name: Some(name.to_string()),
+        parents: parent.map(|p| vec![p.to_string()]),
+        size: Some(String::from("0")),
+        ..Default::default()
+    }
+}
+
+/// Creates a Drive folder with a given id, name and (optional) parent.
+fn drive_folder(id: &str, name: &str, parent: Option<&str>) -> drive3::File {
+    drive3::File {
+        mime_type: Some(String::from("application/vnd.google-apps.folder")),
+        ..drive_file(id, name, parent)
+    }
+}
+
+/// Adds a Drive file to a FileManager under a parent identified by its Drive id.
+fn add_file(manager: &mut FileManager, drive_file: drive3::File, parent: &str) -> u64 {
+    let file = File::from_drive_file(
+        manager.next_available_inode(),
+        drive_file,
+        &Config::default(),
+    );
+    let inode = file.inode();
+    manager
+        .add_file_locally(file, Some(FileId::DriveId(parent.to_string())))
+        .unwrap();
+    inode
+}
+
 #[test]
 fn some_test() {
     assert_eq!(2 + 2, 4);
 }
+
+#[test]
+fn read_sees_pending_writes() {
+    let mut df = DriveFacade::new(&test_config());
+    df.cache_content("some_id", b"hello world");
+
+    df.write(String::from("some_id"), 6, b"there");
+    assert_eq!(df.read("some_id", None, 0, 100), Some(&b"hello there"[..]));
+
+    df.write(String::from("some_id"), 9, b"eeeee");
+    assert_eq!(df.read("some_id", None, 6, 3), Some(&b"the"[..]));
+    assert_eq!(
+        df.read("some_id", None, 0, 100),
+        Some(&b"hello theeeeee"[..])
+    );
+
+    // Writes past the end of the content are padded with zeros, even in a range read on its own.
+    df.write(String::from("some_id"), 16, b"!");
+    assert_eq!(df.read("some_id", None, 12, 10), Some(&b"ee\0\0!"[..]));
+    assert_eq!(df.read("some_id", None, 3, 4), Some(&b"lo t"[..]));
+}
+
+#[test]
+fn conflicting_allow_options_are_resolved() {
+    let options = vec![
+        String::from("fsname=GCSF"),
+        String::from("allow_root"),
+        String::from("allow_other"),
+    ];
+
+    let mut config = Config {
+        mount_options: Some(options.clone()),
+        ..Default::default()
+    };
+    assert_eq!(config.mount_options(), options);
+
+    config.prefer_allow_other = Some(true);
+    assert!(has_mount_option(&config.mount_options(), "allow_other"));
+    assert!(!has_mount_option(&config.mount_options(), "allow_root"));
+
+    config.prefer_allow_other = Some(false);
+    assert!(!has_mount_option(&config.mount_options(), "allow_other"));
+    assert!(has_mount_option(&config.mount_options(), "allow_root"));
+}
+
+#[test]
+fn huge_directories_can_be_paged() {
+    let mut manager = test_manager();
+    add_file(
+        &mut manager,
+        drive_folder("huge", "huge", Some("root")),
+        "root",
+    );
+    for i in 0..5000 {
+        let name = format!("file_{}", i);
+        add_file(&mut manager, drive_file(&name, &name, Some("huge")), "huge");
+    }
+
+    let dir = FileId::DriveId(String::from("huge"));
+    let mut names = Vec::new();
+    loop {
+        let page: Vec<String> = manager
+            .get_children_from(&dir, names.len())
+            .unwrap()
+            .take(700)
+            .map(File::name)
+            .collect();
+        if page.is_empty() {
+            break;
+        }
+        names.extend(page);
+    }
+
+    assert_eq!(names.len(), 5000);
+    assert_eq!(names[0], "file_0");
+    assert_eq!(names[4999], "file_4999");
+}
+
+#[test]
+fn folder_mappings_graft_folders() {
+    let mut manager = test_manager();
+    add_file(
+        &mut manager,
+        drive_folder("projects", "Projects", Some("root")),
+        "root",
+    );
+    let foo = add_file(
+        &mut manager,
+        drive_folder("foo", "Foo", Some("projects")),
+        "projects",
+    );
+    add_file(
+        &mut manager,
+        drive_file("notes", "notes.txt", Some("foo")),
+        "foo",
+    );
+
+    let mapping = FolderMapping {
+        drive_id_or_path: String::from("Projects/Foo"),
+        mount_path: String::from("/work"),
+    };
+    manager.apply_folder_mappings(&[mapping]).unwrap();
+
+    assert_eq!(manager.resolve_path("/work"), Some(foo));
+    assert!(manager.resolve_path("/work/notes.txt").is_some());
+    assert_eq!(manager.resolve_path("/Projects/Foo"), None);
+
+    let conflicting = FolderMapping {
+        drive_id_or_path: String::from("foo"),
+        mount_path: String::from("/elsewhere"),
+    };
+    assert!(manager.apply_folder_mappings(&[conflicting]).is_err());
+}
+
+#[test]
+fn md5_checksums_match_drive_format() {
+    assert_eq!(md5_checksum(b"hello"), "5d41402abc4b2a76b9719d911017c592");
+    assert_eq!(md5_checksum(b""), "d41d8cd98f00b204e9800998ecf8427e");
+}
+
+#[test]
+fn disk_cache_survives_restarts() {
+    let cache_dir = env::temp_dir().join("gcsf-tests-disk-cache");
+    let config = Config {
+        cache_dir: Some(cache_dir),
+        ..test_config()
+    };
+
+    let mut df = DriveFacade::new(&config);
+    df.cache_content("cached_id", b"cached content");
+    assert!(df.disk_cached_ids().contains(&String::from("cached_id")));
+
+    let mut df = DriveFacade::new(&config);
+    assert_eq!(
+        df.cached_content("cached_id"),
+        Some(b"cached content".to_vec())
+    );
+
+    df.invalidate("cached_id");
+    assert_eq!(df.cached_content("cached_id"), None);
+    assert!(!df.disk_cached_ids().contains(&String::from("cached_id")));
+}
+
+#[test]
+fn read_only_tokens_are_detected() {
+    let token = |scopes: &str| {
+        format!(
+            r#"{{"tokens":[{{"hash":1,"scopes":{},"token":{{"access_token":"a","refresh_token":"r","token_type":"Bearer","expires_in":3600,"expires_in_timestamp":0}}}}]}}"#,
+            scopes
+        )
+    };
+
+    assert!(is_read_only_token(&token(
+        r#"["https://www.googleapis.com/auth/drive.readonly"]"#
+    )));
+    assert!(!is_read_only_token(&token(
+        r#"["https://www.googleapis.com/auth/drive"]"#
+    )));
+    assert!(!is_read_only_token(&token("null")));
+    assert!(!is_read_only_token("not json"));
+}
+
+#[test]
+fn writes_to_read_only_mounts_fail_with_erofs() {
+    let (url, requests) = mock_drive_api(|request_line| {
+        if request_line.starts_with("GET /drive/v3/files?") {
+            String::from(
+                r#"{"files":[{"id":"f","name":"f.txt","mimeType":"text/plain","parents":["root"]}]}"#,
+            )
+        } else {
+            String::from("{}")
+        }
+    });
+    let config = Config {
+        read_only: Some(true),
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "read-only-write")
+    };
+
+    let (result, pending) = with_timeout(move || {
+        let mut fs = Gcsf::with_config(config).unwrap();
+        let inode = fs.manager.resolve_path("/f.txt").unwrap();
+        let fh = fs.manager.open(inode, O_WRONLY as u32);
+        let result = fs.write_data(inode, fh, 0, b"data");
+        (result, fs.manager.df.has_pending_writes("f"))
+    });
+
+    assert_eq!(result, Err(EROFS));
+    assert!(!pending);
+    assert!(requests
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|request| !request.contains("upload")));
+}
+
+#[test]
+fn read_only_mounts_keep_using_a_full_scope_token() {
+    let (url, _) = mock_drive_api(|request_line| {
+        if request_line.starts_with("GET /drive/v3/files?") {
+            String::from(
+                r#"{"files":[{"id":"f","name":"f.txt","mimeType":"text/plain","parents":["root"]}]}"#,
+            )
+        } else {
+            String::from("{}")
+        }
+    });
+    let config = Config {
+        read_only: Some(true),
+        ..mock_api_config(url, "read-only-full-scope")
+    };
+
+    // Asking for a read-only token would start an OAuth flow, since only a full one is stored.
+    let (read_only, names) = with_timeout(move || {
+        let mut df = DriveFacade::new(&config);
+        let names = df
+            .get_all_files(None, Some(false))
+            .map(|files| files.into_iter().filter_map(|f| f.name).collect::<Vec<_>>())
+            .map_err(|e| e.to_string());
+        (df.is_read_only(), names)
+    });
+    assert!(read_only);
+    assert_eq!(names.unwrap(), vec!["f.txt"]);
+}
+
+#[test]
+fn read_only_tokens_use_the_read_only_scope() {
+    let (url, _) = mock_drive_api(|_| String::from(r#"{"files":[]}"#));
+    let config = mock_api_config_with_scopes(url, "read-only-scope", &[drive3::Scope::Readonly]);
+
+    let (read_only, files) = with_timeout(move || {
+        let mut df = DriveFacade::new(&config);
+        let files = df
+            .get_all_files(None, Some(false))
+            .map(|files| files.len())
+            .map_err(|e| e.to_string());
+        (df.is_read_only(), files)
+    });
+    assert!(read_only);
+    assert_eq!(files.unwrap(), 0);
+}
+
+#[test]
+fn imminent_token_expiry_triggers_refresh() {
+    let token = r#"{"tokens":[{"hash":1,"scopes":null,"token":{"access_token":"a","refresh_token":"r","token_type":"Bearer","expires_in":3600,"expires_in_timestamp":10000}}]}"#;
+
+    assert!(!token_expires_within(token, 5000, 300));
+    assert!(token_expires_within(token, 9800, 300));
+    assert!(token_expires_within(token, 20000, 300));
+    assert!(!token_expires_within("not json", 20000, 300));
+}
+
+#[test]
+fn export_formats_are_configurable() {
+    let drawing = "application/vnd.google-apps.drawing";
+    let default_config = Config::default();
+    assert_eq!(
+        default_config.export_type(drawing),
+        Some(String::from("image/png"))
+    );
+    assert_eq!(default_config.export_type("text/plain"), None);
+
+    let config = Config {
+        add_extensions_to_special_files: Some(true),
+        export_formats: Some(ExportFormats {
+            drawing: Some(String::from("svg")),
+            document: Some(String::from("application/pdf")),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        config.export_type(drawing),
+        Some(String::from("image/svg+xml"))
+    );
+    assert_eq!(
+        config.export_type("application/vnd.google-apps.document"),
+        Some(String::from("application/pdf"))
+    );
+    assert_eq!(
+        config.export_type("application/vnd.google-apps.spreadsheet"),
+        Some(String::from(
+            "application/vnd.oasis.opendocument.spreadsheet"
+        ))
+    );
+
+    let mut sketch = drive_file("sketch", "sketch", None);
+    sketch.mime_type = Some(String::from(drawing));
+    assert_eq!(
+        File::from_drive_file(4, sketch, &config).name,
+        "sketch#.svg"
+    );
+}
+
+#[test]
+fn forgotten_inodes_release_cached_content() {
+    let mut manager = test_manager();
+    let inode = add_file(&mut manager, drive_file("big", "big.bin", None), "root");
+    manager.df.cache_content("big", &[0; 64]);
+
+    manager.remember(inode);
+    manager.remember(inode);
+    assert_eq!(manager.lookup_count(inode), 2);
+
+    manager.forget(inode, 1);
+    assert_eq!(manager.lookup_count(inode), 1);
+    assert!(manager.df.cached_content("big").is_some());
+
+    manager.forget(inode, 1);
+    assert_eq!(manager.lookup_count(inode), 0);
+    assert!(manager.df.cached_content("big").is_none());
+    // The file is still in the tree, so it is kept for resolving paths.
+    assert!(manager.get_file(&FileId::Inode(inode)).is_some());
+
+    // A file which is removed while the kernel refers to it is kept until it is forgotten, but
+    // only its open handles can still use it.
+    let gone = add_file(&mut manager, drive_file("gone", "gone.txt", None), "root");
+    manager.remember(gone);
+    let fh = manager.open(gone, 0);
+    manager
+        .delete_locally(&FileId::DriveId(String::from("gone")))
+        .unwrap();
+    assert!(!manager.contains(&FileId::Inode(gone)));
+    assert_eq!(manager.resolve_path("/gone.txt"), None);
+    assert!(manager.get_file(&FileId::Inode(gone)).is_some());
+
+    manager.release(fh).unwrap();
+    assert!(manager.get_file(&FileId::Inode(gone)).is_none());
+    let files = manager.files.len();
+
+    manager.forget(gone, 1);
+    assert_eq!(manager.files.len(), files - 1);
+}
+
+#[test]
+fn unknown_quota_reports_configured_free_space() {
+    let config = Config {
+        reported_free_bytes: Some(1024),
+        ..Default::default()
+    };
+    assert_eq!(
+        reported_capacity(100, None, config.reported_free_bytes()),
+        1124
+    );
+    assert_eq!(
+        reported_capacity(100, Some(500), config.reported_free_bytes()),
+        500
+    );
+    assert_eq!(Config::default().reported_free_bytes(), 1 << 40);
+}
+
+#[test]
+fn moving_multi_parent_files_only_detaches_the_source_parent() {
+    let mut manager = test_manager();
+    add_file(&mut manager, drive_folder("a", "a", Some("root")), "root");
+    add_file(&mut manager, drive_folder("b", "b", Some("root")), "root");
+
+    let mut shared = drive_file("shared", "shared.txt", Some("a"));
+    shared.parents = Some(vec![String::from("a"), String::from("b")]);
+    add_file(&mut manager, shared, "a");
+
+    let id = FileId::DriveId(String::from("shared"));
+    let source = manager.local_parent_drive_id(&id);
+    assert_eq!(source, Some(String::from("a")));
+
+    let parents = vec![String::from("a"), String::from("b")];
+    assert_eq!(
+        parents_to_remove(&parents, source.as_ref().map(|s| s.as_str())),
+        vec![String::from("a")]
+    );
+    assert_eq!(parents_to_remove(&parents, Some("elsewhere")), parents);
+    assert_eq!(parents_to_remove(&parents, None), parents);
+}
+
+#[test]
+fn mount_flags_merge_with_configured_options() {
+    let mut config = Config {
+        mount_options: Some(vec![
+            String::from("fsname=GCSF"),
+            String::from("allow_root"),
+        ]),
+        ..Default::default()
+    };
+
+    config.add_mount_option("fsname=GCSF");
+    config.add_mount_option("allow_other");
+    config.add_mount_option("allow_other");
+
+    let options = config.mount_options();
+    assert_eq!(
+        options,
+        vec![String::from("fsname=GCSF"), String::from("allow_other")]
+    );
+
+    let mut config = Config::default();
+    config.add_mount_option("allow_root");
+    assert_eq!(config.mount_options(), vec![String::from("allow_root")]);
+}
+
+#[test]
+fn files_can_change_type_between_syncs() {
+    let mut manager = test_manager();
+    let folder = add_file(&mut manager, drive_folder("x", "x", Some("root")), "root");
+    let child = add_file(&mut manager, drive_file("c", "c.txt", Some("x")), "x");
+    let id = FileId::Inode(folder);
+
+    let mut doc = drive_file("x", "x", Some("root"));
+    doc.mime_type = Some(String::from("application/vnd.google-apps.document"));
+    manager.update_locally(&id, doc).unwrap();
+
+    assert_eq!(manager.get_file(&id).unwrap().kind(), FileType::RegularFile);
+    assert!(manager.get_file(&FileId::Inode(child)).is_none());
+    assert!(!manager.contains(&FileId::DriveId(String::from("c"))));
+
+    manager
+        .update_locally(&id, drive_folder("x", "x", Some("root")))
+        .unwrap();
+    let file = manager.get_file(&id).unwrap();
+    assert_eq!(file.kind(), FileType::Directory);
+    assert_eq!(file.attr.size, 512);
+    assert_eq!(manager.get_children(&id).unwrap().len(), 0);
+}
+
+#[test]
+fn ended_sessions_exit_unless_auto_remount_is_set() {
+    let mut sessions = 0;
+    assert!(mount_until_unmounted(Config::default().auto_remount(), || {
+        sessions += 1;
+        Ok(())
+    })
+    .is_ok());
+    assert_eq!(sessions, 1);
+
+    let config = Config {
+        auto_remount: Some(true),
+        ..Default::default()
+    };
+    let mut sessions = 0;
+    let result = mount_until_unmounted(config.auto_remount(), || {
+        sessions += 1;
+        if sessions < 3 {
+            Ok(())
+        } else {
+            Err(err_msg("mount point is gone"))
+        }
+    });
+    assert!(result.is_err());
+    assert_eq!(sessions, 3);
+}
+
+#[test]
+fn docs_can_be_read_as_plain_text() {
+    let config = Config {
+        add_extensions_to_special_files: Some(true),
+        export_formats: Some(ExportFormats {
+            document: Some(String::from("text/plain")),
+            ..Default::default()
+        }),
+        ..test_config()
+    };
+    let doc_type = "application/vnd.google-apps.document";
+    assert_eq!(
+        config.export_type(doc_type),
+        Some(String::from("text/plain"))
+    );
+
+    let mut doc = drive_file("doc", "notes", None);
+    doc.mime_type = Some(String::from(doc_type));
+    assert_eq!(File::from_drive_file(4, doc, &config).name, "notes#.txt");
+
+    let text = "line one\nline two\n";
+    let content = read_content(io::Cursor::new(text.as_bytes().to_vec())).unwrap();
+    assert_eq!(content, text.as_bytes());
+
+    let mut df = DriveFacade::new(&config);
+    df.cache_content("doc", &content);
+    assert_eq!(
+        df.read("doc", Some(String::from(doc_type)), 5, 3),
+        Some(&b"one"[..])
+    );
+}
+
+#[test]
+fn disk_cache_follows_cache_dir_changes() {
+    let root = env::temp_dir().join("gcsf-tests-relocation");
+    let _ = fs::remove_dir_all(&root);
+    let config = Config {
+        config_dir: Some(root.join("config")),
+        session_name: Some(String::from("relocated_session")),
+        cache_dir: Some(root.join("old")),
+        ..test_config()
+    };
+    fs::create_dir_all(config.config_dir()).unwrap();
+
+    let mut df = DriveFacade::new(&config);
+    df.cache_content("a", b"first");
+    df.cache_content("b", b"second");
+
+    let moved = Config {
+        cache_dir: Some(root.join("new")),
+        ..config.clone()
+    };
+    relocate_cache(&moved).unwrap();
+
+    assert!(!config.cache_dir().unwrap().exists());
+    let mut df = DriveFacade::new(&moved);
+    let mut ids = df.disk_cached_ids();
+    ids.sort();
+    assert_eq!(ids, vec![String::from("a"), String::from("b")]);
+    assert_eq!(df.cached_content("b"), Some(b"second".to_vec()));
+}
+
+#[test]
+fn zero_length_reads_and_writes_are_no_ops() {
+    let mut df = DriveFacade::new(&test_config());
+
+    // The file is neither cached nor pending, so anything but a no-op would reach Drive.
+    assert_eq!(df.read("native", None, 0, 0), Some(&[][..]));
+
+    df.write(String::from("native"), 0, &[]);
+    assert!(!df.has_pending_writes("native"));
+
+    df.write(String::from("native"), 0, b"x");
+    assert!(df.has_pending_writes("native"));
+}
+
+#[test]
+fn search_directories_list_their_results() {
+    let mut manager = test_manager();
+    manager.add_search_dir().unwrap();
+    let budget = add_file(
+        &mut manager,
+        drive_file("budget", "budget.ods", None),
+        "root",
+    );
+
+    let search_dir = manager.resolve_path("/.search").unwrap();
+    let query = "name contains 'budget'";
+    let search = manager.lookup_search(search_dir, query).unwrap();
+    assert!(manager.is_search(search));
+    assert_eq!(manager.lookup_search(search_dir, query), Some(search));
+    assert_eq!(manager.lookup_search(budget, query), None);
+
+    manager
+        .set_search_results(search, vec![drive_file("budget", "budget.ods", None)])
+        .unwrap();
+    let result = manager
+        .resolve_path("/.search/name contains 'budget'/budget.ods")
+        .unwrap();
+    assert_ne!(result, budget);
+    assert_eq!(
+        manager.get_inode(&FileId::DriveId(String::from("budget"))),
+        Some(budget)
+    );
+
+    manager.set_search_results(search, Vec::new()).unwrap();
+    assert_eq!(
+        manager.get_children(&FileId::Inode(search)).unwrap().len(),
+        0
+    );
+    assert!(manager.get_file(&FileId::Inode(result)).is_none());
+}
+
+#[test]
+fn hard_links_map_to_additional_parents() {
+    let mut manager = test_manager();
+    let a = add_file(&mut manager, drive_folder("a", "a", Some("root")), "root");
+    let b = add_file(&mut manager, drive_folder("b", "b", Some("root")), "root");
+    let file = add_file(&mut manager, drive_file("f", "f.txt", Some("a")), "a");
+    let nlink = manager.get_file(&FileId::Inode(file)).unwrap().attr.nlink;
+
+    // Drive has a single name per file, so links can not be renamed. This is checked before
+    // anything is sent to Drive.
+    assert!(!manager.is_valid_link_name(file, "g.txt"));
+    assert!(manager.link(file, b, String::from("g.txt")).is_err());
+    assert_eq!(manager.resolve_path("/b/g.txt"), None);
+
+    manager
+        .link_locally(file, b, String::from("f.txt"))
+        .unwrap();
+    assert!(manager
+        .link_locally(file, b, String::from("f.txt"))
+        .is_err());
+    assert_eq!(manager.resolve_path("/b/f.txt"), Some(file));
+    assert_eq!(manager.resolve_path("/a/f.txt"), Some(file));
+    assert_eq!(manager.links_under(b), vec![(file, String::from("f.txt"))]);
+    assert_eq!(
+        manager.get_file(&FileId::Inode(file)).unwrap().attr.nlink,
+        nlink + 1
+    );
+
+    // Removing the original path moves the file to its remaining link.
+    assert!(manager.unlink_locally(a, "f.txt").unwrap());
+    assert_eq!(manager.resolve_path("/a/f.txt"), None);
+    assert_eq!(manager.resolve_path("/b/f.txt"), Some(file));
+    assert_eq!(manager.links_under(b), vec![]);
+    assert_eq!(
+        manager.get_file(&FileId::Inode(file)).unwrap().attr.nlink,
+        nlink
+    );
+
+    // The last link is not handled as a link anymore.
+    assert!(!manager.unlink_locally(b, "f.txt").unwrap());
+    assert_eq!(manager.resolve_path("/b/f.txt"), Some(file));
+    assert!(manager.get_children(&FileId::Inode(a)).unwrap().is_empty());
+}
+
+#[test]
+fn hard_links_may_use_the_shown_name_of_duplicates() {
+    let config = Config {
+        rename_identical_files: Some(true),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    manager
+        .add_file_locally(
+            File::from_drive_file(1, drive_folder("root", ".", None), &config),
+            None,
+        )
+        .unwrap();
+    add_file(&mut manager, drive_file("f", "f.txt", Some("root")), "root");
+    let second = add_file(&mut manager, drive_file("g", "f.txt", Some("root")), "root");
+    assert_eq!(manager.resolve_path("/f.txt.1"), Some(second));
+
+    assert!(manager.is_valid_link_name(second, "f.txt.1"));
+    assert!(manager.is_valid_link_name(second, "f.txt"));
+    assert!(!manager.is_valid_link_name(second, "g.txt"));
+}
+
+#[test]
+fn large_files_can_be_downloaded_in_parallel_ranges() {
+    assert_eq!(split_ranges(10, 3), vec![(0, 3), (4, 7), (8, 9)]);
+    assert_eq!(split_ranges(2, 4), vec![(0, 0), (1, 1)]);
+    assert_eq!(split_ranges(0, 4), vec![]);
+    assert_eq!(split_ranges(5, 1), vec![(0, 4)]);
+
+    let data: Arc<Vec<u8>> = Arc::new((0..100_000u32).map(|i| (i % 251) as u8).collect());
+    let active = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+
+    // A mock server which takes a while to serve each range.
+    let server = {
+        let (data, active, peak) = (data.clone(), active.clone(), peak.clone());
+        move |start: u64, end: u64| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            active.fetch_sub(1, Ordering::SeqCst);
+            Ok(data[start as usize..end as usize + 1].to_vec())
+        }
+    };
+
+    let single = fetch_ranges(split_ranges(data.len() as u64, 1), server.clone()).unwrap();
+    assert_eq!(peak.swap(0, Ordering::SeqCst), 1);
+
+    let parallel = fetch_ranges(split_ranges(data.len() as u64, 4), server).unwrap();
+    assert!(peak.load(Ordering::SeqCst) > 1);
+    assert_eq!(single, *data);
+    assert_eq!(parallel, *data);
+
+    assert_eq!(Config::default().download_parallelism(), 1);
+    let config = Config {
+        download_parallelism: Some(64),
+        ..Default::default()
+    };
+    assert_eq!(config.download_parallelism(), 8);
+}
+
+#[test]
+fn orphaned_files_resolve_to_their_drive_id() {
+    let config = test_config();
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    assert!(manager.next_available_inode() > 3);
+
+    manager
+        .add_file_locally(
+            File::from_drive_file(1, drive_folder("root", ".", None), &config),
+            None,
+        )
+        .unwrap();
+    let shared = File::from_drive_file(3, drive_folder("shared", "Shared with me", None), &config);
+    manager
+        .add_file_locally(shared, Some(FileId::Inode(1)))
+        .unwrap();
+
+    // The parent of the orphan is unknown, so it is placed in "Shared with me".
+    let orphan = add_file(
+        &mut manager,
+        drive_file("orphan", "orphan.txt", Some("missing")),
+        "shared",
+    );
+    assert_ne!(orphan, 3);
+
+    let id = FileId::ParentAndName {
+        parent: 3,
+        name: String::from("orphan.txt"),
+    };
+    assert_eq!(manager.get_drive_id(&id), Some(String::from("orphan")));
+    assert_eq!(
+        manager.get_file(&FileId::Inode(3)).unwrap().name,
+        "Shared with me"
+    );
+    assert_eq!(manager.file_is_trashed(&id).unwrap(), false);
+}
+
+#[test]
+fn pinned_root_id_skips_the_lookup() {
+    let config = Config {
+        root_drive_id: Some(String::from("0AB1cD2eF3gH4Uk9PVA")),
+        ..test_config()
+    };
+
+    // The session has no token, so this would fail if it reached Drive.
+    let mut df = DriveFacade::new(&config);
+    assert_eq!(df.root_id().unwrap(), "0AB1cD2eF3gH4Uk9PVA");
+}
+
+#[test]
+fn large_edits_go_through_scratch_files() {
+    let config = Config {
+        session_name: Some(String::from("scratch_session")),
+        cache_dir: Some(env::temp_dir().join("gcsf-tests-scratch")),
+        scratch_on_disk: Some(true),
+        ..test_config()
+    };
+    let _ = fs::remove_dir_all(config.scratch_dir().unwrap());
+
+    let mut df = DriveFacade::new(&config);
+    df.cache_content("large", &vec![0; 4 * 1024 * 1024]);
+
+    df.write(String::from("large"), 3 * 1024 * 1024, b"edited");
+    df.write(String::from("large"), 4 * 1024 * 1024 + 10, b"appended");
+    assert!(df.has_pending_writes("large"));
+    assert_eq!(df.pending_write_bytes("large"), 0);
+    assert!(config.scratch_dir().unwrap().join("large").exists());
+
+    assert_eq!(
+        df.read("large", None, 3 * 1024 * 1024, 6),
+        Some(&b"edited"[..])
+    );
+    assert_eq!(
+        df.read("large", None, 4 * 1024 * 1024 + 8, 100),
+        Some(&b"\0\0appended"[..])
+    );
+    assert_eq!(df.read("large", None, 0, 4), Some(&[0, 0, 0, 0][..]));
+}
+
+#[test]
+fn mount_check_is_retried() {
+    let config = Config::default();
+    let mut attempts = 0;
+    let result = retry(
+        config.mount_check_retries(),
+        Duration::from_millis(1),
+        || {
+            attempts += 1;
+            if attempts == 1 {
+                Err(err_msg("fuse: attempt to remount on active mount point"))
+            } else {
+                Ok(attempts)
+            }
+        },
+    );
+    assert_eq!(result.unwrap(), 2);
+
+    let mut attempts = 0;
+    let result: Result<(), _> = retry(1, Duration::from_millis(1), || {
+        attempts += 1;
+        Err(err_msg("still mounted"))
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 2);
+    assert!(config.mount_check_fatal());
+}
+
+#[test]
+fn drive_labels_become_xattrs() {
+    let response = r#"{
+        "labels": [
+            {
+                "id": "classification",
+                "revisionId": "1",
+                "fields": {
+                    "level": {"id": "level", "valueType": "selection", "selection": ["confidential"]},
+                    "owner": {"id": "owner", "valueType": "user", "user": [{"emailAddress": "a@example.com"}]},
+                    "tags": {"id": "tags", "valueType": "text", "text": ["x", "y"]}
+                }
+            },
+            {"id": "reviewed"}
+        ],
+        "kind": "drive#labelList"
+    }"#;
+
+    let mut labels = parse_labels(response).unwrap();
+    labels.sort();
+    let expected: Vec<(String, String)> = vec![
+        ("user.drive.label.classification", ""),
+        ("user.drive.label.classification.level", "confidential"),
+        ("user.drive.label.classification.owner", "a@example.com"),
+        ("user.drive.label.classification.tags", "x,y"),
+        ("user.drive.label.reviewed", ""),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect();
+    assert_eq!(labels, expected);
+
+    assert_eq!(parse_labels("{}").unwrap(), vec![]);
+
+    // Without fetch_labels, no request is made and files have no labels.
+    let mut df = DriveFacade::new(&test_config());
+    assert_eq!(df.labels("some_file").unwrap(), vec![]);
+}
+
+#[test]
+fn labels_are_fetched_with_the_stored_access_token() {
+    let (url, requests) = mock_drive_api(|request_line| {
+        if request_line.starts_with("GET /drive/v3/files/f/listLabels") {
+            String::from(r#"{"labels":[{"id":"reviewed"}]}"#)
+        } else {
+            String::from("{}")
+        }
+    });
+    let config = Config {
+        fetch_labels: Some(true),
+        ..mock_api_config(url, "labels")
+    };
+
+    let labels = with_timeout(move || {
+        DriveFacade::new(&config)
+            .labels("f")
+            .map_err(|e| e.to_string())
+    })
+    .unwrap();
+    assert_eq!(
+        labels,
+        vec![(String::from("user.drive.label.reviewed"), String::new())]
+    );
+
+    let requests = requests.lock().unwrap();
+    assert!(
+        requests[0].contains("Authorization: Bearer a"),
+        "{:?}",
+        requests
+    );
+}
+
+#[test]
+fn failed_uploads_are_kept_and_retried() {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&attempts);
+    let (url, requests) = mock_drive_api_with_status(move |request_line| {
+        if request_line.starts_with("PATCH /resumable/upload/drive/v3/files/f?")
+            && counter.fetch_add(1, Ordering::SeqCst) == 0
+        {
+            ("500 Internal Server Error", String::from("{}"))
+        } else {
+            ("200 OK", String::from(r#"{"id":"f"}"#))
+        }
+    });
+    let config = Config {
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "flush-retry")
+    };
+
+    let (failed, kept, retried) = with_timeout(move || {
+        let mut df = DriveFacade::new(&config);
+        df.write(String::from("f"), 0, b"new content");
+        let failed = df.flush("f").is_err();
+        let kept = df.has_pending_writes("f");
+        df.retry_failed_flushes();
+        (failed, kept, !df.has_pending_writes("f"))
+    });
+
+    assert!(failed);
+    assert!(kept);
+    assert!(retried);
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    let uploaded = requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|request| request.starts_with("POST /upload-session"))
+        .count();
+    assert_eq!(uploaded, 1);
+}
+
+#[test]
+fn dirty_files_are_flushed_on_shutdown() {
+    let dirty = vec![String::from("a"), String::from("b"), String::from("c")];
+
+    let mut flushed = Vec::new();
+    let unsaved = flush_until(dirty.clone(), Duration::from_secs(30), |id| {
+        flushed.push(id.to_string());
+        if id == "b" {
+            Err(err_msg("upload failed"))
+        } else {
+            Ok(())
+        }
+    });
+    assert_eq!(flushed, dirty);
+    assert_eq!(unsaved, vec![String::from("b")]);
+
+    // Once the timeout has passed, the remaining files are reported instead of flushed.
+    let mut flushed = 0;
+    let unsaved = flush_until(dirty.clone(), Duration::from_secs(0), |_| {
+        flushed += 1;
+        Ok(())
+    });
+    assert_eq!(flushed, 0);
+    assert_eq!(unsaved, dirty);
+
+    let mut df = DriveFacade::new(&test_config());
+    assert!(df.flush_all(Duration::from_secs(0)).is_empty());
+}
+
+#[test]
+fn duplicate_names_follow_the_template() {
+    let mut file =
+        File::from_drive_file(4, drive_file("r", "report.pdf", None), &Config::default());
+    assert_eq!(file.name(), "report.pdf");
+
+    file.identical_name_id = Some(1);
+    assert_eq!(file.name(), "report.pdf.1");
+    assert_eq!(
+        file.name_with_template("{stem} ({n}){ext}"),
+        "report (1).pdf"
+    );
+
+    file.name = String::from(".bashrc");
+    assert_eq!(file.name_with_template("{stem} ({n}){ext}"), ".bashrc (1)");
+
+    let config = Config {
+        rename_identical_files: Some(true),
+        duplicate_name_template: Some(String::from("{stem} ({n}){ext}")),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    manager
+        .add_file_locally(
+            File::from_drive_file(1, drive_folder("root", ".", None), &config),
+            None,
+        )
+        .unwrap();
+    let first = add_file(
+        &mut manager,
+        drive_file("a", "report.pdf", Some("root")),
+        "root",
+    );
+    let second = add_file(
+        &mut manager,
+        drive_file("b", "report.pdf", Some("root")),
+        "root",
+    );
+
+    assert_eq!(manager.resolve_path("/report.pdf"), Some(first));
+    assert_eq!(manager.resolve_path("/report (1).pdf"), Some(second));
+    assert_eq!(manager.resolve_path("/report.pdf (1)"), None);
+}
+
+#[test]
+fn transfers_in_progress_are_listed() {
+    let mut manager = test_manager();
+    manager.add_status_dir().unwrap();
+    add_file(
+        &mut manager,
+        drive_file("v", "video.mp4", Some("root")),
+        "root",
+    );
+    let status = manager.resolve_path("/.gcsf/transfers").unwrap();
+    assert!(manager.is_transfers_file(status));
+    assert_eq!(manager.transfer_status(), "");
+
+    {
+        let _transfer = manager.df.track_transfer("v", true, Some(200));
+        let mut reader = ProgressReader::new(
+            io::Cursor::new(vec![0u8; 200]),
+            "v",
+            Arc::clone(manager.df.transfers()),
+        );
+        let mut buf = [0; 50];
+        io::Read::read_exact(&mut reader, &mut buf).unwrap();
+        assert_eq!(
+            manager.transfer_status(),
+            "upload video.mp4 50/200 bytes (25%)\n"
+        );
+
+        io::Seek::seek(&mut reader, io::SeekFrom::Start(100)).unwrap();
+        assert_eq!(
+            manager.transfer_status(),
+            "upload video.mp4 100/200 bytes (50%)\n"
+        );
+    }
+
+    assert_eq!(manager.transfer_status(), "");
+}
+
+#[test]
+fn root_name_only_appears_in_debug_output() {
+    let config = Config {
+        root_name: Some(String::from("My Drive")),
+        root_drive_id: Some(String::from("0ARoot")),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = manager.new_root_file();
+    manager.add_file_locally(root, None).unwrap();
+    let file = add_file(
+        &mut manager,
+        drive_file("f", "f.txt", Some("0ARoot")),
+        "0ARoot",
+    );
+
+    assert!(format!("{:?}", manager).contains("  1 => My Drive\n"));
+    assert_eq!(manager.resolve_path("/f.txt"), Some(file));
+    assert_eq!(manager.resolve_path("/My Drive/f.txt"), None);
+
+    // Changes to "My Drive" itself do not rename the root.
+    manager
+        .update_locally(
+            &FileId::DriveId(String::from("0ARoot")),
+            drive_folder("0ARoot", "My Drive (renamed)", None),
+        )
+        .unwrap();
+    assert_eq!(
+        manager.get_file(&FileId::Inode(1)).unwrap().name,
+        "My Drive"
+    );
+}
+
+#[test]
+fn append_handles_write_at_the_current_end() {
+    let mut manager = test_manager();
+    let log = add_file(
+        &mut manager,
+        drive_file("log", "log.txt", Some("root")),
+        "root",
+    );
+    manager.df.cache_content("log", b"");
+
+    let append = (O_WRONLY | O_APPEND) as u32;
+    let a = manager.open(log, append);
+    let b = manager.open(log, append);
+
+    // The kernel thinks that both handles are still at the start of the file.
+    assert_eq!(
+        manager.write_through_handle(a, log, 0, b"first\n").unwrap(),
+        0
+    );
+    assert_eq!(
+        manager
+            .write_through_handle(b, log, 0, b"second\n")
+            .unwrap(),
+        6
+    );
+    assert_eq!(
+        manager.write_through_handle(a, log, 6, b"third\n").unwrap(),
+        13
+    );
+    assert_eq!(
+        manager.df.read("log", None, 0, 100),
+        Some(&b"first\nsecond\nthird\n"[..])
+    );
+
+    // Other handles still write where they are told to, without truncating the file.
+    let c = manager.open(log, O_WRONLY as u32);
+    assert_eq!(
+        manager.write_through_handle(c, log, 0, b"FIRST").unwrap(),
+        0
+    );
+    assert_eq!(manager.get_file(&FileId::Inode(log)).unwrap().attr.size, 19);
+
+    manager.release(a).unwrap();
+    manager.release(b).unwrap();
+    manager.release(c).unwrap();
+    assert_eq!(manager.write_through_handle(a, log, 0, b"!").unwrap(), 0);
+}
+
+#[test]
+fn files_recreated_within_the_grace_period_are_not_trashed() {
+    let config = Config {
+        delete_grace_seconds: Some(60),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    manager
+        .add_file_locally(
+            File::from_drive_file(1, drive_folder("root", ".", None), &config),
+            None,
+        )
+        .unwrap();
+    manager.add_status_dir().unwrap();
+    let file = add_file(
+        &mut manager,
+        drive_file("n", "notes.txt", Some("root")),
+        "root",
+    );
+    assert!(manager.has_delete_grace_period());
+
+    manager.delete_later(&FileId::Inode(file), false).unwrap();
+    assert_eq!(manager.resolve_path("/notes.txt"), None);
+    assert_eq!(
+        manager.resolve_path("/.gcsf/pending-deletes/notes.txt"),
+        Some(file)
+    );
+
+    // Nothing is due yet, so Drive is not contacted.
+    manager.apply_pending_deletes(false);
+    assert_eq!(manager.restore_pending_delete(1, "other.txt"), None);
+    assert_eq!(manager.restore_pending_delete(1, "notes.txt"), Some(file));
+    assert_eq!(manager.resolve_path("/notes.txt"), Some(file));
+
+    // The restored file is not pending anymore, so it is left alone even when unmounting.
+    manager.apply_pending_deletes(true);
+    assert_eq!(manager.resolve_path("/notes.txt"), Some(file));
+
+    // Re-created files start out empty.
+    manager.df.cache_content("n", b"old content");
+    manager.truncate(&FileId::Inode(file)).unwrap();
+    assert_eq!(manager.get_file(&FileId::Inode(file)).unwrap().attr.size, 0);
+    assert!(manager.df.has_pending_writes("n"));
+    manager.write(FileId::Inode(file), 0, b"new").unwrap();
+    assert_eq!(manager.df.read("n", None, 0, 100), Some(&b"new"[..]));
+}
+
+#[test]
+fn anyone_with_link_permissions_can_be_created_and_revoked() {
+    let permission = sharing_permission("writer", None).unwrap();
+    assert_eq!(permission.type_, Some(String::from("anyone")));
+    assert_eq!(permission.role, Some(String::from("writer")));
+    assert_eq!(permission.allow_file_discovery, Some(false));
+    assert_eq!(permission.domain, None);
+
+    let permission = sharing_permission("reader", Some("example.com")).unwrap();
+    assert_eq!(permission.type_, Some(String::from("domain")));
+    assert_eq!(permission.domain, Some(String::from("example.com")));
+    assert!(sharing_permission("owner", None).is_err());
+
+    let permissions = vec![
+        drive3::Permission {
+            id: Some(String::from("anyoneWithLink")),
+            ..sharing_permission("reader", None).unwrap()
+        },
+        drive3::Permission {
+            id: Some(String::from("12345")),
+            type_: Some(String::from("user")),
+            role: Some(String::from("owner")),
+            ..Default::default()
+        },
+        drive3::Permission {
+            id: Some(String::from("67890")),
+            ..sharing_permission("reader", Some("example.com")).unwrap()
+        },
+    ];
+    assert_eq!(
+        anyone_permission_ids(&permissions),
+        vec![String::from("anyoneWithLink")]
+    );
+}
+
+/// A clock which only moves when told to.
+#[derive(Clone)]
+struct MockClock(Arc<Mutex<SystemTime>>);
+
+impl MockClock {
+    fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[test]
+fn syncs_follow_the_injected_clock() {
+    let clock = MockClock(Arc::new(Mutex::new(SystemTime::UNIX_EPOCH)));
+    let mut manager = test_manager();
+    manager.set_clock(Arc::new(clock.clone()));
+
+    // The last sync just happened, so the next one is suppressed without contacting Drive.
+    assert!(!manager.sync_due());
+    assert!(manager.sync().is_err());
+
+    clock.advance(manager.sync_interval - Duration::from_secs(1));
+    assert!(!manager.sync_due());
+
+    clock.advance(Duration::from_secs(1));
+    assert!(manager.sync_due());
+
+    // A clock which went backwards does not trigger a sync.
+    manager.last_sync = SystemTime::UNIX_EPOCH + Duration::from_secs(3600);
+    assert!(!manager.sync_due());
+}
+
+#[test]
+fn interrupted_downloads_resume_with_the_missing_bytes() {
+    assert_eq!(missing_ranges(&[], 10, 4), vec![(0, 3), (4, 7), (8, 9)]);
+    assert_eq!(missing_ranges(&[(4, 7), (0, 3)], 10, 4), vec![(8, 9)]);
+    assert_eq!(missing_ranges(&[(2, 3)], 6, 100), vec![(0, 1), (4, 5)]);
+    assert_eq!(missing_ranges(&[(0, 9)], 10, 4), vec![]);
+
+    let dir = env::temp_dir().join("gcsf-tests-resume");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("big");
+    let content = b"hello world";
+
+    // The first attempt is interrupted after the first chunk.
+    for name in &["big", "big.partial", "big.extents"] {
+        let _ = fs::remove_file(dir.join(name));
+    }
+    let interrupted = resume_download(&path, 11, 4, |start, end| {
+        if start == 0 {
+            Ok(content[start as usize..=end as usize].to_vec())
+        } else {
+            Err(err_msg("connection reset"))
+        }
+    });
+    assert!(interrupted.is_err());
+    assert!(!path.exists());
+
+    // After a restart, only the remaining bytes are requested.
+    let mut requested = Vec::new();
+    let data = resume_download(&path, 11, 4, |start, end| {
+        requested.push((start, end));
+        Ok(content[start as usize..=end as usize].to_vec())
+    })
+    .unwrap();
+    assert_eq!(requested, vec![(4, 7), (8, 10)]);
+    assert_eq!(&data[..], &content[..]);
+    assert_eq!(fs::read(&path).unwrap(), content.to_vec());
+    assert!(!dir.join("big.partial").exists());
+    assert!(!dir.join("big.extents").exists());
+}
+
+#[test]
+fn files_over_the_size_limit_are_streamed() {
+    let df = DriveFacade::new(&test_config());
+    assert!(!df.streams("video", Some("video/mp4"), 50 << 30));
+
+    let config = Config {
+        cache_max_file_bytes: Some(1 << 30),
+        ..test_config()
+    };
+    let mut df = DriveFacade::new(&config);
+    assert!(df.streams("video", Some("video/mp4"), 50 << 30));
+    assert!(df.streams("video", None, (1 << 30) + 1));
+    assert!(!df.streams("video", Some("video/mp4"), 1 << 30));
+
+    // Exported files have no byte ranges.
+    assert!(!df.streams(
+        "doc",
+        Some("application/vnd.google-apps.document"),
+        50 << 30
+    ));
+
+    // Unsaved changes need the full content.
+    df.write(String::from("video"), 0, b"edited");
+    assert!(!df.streams("video", Some("video/mp4"), 50 << 30));
+
+    // Zero-length reads never reach Drive.
+    assert_eq!(df.read_streaming("other", 0, 0), Some(&[][..]));
+    assert_eq!(df.cached_content("other"), None);
+}
+
+#[test]
+fn files_can_not_be_moved_into_pseudo_directories() {
+    let mut manager = test_manager();
+    manager.add_shared_dir().unwrap();
+    manager.add_search_dir().unwrap();
+    manager.add_status_dir().unwrap();
+    let folder = add_file(
+        &mut manager,
+        drive_folder("d", "docs", Some("root")),
+        "root",
+    );
+    let file = add_file(&mut manager, drive_file("a", "a.txt", Some("root")), "root");
+    let search_dir = manager.resolve_path("/.search").unwrap();
+    let search = manager
+        .lookup_search(search_dir, "name contains 'a'")
+        .unwrap();
+
+    for path in &[
+        "/Shared with me",
+        "/.search",
+        "/.gcsf",
+        "/.gcsf/pending-deletes",
+    ] {
+        let target = manager.resolve_path(path).unwrap();
+        assert!(manager.is_pseudo_dir(target), "{}", path);
+        assert!(manager
+            .rename(&FileId::Inode(file), target, String::from("a.txt"))
+            .is_err());
+        assert_eq!(manager.resolve_path("/a.txt"), Some(file));
+    }
+    assert!(manager.is_pseudo_dir(search));
+    assert!(manager
+        .rename(&FileId::Inode(file), search, String::from("a.txt"))
+        .is_err());
+    assert_eq!(manager.resolve_path("/a.txt"), Some(file));
+
+    // Real folders and Trash are still valid targets.
+    assert!(!manager.is_pseudo_dir(1));
+    assert!(!manager.is_pseudo_dir(2));
+    assert!(!manager.is_pseudo_dir(folder));
+    assert!(!manager.is_pseudo_dir(file));
+}
+
+#[test]
+fn drive_connections_use_the_configured_keepalive() {
+    let config = Config {
+        tcp_keepalive_seconds: Some(45),
+        ..test_config()
+    };
+    assert_eq!(config.tcp_keepalive(), Some(Duration::from_secs(45)));
+    assert_eq!(test_config().tcp_keepalive(), None);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let keepalive = |connector: KeepaliveConnector| {
+        let stream = connector.connect("127.0.0.1", port, "http").unwrap();
+        Socket::from(stream.0).keepalive().unwrap()
+    };
+
+    assert_eq!(
+        keepalive(KeepaliveConnector::new(config.tcp_keepalive())),
+        Some(Duration::from_secs(45))
+    );
+    assert_eq!(keepalive(KeepaliveConnector::new(None)), None);
+}
+
+#[test]
+fn tree_lists_special_directories_up_to_depth() {
+    let mut manager = test_manager();
+    manager.add_shared_dir().unwrap();
+    manager.add_search_dir().unwrap();
+    manager.add_status_dir().unwrap();
+
+    let tree = manager.tree(None);
+    for name in &[
+        "Shared with me",
+        ".search",
+        ".gcsf",
+        "transfers",
+        "pending-deletes",
+    ] {
+        assert!(tree.contains(&format!("=> {}\n", name)), "{}", name);
+    }
+    assert!(format!("{:?}", manager).contains(&tree));
+
+    let shallow = manager.tree(Some(1));
+    assert!(shallow.contains("=> .gcsf\n"));
+    assert!(!shallow.contains("transfers"));
+    assert_eq!(manager.tree(Some(0)).lines().count(), 1);
+}
+
+#[test]
+fn expired_shares_are_hidden_after_a_permission_error() {
+    let config = Config {
+        hide_expired_shares: Some(true),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &Config::default());
+    manager.add_file_locally(root, None).unwrap();
+    manager.add_shared_dir().unwrap();
+    let shared = manager.resolve_path("/Shared with me").unwrap();
+    let expired = manager.next_available_inode();
+    let file = File::from_drive_file(expired, drive_file("s", "expired.txt", None), &config);
+    manager
+        .add_file_locally(file, Some(FileId::Inode(shared)))
+        .unwrap();
+    add_file(
+        &mut manager,
+        drive_file("o", "own.txt", Some("root")),
+        "root",
+    );
+
+    let denied = err_msg("Downloading bytes 0-9 failed: 403 Forbidden");
+    manager.df.note_read_error("s", &denied);
+    manager.df.note_read_error("o", &denied);
+    manager
+        .df
+        .note_read_error("x", &err_msg("Downloading bytes 0-9 failed: 404 Not Found"));
+    assert!(manager.df.is_denied("s"));
+    assert!(!manager.df.is_denied("x"));
+
+    manager.prune_expired_shares();
+    assert!(!manager.contains(&FileId::Inode(expired)));
+    assert!(manager.resolve_path("/own.txt").is_some());
+    assert!(!manager.df.is_denied("s"));
+
+    assert!(is_permission_error(
+        "BadRequest(ErrorResponse { error: ServerError { code: 403, message: \"The user does not have sufficient permissions for this file.\", errors: [ServerMessage { reason: \"insufficientFilePermissions\" }] } })"
+    ));
+    assert!(!is_permission_error(
+        "BadRequest(ErrorResponse { error: ServerError { code: 403, errors: [ServerMessage { reason: \"userRateLimitExceeded\" }] } })"
+    ));
+}
+
+#[test]
+fn denied_reads_of_removed_files_do_not_break_syncs() {
+    let (url, _) = mock_drive_api(|request_line| {
+        if request_line.contains("/changes/startPageToken") {
+            String::from(r#"{"startPageToken":"1"}"#)
+        } else if request_line.contains("/changes?") {
+            String::from(r#"{"newStartPageToken":"1","changes":[]}"#)
+        } else {
+            String::from("{}")
+        }
+    });
+    let config = Config {
+        hide_expired_shares: Some(true),
+        ..mock_api_config(url, "denied-then-removed")
+    };
+
+    let (removed, still_denied) = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        manager.sync_interval = Duration::from_secs(0);
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        manager.add_shared_dir().unwrap();
+        let shared = manager.resolve_path("/Shared with me").unwrap();
+        let inode = manager.next_available_inode();
+        let file = File::from_drive_file(inode, drive_file("s", "expired.txt", None), &config);
+        manager
+            .add_file_locally(file, Some(FileId::Inode(shared)))
+            .unwrap();
+
+        // The read of the file is denied and then the file is removed, while a denied read of
+        // an id which was never mapped is pending as well.
+        let denied = err_msg("Downloading bytes 0-9 failed: 403 Forbidden");
+        manager.df.note_read_error("s", &denied);
+        manager.df.note_read_error("unknown", &denied);
+        manager
+            .delete_locally(&FileId::DriveId(String::from("s")))
+            .unwrap();
+
+        let _ = manager.sync();
+        (
+            manager.resolve_path("/Shared with me/expired.txt").is_none(),
+            manager.df.is_denied("s") || manager.df.is_denied("unknown"),
+        )
+    });
+
+    assert!(removed);
+    assert!(!still_denied);
+}
+
+#[test]
+fn spreadsheets_exported_as_xlsx_keep_their_binary_content() {
+    let config = Config {
+        export_formats: Some(ExportFormats {
+            spreadsheet: Some(String::from("xlsx")),
+            ..Default::default()
+        }),
+        add_extensions_to_special_files: Some(true),
+        ..test_config()
+    };
+    let sheet_mime = "application/vnd.google-apps.spreadsheet";
+    assert_eq!(
+        config.export_type(sheet_mime),
+        Some(String::from(
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        ))
+    );
+
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &Config::default());
+    manager.add_file_locally(root, None).unwrap();
+    let inode = manager.next_available_inode();
+    let sheet = File::from_drive_file(
+        inode,
+        drive3::File {
+            mime_type: Some(sheet_mime.to_string()),
+            size: None,
+            ..drive_file("sheet", "Budget", Some("root"))
+        },
+        &config,
+    );
+    manager
+        .add_file_locally(sheet, Some(FileId::DriveId(String::from("root"))))
+        .unwrap();
+    assert!(manager.resolve_path("/Budget#.xlsx").is_some());
+
+    // An XLSX workbook is a zip archive with one entry per tab.
+    let mut workbook = b"PK\x03\x04\x14\x00\x00\x00\x08\x00".to_vec();
+    for tab in &["xl/worksheets/sheet1.xml", "xl/worksheets/sheet2.xml"] {
+        workbook.extend_from_slice(tab.as_bytes());
+        workbook.extend_from_slice(&[0x00, 0xff, 0x0d, 0x0a, 0x1a, 0x80]);
+    }
+    workbook.extend_from_slice(b"PK\x05\x06");
+    manager.df.cache_content("sheet", &workbook);
+
+    let len = workbook.len();
+    let read = manager
+        .df
+        .read("sheet", Some(sheet_mime.to_string()), 0, 4096)
+        .unwrap()
+        .to_vec();
+    assert_eq!(&read[..4], b"PK\x03\x04");
+    assert_eq!(read, workbook);
+
+    assert_eq!(
+        manager.get_file(&FileId::Inode(inode)).unwrap().attr.size,
+        10 * 1024 * 1024
+    );
+    manager.correct_size(inode);
+    assert_eq!(
+        manager.get_file(&FileId::Inode(inode)).unwrap().attr.size,
+        len as u64
+    );
+}
+
+#[test]
+fn editor_temp_files_are_only_uploaded_once_renamed() {
+    assert!(is_editor_temp_name("notes.txt.swp"));
+    assert!(is_editor_temp_name("notes.txt~"));
+    assert!(is_editor_temp_name(".#notes.txt"));
+    assert!(is_editor_temp_name("#notes.txt#"));
+    assert!(!is_editor_temp_name("notes.txt"));
+    assert!(!is_editor_temp_name("#"));
+
+    let config = Config {
+        defer_temp_uploads: Some(true),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &Config::default());
+    manager.add_file_locally(root, None).unwrap();
+    let notes = add_file(
+        &mut manager,
+        drive_file("n", "notes.txt", Some("root")),
+        "root",
+    );
+
+    // Creating the temp file would hang on the OAuth flow if it reached Drive.
+    let inode = manager.next_available_inode();
+    let temp = File::from_drive_file(inode, drive_file("", "notes.txt~", Some("root")), &config);
+    manager.create_file(temp, Some(FileId::Inode(1))).unwrap();
+    let temp = FileId::Inode(inode);
+    assert!(manager.is_deferred(&temp));
+    let local_id = manager.get_drive_id(&temp).unwrap();
+
+    let fh = manager.open(inode, O_WRONLY as u32);
+    manager
+        .write_through_handle(fh, inode, 0, b"new notes")
+        .unwrap();
+    manager.release(fh).unwrap();
+    manager.flush(&temp).unwrap();
+    assert!(manager.df.has_pending_writes(&local_id));
+
+    manager.rename(&temp, 1, String::from("notes.txt")).unwrap();
+
+    assert_eq!(manager.resolve_path("/notes.txt"), Some(notes));
+    assert!(!manager.contains(&temp));
+    assert_eq!(manager.get_children(&FileId::Inode(1)).unwrap().len(), 1);
+    assert!(!manager.df.is_deferred(&local_id));
+    assert!(!manager.df.has_pending_writes(&local_id));
+    assert!(manager.df.has_pending_writes("n"));
+    assert_eq!(
+        manager.get_file(&FileId::Inode(notes)).unwrap().attr.size,
+        9
+    );
+    assert_eq!(manager.df.read("n", None, 0, 100), Some(&b"new notes"[..]));
+}
+
+#[test]
+fn flushing_unchanged_content_does_not_upload() {
+    let config = test_config();
+    let mut df = DriveFacade::new(&config);
+    df.cache_content("f", b"hello world");
+
+    // Uploading would hang on the OAuth flow, so each flush must return without reaching Drive.
+    df.write(String::from("f"), 0, b"hello");
+    df.write(String::from("f"), 6, b"world");
+    df.write(String::from("f"), 0, b"j");
+    df.write(String::from("f"), 0, b"h");
+    assert!(df.has_pending_writes("f"));
+    for _ in 0..3 {
+        df.flush("f").unwrap();
+        assert!(!df.has_pending_writes("f"));
+    }
+    assert_eq!(df.read("f", None, 0, 100), Some(&b"hello world"[..]));
+
+    let mut manager = test_manager();
+    let inode = add_file(&mut manager, drive_file("g", "g.txt", Some("root")), "root");
+    manager.df.cache_content("g", b"abc");
+    manager.write(FileId::Inode(inode), 1, b"b").unwrap();
+    manager.flush(&FileId::Inode(inode)).unwrap();
+    manager.flush(&FileId::Inode(inode)).unwrap();
+    assert!(!manager.df.has_pending_writes("g"));
+}
+
+#[test]
+fn duplicates_are_numbered_in_listing_order() {
+    let config = Config {
+        rename_identical_files: Some(true),
+        list_order: Some(String::from("createdTime")),
+        ..test_config()
+    };
+    assert_eq!(config.list_order(), Some(String::from("createdTime")));
+
+    // Drive returns the same order on every mount when `orderBy` is set.
+    let listed = vec![
+        drive_folder("d", "docs", Some("root")),
+        drive_file("a", "report.pdf", Some("root")),
+        drive_file("b", "report.pdf", Some("d")),
+        drive_file("c", "report.pdf", Some("root")),
+        drive_file("e", "notes.txt", Some("d")),
+    ];
+    let names = || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &Config::default());
+        manager.add_file_locally(root, None).unwrap();
+        manager.add_shared_dir().unwrap();
+        manager.add_listed_files(listed.clone()).unwrap();
+
+        ["a", "b", "c"]
+            .iter()
+            .map(|id| {
+                let file = manager.get_file(&FileId::DriveId(id.to_string())).unwrap();
+                (manager.display_name(file), manager.tree(None))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let first = names();
+    assert_eq!(first, names());
+    assert_eq!(first[0].0, "report.pdf");
+    assert_eq!(first[1].0, "report.pdf.1");
+    assert_eq!(first[2].0, "report.pdf.2");
+}
+
+#[test]
+fn reads_correct_stale_sizes() {
+    let mut manager = test_manager();
+    let grown = add_file(
+        &mut manager,
+        drive3::File {
+            size: Some(String::from("5")),
+            ..drive_file("g", "grown.txt", Some("root"))
+        },
+        "root",
+    );
+    let shrunk = add_file(
+        &mut manager,
+        drive3::File {
+            size: Some(String::from("100")),
+            ..drive_file("s", "shrunk.txt", Some("root"))
+        },
+        "root",
+    );
+    let size =
+        |manager: &FileManager, ino| manager.get_file(&FileId::Inode(ino)).unwrap().attr.size;
+
+    // The files were edited on Drive, but no sync has happened since.
+    manager.df.cache_content("g", b"hello, world");
+    manager.df.cache_content("s", b"bye");
+    assert_eq!(
+        manager.df.read("g", None, 0, 4096),
+        Some(&b"hello, world"[..])
+    );
+    manager.correct_size(grown);
+    assert_eq!(size(&manager, grown), 12);
+    manager.correct_size(shrunk);
+    assert_eq!(size(&manager, shrunk), 3);
+
+    // Unsaved writes determine the size until they are uploaded.
+    manager.write(FileId::Inode(shrunk), 3, b"!!").unwrap();
+    manager
+        .get_mut_file(&FileId::Inode(shrunk))
+        .unwrap()
+        .attr
+        .size = 5;
+    manager.correct_size(shrunk);
+    assert_eq!(size(&manager, shrunk), 5);
+}
+
+#[test]
+fn requests_in_flight_never_exceed_the_limit() {
+    let config = Config {
+        global_api_concurrency: Some(2),
+        ..test_config()
+    };
+    assert_eq!(config.global_api_concurrency(), Some(2));
+    assert_eq!(
+        Config {
+            global_api_concurrency: Some(0),
+            ..test_config()
+        }
+        .global_api_concurrency(),
+        None
+    );
+
+    let limiter = Arc::new(RequestLimiter::new(config.global_api_concurrency()));
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let limiter = Arc::clone(&limiter);
+            let max_in_flight = Arc::clone(&max_in_flight);
+            thread::spawn(move || {
+                let _permit = RequestLimiter::acquire(&limiter);
+                max_in_flight.fetch_max(limiter.in_flight(), Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(max_in_flight.load(Ordering::SeqCst) <= 2);
+    assert_eq!(limiter.in_flight(), 0);
+
+    // Connections hold their permit until they are closed.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let limiter = Arc::new(RequestLimiter::new(Some(1)));
+    let connector = Arc::new(LimitedConnector::new(
+        KeepaliveConnector::new(None),
+        Arc::clone(&limiter),
+    ));
+    let first = connector.connect("127.0.0.1", port, "http").unwrap();
+    assert_eq!(limiter.in_flight(), 1);
+
+    let second = {
+        let connector = Arc::clone(&connector);
+        thread::spawn(move || connector.connect("127.0.0.1", port, "http").is_ok())
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(limiter.in_flight(), 1);
+    drop(first);
+    assert!(second.join().unwrap());
+    assert_eq!(limiter.in_flight(), 0);
+}
+
+#[test]
+fn prefetch_caches_the_files_under_a_path() {
+    let config = Config {
+        cache_dir: Some(env::temp_dir().join("gcsf-tests-prefetch")),
+        ..test_config()
+    };
+    let _ = fs::remove_dir_all(config.cache_dir().unwrap());
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &Config::default());
+    manager.add_file_locally(root, None).unwrap();
+    let docs = add_file(
+        &mut manager,
+        drive_folder("docs", "docs", Some("root")),
+        "root",
+    );
+    add_file(&mut manager, drive_file("a", "a.txt", Some("docs")), "docs");
+    add_file(&mut manager, drive_file("b", "b.txt", Some("docs")), "docs");
+    add_file(
+        &mut manager,
+        drive_folder("sub", "sub", Some("docs")),
+        "docs",
+    );
+    add_file(&mut manager, drive_file("c", "c.txt", Some("sub")), "sub");
+    add_file(
+        &mut manager,
+        drive_file("other", "other.txt", Some("root")),
+        "root",
+    );
+
+    let ids = |targets: Vec<(String, Option<String>)>| {
+        let mut ids: Vec<String> = targets.into_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(ids(manager.prefetch_targets(docs, false)), vec!["a", "b"]);
+    assert_eq!(
+        ids(manager.prefetch_targets(docs, true)),
+        vec!["a", "b", "c"]
+    );
+
+    // Stands in for downloading each file into the disk cache.
+    let worker_config = config.clone();
+    let failures = prefetch_concurrently(
+        manager.prefetch_targets(docs, true),
+        2,
+        move || DriveFacade::new(&worker_config),
+        |df, id, _mime| {
+            df.cache_content(id, format!("content of {}", id).as_bytes());
+            Ok(())
+        },
+    );
+    assert!(failures.is_empty());
+
+    let mut df = DriveFacade::new(&config);
+    let mut cached = df.disk_cached_ids();
+    cached.sort();
+    assert_eq!(cached, vec!["a", "b", "c"]);
+    assert_eq!(df.cached_content("c"), Some(b"content of c".to_vec()));
+    // Files which are cached already are not downloaded again.
+    assert!(!df.prefetch("a", None).unwrap());
+
+    let failures = prefetch_concurrently(
+        vec![(String::from("x"), None)],
+        4,
+        || (),
+        |_, id, _| Err(err_msg(format!("{} is gone", id))),
+    );
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].0, "x");
+}
+
+#[test]
+fn files_and_folders_with_the_same_name_are_both_accessible() {
+    let mut manager = test_manager();
+    let folder = add_file(
+        &mut manager,
+        drive_folder("d", "report", Some("root")),
+        "root",
+    );
+    let file = add_file(
+        &mut manager,
+        drive_file("f", "report", Some("root")),
+        "root",
+    );
+    add_file(&mut manager, drive_file("i", "index.md", Some("d")), "d");
+
+    assert_eq!(manager.resolve_path("/report"), Some(folder));
+    assert_eq!(manager.resolve_path("/report.1"), Some(file));
+    assert!(manager.resolve_path("/report/index.md").is_some());
+
+    let mut names: Vec<String> = manager
+        .get_children(&FileId::Inode(1))
+        .unwrap()
+        .into_iter()
+        .map(|child| manager.display_name(child))
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["report", "report.1"]);
+
+    // Files only collide with folders which end up next to them.
+    let mut manager = test_manager();
+    manager.add_shared_dir().unwrap();
+    manager
+        .add_listed_files(vec![
+            drive_folder("x", "notes", Some("root")),
+            drive_folder("d", "docs", Some("root")),
+            drive_file("n", "notes", Some("d")),
+        ])
+        .unwrap();
+    assert!(manager.resolve_path("/notes").is_some());
+    assert!(manager.resolve_path("/docs/notes").is_some());
+}
+
+#[test]
+fn max_read_and_write_bytes_replace_the_mount_options() {
+    let config = Config {
+        mount_options: Some(vec![
+            String::from("fsname=GCSF"),
+            String::from("max_write=131072"),
+        ]),
+        max_read_bytes: Some(262_144),
+        max_write_bytes: Some(1_048_576),
+        ..Default::default()
+    };
+    let options = config.mount_options();
+    assert!(has_mount_option(&options, "max_read=262144"));
+    assert!(has_mount_option(&options, "max_write=1048576"));
+    assert!(!has_mount_option(&options, "max_write=131072"));
+    assert!(has_mount_option(&options, "big_writes"));
+
+    // The fuse crate can't receive writes larger than 16 MiB.
+    let config = Config {
+        max_write_bytes: Some(u32::max_value()),
+        ..Default::default()
+    };
+    assert!(has_mount_option(
+        &config.mount_options(),
+        "max_write=16777216"
+    ));
+}
+
+#[test]
+fn sessions_of_the_same_account_are_detected() {
+    assert!(same_account("0123", "0123"));
+    assert!(same_account("0123\n", " 0123"));
+    assert!(!same_account("0123", "4567"));
+    assert!(!same_account("", ""));
+
+    let dir = env::temp_dir().join("gcsf-tests-accounts");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let session = |name: &str| Config {
+        config_dir: Some(dir.clone()),
+        session_name: Some(String::from(name)),
+        ..Default::default()
+    };
+
+    record_account(&session("work"), "0123").unwrap();
+    record_account(&session("personal"), "4567").unwrap();
+    // Left behind by a process which is no longer running.
+    fs::write(dir.join(".crashed.account"), "not-a-pid\n0123\n").unwrap();
+
+    assert_eq!(
+        sessions_sharing_account(&session("backup"), "0123"),
+        vec!["work"]
+    );
+    assert!(sessions_sharing_account(&session("work"), "0123").is_empty());
+    assert!(sessions_sharing_account(&session("backup"), "89ab").is_empty());
+}
+
+#[test]
+fn manifest_lists_every_file_with_its_metadata() {
+    let mut manager = test_manager();
+    add_file(
+        &mut manager,
+        drive_folder("d", "docs", Some("root")),
+        "root",
+    );
+    add_file(
+        &mut manager,
+        drive3::File {
+            size: Some(String::from("5")),
+            mime_type: Some(String::from("text/plain")),
+            md5_checksum: Some(String::from("5d41402abc4b2a76b9719d911017c592")),
+            ..drive_file("h", "hello, world.txt", Some("d"))
+        },
+        "d",
+    );
+
+    let mut json = Vec::new();
+    manager
+        .write_manifest(&mut json, ManifestFormat::Json)
+        .unwrap();
+    let entries: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["path"], "/docs/hello, world.txt");
+    assert_eq!(entries[0]["id"], "h");
+    assert_eq!(entries[0]["size"], 5);
+    assert_eq!(entries[0]["mimeType"], "text/plain");
+    assert_eq!(
+        entries[0]["md5Checksum"],
+        "5d41402abc4b2a76b9719d911017c592"
+    );
+
+    let mut csv = Vec::new();
+    manager
+        .write_manifest(&mut csv, ManifestFormat::Csv)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "path,id,size,mimeType,md5Checksum\n\
+         \"/docs/hello, world.txt\",h,5,text/plain,5d41402abc4b2a76b9719d911017c592\n"
+    );
+}
+
+#[test]
+fn files_restored_on_drive_leave_the_local_trash() {
+    let mut manager = test_manager();
+    manager.add_shared_dir().unwrap();
+    manager.add_trash_dir().unwrap();
+    add_file(
+        &mut manager,
+        drive_folder("d", "docs", Some("root")),
+        "root",
+    );
+    add_file(&mut manager, drive_file("f", "a.txt", Some("d")), "d");
+    add_file(&mut manager, drive_file("g", "b.txt", Some("gone")), "root");
+
+    for id in &["f", "g"] {
+        manager
+            .move_file_to_trash(&FileId::DriveId(id.to_string()), false)
+            .unwrap();
+    }
+    assert!(manager.resolve_path("/Trash/a.txt").is_some());
+    assert!(manager.resolve_path("/docs/a.txt").is_none());
+
+    let untrash = |id: &str, name: &str, parent: &str| drive3::Change {
+        file_id: Some(id.to_string()),
+        file: Some(drive3::File {
+            trashed: Some(false),
+            ..drive_file(id, name, Some(parent))
+        }),
+        ..Default::default()
+    };
+    manager.apply_change(untrash("f", "a.txt", "d")).unwrap();
+    assert!(manager.resolve_path("/Trash/a.txt").is_none());
+    assert!(manager.resolve_path("/docs/a.txt").is_some());
+
+    // The parent of this file is unknown, so it is restored to "Shared with me".
+    manager.apply_change(untrash("g", "b.txt", "gone")).unwrap();
+    assert!(manager.resolve_path("/Trash/b.txt").is_none());
+    assert!(manager.resolve_path("/Shared with me/b.txt").is_some());
+}
+
+#[test]
+fn writes_into_the_reserved_free_space_are_rejected() {
+    let config = Config {
+        reserve_bytes: Some(100),
+        ..Default::default()
+    };
+    let reserve = config.reserve_bytes();
+
+    // 1000 bytes of quota, 850 used and 40 not flushed yet.
+    assert!(!breaches_reserve(850, Some(1000), 40 + 10, reserve));
+    assert!(breaches_reserve(850, Some(1000), 40 + 11, reserve));
+    // Creating an empty file is refused once the reserve is already breached.
+    assert!(breaches_reserve(950, Some(1000), 0, reserve));
+
+    // Without a known limit, there is nothing to protect.
+    assert!(!breaches_reserve(850, None, 1 << 40, reserve));
+    assert_eq!(Config::default().reserve_bytes(), 0);
+}
+
+/// Starts a mock Drive API on a local port, which answers each request with the body which
+/// `respond` returns for its request line. Returns the base URL of the API and the requests it
+/// received, each with its request line, headers and body.
+fn mock_drive_api<F>(respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    mock_drive_api_with_status(move |request_line| ("200 OK", respond(request_line)))
+}
+
+/// Like `mock_drive_api`, but `respond` also returns the status of each response, e.g.
+/// "429 Too Many Requests".
+fn mock_drive_api_with_status<F>(respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+where
+    F: Fn(&str) -> (&'static str, String) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    // Resumable uploads continue at the URL in the Location header of the first response.
+    let upload_session = format!("{}upload-session", url);
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = Arc::clone(&requests);
+    let respond = Arc::new(respond);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let respond = Arc::clone(&respond);
+            let received = Arc::clone(&received);
+            let upload_session = upload_session.clone();
+            // Each connection is answered on its own, so that slow responses do not hold up
+            // concurrent requests.
+            thread::spawn(move || {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                let mut expected_len = None;
+                while expected_len.map_or(true, |len| request.len() < len) {
+                    match io::Read::read(&mut stream, &mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                    if expected_len.is_none() {
+                        // Once the headers are complete, the length of the body is known.
+                        expected_len =
+                            request
+                                .windows(4)
+                                .position(|w| w == b"\r\n\r\n")
+                                .map(|end| {
+                                    let body_len = String::from_utf8_lossy(&request[..end])
+                                        .lines()
+                                        .filter_map(|header| {
+                                            let header = header.to_lowercase();
+                                            header
+                                                .trim_start_matches("content-length:")
+                                                .trim()
+                                                .parse::<usize>()
+                                                .ok()
+                                                .filter(|_| header.starts_with("content-length:"))
+                                        })
+                                        .next()
+                                        .unwrap_or(0);
+                                    end + 4 + body_len
+                                });
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let request_line = request.lines().next().unwrap_or_default().to_string();
+                let (status, body) = respond(&request_line);
+                received.lock().unwrap().push(request);
+
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Location: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    upload_session,
+                    body
+                );
+                let _ = io::Write::write_all(&mut stream, response.as_bytes());
+            });
+        }
+    });
+    (url, requests)
+}
+
+/// A config for a session which talks to the API at `url`. Its token does not expire and is stored
+/// under the hash which yup-oauth2 looks it up by, so that no OAuth flow is started.
+fn mock_api_config(url: String, session_name: &str) -> Config {
+    mock_api_config_with_scopes(url, session_name, &[drive3::Scope::Full])
+}
+
+/// Like `mock_api_config`, but the session has a token for each of `scopes`.
+fn mock_api_config_with_scopes(
+    url: String,
+    session_name: &str,
+    scopes: &[drive3::Scope],
+) -> Config {
+    let dir = env::temp_dir().join("gcsf-tests-mock-api");
+    fs::create_dir_all(&dir).unwrap();
+    let tokens: Vec<String> = scopes
+        .iter()
+        .map(|scope| {
+            let mut hasher = DefaultHasher::new();
+            [scope.as_ref()][..].hash(&mut hasher);
+            format!(
+                r#"{{"hash":{},"scopes":["{}"],"token":{{"access_token":"a","refresh_token":"r","token_type":"Bearer","expires_in":3600,"expires_in_timestamp":4102444800}}}}"#,
+                hasher.finish(),
+                scope.as_ref()
+            )
+        })
+        .collect();
+    fs::write(
+        dir.join(session_name),
+        format!(r#"{{"tokens":[{}]}}"#, tokens.join(",")),
+    )
+    .unwrap();
+
+    Config {
+        config_dir: Some(dir),
+        session_name: Some(session_name.to_string()),
+        api_base_url: Some(url),
+        ..test_config()
+    }
+}
+
+/// Runs `f` in another thread and returns its result. Fails instead of hanging if `f` does not
+/// finish in time, e.g. because it started an OAuth flow after all.
+fn with_timeout<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+    receiver.recv_timeout(Duration::from_secs(30)).unwrap()
+}
+
+#[test]
+fn drive_facade_uses_the_configured_api_base_url() {
+    const FILE_LIST: &str = r#"{"kind":"drive#fileList","files":[{"id":"a","name":"a.txt","mimeType":"text/plain","parents":["root"]},{"id":"b","name":"b.txt","mimeType":"text/plain","parents":["root"]}]}"#;
+
+    let (url, requests) = mock_drive_api(|_| String::from(FILE_LIST));
+    let config = mock_api_config(url, "file-list");
+    assert!(!config.api_base_url().ends_with('/'));
+
+    let names = with_timeout(move || {
+        DriveFacade::new(&config)
+            .get_all_files(None, Some(false))
+            .map(|files| files.into_iter().filter_map(|f| f.name).collect::<Vec<_>>())
+            .map_err(|e| e.to_string())
+    })
+    .unwrap();
+    assert_eq!(names, vec!["a.txt", "b.txt"]);
+
+    let requests = requests.lock().unwrap();
+    assert!(
+        requests[0].starts_with("GET /drive/v3/files?"),
+        "{:?}",
+        requests
+    );
+}
+
+#[test]
+fn created_files_are_parented_to_the_target_folder() {
+    let config = Config {
+        defer_temp_uploads: Some(true),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &Config::default());
+    manager.add_file_locally(root, None).unwrap();
+    manager.add_shared_dir().unwrap();
+    let folder = add_file(
+        &mut manager,
+        drive_folder("d", "docs", Some("root")),
+        "root",
+    );
+
+    // Temp files are only created locally, so creating them does not reach Drive.
+    for (name, parents) in &[
+        ("a.txt~", None),
+        ("b.txt~", Some(vec![String::from("root")])),
+    ] {
+        let inode = manager.next_available_inode();
+        let file = File::from_drive_file(
+            inode,
+            drive3::File {
+                parents: parents.clone(),
+                ..drive_file("", name, None)
+            },
+            &config,
+        );
+        manager
+            .create_file(file, Some(FileId::Inode(folder)))
+            .unwrap();
+
+        let file = manager.get_file(&FileId::Inode(inode)).unwrap();
+        assert_eq!(
+            file.drive_file.as_ref().unwrap().parents,
+            Some(vec![String::from("d")])
+        );
+        assert_eq!(file.drive_parent(), Some(String::from("d")));
+    }
+
+    // "Shared with me" is not a Drive folder.
+    let inode = manager.next_available_inode();
+    let file = File::from_drive_file(inode, drive_file("", "c.txt~", None), &config);
+    assert!(manager.create_file(file, Some(FileId::Inode(3))).is_err());
+    assert!(!manager.contains(&FileId::Inode(inode)));
+}
+
+#[test]
+fn mirror_mode_has_no_special_directories() {
+    let config = Config {
+        mirror_mode: Some(true),
+        root_drive_id: Some(String::from("root")),
+        delete_grace_seconds: Some(60),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    manager
+        .populate_with(vec![
+            drive_folder("d", "docs", Some("root")),
+            drive_file("a", "a.txt", Some("d")),
+            drive_file("s", "shared.txt", Some("someone-elses-folder")),
+        ])
+        .unwrap();
+
+    let names: Vec<String> = manager
+        .get_children(&FileId::Inode(1))
+        .unwrap()
+        .into_iter()
+        .map(|child| manager.display_name(child))
+        .collect();
+    assert_eq!(names, vec!["docs"]);
+    assert!(manager.resolve_path("/docs/a.txt").is_some());
+    assert!(!manager.contains(&FileId::DriveId(String::from("s"))));
+    assert!(!manager.contains(&FileId::Inode(3)));
+    assert!(!manager.has_delete_grace_period());
+
+    // Files trashed remotely disappear instead of moving to Trash.
+    manager
+        .move_file_to_trash(&FileId::DriveId(String::from("a")), false)
+        .unwrap();
+    assert!(manager.resolve_path("/docs/a.txt").is_none());
+    assert!(!manager.contains(&FileId::DriveId(String::from("a"))));
+}
+
+#[test]
+fn changes_without_a_known_parent_go_to_shared_with_me() {
+    let mut manager = test_manager();
+    manager.add_shared_dir().unwrap();
+
+    let change = |file: drive3::File| drive3::Change {
+        file_id: file.id.clone(),
+        file: Some(file),
+        ..Default::default()
+    };
+    manager
+        .apply_change(change(drive_file("a", "a.txt", None)))
+        .unwrap();
+    manager
+        .apply_change(change(drive_file("b", "b.txt", Some("invisible"))))
+        .unwrap();
+    assert!(manager.resolve_path("/Shared with me/a.txt").is_some());
+    assert!(manager.resolve_path("/Shared with me/b.txt").is_some());
+
+    // A later change moves the file once its parent is known.
+    manager
+        .apply_change(change(drive_folder("d", "docs", Some("root"))))
+        .unwrap();
+    manager
+        .apply_change(change(drive_file("b", "b.txt", Some("d"))))
+        .unwrap();
+    assert!(manager.resolve_path("/Shared with me/b.txt").is_none());
+    assert!(manager.resolve_path("/docs/b.txt").is_some());
+}
+
+#[test]
+fn documents_can_be_shown_as_a_directory_of_exports() {
+    let (url, requests) = mock_drive_api(|request| {
+        if request.contains("mimeType=application%2Fpdf") {
+            String::from("pdf export")
+        } else {
+            String::from("docx export")
+        }
+    });
+    let config = Config {
+        multi_export: Some(ExportFormats {
+            document: Some(String::from("pdf, docx")),
+            ..Default::default()
+        }),
+        ..mock_api_config(url, "multi-export")
+    };
+    assert_eq!(
+        config.multi_export_types("application/vnd.google-apps.document"),
+        vec![
+            "application/pdf",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        ]
+    );
+    assert!(config
+        .multi_export_types("application/vnd.google-apps.spreadsheet")
+        .is_empty());
+
+    let contents = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        let document = manager.next_available_inode();
+        let drive_file = drive3::File {
+            mime_type: Some(String::from("application/vnd.google-apps.document")),
+            ..drive_file("r", "Report", Some("root"))
+        };
+        let file = File::from_drive_file(document, drive_file, &config);
+        manager
+            .add_file_locally(file, Some(FileId::Inode(1)))
+            .unwrap();
+        assert_eq!(
+            manager.get_file(&FileId::Inode(document)).unwrap().kind(),
+            FileType::Directory
+        );
+
+        let mut contents = Vec::new();
+        for path in &["/Report/Report.pdf", "/Report/Report.docx"] {
+            let file = manager
+                .get_file(&FileId::Inode(manager.resolve_path(path).unwrap()))
+                .unwrap()
+                .clone();
+            let data = manager
+                .df
+                .read(&file.drive_id().unwrap(), file.mime_type(), 0, 100)
+                .map(|data| String::from_utf8_lossy(data).into_owned());
+            contents.push(data);
+        }
+        contents
+    });
+    assert_eq!(
+        contents,
+        vec![
+            Some(String::from("pdf export")),
+            Some(String::from("docx export"))
+        ]
+    );
+
+    // Both files are exported from the same document.
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests
+        .iter()
+        .all(|request| request.starts_with("GET /drive/v3/files/r/export?")));
+}
+
+#[test]
+fn paused_sync_does_nothing_until_resumed() {
+    let dir = env::temp_dir().join("gcsf-tests-sync-paused");
+    fs::create_dir_all(&dir).unwrap();
+    let config = Config {
+        config_dir: Some(dir),
+        session_name: Some(String::from("paused")),
+        root_drive_id: Some(String::from("root")),
+        ..test_config()
+    };
+    let _ = fs::remove_file(config.sync_paused_file());
+
+    let clock = MockClock(Arc::new(Mutex::new(SystemTime::UNIX_EPOCH)));
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    manager.set_clock(Arc::new(clock.clone()));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+    manager.add_file_locally(root, None).unwrap();
+    manager.add_status_dir().unwrap();
+    let sync_enabled = manager.resolve_path("/.gcsf/sync_enabled").unwrap();
+    assert!(manager.is_sync_enabled_file(sync_enabled));
+    assert_eq!(manager.sync_enabled_status(), "1\n");
+
+    manager.set_sync_paused(true).unwrap();
+    assert_eq!(manager.sync_enabled_status(), "0\n");
+
+    // A sync is due, but it returns before contacting Drive.
+    clock.advance(manager.sync_interval);
+    assert!(manager.sync_due());
+    assert!(manager.sync().is_err());
+    assert_eq!(manager.last_sync, SystemTime::UNIX_EPOCH);
+
+    // The next mount of the session is paused as well.
+    assert!(FileManager::new(&config, DriveFacade::new(&config)).sync_paused());
+
+    manager.set_sync_paused(false).unwrap();
+    assert!(!manager.sync_paused());
+    assert!(!config.sync_paused_file().exists());
+    assert!(!FileManager::new(&config, DriveFacade::new(&config)).sync_paused());
+}
+
+#[test]
+fn empty_drive_mounts_with_only_the_special_directories() {
+    // An empty Drive, whose file lists do not even contain an empty list of files.
+    let (url, requests) = mock_drive_api(|_| String::from(r#"{"kind":"drive#fileList"}"#));
+    let config = Config {
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "empty-drive")
+    };
+
+    let tree = with_timeout(move || {
+        FileManager::with_drive_facade(&config, DriveFacade::new(&config))
+            .map(|manager| {
+                let names = |ino: u64| -> Vec<String> {
+                    let mut names: Vec<String> = manager
+                        .get_children(&FileId::Inode(ino))
+                        .unwrap()
+                        .into_iter()
+                        .map(|child| manager.display_name(child))
+                        .collect();
+                    names.sort();
+                    names
+                };
+                let gcsf = manager.resolve_path("/.gcsf").unwrap();
+                (names(1), names(2), names(3), names(gcsf))
+            })
+            .map_err(|e| e.to_string())
+    })
+    .unwrap();
+
+    assert_eq!(tree.0, vec![".gcsf", ".search", "Shared with me", "Trash"]);
+    assert!(tree.1.is_empty());
+    assert!(tree.2.is_empty());
+    assert_eq!(tree.3, vec!["pending-deletes", "sync_enabled", "transfers"]);
+
+    // Both My Drive and Trash were listed.
+    assert_eq!(requests.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn drive_requests_carry_the_configured_user_agent() {
+    assert!(test_config().user_agent().starts_with("gcsf/"));
+
+    let (url, requests) = mock_drive_api(|request_line| {
+        if request_line.contains("alt=media") {
+            String::from("content")
+        } else {
+            String::from(r#"{"kind":"drive#fileList","files":[]}"#)
+        }
+    });
+    let config = Config {
+        user_agent: Some(String::from("backup-host/1.0")),
+        ..mock_api_config(url, "user-agent")
+    };
+
+    let content = with_timeout(move || {
+        let mut df = DriveFacade::new(&config);
+        df.get_all_files(None, Some(false)).unwrap();
+        df.read_streaming("a", 0, 7).map(<[u8]>::to_vec)
+    });
+    assert_eq!(content, Some(b"content".to_vec()));
+
+    // Both requests made through the Drive hub and direct downloads identify themselves.
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    for request in requests.iter() {
+        assert!(
+            request
+                .lines()
+                .any(|header| header.to_lowercase() == "user-agent: backup-host/1.0"),
+            "{}",
+            request
+        );
+    }
+}
+
+#[test]
+fn moves_recover_from_stale_node_ids() {
+    let mut manager = test_manager();
+    add_file(
+        &mut manager,
+        drive_folder("d", "docs", Some("root")),
+        "root",
+    );
+    let file = add_file(&mut manager, drive_file("a", "a.txt", Some("root")), "root");
+
+    // The node of a deleted file is left behind for "a.txt".
+    add_file(&mut manager, drive_file("t", "t.txt", Some("root")), "root");
+    let stale = manager
+        .get_node_id(&FileId::DriveId(String::from("t")))
+        .unwrap();
+    manager
+        .apply_change(drive3::Change {
+            file_id: Some(String::from("t")),
+            removed: Some(true),
+            file: Some(drive_file("t", "t.txt", Some("root"))),
+            ..Default::default()
+        })
+        .unwrap();
+    manager.node_ids.insert(file, stale.clone());
+
+    manager
+        .update_locally(
+            &FileId::DriveId(String::from("a")),
+            drive_file("a", "a.txt", Some("d")),
+        )
+        .unwrap();
+    assert_eq!(manager.resolve_path("/docs/a.txt"), Some(file));
+    assert_ne!(manager.node_ids[&file], stale);
+}
+
+#[test]
+fn folders_can_not_be_moved_into_themselves() {
+    let mut manager = test_manager();
+    let outer = add_file(
+        &mut manager,
+        drive_folder("o", "outer", Some("root")),
+        "root",
+    );
+    let inner = add_file(&mut manager, drive_folder("i", "inner", Some("o")), "o");
+
+    // Both moves fail locally, before Drive is contacted.
+    assert!(manager
+        .rename(&FileId::Inode(outer), inner, String::from("outer"))
+        .is_err());
+    assert!(manager
+        .rename(&FileId::Inode(outer), outer, String::from("outer"))
+        .is_err());
+    assert_eq!(manager.resolve_path("/outer/inner"), Some(inner));
+    assert_eq!(manager.get_children(&FileId::Inode(1)).unwrap().len(), 1);
+}
+
+#[test]
+fn skip_trash_can_be_overridden_for_a_mount() {
+    let mut config = Config {
+        skip_trash: Some(false),
+        ..test_config()
+    };
+
+    config.override_skip_trash(None);
+    assert!(!FileManager::new(&config, DriveFacade::new(&config)).skip_trash);
+
+    config.override_skip_trash(Some(true));
+    assert!(FileManager::new(&config, DriveFacade::new(&config)).skip_trash);
+
+    // Without a config value, the override still applies.
+    let mut config = test_config();
+    config.override_skip_trash(Some(true));
+    assert!(FileManager::new(&config, DriveFacade::new(&config)).skip_trash);
+}
+
+#[test]
+fn rate_limited_change_pages_are_retried_without_losing_changes() {
+    assert!(is_rate_limit_error(
+        "BadRequest(ErrorResponse { error: ServerError { code: 429, message: \"Rate Limit Exceeded\" } })"
+    ));
+    assert!(is_rate_limit_error("reason: \"userRateLimitExceeded\""));
+    assert!(!is_rate_limit_error("403 Forbidden"));
+
+    let second_page_requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&second_page_requests);
+    let (url, requests) = mock_drive_api_with_status(move |request_line| {
+        if request_line.contains("/changes/startPageToken") {
+            ("200 OK", String::from(r#"{"startPageToken":"1"}"#))
+        } else if request_line.contains("pageToken=1&") {
+            (
+                "200 OK",
+                String::from(r#"{"nextPageToken":"2","changes":[{"fileId":"a","removed":false}]}"#),
+            )
+        } else if request_line.contains("pageToken=2") {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                (
+                    "429 Too Many Requests",
+                    String::from(
+                        r#"{"error":{"errors":[{"domain":"usageLimits","reason":"rateLimitExceeded","message":"Rate Limit Exceeded"}],"code":429,"message":"Rate Limit Exceeded"}}"#,
+                    ),
+                )
+            } else {
+                (
+                    "200 OK",
+                    String::from(
+                        r#"{"newStartPageToken":"3","changes":[{"fileId":"b","removed":false}]}"#,
+                    ),
+                )
+            }
+        } else {
+            (
+                "200 OK",
+                String::from(r#"{"newStartPageToken":"3","changes":[]}"#),
+            )
+        }
+    });
+    let config = mock_api_config(url, "rate-limited-changes");
+
+    let changes = with_timeout(move || {
+        let mut df = DriveFacade::new(&config);
+        df.rate_limit_backoff = Duration::from_millis(1);
+        let first = df.get_all_changes().map_err(|e| e.to_string())?;
+        let second = df.get_all_changes().map_err(|e| e.to_string())?;
+        let ids = |changes: Vec<drive3::Change>| -> Vec<String> {
+            changes.into_iter().filter_map(|c| c.file_id).collect()
+        };
+        Ok::<_, String>((ids(first), ids(second)))
+    })
+    .unwrap();
+
+    // Both pages arrived despite the rate limit, and the next sync starts after the last page.
+    assert_eq!(changes.0, vec!["a", "b"]);
+    assert!(changes.1.is_empty());
+    assert_eq!(second_page_requests.load(Ordering::SeqCst), 2);
+    let requests = requests.lock().unwrap();
+    assert!(requests.last().unwrap().contains("pageToken=3"));
+}
+
+#[test]
+fn descriptions_round_trip_through_drive() {
+    let (url, requests) = mock_drive_api(|_| String::from(r#"{"id":"d"}"#));
+    let config = Config {
+        expose_descriptions: Some(true),
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "descriptions")
+    };
+
+    let descriptions = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        let folder = File::from_drive_file(
+            manager.next_available_inode(),
+            drive_folder("d", "Reports", Some("root")),
+            &config,
+        );
+        manager
+            .add_file_locally(folder, Some(FileId::Inode(1)))
+            .unwrap();
+        let id = FileId::DriveId(String::from("d"));
+
+        let before = manager.description(&id);
+        manager.set_description(&id, "Q3 drafts").unwrap();
+        let after = manager.description(&id);
+        manager.set_description(&id, "").unwrap();
+        (before, after, manager.description(&id))
+    });
+
+    // Missing and empty descriptions both read as absent, i.e. ENODATA.
+    assert_eq!(descriptions, (None, Some(String::from("Q3 drafts")), None));
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].starts_with("PATCH /drive/v3/files/d?"));
+    assert!(requests[0].contains(r#""description":"Q3 drafts""#));
+    assert!(requests[1].contains(r#""description":"""#));
+}
+
+#[test]
+fn invalid_config_files_are_reported_instead_of_panicking() {
+    let dir = env::temp_dir().join("gcsf-tests-config-files");
+    fs::create_dir_all(&dir).unwrap();
+
+    let valid = dir.join("valid.toml");
+    fs::write(&valid, "sync_interval = 20\nskip_trash = true\n").unwrap();
+    let config = Config::from_file(&valid).unwrap();
+    assert_eq!(config.sync_interval(), Duration::from_secs(20));
+    assert!(config.skip_trash());
+
+    let malformed = dir.join("malformed.toml");
+    fs::write(&malformed, "sync_interval = 20\nskip_trash\n").unwrap();
+    let error = Config::from_file(&malformed).unwrap_err().to_string();
+    assert!(error.contains("malformed.toml"), "{}", error);
+    assert!(error.contains("line 2"), "{}", error);
+
+    let wrong_type = dir.join("wrong_type.toml");
+    fs::write(&wrong_type, "sync_interval = \"soon\"\n").unwrap();
+    let error = Config::from_file(&wrong_type).unwrap_err().to_string();
+    assert!(error.contains("sync_interval"), "{}", error);
+}
+
+#[test]
+fn dot_dot_entries_point_to_the_parent_directory() {
+    assert!(test_config().dot_entries());
+
+    let mut manager = test_manager();
+    let docs = add_file(
+        &mut manager,
+        drive_folder("d", "docs", Some("root")),
+        "root",
+    );
+    let drafts = add_file(&mut manager, drive_folder("e", "drafts", Some("d")), "d");
+    let file = add_file(&mut manager, drive_file("a", "a.txt", Some("e")), "e");
+
+    assert_eq!(manager.parent_inode(1), Some(1));
+    assert_eq!(manager.parent_inode(docs), Some(1));
+    assert_eq!(manager.parent_inode(drafts), Some(docs));
+    assert_eq!(manager.parent_inode(file), Some(drafts));
+    assert_eq!(manager.parent_inode(1000), None);
+}
+
+#[test]
+fn interrupted_populate_still_mounts_and_completes_later() {
+    let second_page_requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&second_page_requests);
+    let (url, _) = mock_drive_api_with_status(move |request_line| {
+        if request_line.contains("%3D+true") || request_line.contains("%3D%20true") {
+            // Trash
+            ("200 OK", String::from(r#"{"files":[]}"#))
+        } else if request_line.contains("pageToken=p2") {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                (
+                    "500 Internal Server Error",
+                    String::from(r#"{"error":{"code":500,"message":"Backend Error"}}"#),
+                )
+            } else {
+                (
+                    "200 OK",
+                    String::from(
+                        r#"{"files":[{"id":"b","name":"b.txt","mimeType":"text/plain","parents":["d"]},{"id":"x","name":"extra","mimeType":"application/vnd.google-apps.folder","parents":["root"]}]}"#,
+                    ),
+                )
+            }
+        } else {
+            (
+                "200 OK",
+                String::from(
+                    r#"{"nextPageToken":"p2","files":[{"id":"d","name":"docs","mimeType":"application/vnd.google-apps.folder","parents":["root"]},{"id":"a","name":"a.txt","mimeType":"text/plain","parents":["d"]},{"id":"c","name":"c.txt","mimeType":"text/plain","parents":["x"]}]}"#,
+                ),
+            )
+        }
+    });
+    let config = Config {
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "interrupted-populate")
+    };
+
+    let paths = |manager: &FileManager| -> Vec<bool> {
+        [
+            "/docs/a.txt",
+            "/Shared with me/c.txt",
+            "/docs/b.txt",
+            "/extra/c.txt",
+            "/.gcsf/transfers",
+            "/Trash",
+        ]
+        .iter()
+        .map(|path| manager.resolve_path(path).is_some())
+        .collect()
+    };
+    let (before, after) = with_timeout(move || {
+        let mut manager =
+            FileManager::with_drive_facade(&config, DriveFacade::new(&config)).unwrap();
+        let before = paths(&manager);
+        manager.complete_populate().unwrap();
+        (before, paths(&manager))
+    });
+
+    // The first page and the special directories are there despite the failed second page.
+    assert_eq!(before, vec![true, true, false, false, true, true]);
+    assert_eq!(after, vec![true, false, true, true, true, true]);
+    assert_eq!(second_page_requests.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn files_are_emptied_from_trash_after_the_retention() {
+    let (url, requests) = mock_drive_api(|_| String::new());
+    let config = Config {
+        trash_auto_empty_days: Some(1),
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "trash-retention")
+    };
+    assert_eq!(
+        config.trash_retention(),
+        Some(Duration::from_secs(24 * 60 * 60))
+    );
+    assert_eq!(
+        Config {
+            trash_auto_empty_days: Some(0),
+            ..test_config()
+        }
+        .trash_retention(),
+        None
+    );
+
+    let trash = with_timeout(move || {
+        let clock = MockClock(Arc::new(Mutex::new(SystemTime::UNIX_EPOCH)));
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        manager.set_clock(Arc::new(clock.clone()));
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        manager.add_trash_dir().unwrap();
+        add_file(
+            &mut manager,
+            drive_file("o", "old.txt", Some("root")),
+            "root",
+        );
+        add_file(
+            &mut manager,
+            drive_file("n", "new.txt", Some("root")),
+            "root",
+        );
+
+        let half_a_day = Duration::from_secs(12 * 60 * 60);
+        manager
+            .move_file_to_trash(&FileId::DriveId(String::from("o")), false)
+            .unwrap();
+        clock.advance(half_a_day);
+        manager
+            .move_file_to_trash(&FileId::DriveId(String::from("n")), false)
+            .unwrap();
+        clock.advance(half_a_day);
+
+        manager.empty_old_trash();
+        (
+            manager.resolve_path("/Trash/old.txt").is_some(),
+            manager.resolve_path("/Trash/new.txt").is_some(),
+        )
+    });
+
+    // Only the file which spent a whole day in Trash is deleted.
+    assert_eq!(trash, (false, true));
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with("DELETE /drive/v3/files/o?"));
+}
+
+#[test]
+fn trash_is_listed_alongside_my_drive() {
+    assert_eq!(Config::default().api_concurrency(), 2);
+
+    // Returns whether Trash was requested while My Drive was still being listed. The listing of
+    // My Drive is held back for a while in order to give a concurrent request for Trash a chance.
+    let populate = |api_concurrency: usize| {
+        let trash_requested = Arc::new((Mutex::new(false), Condvar::new()));
+        let overlapped = Arc::new(Mutex::new(None));
+        let (requested, seen) = (Arc::clone(&trash_requested), Arc::clone(&overlapped));
+        let (url, _) = mock_drive_api(move |request_line| {
+            let (ref lock, ref signal) = *requested;
+            if request_line.contains("%3D+true") || request_line.contains("%3D%20true") {
+                *lock.lock().unwrap() = true;
+                signal.notify_all();
+                String::from(
+                    r#"{"files":[{"id":"t","name":"old.txt","mimeType":"text/plain","parents":["root"]}]}"#,
+                )
+            } else if request_line.starts_with("GET /drive/v3/files?") {
+                let wait = if api_concurrency > 1 { 10 } else { 1 };
+                let (done, _) = signal
+                    .wait_timeout_while(lock.lock().unwrap(), Duration::from_secs(wait), |done| {
+                        !*done
+                    })
+                    .unwrap();
+                seen.lock().unwrap().get_or_insert(*done);
+                String::from(
+                    r#"{"files":[{"id":"a","name":"a.txt","mimeType":"text/plain","parents":["root"]}]}"#,
+                )
+            } else {
+                String::from("{}")
+            }
+        });
+        let config = Config {
+            api_concurrency: Some(api_concurrency),
+            root_drive_id: Some(String::from("root")),
+            ..mock_api_config(url, &format!("trash-concurrency-{}", api_concurrency))
+        };
+        let found = with_timeout(move || {
+            let manager =
+                FileManager::with_drive_facade(&config, DriveFacade::new(&config)).unwrap();
+            ["/a.txt", "/Trash/old.txt"]
+                .iter()
+                .map(|path| manager.resolve_path(path).is_some())
+                .collect::<Vec<_>>()
+        });
+        let overlapped = overlapped.lock().unwrap().unwrap_or(false);
+        (overlapped, found)
+    };
+
+    let (serial, serial_found) = populate(1);
+    let (concurrent, concurrent_found) = populate(2);
+
+    assert_eq!(serial_found, vec![true, true]);
+    assert_eq!(concurrent_found, serial_found);
+    assert!(!serial);
+    assert!(concurrent);
+}
+
+#[test]
+fn content_is_written_by_drive_id() {
+    let (url, requests) = mock_drive_api(|request_line| {
+        if request_line.contains("alt=media") {
+            String::from("\u{0}binary\r\ncontent\u{0}")
+        } else if request_line.contains("/export?") {
+            String::from("exported text")
+        } else if request_line.contains("/files/doc?") {
+            String::from(r#"{"id":"doc","mimeType":"application/vnd.google-apps.document"}"#)
+        } else {
+            String::from(r#"{"id":"bin","mimeType":"application/octet-stream"}"#)
+        }
+    });
+    let config = mock_api_config(url, "cat-id");
+
+    let (binary, exported) = with_timeout(move || {
+        let df = DriveFacade::new(&config);
+        let mut binary = Vec::new();
+        let written = df.write_content("bin", None, &mut binary).unwrap();
+        assert_eq!(written, binary.len() as u64);
+        let mut exported = Vec::new();
+        df.write_content("doc", Some("text/plain"), &mut exported)
+            .unwrap();
+        (binary, exported)
+    });
+
+    assert_eq!(binary, b"\0binary\r\ncontent\0".to_vec());
+    assert_eq!(exported, b"exported text".to_vec());
+    let requests = requests.lock().unwrap();
+    assert!(requests.iter().any(
+        |request| request.starts_with("GET /drive/v3/files/doc/export?")
+            && request.contains("mimeType=text%2Fplain")
+    ));
+}
+
+#[test]
+fn drive_files_added_twice_keep_a_consistent_inode() {
+    let mut manager = test_manager();
+    add_file(&mut manager, drive_folder("a", "a", Some("root")), "root");
+    add_file(&mut manager, drive_folder("b", "b", Some("root")), "root");
+
+    let first = add_file(&mut manager, drive_file("dup", "dup.txt", Some("a")), "a");
+    let second = add_file(&mut manager, drive_file("dup", "dup.txt", Some("b")), "b");
+    assert_ne!(first, second);
+
+    // Lookups by Drive id keep resolving to the first inode.
+    let by_drive_id = FileId::DriveId(String::from("dup"));
+    assert_eq!(manager.get_inode(&by_drive_id), Some(first));
+
+    // Deleting the duplicate leaves the mapping alone.
+    let third = add_file(
+        &mut manager,
+        drive_file("dup", "dup.txt", Some("root")),
+        "root",
+    );
+    manager.delete_locally(&FileId::Inode(third)).unwrap();
+    assert_eq!(manager.get_inode(&by_drive_id), Some(first));
+
+    // Once the first inode is gone, the remaining one takes over instead of leaving a dangling
+    // mapping.
+    manager.delete_locally(&FileId::Inode(first)).unwrap();
+    assert_eq!(manager.get_inode(&by_drive_id), Some(second));
+    assert!(manager.get_file(&by_drive_id).is_some());
+
+    manager.delete_locally(&FileId::Inode(second)).unwrap();
+    assert!(!manager.contains(&by_drive_id));
+}
+
+#[test]
+fn last_known_content_is_served_after_a_failed_sync() {
+    // Drive is unreachable after the change was received.
+    let (url, _) = mock_drive_api_with_status(|_| {
+        (
+            "500 Internal Server Error",
+            String::from(r#"{"error":{"code":500,"message":"Backend Error"}}"#),
+        )
+    });
+    let config = Config {
+        serve_stale_on_sync_error: Some(true),
+        ..mock_api_config(url, "serve-stale")
+    };
+    assert!(!test_config().serve_stale_on_sync_error());
+
+    let (before_failure, after_failure, possibly_stale) = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        manager.sync_interval = Duration::from_secs(0);
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        add_file(&mut manager, drive_file("f", "f.txt", Some("root")), "root");
+        manager.df.cache_content("f", b"last known");
+
+        manager
+            .apply_change(drive3::Change {
+                file_id: Some(String::from("f")),
+                file: Some(drive_file("f", "f.txt", Some("root"))),
+                ..Default::default()
+            })
+            .unwrap();
+        // Without a failed sync, the changed file is not served from its old content.
+        let before_failure = manager.df.read("f", None, 0, 100).map(<[u8]>::to_vec);
+
+        assert!(manager.sync().is_err());
+        let after_failure = manager.df.read("f", None, 0, 100).map(<[u8]>::to_vec);
+        (
+            before_failure,
+            after_failure,
+            manager.df.is_possibly_stale("f"),
+        )
+    });
+
+    assert_eq!(before_failure, None);
+    assert_eq!(after_failure, Some(b"last known".to_vec()));
+    assert!(possibly_stale);
+}
+
+#[test]
+fn inodes_are_offset_by_the_inode_base() {
+    assert_eq!(Config::default().inode_base(), 0);
+    let base = Config {
+        inode_base: Some(1000),
+        ..test_config()
+    }
+    .inode_base();
+
+    // The root, Trash and "Shared with me" are offset like all other files.
+    assert_eq!(kernel_inode(base, 1), 1001);
+    assert_eq!(kernel_inode(base, 2), 1002);
+    assert_eq!(kernel_inode(base, 3), 1003);
+
+    // The kernel refers to the root as 1, but also sees its offset inode in its attributes.
+    assert_eq!(internal_inode(base, 1), 1);
+    assert_eq!(internal_inode(base, 1001), 1);
+    assert_eq!(internal_inode(base, 1002), 2);
+
+    let mut manager = test_manager();
+    let inode = add_file(&mut manager, drive_file("a", "a.txt", Some("root")), "root");
+    assert!(kernel_inode(base, inode) > base);
+    assert_eq!(internal_inode(base, kernel_inode(base, inode)), inode);
+
+    // Inode numbers below the base belong to no file.
+    assert_eq!(internal_inode(base, 500), 0);
+    assert!(!manager.contains(&FileId::Inode(internal_inode(base, 500))));
+
+    // Without a base, inodes are left as they are.
+    assert_eq!(kernel_inode(0, inode), inode);
+    assert_eq!(internal_inode(0, inode), inode);
+}
+
+#[test]
+fn directories_can_be_removed_with_an_inode_base() {
+    let (url, _) = mock_drive_api(|request_line| {
+        if request_line.starts_with("GET /drive/v3/files?") {
+            String::from(
+                r#"{"files":[{"id":"a","name":"a","mimeType":"application/vnd.google-apps.folder","parents":["root"]},{"id":"b","name":"b","mimeType":"application/vnd.google-apps.folder","parents":["a"]}]}"#,
+            )
+        } else {
+            String::from("{}")
+        }
+    });
+    let config = Config {
+        inode_base: Some(1000),
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "rmdir-inode-base")
+    };
+    let base = config.inode_base();
+
+    let (removed, still_listed) = with_timeout(move || {
+        let mut fs = Gcsf::with_config(config).unwrap();
+        let a = fs.manager.resolve_path("/a").unwrap();
+        // The kernel refers to "a" by its offset inode, which rmdir translates exactly once.
+        let removed = fs.remove_entry(internal_inode(base, kernel_inode(base, a)), "b");
+        (removed, fs.manager.resolve_path("/a/b").is_some())
+    });
+
+    assert_eq!(removed, Ok(()));
+    assert!(!still_listed);
+}
+
+#[test]
+fn files_without_a_drive_id_keep_their_writes_locally() {
+    let mut manager = test_manager();
+    manager.add_status_dir().unwrap();
+
+    // A file which was added locally before Drive assigned it an id.
+    let inode = manager.next_available_inode();
+    let file = File::from_drive_file(
+        inode,
+        drive3::File {
+            name: Some(String::from("fresh.txt")),
+            mime_type: Some(String::from("text/plain")),
+            parents: Some(vec![String::from("root")]),
+            ..Default::default()
+        },
+        &test_config(),
+    );
+    manager
+        .add_file_locally(file, Some(FileId::Inode(1)))
+        .unwrap();
+    assert!(manager.get_drive_id(&FileId::Inode(inode)).is_none());
+
+    manager.write(FileId::Inode(inode), 0, b"hello").unwrap();
+    manager.write(FileId::Inode(inode), 5, b" world").unwrap();
+
+    // The writes are kept under a local id until the file is created on Drive.
+    let local_id = manager.get_drive_id(&FileId::Inode(inode)).unwrap();
+    assert!(manager.is_deferred(&FileId::Inode(inode)));
+    assert_eq!(
+        manager.get_inode(&FileId::DriveId(local_id.clone())),
+        Some(inode)
+    );
+    assert_eq!(
+        manager.df.read(&local_id, None, 0, 100),
+        Some(&b"hello world"[..])
+    );
+
+    // Files which are not Drive files can not be written to, but do not bring the file system
+    // down either.
+    let status_dir = manager.resolve_path("/.gcsf").unwrap();
+    assert!(manager.write(FileId::Inode(status_dir), 0, b"x").is_err());
+    assert!(manager.write(FileId::Inode(9999), 0, b"x").is_err());
+}
+
+#[test]
+fn drive_parents_are_exposed_for_multi_parent_files() {
+    let config = Config {
+        expose_parents: Some(true),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+    manager.add_file_locally(root, None).unwrap();
+    manager.add_search_dir().unwrap();
+    add_file(&mut manager, drive_folder("a", "a", Some("root")), "root");
+    add_file(&mut manager, drive_folder("b", "b", Some("root")), "root");
+
+    let shared = add_file(
+        &mut manager,
+        drive3::File {
+            parents: Some(vec![String::from("a"), String::from("b")]),
+            ..drive_file("s", "shared.txt", Some("a"))
+        },
+        "a",
+    );
+    let parents = manager.drive_parents(&FileId::Inode(shared)).unwrap();
+    assert_eq!(
+        parent_xattrs(&parents),
+        vec![
+            (String::from("user.drive.parents"), String::from("a,b")),
+            (String::from("user.drive.parentCount"), String::from("2")),
+        ]
+    );
+
+    // Pseudo-directories have no parents on Drive.
+    let search = manager.resolve_path("/.search").unwrap();
+    assert_eq!(manager.drive_parents(&FileId::Inode(search)), None);
+
+    // Disabled by default.
+    assert!(!test_config().expose_parents());
+    assert_eq!(
+        test_manager().drive_parents(&FileId::DriveId(String::from("root"))),
+        None
+    );
+}
+
+#[test]
+fn hung_syncs_are_given_up_on_and_retried() {
+    let change_requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&change_requests);
+    let (url, _) = mock_drive_api(move |request_line| {
+        if request_line.contains("/changes/startPageToken") {
+            String::from(r#"{"startPageToken":"1"}"#)
+        } else if request_line.contains("/changes?") {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                // The first attempt hangs for longer than the timeout.
+                thread::sleep(Duration::from_secs(4));
+                String::from(r#"{"newStartPageToken":"1","changes":[]}"#)
+            } else {
+                String::from(
+                    r#"{"newStartPageToken":"2","changes":[{"fileId":"n","file":{"id":"n","name":"new.txt","mimeType":"text/plain","parents":["root"]}}]}"#,
+                )
+            }
+        } else {
+            String::from("{}")
+        }
+    });
+    let config = Config {
+        sync_timeout_seconds: Some(1),
+        ..mock_api_config(url, "sync-timeout")
+    };
+    assert_eq!(config.sync_timeout(), Some(Duration::from_secs(1)));
+    assert_eq!(test_config().sync_timeout(), None);
+
+    let (first, elapsed, second, found) = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        manager.sync_interval = Duration::from_secs(0);
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+
+        let start = Instant::now();
+        let first = manager.sync().map_err(|e| e.to_string());
+        let elapsed = start.elapsed();
+        let second = manager.sync().map_err(|e| e.to_string());
+        (
+            first,
+            elapsed,
+            second,
+            manager.resolve_path("/new.txt").is_some(),
+        )
+    });
+
+    assert!(first.is_err());
+    assert!(elapsed < Duration::from_secs(3));
+    // The next sync fetches the changes which the hung one missed.
+    assert_eq!(second, Ok(()));
+    assert!(found);
+    assert_eq!(change_requests.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn control_characters_in_names_are_sanitized() {
+    let config = Config {
+        sanitize_control_chars: Some(true),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+    manager.add_file_locally(root, None).unwrap();
+
+    let original = "evil\nname\u{200B}\u{202E}.txt";
+    let file = File::from_drive_file(
+        manager.next_available_inode(),
+        drive_file("e", original, Some("root")),
+        &config,
+    );
+    manager
+        .add_file_locally(file, Some(FileId::DriveId(String::from("root"))))
+        .unwrap();
+
+    let names: Vec<String> = manager
+        .get_children(&FileId::Inode(1))
+        .unwrap()
+        .into_iter()
+        .map(|child| manager.display_name(child))
+        .collect();
+    assert_eq!(names, vec!["evil_name__.txt"]);
+    assert!(manager.resolve_path("/evil_name__.txt").is_some());
+
+    // Drive operations still see the original name.
+    let file = manager
+        .get_file(&FileId::DriveId(String::from("e")))
+        .unwrap();
+    assert_eq!(
+        file.drive_file.as_ref().unwrap().name,
+        Some(original.to_string())
+    );
+
+    // Names are left alone by default.
+    let unsanitized = File::from_drive_file(10, drive_file("e", original, None), &test_config());
+    assert_eq!(unsanitized.name, original);
+}
+
+#[test]
+fn logins_which_never_complete_time_out() {
+    assert_eq!(
+        Config::default().login_timeout(),
+        Some(Duration::from_secs(300))
+    );
+    assert_eq!(
+        Config {
+            login_timeout_seconds: Some(0),
+            ..Config::default()
+        }
+        .login_timeout(),
+        None
+    );
+
+    // An authenticator which waits forever for the redirect or the code.
+    let (_never_sent, never_received) = mpsc::channel::<()>();
+    let never_received = Mutex::new(never_received);
+    let start = Instant::now();
+    let result = with_login_timeout(Some(Duration::from_millis(200)), move || {
+        let _ = never_received.lock().unwrap().recv();
+        Ok(())
+    });
+    let message = result.unwrap_err().to_string();
+    assert!(message.to_lowercase().contains("authentication timed out"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // Completed logins report their outcome.
+    assert!(with_login_timeout(Some(Duration::from_secs(5)), || Ok(())).is_ok());
+    assert!(with_login_timeout(None, || Err(err_msg("access denied"))).is_err());
+}
+
+#[test]
+fn restoring_from_trash_depends_on_how_files_were_trashed() {
+    let (url, requests) = mock_drive_api(|request_line| {
+        if request_line.starts_with("GET") {
+            String::from(r#"{"parents":["d"]}"#)
+        } else {
+            String::from("{}")
+        }
+    });
+    let config = Config {
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "explicitly-trashed")
+    };
+
+    let (before, after) = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        manager.add_trash_dir().unwrap();
+        // "e.txt" was trashed on its own, "i.txt" because its folder "d" was.
+        for &(id, name, explicitly) in &[("e", "e.txt", true), ("i", "i.txt", false)] {
+            let drive_file = drive3::File {
+                trashed: Some(true),
+                explicitly_trashed: Some(explicitly),
+                ..drive_file(id, name, Some("d"))
+            };
+            let file = File::from_drive_file(manager.next_available_inode(), drive_file, &config);
+            manager
+                .add_file_locally(file, Some(FileId::Inode(2)))
+                .unwrap();
+        }
+
+        let ids = [
+            FileId::DriveId(String::from("e")),
+            FileId::DriveId(String::from("i")),
+        ];
+        let before: Vec<_> = ids
+            .iter()
+            .map(|id| manager.explicitly_trashed(id))
+            .collect();
+        manager.rename(&ids[0], 1, String::from("e.txt")).unwrap();
+        manager.rename(&ids[1], 1, String::from("i.txt")).unwrap();
+        let after: Vec<_> = ids
+            .iter()
+            .map(|id| manager.explicitly_trashed(id))
+            .collect();
+        assert!(manager.resolve_path("/e.txt").is_some());
+        assert!(manager.resolve_path("/i.txt").is_some());
+        (before, after)
+    });
+
+    assert_eq!(before, vec![Some(true), Some(false)]);
+    assert_eq!(after, vec![None, None]);
+
+    let requests = requests.lock().unwrap();
+    let update = |id: &str| {
+        requests
+            .iter()
+            .find(|request| request.starts_with(&format!("PATCH /drive/v3/files/{}?", id)))
+            .cloned()
+            .unwrap()
+    };
+    // The explicitly trashed file is restored itself, while the other one only leaves its trashed
+    // folder, which stays in Trash.
+    let explicit = update("e");
+    assert!(explicit.contains(r#""trashed":false"#));
+    assert!(explicit.contains("removeParents=d"));
+    let inherited = update("i");
+    assert!(!inherited.contains("trashed"));
+    assert!(inherited.contains("removeParents=d"));
+    assert!(inherited.contains("addParents=root"));
+}
+
+#[test]
+fn writes_are_uploaded_once_when_the_last_handle_is_released() {
+    assert!(!Config::default().upload_on_release());
+    let (url, requests) = mock_drive_api(|_| String::from(r#"{"id":"f"}"#));
+    let config = Config {
+        upload_on: Some(String::from("release")),
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "upload-on-release")
+    };
+    let uploads = move || {
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| request.starts_with("PATCH /upload/drive/v3/files/f?"))
+            .count()
+    };
+
+    let (before_release, pending) = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        let inode = add_file(&mut manager, drive_file("f", "f.txt", Some("root")), "root");
+        let id = FileId::Inode(inode);
+
+        // An editor which writes through two handles and closes one of them in between.
+        let first = manager.open(inode, O_WRONLY as u32);
+        let second = manager.open(inode, O_WRONLY as u32);
+        for i in 0..10 {
+            manager.write_through_handle(first, inode, i, b"x").unwrap();
+            manager.flush_on_close(&id).unwrap();
+        }
+        manager.release(second).unwrap();
+        let before_release = manager.df.has_pending_writes("f");
+        manager.release(first).unwrap();
+        (before_release, manager.df.has_pending_writes("f"))
+    });
+
+    assert!(before_release);
+    assert!(!pending);
+    assert_eq!(uploads(), 1);
+}
+
+#[test]
+fn shared_drives_can_be_mounted_as_the_root() {
+    let (url, requests) = mock_drive_api(|request_line| {
+        if !request_line.starts_with("GET /drive/v3/files?") {
+            String::from("{}")
+        } else if request_line.contains("%3D+true") || request_line.contains("%3D%20true") {
+            String::from(
+                r#"{"files":[{"id":"t","name":"old.txt","mimeType":"text/plain","parents":["sd"],"trashed":true}]}"#,
+            )
+        } else {
+            String::from(
+                r#"{"files":[{"id":"a","name":"a.txt","mimeType":"text/plain","parents":["sd"]}]}"#,
+            )
+        }
+    });
+    let config = Config {
+        shared_drive_id: Some(String::from("sd")),
+        ..mock_api_config(url, "shared-drive-root")
+    };
+
+    let (root, found) = with_timeout(move || {
+        let manager = FileManager::with_drive_facade(&config, DriveFacade::new(&config)).unwrap();
+        let found = ["/a.txt", "/Trash/old.txt"]
+            .iter()
+            .map(|path| manager.resolve_path(path).is_some())
+            .collect::<Vec<_>>();
+        (manager.get_drive_id(&FileId::Inode(1)), found)
+    });
+
+    assert_eq!(root, Some(String::from("sd")));
+    assert_eq!(found, vec![true, true]);
+
+    // Both the tree and Trash are listed from the Shared Drive, whose id is also its root id.
+    let requests = requests.lock().unwrap();
+    let listings = requests
+        .iter()
+        .filter(|request| request.starts_with("GET /drive/v3/files?"))
+        .collect::<Vec<_>>();
+    assert_eq!(listings.len(), 2);
+    for listing in listings {
+        let request_line = listing.lines().next().unwrap();
+        assert!(request_line.contains("corpora=teamDrive"));
+        assert!(request_line.contains("teamDriveId=sd"));
+        assert!(!request_line.contains("root"));
+    }
+}
+
+#[test]
+fn heartbeat_summarizes_the_mount() {
+    assert_eq!(Config::default().heartbeat_interval(), None);
+    assert_eq!(
+        Config {
+            heartbeat_interval_seconds: Some(60),
+            ..Config::default()
+        }
+        .heartbeat_interval(),
+        Some(Duration::from_secs(60))
+    );
+
+    let stats = HeartbeatStats {
+        last_sync: SystemTime::UNIX_EPOCH,
+        cached_items: 40,
+        pending_writes: 2,
+    };
+    assert_eq!(
+        heartbeat_summary(&stats, SystemTime::UNIX_EPOCH + Duration::from_secs(12)),
+        "alive: last sync 12s ago, 40 cached items, 2 pending writes"
+    );
+
+    let clock = MockClock(Arc::new(Mutex::new(SystemTime::UNIX_EPOCH)));
+    let mut manager = test_manager();
+    manager.set_clock(Arc::new(clock.clone()));
+    let inode = add_file(&mut manager, drive_file("f", "f.txt", Some("root")), "root");
+    manager.df.cache_content("g", b"cached");
+    manager.write(FileId::Inode(inode), 0, b"unsaved").unwrap();
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(
+        manager.heartbeat(),
+        "alive: last sync 5s ago, 1 cached items, 1 pending writes"
+    );
+}
+
+#[test]
+fn excluded_folders_are_left_out_with_their_children() {
+    let config = Config {
+        exclude_ids: Some(vec![String::from("big")]),
+        root_drive_id: Some(String::from("root")),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    manager
+        .populate_with(vec![
+            drive_folder("big", "Big", Some("root")),
+            drive_folder("sub", "Sub", Some("big")),
+            drive_file("deep", "deep.txt", Some("sub")),
+            drive_file("kept", "kept.txt", Some("root")),
+            // Listed before its parent, so it waits in "Shared with me" at first.
+            drive_file("early", "early.txt", Some("later")),
+            drive_folder("later", "Later", Some("big")),
+        ])
+        .unwrap();
+
+    assert!(manager.resolve_path("/kept.txt").is_some());
+    for id in &["big", "sub", "deep", "early", "later"] {
+        assert!(
+            !manager.contains(&FileId::DriveId(id.to_string())),
+            "{}",
+            id
+        );
+    }
+    assert!(manager.resolve_path("/Big").is_none());
+
+    // Files which later appear inside an excluded folder stay out as well.
+    let change = |id: &str, name: &str, parent: &str| drive3::Change {
+        file_id: Some(id.to_string()),
+        file: Some(drive_file(id, name, Some(parent))),
+        ..Default::default()
+    };
+    manager
+        .apply_change(change("new", "new.txt", "sub"))
+        .unwrap();
+    manager
+        .apply_change(change("kept", "kept.txt", "big"))
+        .unwrap();
+    assert!(!manager.contains(&FileId::DriveId(String::from("new"))));
+    assert!(!manager.contains(&FileId::DriveId(String::from("kept"))));
+}
+
+#[test]
+fn statfs_reports_free_inodes() {
+    let mut manager = test_manager();
+    add_file(&mut manager, drive_file("a", "a.txt", Some("root")), "root");
+    add_file(&mut manager, drive_file("b", "b.txt", Some("root")), "root");
+    let used = manager.files.len() as u64;
+    assert_eq!(used, 3);
+
+    let (files, ffree) = inode_counts(used, Config::default().max_inodes());
+    assert_eq!(files - ffree, used);
+    assert!(ffree > 0);
+
+    let config = Config {
+        max_inodes: Some(1000),
+        ..Config::default()
+    };
+    assert_eq!(inode_counts(used, config.max_inodes()), (1000, 997));
+    // A configured maximum which is used up reports no free inodes instead of wrapping around.
+    assert_eq!(inode_counts(2000, config.max_inodes()), (2000, 0));
+}
+
+#[test]
+fn files_owned_by_others_are_read_only() {
+    let config = Config {
+        readonly_not_owned: Some(true),
+        ..test_config()
+    };
+    let owned_by_me = |id: &str, owned: Option<bool>| drive3::File {
+        owned_by_me: owned,
+        ..drive_file(id, &format!("{}.txt", id), Some("root"))
+    };
+
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+    manager.add_file_locally(root, None).unwrap();
+    let mut inodes = Vec::new();
+    for &(id, owned) in &[
+        ("theirs", Some(false)),
+        ("mine", Some(true)),
+        ("unknown", None),
+    ] {
+        let file = File::from_drive_file(
+            manager.next_available_inode(),
+            owned_by_me(id, owned),
+            &config,
+        );
+        inodes.push(file.inode());
+        manager
+            .add_file_locally(file, Some(FileId::Inode(1)))
+            .unwrap();
+    }
+    let folder = File::from_drive_file(
+        manager.next_available_inode(),
+        drive3::File {
+            owned_by_me: Some(false),
+            ..drive_folder("shared", "shared", Some("root"))
+        },
+        &config,
+    );
+    let folder_inode = folder.inode();
+    manager
+        .add_file_locally(folder, Some(FileId::Inode(1)))
+        .unwrap();
+
+    let perms: Vec<u16> = inodes
+        .iter()
+        .map(|&ino| manager.get_file(&FileId::Inode(ino)).unwrap().attr.perm)
+        .collect();
+    assert_eq!(perms, vec![0o555, 0o755, 0o755]);
+    let read_only: Vec<bool> = inodes
+        .iter()
+        .map(|&ino| manager.is_read_only_not_owned(ino))
+        .collect();
+    assert_eq!(read_only, vec![true, false, false]);
+    assert!(!manager.is_read_only_not_owned(folder_inode));
+
+    // Without the option, files of others stay writable.
+    let theirs = File::from_drive_file(10, owned_by_me("theirs", Some(false)), &test_config());
+    assert_eq!(theirs.attr.perm, 0o755);
+}
+
+#[test]
+fn compressed_disk_cache_round_trips() {
+    assert_eq!(
+        Config::default().cache_compression(),
+        CacheCompression::None
+    );
+    let text = "All work and no play makes Jack a dull boy.\n"
+        .repeat(1000)
+        .into_bytes();
+    let png = [&b"\x89PNG\r\n\x1a\n"[..], &text[..]].concat();
+
+    for &(compression, name) in &[
+        (CacheCompression::Gzip, "gzip"),
+        (CacheCompression::Zstd, "zstd"),
+    ] {
+        let cache_dir = env::temp_dir().join(format!("gcsf-tests-compressed-cache-{}", name));
+        let config = Config {
+            cache_dir: Some(cache_dir),
+            cache_compression: Some(name.to_uppercase()),
+            ..test_config()
+        };
+        assert_eq!(config.cache_compression(), compression);
+
+        let mut df = DriveFacade::new(&config);
+        df.cache_content("text", &text);
+        df.cache_content("image", &png);
+
+        // The text is stored compressed, while the image is stored as it is.
+        let stored = |id: &str| fs::read(config.cache_dir().unwrap().join(id)).unwrap();
+        assert!(stored("text").len() < text.len() / 10);
+        assert_eq!(stored("image"), [&b"GCSF-NONE\n"[..], &png[..]].concat());
+
+        // A new facade has nothing in memory, so the content is read from disk.
+        let mut df = DriveFacade::new(&config);
+        assert_eq!(df.cached_content("text"), Some(text.clone()));
+        assert_eq!(df.cached_content("image"), Some(png.clone()));
+    }
+}
+
+#[test]
+fn content_which_looks_like_a_cache_header_round_trips() {
+    let content = b"GCSF-GZIP\nnot actually compressed".to_vec();
+
+    for &name in &["none", "gzip", "zstd"] {
+        let cache_dir = env::temp_dir().join(format!("gcsf-tests-header-like-cache-{}", name));
+        let config = Config {
+            cache_dir: Some(cache_dir),
+            cache_compression: Some(name.to_string()),
+            ..test_config()
+        };
+
+        DriveFacade::new(&config).cache_content("header", &content);
+        let mut df = DriveFacade::new(&config);
+        assert_eq!(df.cached_content("header"), Some(content.clone()));
+    }
+}
+
+#[test]
+fn listing_the_children_of_a_regular_file_fails() {
+    let mut manager = test_manager();
+    let file = add_file(&mut manager, drive_file("f", "f.txt", Some("root")), "root");
+
+    assert!(manager.get_children(&FileId::Inode(file)).is_none());
+    assert!(manager.get_children_from(&FileId::Inode(file), 0).is_none());
+    assert!(manager
+        .get_children(&FileId::Inode(1))
+        .unwrap()
+        .iter()
+        .any(|child| child.inode() == file));
+}
+
+#[test]
+fn gcsf_mounts_are_recognized() {
+    let proc_mounts = "sysfs /sys sysfs rw,nosuid 0 0\n\
+                       GCSF /home/user/drive fuse rw,nosuid,nodev 0 0\n\
+                       GCSF /home/user/My\\040Drive fuse rw,nosuid,nodev 0 0\n\
+                       sshfs /home/user/remote fuse.sshfs rw 0 0\n";
+    assert!(is_gcsf_mount(proc_mounts, Path::new("/home/user/drive")));
+    assert!(is_gcsf_mount(proc_mounts, Path::new("/home/user/My Drive")));
+    assert!(!is_gcsf_mount(proc_mounts, Path::new("/home/user/remote")));
+    assert!(!is_gcsf_mount(proc_mounts, Path::new("/home/user")));
+
+    let mount_output = "/dev/disk1s1 on / (apfs, local, journaled)\n\
+                        GCSF on /Users/user/My Drive (osxfuse, nodev, nosuid)\n";
+    assert!(is_gcsf_mount(
+        mount_output,
+        Path::new("/Users/user/My Drive")
+    ));
+    assert!(!is_gcsf_mount(mount_output, Path::new("/")));
+}
+
+#[test]
+fn mountpoints_are_resolved_without_accessing_them() {
+    let cwd = Path::new("/home/user");
+    assert_eq!(
+        absolute_mountpoint(cwd, Path::new("drive")),
+        Path::new("/home/user/drive")
+    );
+    assert_eq!(
+        absolute_mountpoint(cwd, Path::new("./mnt/../drive/")),
+        Path::new("/home/user/drive")
+    );
+    assert_eq!(
+        absolute_mountpoint(cwd, Path::new("/mnt/drive")),
+        Path::new("/mnt/drive")
+    );
+
+    // A mountpoint which can not be accessed is still resolved.
+    let missing = env::temp_dir().join("gcsf-tests-missing-mountpoint");
+    assert_eq!(absolute_mountpoint(cwd, &missing), missing);
+}
+
+#[test]
+fn stale_account_records_are_removed() {
+    let dir = env::temp_dir().join("gcsf-tests-stale-accounts");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let config = Config {
+        config_dir: Some(dir.clone()),
+        session_name: Some(String::from("work")),
+        ..Default::default()
+    };
+
+    record_account(&config, "0123").unwrap();
+    fs::write(dir.join(".crashed.account"), "not-a-pid\n0123\n").unwrap();
+
+    assert_eq!(remove_stale_account_records(&config), vec!["crashed"]);
+    assert!(config.account_file().exists());
+    assert!(!dir.join(".crashed.account").exists());
+}
+
+#[test]
+fn folders_beyond_the_max_depth_are_loaded_on_access() {
+    let (url, requests) = mock_drive_api(|_| {
+        String::from(
+            r#"{"files":[
+                {"id":"c","name":"c.txt","parents":["b"],"mimeType":"text/plain"},
+                {"id":"d","name":"d","parents":["b"],"mimeType":"application/vnd.google-apps.folder"}
+            ]}"#,
+        )
+    });
+    let config = Config {
+        max_tree_depth: Some(2),
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "max-tree-depth")
+    };
+
+    let (before, after) = with_timeout(move || {
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        manager
+            .populate_with(vec![
+                drive_folder("a", "a", Some("root")),
+                drive_folder("b", "b", Some("a")),
+                drive_file("c", "c.txt", Some("b")),
+                drive_folder("d", "d", Some("b")),
+                drive_file("e", "e.txt", Some("d")),
+            ])
+            .unwrap();
+        let paths = |manager: &FileManager| -> Vec<bool> {
+            ["/a", "/a/b", "/a/b/c.txt", "/a/b/d", "/a/b/d/e.txt"]
+                .iter()
+                .map(|path| manager.resolve_path(path).is_some())
+                .collect()
+        };
+        let before = paths(&manager);
+
+        // Changes below a folder which is not loaded yet are left for the listing.
+        manager
+            .apply_change(drive3::Change {
+                file_id: Some(String::from("c")),
+                file: Some(drive_file("c", "c.txt", Some("b"))),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(!manager.contains(&FileId::DriveId(String::from("c"))));
+
+        let b = manager.resolve_path("/a/b").unwrap();
+        manager.load_children(&FileId::Inode(b)).unwrap();
+        manager.load_children(&FileId::Inode(b)).unwrap();
+        (before, paths(&manager))
+    });
+
+    assert_eq!(before, vec![true, true, false, false, false]);
+    assert_eq!(after, vec![true, true, true, true, false]);
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn shared_files_with_visible_parents_are_placed_inline() {
+    let config = Config {
+        root_drive_id: Some(String::from("root")),
+        ..test_config()
+    };
+    let shared = |mut file: drive3::File| {
+        file.owned_by_me = Some(false);
+        file
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    manager
+        .populate_with(vec![
+            // Listed before its parent, so it waits in "Shared with me" at first.
+            shared(drive_file("notes", "notes.txt", Some("team"))),
+            shared(drive_folder("team", "Team", Some("root"))),
+            shared(drive_file("orphan", "orphan.txt", Some("hidden"))),
+        ])
+        .unwrap();
+
+    assert!(manager.resolve_path("/Team/notes.txt").is_some());
+    assert!(manager.resolve_path("/Shared with me/notes.txt").is_none());
+    assert!(manager.resolve_path("/Shared with me/orphan.txt").is_some());
+}
+
+#[test]
+fn daemon_files_are_hidden_in_the_config_dir() {
+    let config = test_config();
+    let dir = config.config_dir().clone();
+    assert_eq!(config.log_file(), dir.join(".nonexistent_session.log"));
+    assert_eq!(config.pid_file(), dir.join(".nonexistent_session.pid"));
+}
+
+#[test]
+fn daemonized_mounts_report_their_status_to_the_shell() {
+    let (shell, mount) = UnixStream::pair().unwrap();
+    report_mount_status(mount, false).unwrap();
+    assert_eq!(mount_exit_code(shell), 1);
+
+    // A mount which exits before reporting anything failed as well.
+    let (shell, mount) = UnixStream::pair().unwrap();
+    drop(mount);
+    assert_eq!(mount_exit_code(shell), 1);
+
+    let (shell, mount) = UnixStream::pair().unwrap();
+    report_mount_status(mount, true).unwrap();
+    assert_eq!(mount_exit_code(shell), 0);
+}
+
+#[test]
+fn streamed_reads_are_served_from_the_fetched_chunk() {
+    assert_eq!(test_config().download_chunk_size(), 8 * 1024 * 1024);
+
+    let (url, requests) = mock_drive_api(|_| String::from("0123456789abcdef"));
+    let config = Config {
+        download_chunk_size: Some(16),
+        ..mock_api_config(url, "download-chunks")
+    };
+
+    let (reads, chunk_requests) = with_timeout(move || {
+        let mut df = DriveFacade::new(&config);
+        let mut read = |offset, size| df.read_streaming("a", offset, size).map(<[u8]>::to_vec);
+        let reads = vec![
+            read(4, 4),
+            read(10, 4),
+            // Seeking backward within the chunk.
+            read(0, 4),
+        ];
+        let chunk_requests = requests.lock().unwrap().len();
+        (reads, chunk_requests)
+    });
+
+    assert_eq!(
+        reads,
+        vec![
+            Some(b"4567".to_vec()),
+            Some(b"abcd".to_vec()),
+            Some(b"0123".to_vec()),
+        ]
+    );
+    assert_eq!(chunk_requests, 1);
+}
+
+#[test]
+fn only_registered_handles_can_be_written_through() {
+    let mut manager = test_manager();
+    let a = add_file(&mut manager, drive_file("a", "a.txt", Some("root")), "root");
+    let b = add_file(&mut manager, drive_file("b", "b.txt", Some("root")), "root");
+
+    assert!(!manager.is_open(1, a));
+    let fh = manager.open(a, O_WRONLY as u32);
+    assert!(manager.is_open(fh, a));
+    assert!(!manager.is_open(fh, b));
+    assert!(!manager.is_open(fh + 1, a));
+
+    manager.release(fh).unwrap();
+    assert!(!manager.is_open(fh, a));
+}
+
+#[test]
+fn files_matching_exclude_patterns_are_left_out() {
+    let config = Config {
+        root_drive_id: Some(String::from("root")),
+        ..test_config()
+    };
+    let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+    assert!(manager.set_exclude_patterns(&[String::from("[")]).is_err());
+    manager
+        .set_exclude_patterns(&[String::from("*.tmp"), String::from("/Archive")])
+        .unwrap();
+    manager
+        .populate_with(vec![
+            drive_file("notes", "notes.tmp", Some("root")),
+            drive_file("keep", "keep.txt", Some("root")),
+            // Listed before its parent.
+            drive_file("old", "old.txt", Some("archive")),
+            drive_folder("archive", "Archive", Some("root")),
+            drive_folder("docs", "docs", Some("root")),
+            drive_folder("nested", "Archive", Some("docs")),
+            drive_file("build", "build.tmp", Some("nested")),
+        ])
+        .unwrap();
+
+    for id in &["notes", "old", "archive", "build"] {
+        assert!(
+            !manager.contains(&FileId::DriveId(id.to_string())),
+            "{}",
+            id
+        );
+    }
+    assert!(manager.resolve_path("/keep.txt").is_some());
+    // Patterns starting with "/" only match paths from the root.
+    assert!(manager.resolve_path("/docs/Archive").is_some());
+
+    let change = |id: &str, name: &str, parent: &str| drive3::Change {
+        file_id: Some(id.to_string()),
+        file: Some(drive_file(id, name, Some(parent))),
+        ..Default::default()
+    };
+    manager
+        .apply_change(change("new", "new.txt", "archive"))
+        .unwrap();
+    manager
+        .apply_change(change("keep", "keep.tmp", "root"))
+        .unwrap();
+    assert!(!manager.contains(&FileId::DriveId(String::from("new"))));
+    assert!(!manager.contains(&FileId::DriveId(String::from("keep"))));
+}
+
+#[test]
+fn special_extensions_use_the_configured_separator() {
+    let config = Config {
+        add_extensions_to_special_files: Some(true),
+        special_extension_separator: Some(String::new()),
+        ..test_config()
+    };
+    assert_eq!(test_config().special_extension_separator(), "#");
+
+    let mut sheet = drive_file("sheet", "budget", None);
+    sheet.mime_type = Some(String::from("application/vnd.google-apps.spreadsheet"));
+    let file = File::from_drive_file(4, sheet, &config);
+    assert_eq!(file.name, "budget.ods");
+
+    // Renames keep the extension locally, but not on Drive.
+    assert_eq!(file.drive_name("plan.ods", &config), "plan");
+    assert_eq!(file.drive_name("plan.xlsx", &config), "plan.xlsx");
+    assert_eq!(file.drive_name(".ods", &config), ".ods");
+
+    let plain = File::from_drive_file(5, drive_file("f", "f.ods", None), &config);
+    assert_eq!(plain.drive_name("g.ods", &config), "g.ods");
+}
+
+#[test]
+fn directories_are_created_on_drive_as_folders() {
+    let (url, requests) = mock_drive_api(|_| {
+        String::from(
+            r#"{"id":"made","name":"projects","mimeType":"application/vnd.google-apps.folder"}"#,
+        )
+    });
+    let config = Config {
+        root_drive_id: Some(String::from("root")),
+        ..mock_api_config(url, "create-directory")
+    };
+
+    let (attr, file) = with_timeout(move || {
+        let clock = MockClock(Arc::new(Mutex::new(SystemTime::UNIX_EPOCH)));
+        clock.advance(Duration::from_secs(1_500_000_000));
+        let mut manager = FileManager::new(&config, DriveFacade::new(&config));
+        let root = File::from_drive_file(1, drive_folder("root", ".", None), &config);
+        manager.add_file_locally(root, None).unwrap();
+        manager.set_clock(Arc::new(clock));
+        let attr = manager.create_directory("projects", 1).unwrap();
+        let file = manager.get_file(&FileId::Inode(attr.ino)).unwrap().clone();
+        (attr, file)
+    });
+
+    assert_eq!(attr.kind, FileType::Directory);
+    assert_eq!(attr.perm, 0o755);
+    assert_eq!(attr.nlink, 2);
+    assert_eq!(attr.crtime.sec, 1_500_000_000);
+    assert_eq!(file.drive_id(), Some(String::from("made")));
+    assert_eq!(file.drive_parent(), Some(String::from("root")));
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with("POST /upload/drive/v3/files?"));
+}